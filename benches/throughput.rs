@@ -0,0 +1,143 @@
+use std::io::{BufRead, BufReader};
+
+use auxon_sdk::plugin_utils::ingest::Config;
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use dlt_core::parse::ParsedMessage;
+use modality_dlt::{
+    consume_dlt_storage_header_sync,
+    convert::{dlt_message_to_event_attrs_interned, AttrKeyInterner},
+    mock::MockIngest,
+    read_dlt_message_sync,
+    send::{message_channel, HasCommonConfig, Sender, DEFAULT_BATCH_SIZE},
+    CommonConfig,
+};
+use serde::{Deserialize, Serialize};
+
+/// The recorded capture used by the integration tests
+const CORPUS: &[u8] = include_bytes!("../integration-test/test-data/foo.dlt");
+
+/// The corpus is small, so repeat it to get a batch that resembles a
+/// busy gateway.
+const CORPUS_REPEAT: usize = 100;
+
+fn load_corpus() -> Vec<dlt_core::dlt::Message> {
+    let mut messages = vec![];
+    let mut reader = BufReader::new(CORPUS);
+    while !reader.fill_buf().unwrap().is_empty() {
+        consume_dlt_storage_header_sync(&mut reader).unwrap();
+        if let ParsedMessage::Item(msg) = read_dlt_message_sync(&mut reader).unwrap() {
+            messages.push(msg);
+        }
+    }
+
+    let single_pass = messages.clone();
+    for _ in 1..CORPUS_REPEAT {
+        messages.extend(single_pass.iter().cloned());
+    }
+    messages
+}
+
+#[derive(Serialize, Deserialize)]
+struct BenchConfig {
+    #[serde(flatten)]
+    common: CommonConfig,
+}
+
+impl HasCommonConfig for BenchConfig {
+    fn common_config(&self) -> &CommonConfig {
+        &self.common
+    }
+}
+
+fn config(batch_size: usize) -> Config<BenchConfig> {
+    let mut config = Config::<BenchConfig>::load("MODALITY_DLT_BENCH_").unwrap();
+    config.plugin.common = CommonConfig {
+        timeline_from_application_id: Some(true),
+        batch_size: Some(batch_size),
+        ..Default::default()
+    };
+    config
+}
+
+/// Send `messages` through a [Sender], the way the plugins do: read
+/// from a channel, converted, batched, and sent to the sink.
+async fn send_through(messages: Vec<dlt_core::dlt::Message>, config: Config<BenchConfig>) -> usize {
+    let (tx, mut rx) = message_channel(&config.plugin.common);
+    let mut sender = Sender::new(MockIngest::new(), config);
+    let reader = tokio::spawn(async move {
+        for msg in messages {
+            if tx.send(ParsedMessage::Item(msg)).await.is_err() {
+                break;
+            }
+        }
+    });
+    sender.run(&mut rx).await.unwrap();
+    reader.await.unwrap();
+    sender.sink().timeline_switches
+}
+
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Bytes(CORPUS.len() as u64));
+    group.bench_function("corpus", |b| {
+        b.iter(|| {
+            let mut reader = BufReader::new(CORPUS);
+            while !reader.fill_buf().unwrap().is_empty() {
+                consume_dlt_storage_header_sync(&mut reader).unwrap();
+                black_box(read_dlt_message_sync(&mut reader).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn convert(c: &mut Criterion) {
+    let messages = load_corpus();
+    let mut group = c.benchmark_group("convert");
+    group.throughput(Throughput::Elements(messages.len() as u64));
+    group.bench_function("corpus", |b| {
+        let config = CommonConfig::default();
+        let mut interner = AttrKeyInterner::new();
+        b.iter(|| {
+            for msg in messages.iter() {
+                black_box(dlt_message_to_event_attrs_interned(
+                    msg,
+                    &config,
+                    &mut interner,
+                ));
+            }
+        })
+    });
+    group.finish();
+}
+
+/// The whole send path, against an in-memory sink. A batch size of 1
+/// is what sending each event as it's converted costs; the default
+/// shows what grouping events by timeline saves.
+fn send(c: &mut Criterion) {
+    let messages = load_corpus();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("send");
+    group.throughput(Throughput::Elements(messages.len() as u64));
+    for batch_size in [1, DEFAULT_BATCH_SIZE] {
+        group.bench_with_input(
+            BenchmarkId::new("batch_size", batch_size),
+            &batch_size,
+            |b, batch_size| {
+                b.iter_batched(
+                    || (messages.clone(), config(*batch_size)),
+                    |(messages, config)| black_box(rt.block_on(send_through(messages, config))),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read, convert, send);
+criterion_main!(benches);
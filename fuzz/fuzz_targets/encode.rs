@@ -0,0 +1,42 @@
+#![no_main]
+
+extern crate modality_dlt;
+use dlt_core::{dlt, parse::ParsedMessage};
+use libfuzzer_sys::fuzz_target;
+use modality_dlt::{
+    encode::{encode, encode_framed},
+    stream::{DltStream, Framing},
+};
+
+// Any message which can be read is encoded back into something which
+// reads as the same message, with or without framing.
+fuzz_target!(|data: &[u8]| {
+    let Ok(ParsedMessage::Item(msg)) = modality_dlt::read_dlt_message_sync(data) else {
+        return;
+    };
+    // Trace info isn't kept, so it can't be written back
+    if let dlt::PayloadContent::Verbose(args) = &msg.payload {
+        if args.iter().any(|arg| arg.type_info.has_trace_info) {
+            return;
+        }
+    }
+
+    let bytes = encode(&msg).expect("a message which was read can be encoded");
+    let Ok(ParsedMessage::Item(reread)) = modality_dlt::read_dlt_message_sync(bytes.as_slice())
+    else {
+        panic!("encoded message can't be read back");
+    };
+    assert_eq!(msg, reread);
+
+    for framing in [Framing::Raw, Framing::StorageHeader, Framing::SerialHeader] {
+        let bytes = encode_framed(&msg, framing, None).unwrap();
+        let mut records = DltStream::new(bytes.as_slice(), framing);
+        let record = records.next().expect("one record").expect("a valid record");
+        assert!(records.next().is_none());
+        assert_eq!(record.size, bytes.len() as u64);
+        let ParsedMessage::Item(reread) = record.message else {
+            panic!("encoded message can't be read back");
+        };
+        assert_eq!(msg, reread);
+    }
+});
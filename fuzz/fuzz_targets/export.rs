@@ -0,0 +1,99 @@
+#![no_main]
+
+extern crate modality_dlt;
+use auxon_sdk::api::{AttrKey, AttrVal};
+use dlt_core::{dlt, parse::ParsedMessage};
+use libfuzzer_sys::fuzz_target;
+use modality_dlt::{
+    convert::dlt_message_to_event_attrs, encode::encode, export::event_attrs_to_dlt_message,
+    CommonConfig,
+};
+
+/// The attributes which the export rebuilds messages from
+fn exported_attrs(attrs: &[(AttrKey, AttrVal)]) -> Vec<String> {
+    const KEYS: &[&str] = &[
+        "event.ecu_id",
+        "event.session_id",
+        "event.timestamp",
+        "event.application_id",
+        "event.context_id",
+        "event.message_type",
+        "event.log_level",
+        "event.application_trace_type",
+        "event.network_trace_type",
+        "event.control_type",
+        "event.payload_type",
+        "event.message_id",
+        "event.service_id",
+        "event.status",
+    ];
+    attrs
+        .iter()
+        .filter(|(k, _)| KEYS.contains(&k.as_ref()) || k.as_ref().starts_with("event.payload"))
+        // Debug, so NaNs compare equal
+        .map(|(k, v)| format!("{}={v:?}", k.as_ref()))
+        .collect()
+}
+
+/// Whether the conversion keeps everything needed to rebuild `msg`'s
+/// attributes: ids fit in 4 bytes, and verbose arguments aren't dropped
+/// and have names which can't be mistaken for positions.
+fn exportable(msg: &dlt::Message) -> bool {
+    let id_ok = |id: &str| id.len() <= 4 && !id.contains('\0');
+    let ids_ok = msg.header.ecu_id.as_deref().map_or(true, id_ok)
+        && msg.extended_header.as_ref().map_or(true, |eh| {
+            id_ok(&eh.application_id) && id_ok(&eh.context_id)
+        });
+    let args_ok = match &msg.payload {
+        dlt::PayloadContent::Verbose(args) => args.iter().all(|arg| {
+            let value_ok = match &arg.value {
+                dlt::Value::Raw(_) => false,
+                dlt::Value::U128(x) => *x < i128::MAX as u128,
+                dlt::Value::StringVal(s) => !s.contains('\0'),
+                _ => true,
+            };
+            let name_ok = arg
+                .name
+                .as_deref()
+                .map_or(true, |name| !name.bytes().all(|b| b.is_ascii_digit()));
+            value_ok && name_ok
+        }),
+        _ => true,
+    };
+    ids_ok && args_ok
+}
+
+// Converting a message to attributes, exporting those back to a
+// message, and converting that again gives the same attributes.
+fuzz_target!(|data: &[u8]| {
+    let Ok(ParsedMessage::Item(msg)) = modality_dlt::read_dlt_message_sync(data) else {
+        return;
+    };
+    if !exportable(&msg) {
+        return;
+    }
+
+    let config = CommonConfig::default();
+    let attrs = dlt_message_to_event_attrs(&msg, &config);
+    let is_log = matches!(
+        msg.extended_header.as_ref().map(|eh| &eh.message_type),
+        Some(dlt::MessageType::Log(level)) if !matches!(level, dlt::LogLevel::Invalid(_))
+    );
+    let exported = match event_attrs_to_dlt_message(&attrs) {
+        Ok(exported) => exported,
+        // Log messages with a valid level can always be exported;
+        // other kinds only if they're ones DLT defines
+        Err(e) => {
+            assert!(!is_log, "{e}");
+            return;
+        }
+    };
+
+    let bytes = encode(&exported).expect("exported messages can be encoded");
+    let Ok(ParsedMessage::Item(reparsed)) = modality_dlt::read_dlt_message_sync(bytes.as_slice())
+    else {
+        panic!("exported message can't be read back");
+    };
+    let reparsed_attrs = dlt_message_to_event_attrs(&reparsed, &config);
+    assert_eq!(exported_attrs(&attrs), exported_attrs(&reparsed_attrs));
+});
@@ -0,0 +1,33 @@
+#![no_main]
+
+extern crate modality_dlt;
+use libfuzzer_sys::fuzz_target;
+use modality_dlt::stream::{DltStream, Framing};
+
+// The first two bytes pick the framing and the maximum message size;
+// the rest is the stream.
+fuzz_target!(|data: &[u8]| {
+    let [framing, max_message_size, data @ ..] = data else {
+        return;
+    };
+    let framing = match framing % 3 {
+        0 => Framing::Raw,
+        1 => Framing::StorageHeader,
+        _ => Framing::SerialHeader,
+    };
+    let max_message_size = *max_message_size as usize;
+
+    let mut end = 0;
+    for record in DltStream::new(data, framing).with_max_message_size(max_message_size) {
+        let Ok(record) = record else {
+            continue;
+        };
+
+        // Records don't overlap, are within the input, and are no
+        // larger than allowed
+        assert!(record.offset >= end);
+        end = record.offset + record.size;
+        assert!(end <= data.len() as u64);
+        assert!(record.size as usize <= max_message_size + 16);
+    }
+});
@@ -3,5 +3,16 @@ set -ex
 
 /modality-reflector import --ingest-protocol-parent-url ${INGEST_PROTOCOL_PARENT_URL} dlt /foo.dlt
 
+# Files which end partway through the last record: once in the middle
+# of its storage header, once in the middle of its payload. Both
+# imports should succeed, and report what was cut off.
+head -c 1175 /foo.dlt > /truncated-storage-header.dlt
+head -c 1215 /foo.dlt > /truncated-payload.dlt
+for f in /truncated-storage-header.dlt /truncated-payload.dlt; do
+    MODALITY_DLT_TRUNCATED_MESSAGE_EVENT=true \
+        /modality-reflector import --ingest-protocol-parent-url ${INGEST_PROTOCOL_PARENT_URL} dlt $f
+done
+
 /modality workspace sync-indices
 /conform spec eval --file /imported.speqtr --dry-run
+/conform spec eval --file /truncated.speqtr --dry-run
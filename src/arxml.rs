@@ -0,0 +1,502 @@
+//! Reading SOME/IP service interfaces and DLT message descriptions from
+//! AUTOSAR XML files.
+//!
+//! Service ids and method ids come from `SOMEIP-SERVICE-INTERFACE-DEPLOYMENT`s,
+//! and the methods, events and fields they deploy are looked up in the
+//! `SERVICE-INTERFACE` they refer to.
+//!
+//! Non-verbose message descriptions come from `DLT-MESSAGE`s, with their
+//! application and context ids from the `DLT-APPLICATION`s and
+//! `DLT-CONTEXT`s which refer to them.
+
+use std::collections::HashMap;
+
+use roxmltree::{Document, Node, NodeId};
+
+use crate::{
+    nonverbose::{Conversion, Linear, NonVerboseMessage, NonVerboseMessages, PredefinedText},
+    payload::{DataType, Parameter},
+    someip::{Method, Service, SomeIpServices},
+    xml::{child, child_text, children, descendants, parse_int, path},
+};
+
+/// How deeply data types may nest, to stop cyclic definitions
+const MAX_TYPE_DEPTH: usize = 32;
+
+const SW_DATA_DEF_PROPS: [&str; 3] = [
+    "SW-DATA-DEF-PROPS",
+    "SW-DATA-DEF-PROPS-VARIANTS",
+    "SW-DATA-DEF-PROPS-CONDITIONAL",
+];
+
+/// Add the SOME/IP deployed service interfaces in `doc` to `services`.
+pub(crate) fn parse_services(doc: &Document, services: &mut SomeIpServices) -> Result<(), String> {
+    let index = Index::new(doc.root_element());
+
+    for deployment in descendants(doc.root_element(), "SOMEIP-SERVICE-INTERFACE-DEPLOYMENT") {
+        let Some(service_id) = child_text(deployment, "SERVICE-INTERFACE-ID").and_then(parse_int)
+        else {
+            continue;
+        };
+        let interface = child_text(deployment, "SERVICE-INTERFACE-REF")
+            .and_then(|r| index.get(r))
+            .ok_or_else(|| {
+                format!("service interface deployment for service {service_id} has no interface")
+            })?;
+
+        let mut service = Service {
+            name: short_name(interface).to_owned(),
+            methods: HashMap::new(),
+        };
+
+        for method in descendants(deployment, "SOMEIP-METHOD-DEPLOYMENT") {
+            let (Some(method_id), Some(operation)) = (
+                child_text(method, "METHOD-ID").and_then(parse_int),
+                child_text(method, "METHOD-REF").and_then(|r| index.get(r)),
+            ) else {
+                continue;
+            };
+
+            let mut request = vec![];
+            let mut response = vec![];
+            for arg in descendants(operation, "ARGUMENT-DATA-PROTOTYPE") {
+                let param = index.parameter(arg)?;
+                match child_text(arg, "DIRECTION").unwrap_or("IN") {
+                    "IN" => request.push(param),
+                    "OUT" => response.push(param),
+                    _ => {
+                        request.push(param.clone());
+                        response.push(param);
+                    }
+                }
+            }
+
+            service.methods.insert(
+                method_id as u16,
+                Method {
+                    name: short_name(operation).to_owned(),
+                    request,
+                    response,
+                },
+            );
+        }
+
+        for event in descendants(deployment, "SOMEIP-EVENT-DEPLOYMENT") {
+            let (Some(event_id), Some(prototype)) = (
+                child_text(event, "EVENT-ID").and_then(parse_int),
+                child_text(event, "EVENT-REF").and_then(|r| index.get(r)),
+            ) else {
+                continue;
+            };
+            service.methods.insert(
+                event_id as u16,
+                Method {
+                    name: short_name(prototype).to_owned(),
+                    request: vec![index.parameter(prototype)?],
+                    response: vec![],
+                },
+            );
+        }
+
+        for field in descendants(deployment, "SOMEIP-FIELD-DEPLOYMENT") {
+            let Some(prototype) = child_text(field, "FIELD-REF").and_then(|r| index.get(r)) else {
+                continue;
+            };
+            let field_param = index.parameter(prototype)?;
+            let accessors: [(&str, &str, fn(&Parameter) -> Method); 3] = [
+                ("GET", "METHOD-ID", Method::field_getter),
+                ("SET", "METHOD-ID", Method::field_setter),
+                ("NOTIFIER", "EVENT-ID", Method::field_notifier),
+            ];
+            for (accessor, id_name, make_method) in accessors {
+                let method_id = child(field, accessor)
+                    .and_then(|accessor| child_text(accessor, id_name))
+                    .and_then(parse_int);
+                if let Some(method_id) = method_id {
+                    service
+                        .methods
+                        .insert(method_id as u16, make_method(&field_param));
+                }
+            }
+        }
+
+        services.add_service(service_id as u16, service);
+    }
+
+    Ok(())
+}
+
+/// Add the DLT message descriptions in `doc` to `messages`.
+pub(crate) fn parse_dlt_messages(
+    doc: &Document,
+    messages: &mut NonVerboseMessages,
+) -> Result<(), String> {
+    let root = doc.root_element();
+    let index = Index::new(root);
+
+    // Messages and contexts are either contained in, or referred to by,
+    // the context or application they belong to
+    let mut context_of_message: HashMap<NodeId, Node> = HashMap::new();
+    for context in descendants(root, "DLT-CONTEXT") {
+        for message in index.members(context, "DLT-MESSAGE") {
+            context_of_message.insert(message.id(), context);
+        }
+    }
+    let mut application_of_context: HashMap<NodeId, &str> = HashMap::new();
+    for application in descendants(root, "DLT-APPLICATION") {
+        let Some(application_id) = child_text(application, "APPLICATION-ID") else {
+            continue;
+        };
+        for context in index.members(application, "DLT-CONTEXT") {
+            application_of_context.insert(context.id(), application_id);
+        }
+    }
+
+    for message in descendants(root, "DLT-MESSAGE") {
+        let Some(message_id) = child_text(message, "MESSAGE-ID").and_then(parse_int) else {
+            continue;
+        };
+        let context = context_of_message.get(&message.id());
+
+        let mut description = NonVerboseMessage {
+            name: short_name(message).to_owned(),
+            application_id: context
+                .and_then(|context| application_of_context.get(&context.id()))
+                .map(|id| id.to_string()),
+            context_id: context
+                .and_then(|context| child_text(*context, "CONTEXT-ID"))
+                .map(ToOwned::to_owned),
+            source_file: child_text(message, "MESSAGE-SOURCE-FILE").map(ToOwned::to_owned),
+            source_line: child_text(message, "MESSAGE-LINE-NUMBER")
+                .and_then(parse_int)
+                .map(|line| line as i64),
+            ..Default::default()
+        };
+
+        let arguments = child(message, "DLT-ARGUMENTS")
+            .into_iter()
+            .flat_map(|args| children(args, "DLT-ARGUMENT"));
+        for arg in arguments {
+            if let Some(param) = index.dlt_argument(arg, None, 0, &mut description)? {
+                description.arguments.push(param);
+            }
+        }
+
+        messages.add_message(message_id as u32, description);
+    }
+
+    Ok(())
+}
+
+fn short_name<'a>(node: Node<'a, '_>) -> &'a str {
+    child_text(node, "SHORT-NAME").unwrap_or_default()
+}
+
+/// Every identifiable element in a document, by its AUTOSAR path (the
+/// short names of it and its ancestors, like `/Package/Element`)
+pub(crate) struct Index<'a, 'i> {
+    by_path: HashMap<String, Node<'a, 'i>>,
+}
+
+impl<'a, 'i> Index<'a, 'i> {
+    pub(crate) fn new(root: Node<'a, 'i>) -> Self {
+        let mut by_path = HashMap::new();
+        for node in root.descendants() {
+            if !node.is_element() || child(node, "SHORT-NAME").is_none() {
+                continue;
+            }
+            let mut names: Vec<&str> = node
+                .ancestors()
+                .filter(|n| n.is_element())
+                .filter_map(|n| child_text(n, "SHORT-NAME"))
+                .collect();
+            names.reverse();
+            by_path.insert(format!("/{}", names.join("/")), node);
+        }
+        Index { by_path }
+    }
+
+    /// The element referred to by `reference`
+    pub(crate) fn get(&self, reference: &str) -> Option<Node<'a, 'i>> {
+        self.by_path.get(reference.trim()).copied()
+    }
+
+    /// The elements called `name` which are inside `node`, or which
+    /// `node` refers to
+    fn members(&self, node: Node<'a, 'i>, name: &'a str) -> Vec<Node<'a, 'i>> {
+        let referenced = node
+            .descendants()
+            .filter(|n| n.is_element() && n.tag_name().name().ends_with("-REF"))
+            .filter_map(|n| self.get(n.text()?));
+        descendants(node, name)
+            .filter(|n| *n != node)
+            .chain(referenced.filter(|n| n.tag_name().name() == name))
+            .collect()
+    }
+
+    /// A DLT message argument, as a parameter. Arguments with predefined
+    /// text aren't in the payload, so they're added to `message` instead.
+    /// Conversions for the argument (and any it contains) are added to
+    /// `message` too.
+    fn dlt_argument(
+        &self,
+        arg: Node<'a, 'i>,
+        parent: Option<&str>,
+        depth: usize,
+        message: &mut NonVerboseMessage,
+    ) -> Result<Option<Parameter>, String> {
+        let name = short_name(arg);
+        let full_name = match parent {
+            Some(parent) => format!("{parent}.{name}"),
+            None => name.to_owned(),
+        };
+        if depth > MAX_TYPE_DEPTH {
+            return Err(format!("argument '{full_name}' is nested too deeply"));
+        }
+
+        if let Some(text) = child_text(arg, "PREDEFINED-TEXT") {
+            message.predefined_text.push(PredefinedText {
+                name: full_name,
+                text: text.to_owned(),
+                position: message.arguments.len(),
+            });
+            return Ok(None);
+        }
+
+        if let Some(entries) = child(arg, "DLT-ARGUMENT-ENTRYS") {
+            let mut members = vec![];
+            for entry in children(entries, "DLT-ARGUMENT") {
+                if let Some(member) =
+                    self.dlt_argument(entry, Some(&full_name), depth + 1, message)?
+                {
+                    members.push(member);
+                }
+            }
+            return Ok(Some(Parameter {
+                name: name.to_owned(),
+                data_type: DataType::Struct(members),
+            }));
+        }
+
+        let props = path(
+            arg,
+            &[
+                "NETWORK-REPRESENTATION",
+                "SW-DATA-DEF-PROPS-VARIANTS",
+                "SW-DATA-DEF-PROPS-CONDITIONAL",
+            ],
+        )
+        .ok_or_else(|| format!("argument '{full_name}' has no NETWORK-REPRESENTATION"))?;
+        let props_ref = |ref_name: &str| child_text(props, ref_name);
+
+        let mut data_type = match (
+            props_ref("BASE-TYPE-REF"),
+            props_ref("IMPLEMENTATION-DATA-TYPE-REF"),
+        ) {
+            (Some(base_ref), _) => self.referenced_type(base_ref, depth)?,
+            (None, Some(type_ref)) => self.referenced_type(type_ref, depth)?,
+            (None, None) => return Err(format!("argument '{full_name}' has no type")),
+        };
+        let fixed_len = child_text(arg, "LENGTH").and_then(parse_int);
+        let variable_length = child_text(arg, "VARIABLE-LENGTH") != Some("false");
+        if let (DataType::String { .. }, Some(len), false) =
+            (&data_type, fixed_len, variable_length)
+        {
+            data_type = DataType::String {
+                fixed_len: Some(len as usize),
+            };
+        }
+
+        let compu_method = props_ref("COMPU-METHOD-REF").and_then(|r| self.get(r));
+        let unit = props_ref("UNIT-REF")
+            .or_else(|| compu_method.and_then(|c| child_text(c, "UNIT-REF")))
+            .and_then(|r| self.get(r))
+            .map(|unit| {
+                child_text(unit, "DISPLAY-NAME")
+                    .unwrap_or(short_name(unit))
+                    .to_owned()
+            });
+        let mut conversion = compu_method.map(compu_conversion).unwrap_or_default();
+        conversion.unit = unit;
+        if conversion.unit.is_some()
+            || conversion.linear.is_some()
+            || !conversion.text_table.is_empty()
+        {
+            message.conversions.insert(full_name, conversion);
+        }
+
+        Ok(Some(Parameter {
+            name: name.to_owned(),
+            data_type,
+        }))
+    }
+
+    /// A named, typed element (an argument, event or field), as a
+    /// parameter
+    pub(crate) fn parameter(&self, node: Node<'a, 'i>) -> Result<Parameter, String> {
+        let name = short_name(node);
+        let type_ref =
+            child_text(node, "TYPE-TREF").ok_or_else(|| format!("'{name}' has no TYPE-TREF"))?;
+        Ok(Parameter {
+            name: name.to_owned(),
+            data_type: self.referenced_type(type_ref, 0)?,
+        })
+    }
+
+    fn referenced_type(&self, reference: &str, depth: usize) -> Result<DataType, String> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(format!("type '{reference}' is nested too deeply"));
+        }
+        let node = self
+            .get(reference)
+            .ok_or_else(|| format!("unknown type '{reference}'"))?;
+        match node.tag_name().name() {
+            "SW-BASE-TYPE" => base_type(node),
+            _ => self.implementation_type(node, depth + 1),
+        }
+    }
+
+    /// An implementation data type, or one of its sub-elements, which are
+    /// described in the same way
+    fn implementation_type(&self, node: Node<'a, 'i>, depth: usize) -> Result<DataType, String> {
+        let name = short_name(node);
+        let props = path(node, &SW_DATA_DEF_PROPS);
+        let props_ref = |ref_name: &str| props.and_then(|props| child_text(props, ref_name));
+        let sub_elements = || {
+            child(node, "SUB-ELEMENTS")
+                .into_iter()
+                .flat_map(|s| children(s, "IMPLEMENTATION-DATA-TYPE-ELEMENT"))
+        };
+
+        match child_text(node, "CATEGORY").unwrap_or_default() {
+            "VALUE" => {
+                let base_ref = props_ref("BASE-TYPE-REF")
+                    .ok_or_else(|| format!("value type '{name}' has no BASE-TYPE-REF"))?;
+                self.referenced_type(base_ref, depth)
+            }
+            "TYPE_REFERENCE" => {
+                let type_ref = props_ref("IMPLEMENTATION-DATA-TYPE-REF").ok_or_else(|| {
+                    format!("type reference '{name}' has no IMPLEMENTATION-DATA-TYPE-REF")
+                })?;
+                self.referenced_type(type_ref, depth)
+            }
+            "STRUCTURE" => {
+                let members = sub_elements()
+                    .map(|member| {
+                        Ok(Parameter {
+                            name: short_name(member).to_owned(),
+                            data_type: self.implementation_type(member, depth + 1)?,
+                        })
+                    })
+                    .collect::<Result<_, String>>()?;
+                Ok(DataType::Struct(members))
+            }
+            "ARRAY" => {
+                // The single sub-element describes the elements, and
+                // how many there are
+                let element = sub_elements()
+                    .next()
+                    .ok_or_else(|| format!("array '{name}' has no element type"))?;
+                let size = child_text(element, "ARRAY-SIZE").and_then(parse_int);
+                let semantics = child_text(element, "ARRAY-SIZE-SEMANTICS");
+                let fixed_len = match semantics {
+                    Some("VARIABLE-SIZE") => None,
+                    _ => size.map(|size| size as usize),
+                };
+                Ok(DataType::Array {
+                    element: Box::new(self.implementation_type(element, depth + 1)?),
+                    fixed_len,
+                })
+            }
+            "VECTOR" => {
+                let element = match descendants(node, "TEMPLATE-TYPE-REF").next() {
+                    Some(template_ref) => {
+                        self.referenced_type(template_ref.text().unwrap_or_default(), depth)?
+                    }
+                    None => {
+                        let element = sub_elements()
+                            .next()
+                            .ok_or_else(|| format!("vector '{name}' has no element type"))?;
+                        self.implementation_type(element, depth + 1)?
+                    }
+                };
+                Ok(DataType::Array {
+                    element: Box::new(element),
+                    fixed_len: None,
+                })
+            }
+            "STRING" => Ok(DataType::String { fixed_len: None }),
+            other => Err(format!(
+                "type '{name}' has an unsupported category '{other}'"
+            )),
+        }
+    }
+}
+
+fn base_type(node: Node) -> Result<DataType, String> {
+    let name = short_name(node);
+    let bits = child_text(node, "BASE-TYPE-SIZE")
+        .and_then(parse_int)
+        .unwrap_or_default();
+    let bytes = bits.div_ceil(8) as u8;
+    Ok(
+        match child_text(node, "BASE-TYPE-ENCODING").unwrap_or("NONE") {
+            "BOOLEAN" => DataType::Bool,
+            "2C" => DataType::Signed(bytes),
+            "IEEE754" if bits == 32 => DataType::Float32,
+            "IEEE754" if bits == 64 => DataType::Float64,
+            "UTF-8" | "UTF-16" | "UCS-2" | "ISO-8859-1" | "ISO-8859-2" | "WINDOWS-1252" => {
+                DataType::String { fixed_len: None }
+            }
+            "NONE" if bits > 0 && bits <= 64 => DataType::Unsigned(bytes),
+            other => {
+                return Err(format!(
+                    "base type '{name}' has an unsupported encoding '{other}' ({bits} bits)"
+                ))
+            }
+        },
+    )
+}
+
+/// The text table and linear scaling of a `COMPU-METHOD`
+fn compu_conversion(compu_method: Node) -> Conversion {
+    let mut conversion = Conversion::default();
+    let Some(scales) = path(compu_method, &["COMPU-INTERNAL-TO-PHYS", "COMPU-SCALES"]) else {
+        return conversion;
+    };
+
+    let limit = |scale: Node, name: &str| {
+        child_text(scale, name)
+            .and_then(|limit| limit.parse::<f64>().ok())
+            .map(|limit| limit as i64)
+    };
+    let coefficients = |coeffs: Node, name: &str| -> Vec<f64> {
+        child(coeffs, name)
+            .into_iter()
+            .flat_map(|c| children(c, "V"))
+            .filter_map(|v| v.text()?.trim().parse().ok())
+            .collect()
+    };
+
+    for scale in children(scales, "COMPU-SCALE") {
+        if let Some(text) = path(scale, &["COMPU-CONST", "VT"]).and_then(|vt| vt.text()) {
+            let Some(lower) = limit(scale, "LOWER-LIMIT") else {
+                continue;
+            };
+            let upper = limit(scale, "UPPER-LIMIT").unwrap_or(lower);
+            conversion
+                .text_table
+                .push((lower, upper, text.trim().to_owned()));
+        } else if let Some(coeffs) = child(scale, "COMPU-RATIONAL-COEFFS") {
+            let numerator = coefficients(coeffs, "COMPU-NUMERATOR");
+            let denominator = coefficients(coeffs, "COMPU-DENOMINATOR");
+            conversion.linear = Some(Linear {
+                offset: numerator.first().copied().unwrap_or(0.0),
+                factor: numerator.get(1).copied().unwrap_or(1.0),
+                divisor: denominator.first().copied().unwrap_or(1.0),
+            });
+        }
+    }
+
+    conversion
+}
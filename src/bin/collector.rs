@@ -1,50 +1,35 @@
 use auxon_sdk::{init_tracing, plugin_utils::ingest::Config};
 use modality_dlt::{
-    read_dlt_message,
-    send::{HasCommonConfig, Sender},
+    collect::{Collector, CollectorConfig, DEFAULT_DLT_PORT},
+    metrics::{self, Metrics},
+    sink::PluginSink,
 };
-use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
-use tracing::info;
-
-#[derive(Serialize, Deserialize)]
-struct CollectorConfig {
-    /// The DLT host to connect to (with TCP).
-    ///
-    /// If not given, defaults to "localhost".
-    host: Option<String>,
-
-    /// The TCP port to connect to on the DLT host.
-    ///
-    /// If not given, defaults to 3490.
-    port: Option<u16>,
-
-    #[serde(flatten)]
-    common: modality_dlt::CommonConfig,
-}
-
-impl HasCommonConfig for CollectorConfig {
-    fn common_config(&self) -> &modality_dlt::CommonConfig {
-        &self.common
-    }
-}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     init_tracing!();
     let config = Config::<CollectorConfig>::load("MODALITY_DLT_")?;
 
-    let dlt_host = config.plugin.host.as_deref().unwrap_or("localhost");
-    let dlt_port = config.plugin.port.unwrap_or(3490);
-    let mut dlt_stream = TcpStream::connect((dlt_host, dlt_port)).await?;
-    info!(%dlt_host, %dlt_port, "Connected to DLT server");
-
-    let client = config.connect_and_authenticate().await?;
-    info!("Connected to Modality backend");
-    let mut sender = Sender::new(client, config);
+    let dlt_host = config
+        .plugin
+        .host
+        .clone()
+        .unwrap_or_else(|| "localhost".to_owned());
+    let dlt_port = config.plugin.port.unwrap_or(DEFAULT_DLT_PORT);
+    let metrics = Metrics::new(format!("{dlt_host}:{dlt_port}"));
+    if let Some(metrics_addr) = config.plugin.metrics_addr {
+        metrics::serve(metrics_addr, metrics.clone()).await?;
+    }
 
-    loop {
-        let parsed_msg = read_dlt_message(&mut dlt_stream).await?;
-        sender.handle_message(parsed_msg).await?;
+    let sink = PluginSink::for_config(&config).await?;
+    let dry_run = sink.is_dry_run();
+    let mut collector = Collector::new(config, sink).with_metrics(metrics);
+    if !dry_run {
+        collector = collector.with_spooling();
     }
+    collector
+        .run((dlt_host.as_str(), dlt_port))
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+    Ok(())
 }
@@ -0,0 +1,59 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter},
+    path::PathBuf,
+};
+
+use auxon_sdk::init_tracing;
+use clap::Parser;
+use modality_dlt::export::{event_attrs_from_json, DltFileWriter, ExportError};
+use tracing::{info, warn};
+
+/// Write events exported from a Modality workspace segment (with
+/// `modality log --format json`) to a .dlt file. The segment isn't
+/// queried directly.
+#[derive(clap::Parser)]
+struct ExporterOpts {
+    /// A JSON Lines file of events, one object of event attributes per
+    /// line. If not given, events are read from stdin.
+    #[clap(long)]
+    events: Option<PathBuf>,
+
+    /// The .dlt file to write
+    dlt_file: PathBuf,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init_tracing!();
+    let opts = ExporterOpts::parse();
+
+    let events: Box<dyn BufRead> = match &opts.events {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
+    };
+    let mut writer = DltFileWriter::new(BufWriter::new(File::create(&opts.dlt_file)?));
+
+    info!(file = %opts.dlt_file.display(), "Exporting events to DLT file");
+    let mut written = 0;
+    let mut skipped = 0;
+    for (idx, line) in events.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let res = event_attrs_from_json(&line).and_then(|attrs| writer.write_event(&attrs));
+        match res {
+            Ok(()) => written += 1,
+            Err(e @ ExportError::Io(_)) => return Err(e.into()),
+            Err(e) => {
+                warn!(line = idx + 1, err = %e, "Skipping event which can't be exported");
+                skipped += 1;
+            }
+        }
+    }
+    writer.flush()?;
+
+    info!(written, skipped, "Finished exporting");
+    Ok(())
+}
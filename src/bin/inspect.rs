@@ -0,0 +1,75 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Write},
+    path::PathBuf,
+};
+
+use auxon_sdk::init_tracing;
+use clap::Parser;
+use modality_dlt::{
+    inspect::{Inspection, DEFAULT_MAX_ERRORS},
+    stream::{DltStream, Framing},
+    DEFAULT_MAX_MESSAGE_SIZE,
+};
+
+/// Report what's in a DLT file: which ECUs, applications and contexts
+/// it has messages from, log levels, message types, non-verbose
+/// message ids, time ranges, message counter gaps and parse errors.
+#[derive(clap::Parser)]
+struct InspectOpts {
+    /// What precedes each message in the file
+    #[clap(long, value_enum, default_value_t = FramingArg::StorageHeader)]
+    framing: FramingArg,
+
+    /// How many of the most frequent non-verbose message ids to list
+    #[clap(long, default_value_t = 20)]
+    top: usize,
+
+    /// How many parse errors to list; the rest are only counted
+    #[clap(long, default_value_t = DEFAULT_MAX_ERRORS)]
+    max_errors: usize,
+
+    /// The largest message to accept, in bytes; larger ones count as
+    /// parse errors
+    #[clap(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: usize,
+
+    dlt_file: PathBuf,
+}
+
+#[derive(Copy, Clone, clap::ValueEnum)]
+enum FramingArg {
+    /// A storage header, as in .dlt files
+    StorageHeader,
+    /// Nothing, as sent by dlt-daemon over TCP
+    Raw,
+    /// A `DLS\x01` serial header
+    SerialHeader,
+}
+
+impl From<FramingArg> for Framing {
+    fn from(framing: FramingArg) -> Self {
+        match framing {
+            FramingArg::StorageHeader => Framing::StorageHeader,
+            FramingArg::Raw => Framing::Raw,
+            FramingArg::SerialHeader => Framing::SerialHeader,
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init_tracing!();
+    let opts = InspectOpts::parse();
+
+    let dlt_file = BufReader::new(File::open(&opts.dlt_file)?);
+    let records =
+        DltStream::new(dlt_file, opts.framing.into()).with_max_message_size(opts.max_message_size);
+    let inspection = Inspection::new()
+        .with_max_errors(opts.max_errors)
+        .inspect(records);
+
+    let mut out = io::stdout().lock();
+    writeln!(out, "File: {}", opts.dlt_file.display())?;
+    inspection.write_report(&mut out, opts.top)?;
+    Ok(())
+}
@@ -0,0 +1,364 @@
+//! The collector's pipeline: reading from dlt-daemon over TCP,
+//! converting messages to events and sending them to an [IngestSink],
+//! with control requests going back to the daemon over the same
+//! connection.
+
+use std::{collections::HashSet, net::SocketAddr, pin::pin, sync::Arc, time::Duration};
+
+use auxon_sdk::plugin_utils::{
+    ingest::{Client, Config},
+    serde::from_str,
+};
+use dlt_core::parse::ParsedMessage;
+use futures::{future::LocalBoxFuture, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::BufReader,
+    net::{TcpStream, ToSocketAddrs},
+};
+use tracing::{info, warn};
+
+use crate::{
+    control::ControlRequest,
+    metrics::{CountingReader, Metrics},
+    mutator::{self, ControlConnection, ControlMutator, DEFAULT_CONTROL_ECU_ID},
+    send::{message_channel, HasCommonConfig, Sender},
+    shutdown::{Shutdown, DEFAULT_SHUTDOWN_TIMEOUT},
+    sink::IngestSink,
+    spool::{Connector, SpoolConfig, SpoolingSender},
+    stream::{DltStream, Framing},
+    CommonConfig, DltPluginError, DEFAULT_MAX_MESSAGE_SIZE,
+};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The TCP port dlt-daemon listens on, if not configured
+pub const DEFAULT_DLT_PORT: u16 = 3490;
+
+#[derive(Serialize, Deserialize)]
+pub struct CollectorConfig {
+    /// The DLT host to connect to (with TCP).
+    ///
+    /// If not given, defaults to "localhost".
+    pub host: Option<String>,
+
+    /// The TCP port to connect to on the DLT host.
+    ///
+    /// If not given, defaults to 3490.
+    pub port: Option<u16>,
+
+    /// Address to serve Prometheus-style metrics on over HTTP, e.g.
+    /// "0.0.0.0:9090".
+    ///
+    /// If not given, metrics are not served.
+    #[serde(default, deserialize_with = "from_str")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Expose mutators which change the daemon's log levels and trace
+    /// status, by sending it control requests.
+    ///
+    /// If not given, defaults to false.
+    #[serde(default, deserialize_with = "from_str")]
+    pub control_mutators: Option<bool>,
+
+    /// The ECU id control requests are addressed to, when a mutation
+    /// doesn't say.
+    ///
+    /// If not given, defaults to "ECU1", dlt-daemon's default.
+    pub control_ecu_id: Option<String>,
+
+    /// How long to wait for the daemon to answer a control request, in
+    /// milliseconds.
+    ///
+    /// If not given, defaults to 2000.
+    #[serde(default, deserialize_with = "from_str")]
+    pub control_response_timeout_ms: Option<u64>,
+
+    #[serde(flatten)]
+    pub spool: SpoolConfig,
+
+    #[serde(flatten)]
+    pub common: CommonConfig,
+}
+
+impl HasCommonConfig for CollectorConfig {
+    fn common_config(&self) -> &CommonConfig {
+        &self.common
+    }
+}
+
+/// Collects from a single connection to dlt-daemon, until the daemon
+/// closes it or the process is asked to shut down.
+pub struct Collector<S: IngestSink> {
+    config: Config<CollectorConfig>,
+    sink: S,
+    metrics: Arc<Metrics>,
+    shutdown: Option<Shutdown>,
+    reconnect: Option<Connector<CollectorConfig, S>>,
+}
+
+impl<S: IngestSink + 'static> Collector<S> {
+    pub fn new(config: Config<CollectorConfig>, sink: S) -> Self {
+        Collector {
+            config,
+            sink,
+            metrics: Metrics::new(""),
+            shutdown: None,
+            reconnect: None,
+        }
+    }
+
+    /// Record progress in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Stop when `shutdown` says, rather than on SIGINT or SIGTERM.
+    /// Its timeout is used instead of `shutdown_timeout_ms`.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// If a `spool_dir` is configured, spool messages while the backend
+    /// is unreachable, reconnecting with `connect`. Without this, losing
+    /// the backend connection is an error.
+    pub fn with_reconnect<F>(mut self, connect: F) -> Self
+    where
+        F: for<'a> Fn(
+                &'a Sender<CollectorConfig, S>,
+            ) -> LocalBoxFuture<'a, Result<S, DltPluginError>>
+            + 'static,
+    {
+        self.reconnect = Some(Box::new(connect));
+        self
+    }
+
+    /// Connect to the daemon at `daemon` and collect from it.
+    pub async fn run(self, daemon: impl ToSocketAddrs) -> Result<(), BoxError> {
+        let Collector {
+            config,
+            sink,
+            metrics,
+            shutdown,
+            reconnect,
+        } = self;
+        let shutdown = shutdown.unwrap_or_else(|| {
+            Shutdown::listen(
+                config
+                    .plugin
+                    .common
+                    .shutdown_timeout_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            )
+        });
+
+        let dlt_stream = TcpStream::connect(daemon).await?;
+        info!(addr = %dlt_stream.peer_addr()?, "Connected to DLT server");
+
+        let max_message_size = config
+            .plugin
+            .common
+            .max_message_size
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+        // The read half feeds the collector; control requests go out
+        // over the write half.
+        let (dlt_read, dlt_write) = dlt_stream.into_split();
+        let control_ecu_id = config
+            .plugin
+            .control_ecu_id
+            .as_deref()
+            .unwrap_or(DEFAULT_CONTROL_ECU_ID);
+        let mut control = ControlConnection::new(dlt_write);
+        if let Some(ms) = config.plugin.control_response_timeout_ms {
+            control = control.with_response_timeout(Duration::from_millis(ms));
+        }
+
+        // Non-verbose dictionaries for particular software versions can
+        // only be picked once the ECU says which version it's running.
+        // Ask the configured ECU now, and every other ECU once its first
+        // message arrives; the answer may have changed since the last
+        // connection.
+        let non_verbose_decoder = config.plugin.common.load_non_verbose_decoder()?;
+        let mut software_versions = non_verbose_decoder
+            .as_ref()
+            .is_some_and(|decoder| decoder.needs_software_version())
+            .then(SoftwareVersionRequests::default);
+        if let Some(software_versions) = &mut software_versions {
+            software_versions.request(&control, control_ecu_id).await;
+        }
+
+        let dry_run = config.plugin.common.dry_run_output.is_some();
+        let control_mutators = config.plugin.control_mutators.unwrap_or(false);
+        if control_mutators && dry_run {
+            warn!("Control mutators aren't available in a dry run");
+        }
+        let annotator = if control_mutators && !dry_run {
+            mutator::serve(&config, ControlMutator::all(&control, control_ecu_id)).await?;
+            Some(control.clone())
+        } else {
+            None
+        };
+
+        let mut records = DltStream::new(
+            BufReader::new(CountingReader::new(dlt_read, metrics.clone())),
+            Framing::Raw,
+        )
+        .with_max_message_size(max_message_size);
+
+        // Read on a separate task, so a slow backend doesn't stall the
+        // DLT connection (and overflow the daemon's buffer) right away.
+        // Reading stops when we're asked to shut down; everything read up
+        // to that point is still sent.
+        let (tx, mut rx) = message_channel(&config.plugin.common);
+        let read_metrics = metrics.clone();
+        // Dropping the write half would shut down our side of the
+        // connection, so `control` is held on to until we're done
+        let read_control = control.clone();
+        let read_shutdown = shutdown.clone();
+        let read_task = tokio::spawn(async move {
+            let mut shutdown = pin!(read_shutdown.requested());
+            loop {
+                let record = tokio::select! {
+                    record = records.next() => match record {
+                        Some(record) => record,
+                        None => return Ok::<_, DltPluginError>("dlt_stream_closed"),
+                    },
+                    signal = &mut shutdown => {
+                        info!(%signal, "Shutting down");
+                        return Ok(signal);
+                    }
+                };
+
+                let record = match record {
+                    Ok(record) => record,
+                    Err(e) if e.skipped_message_size().is_some() => {
+                        warn!(err = %e, "Skipping unparseable DLT message");
+                        read_metrics.record_parse_error();
+                        continue;
+                    }
+                    Err(e) => {
+                        read_metrics.record_parse_error();
+                        return Err(e);
+                    }
+                };
+                read_metrics.record_message(&record.message);
+                read_control.observe(&record.message);
+                if let (Some(software_versions), ParsedMessage::Item(msg)) =
+                    (&mut software_versions, &record.message)
+                {
+                    if let Some(ecu_id) = &msg.header.ecu_id {
+                        software_versions.request(&read_control, ecu_id).await;
+                    }
+                }
+
+                if tx.send(record.message).await.is_err() {
+                    return Ok("sender_stopped");
+                }
+            }
+        });
+
+        let spool_config = config.plugin.spool.clone();
+        let dbc = config.plugin.common.load_dbc()?;
+        let someip_services = config.plugin.common.load_someip_services()?;
+        let mut sender = Sender::new(sink, config).with_metrics(metrics.clone());
+        if let Some(dbc) = dbc {
+            sender = sender.with_annotator(Arc::new(dbc));
+        }
+        if let Some(someip_services) = someip_services {
+            sender = sender.with_annotator(Arc::new(someip_services));
+        }
+        if let Some(non_verbose_decoder) = non_verbose_decoder {
+            sender = sender.with_annotator(Arc::new(non_verbose_decoder));
+        }
+        if let Some(control) = annotator {
+            // Links control responses to the requests that caused them
+            sender = sender.with_annotator(Arc::new(control));
+        }
+        let read_abort = read_task.abort_handle();
+        let send_and_stop = async {
+            let send_res = match (spool_config.spool_dir.as_deref(), reconnect) {
+                (Some(spool_dir), Some(reconnect)) if !dry_run => {
+                    info!(spool_dir = %spool_dir.display(), "Spooling enabled");
+                    let mut spooling_sender = SpoolingSender::with_boxed_connector(
+                        sender,
+                        spool_dir,
+                        &spool_config,
+                        reconnect,
+                    )
+                    .await?;
+                    let res = spooling_sender.run(&mut rx).await;
+                    sender = spooling_sender.into_inner();
+                    res
+                }
+                _ => sender.run(&mut rx).await,
+            };
+
+            // If sending failed, the reader may still be going
+            read_task.abort();
+            let read_res = match read_task.await {
+                Ok(res) => res,
+                Err(e) if e.is_cancelled() => Ok("sender_stopped"),
+                Err(e) => return Err(e.into()),
+            };
+
+            let reason = match &read_res {
+                Ok(reason) => *reason,
+                Err(_) => "read_error",
+            };
+            if let Err(e) = sender.stop("collector_stopped", reason).await {
+                warn!(err = %e, "Failed to send final events");
+            }
+
+            send_res?;
+            read_res?;
+            Ok(())
+        };
+
+        // Sending what was read can take a while, or hang if the
+        // backend is stuck; being asked to stop again cuts it short
+        let res = tokio::select! {
+            res = send_and_stop => res,
+            () = shutdown.abandoned() => {
+                read_abort.abort();
+                Ok(())
+            }
+        };
+        metrics.summary().log("Collector stopped");
+        res
+    }
+}
+
+/// The ECUs asked for their software version on this connection
+#[derive(Default)]
+struct SoftwareVersionRequests {
+    requested: HashSet<String>,
+}
+
+impl SoftwareVersionRequests {
+    /// Ask `ecu_id` for its software version, unless it's been asked
+    /// already.
+    async fn request(&mut self, control: &ControlConnection, ecu_id: &str) {
+        if self.requested.contains(ecu_id) {
+            return;
+        }
+        self.requested.insert(ecu_id.to_owned());
+        match control
+            .send(ecu_id, &ControlRequest::GetSoftwareVersion)
+            .await
+        {
+            Ok(()) => info!(ecu_id, "Requested software version"),
+            Err(e) => warn!(ecu_id, err = %e, "Failed to request software version"),
+        }
+    }
+}
+
+impl<S: IngestSink + From<Client> + 'static> Collector<S> {
+    /// [with_reconnect](Collector::with_reconnect), reconnecting with
+    /// the collector's configuration.
+    pub fn with_spooling(self) -> Self {
+        self.with_reconnect(|sender| Box::pin(async move { Ok(sender.connect().await?.into()) }))
+    }
+}
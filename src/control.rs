@@ -0,0 +1,373 @@
+//! Encoding DLT control requests, and decoding the daemon's responses.
+
+use dlt_core::dlt::{self, ControlType};
+
+pub const SERVICE_SET_LOG_LEVEL: u32 = 0x01;
+pub const SERVICE_SET_TRACE_STATUS: u32 = 0x02;
+pub const SERVICE_SET_DEFAULT_LOG_LEVEL: u32 = 0x11;
+pub const SERVICE_GET_SOFTWARE_VERSION: u32 = 0x13;
+
+/// Service ids from here up are passed on to the application, as
+/// injection messages.
+pub const SERVICE_INJECTION_MIN: u32 = 0xFFF;
+
+/// The application and context ids control requests are sent from
+const CONTROL_APPLICATION_ID: &[u8; 4] = b"MDLT";
+const CONTROL_CONTEXT_ID: &[u8; 4] = b"CTRL";
+
+/// The communication interface the daemon applies settings to
+const COM_INTERFACE: &[u8; 4] = b"remo";
+
+/// The name of a control service, as used in event attributes
+pub fn service_name(service_id: u32) -> Option<&'static str> {
+    Some(match service_id {
+        SERVICE_SET_LOG_LEVEL => "set_log_level",
+        SERVICE_SET_TRACE_STATUS => "set_trace_status",
+        0x03 => "get_log_info",
+        0x04 => "get_default_log_level",
+        0x05 => "store_configuration",
+        0x06 => "reset_to_factory_default",
+        0x0A => "set_message_filtering",
+        SERVICE_SET_DEFAULT_LOG_LEVEL => "set_default_log_level",
+        0x12 => "set_default_trace_status",
+        SERVICE_GET_SOFTWARE_VERSION => "get_software_version",
+        0x15 => "get_default_trace_status",
+        0x17 => "get_log_channel_names",
+        0x1F => "get_trace_status",
+        0xF01 => "buffer_overflow_notification",
+        0xF02 => "sync_time_stamp",
+        id if id >= SERVICE_INJECTION_MIN => "injection",
+        _ => return None,
+    })
+}
+
+/// A DLT log level, as sent in control requests. `Default` means
+/// "whatever the default log level is".
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ControlLogLevel {
+    Default,
+    Off,
+    Fatal,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Verbose,
+}
+
+impl ControlLogLevel {
+    pub fn value(self) -> i8 {
+        match self {
+            ControlLogLevel::Default => -1,
+            ControlLogLevel::Off => 0,
+            ControlLogLevel::Fatal => 1,
+            ControlLogLevel::Error => 2,
+            ControlLogLevel::Warn => 3,
+            ControlLogLevel::Info => 4,
+            ControlLogLevel::Debug => 5,
+            ControlLogLevel::Verbose => 6,
+        }
+    }
+}
+
+impl std::str::FromStr for ControlLogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "default" => ControlLogLevel::Default,
+            "off" => ControlLogLevel::Off,
+            "fatal" => ControlLogLevel::Fatal,
+            "error" => ControlLogLevel::Error,
+            "warn" => ControlLogLevel::Warn,
+            "info" => ControlLogLevel::Info,
+            "debug" => ControlLogLevel::Debug,
+            "verbose" => ControlLogLevel::Verbose,
+            _ => return Err(format!("Unknown log level '{s}'")),
+        })
+    }
+}
+
+/// A DLT trace status, as sent in control requests.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ControlTraceStatus {
+    Default,
+    Off,
+    On,
+}
+
+impl ControlTraceStatus {
+    pub fn value(self) -> i8 {
+        match self {
+            ControlTraceStatus::Default => -1,
+            ControlTraceStatus::Off => 0,
+            ControlTraceStatus::On => 1,
+        }
+    }
+}
+
+impl std::str::FromStr for ControlTraceStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "default" => ControlTraceStatus::Default,
+            "off" => ControlTraceStatus::Off,
+            "on" => ControlTraceStatus::On,
+            _ => return Err(format!("Unknown trace status '{s}'")),
+        })
+    }
+}
+
+/// A control request which can be sent to dlt-daemon
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlRequest {
+    SetLogLevel {
+        application_id: String,
+        context_id: String,
+        log_level: ControlLogLevel,
+    },
+    SetTraceStatus {
+        application_id: String,
+        context_id: String,
+        trace_status: ControlTraceStatus,
+    },
+    SetDefaultLogLevel {
+        log_level: ControlLogLevel,
+    },
+    /// Ask the ECU for its software version
+    GetSoftwareVersion,
+    /// Pass `data` to the injection callback the application registered
+    /// for `service_id` (which must be at least [SERVICE_INJECTION_MIN]).
+    Inject {
+        application_id: String,
+        context_id: String,
+        service_id: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl ControlRequest {
+    pub fn service_id(&self) -> u32 {
+        match self {
+            ControlRequest::SetLogLevel { .. } => SERVICE_SET_LOG_LEVEL,
+            ControlRequest::SetTraceStatus { .. } => SERVICE_SET_TRACE_STATUS,
+            ControlRequest::SetDefaultLogLevel { .. } => SERVICE_SET_DEFAULT_LOG_LEVEL,
+            ControlRequest::GetSoftwareVersion => SERVICE_GET_SOFTWARE_VERSION,
+            ControlRequest::Inject { service_id, .. } => *service_id,
+        }
+    }
+
+    /// The application and context ids for the extended header.
+    /// Injection messages are addressed to their target through these;
+    /// everything else identifies itself as the plugin.
+    fn header_ids(&self) -> ([u8; 4], [u8; 4]) {
+        match self {
+            ControlRequest::Inject {
+                application_id,
+                context_id,
+                ..
+            } => (id_bytes(application_id), id_bytes(context_id)),
+            _ => (*CONTROL_APPLICATION_ID, *CONTROL_CONTEXT_ID),
+        }
+    }
+
+    /// The request's payload: the service id, followed by its
+    /// arguments, all little endian. Injection messages carry the
+    /// length of their data, then the data, and requests for the
+    /// software version have no arguments.
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = self.service_id().to_le_bytes().to_vec();
+        match self {
+            ControlRequest::SetLogLevel {
+                application_id,
+                context_id,
+                log_level,
+            } => {
+                payload.extend_from_slice(&id_bytes(application_id));
+                payload.extend_from_slice(&id_bytes(context_id));
+                payload.push(log_level.value() as u8);
+            }
+            ControlRequest::SetTraceStatus {
+                application_id,
+                context_id,
+                trace_status,
+            } => {
+                payload.extend_from_slice(&id_bytes(application_id));
+                payload.extend_from_slice(&id_bytes(context_id));
+                payload.push(trace_status.value() as u8);
+            }
+            ControlRequest::SetDefaultLogLevel { log_level } => {
+                payload.push(log_level.value() as u8);
+            }
+            ControlRequest::GetSoftwareVersion => return payload,
+            ControlRequest::Inject { data, .. } => {
+                payload.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                payload.extend_from_slice(data);
+                return payload;
+            }
+        }
+        payload.extend_from_slice(COM_INTERFACE);
+        payload
+    }
+
+    /// Encode the request as a complete DLT message addressed to
+    /// `ecu_id`, ready to be written to the daemon's TCP connection.
+    pub fn to_bytes(&self, ecu_id: &str, message_counter: u8) -> Vec<u8> {
+        // Use extended header, with ECU id, version 1, little endian
+        const HEADER_TYPE: u8 = 0x01 | 0x04 | 0x20;
+        // Non-verbose control request with one argument
+        const MESSAGE_INFO: u8 = (1 << 4) | (3 << 1);
+        const HEADERS_LEN: usize = 4 + 4 + 10;
+
+        let payload = self.payload();
+        let len = (HEADERS_LEN + payload.len()) as u16;
+        let (application_id, context_id) = self.header_ids();
+
+        let mut msg = Vec::with_capacity(len as usize);
+        msg.push(HEADER_TYPE);
+        msg.push(message_counter);
+        msg.extend_from_slice(&len.to_be_bytes());
+        msg.extend_from_slice(&id_bytes(ecu_id));
+        msg.push(MESSAGE_INFO);
+        msg.push(1);
+        msg.extend_from_slice(&application_id);
+        msg.extend_from_slice(&context_id);
+        msg.extend_from_slice(&payload);
+        msg
+    }
+}
+
+/// The outcome the daemon reports in a control response
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ControlStatus {
+    Ok,
+    NotSupported,
+    Error,
+    Unknown(u8),
+}
+
+impl ControlStatus {
+    fn from_value(value: u8) -> Self {
+        match value {
+            0 => ControlStatus::Ok,
+            1 => ControlStatus::NotSupported,
+            2 => ControlStatus::Error,
+            n => ControlStatus::Unknown(n),
+        }
+    }
+
+    pub fn value(&self) -> u8 {
+        match self {
+            ControlStatus::Ok => 0,
+            ControlStatus::NotSupported => 1,
+            ControlStatus::Error => 2,
+            ControlStatus::Unknown(n) => *n,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ControlStatus::Ok => "ok",
+            ControlStatus::NotSupported => "not_supported",
+            ControlStatus::Error => "error",
+            ControlStatus::Unknown(_) => "unknown",
+        }
+    }
+}
+
+/// The service id and status at the start of every control response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ControlResponse {
+    pub ecu_id: Option<String>,
+    pub service_id: u32,
+    pub status: ControlStatus,
+}
+
+impl ControlResponse {
+    /// Decode `msg`, if it's a control response.
+    pub fn from_message(msg: &dlt::Message) -> Option<Self> {
+        let is_response = matches!(
+            msg.extended_header.as_ref().map(|eh| &eh.message_type),
+            Some(dlt::MessageType::Control(ControlType::Response))
+        );
+        if !is_response {
+            return None;
+        }
+
+        let payload = control_payload(msg)?;
+        let service_id = service_id(msg, &payload)?;
+        let status = ControlStatus::from_value(*payload.get(4)?);
+        Some(ControlResponse {
+            ecu_id: msg.header.ecu_id.clone(),
+            service_id,
+            status,
+        })
+    }
+}
+
+/// The software version reported by `msg`, if it's a successful
+/// response to a get_software_version request. After the service id and
+/// status, the response carries the length of the version, then the
+/// version.
+pub fn software_version(msg: &dlt::Message) -> Option<String> {
+    let response = ControlResponse::from_message(msg)?;
+    if response.service_id != SERVICE_GET_SOFTWARE_VERSION || response.status != ControlStatus::Ok {
+        return None;
+    }
+
+    let payload = control_payload(msg)?;
+    let len_bytes: [u8; 4] = payload.get(5..9)?.try_into().ok()?;
+    let len = match msg.header.endianness {
+        dlt::Endianness::Big => u32::from_be_bytes(len_bytes),
+        dlt::Endianness::Little => u32::from_le_bytes(len_bytes),
+    } as usize;
+    // Some daemons count a null terminator, and some don't send one
+    let version = payload.get(9..9 + len).or_else(|| payload.get(9..))?;
+    let version = String::from_utf8_lossy(version);
+    Some(version.trim_end_matches('\0').trim().to_owned())
+}
+
+/// The service id of `msg`, if it's a control request.
+pub fn request_service_id(msg: &dlt::Message) -> Option<u32> {
+    let is_request = matches!(
+        msg.extended_header.as_ref().map(|eh| &eh.message_type),
+        Some(dlt::MessageType::Control(ControlType::Request))
+    );
+    if !is_request {
+        return None;
+    }
+
+    let payload = control_payload(msg)?;
+    service_id(msg, &payload)
+}
+
+/// The raw payload of a control message. dlt-core splits off the first
+/// byte as a [ControlType], so put it back.
+pub fn control_payload(msg: &dlt::Message) -> Option<Vec<u8>> {
+    let dlt::PayloadContent::ControlMsg(control_type, bytes) = &msg.payload else {
+        return None;
+    };
+    let mut payload = Vec::with_capacity(1 + bytes.len());
+    payload.push(control_type.value());
+    payload.extend_from_slice(bytes);
+    Some(payload)
+}
+
+/// The service id at the start of a control message payload
+pub fn service_id(msg: &dlt::Message, payload: &[u8]) -> Option<u32> {
+    let bytes: [u8; 4] = payload.get(0..4)?.try_into().ok()?;
+    Some(match msg.header.endianness {
+        dlt::Endianness::Big => u32::from_be_bytes(bytes),
+        dlt::Endianness::Little => u32::from_le_bytes(bytes),
+    })
+}
+
+/// DLT ids are 4 bytes, padded with zeros
+pub(crate) fn id_bytes(id: &str) -> [u8; 4] {
+    let mut bytes = [0u8; 4];
+    for (b, c) in bytes.iter_mut().zip(id.bytes()) {
+        *b = c;
+    }
+    bytes
+}
@@ -1,4 +1,9 @@
-use crate::CommonConfig;
+use std::collections::HashMap;
+
+use crate::{
+    control::{self, ControlResponse},
+    network, CommonConfig,
+};
 use auxon_sdk::api::{AttrKey, AttrVal, Nanoseconds};
 use dlt_core::dlt::{self, ControlType, LogLevel};
 
@@ -35,6 +40,10 @@ impl TimelineKey {
         key
     }
 
+    pub fn ecu_id(&self) -> Option<&str> {
+        self.ecu_id.as_deref()
+    }
+
     pub fn timeline_name(&self) -> String {
         let s = self
             .ecu_id
@@ -75,24 +84,73 @@ impl TimelineKey {
     }
 }
 
-pub fn dlt_message_to_event_name(msg: &dlt::Message) -> String {
+pub fn dlt_message_to_event_name(msg: &dlt::Message) -> &'static str {
     match msg.extended_header.as_ref() {
         Some(extended_header) => match &extended_header.message_type {
-            dlt::MessageType::Log(_) => "log".to_string(),
-            dlt::MessageType::ApplicationTrace(_) => "application_trace".to_string(),
-            dlt::MessageType::NetworkTrace(_) => "network_trace".to_string(),
-            dlt::MessageType::Control(_) => "control".to_string(),
-            dlt::MessageType::Unknown(_) => "unknown".to_string(),
+            dlt::MessageType::Log(_) => "log",
+            dlt::MessageType::ApplicationTrace(_) => "application_trace",
+            dlt::MessageType::NetworkTrace(_) => "network_trace",
+            dlt::MessageType::Control(_) => "control",
+            dlt::MessageType::Unknown(_) => "unknown",
         },
         None => match msg.payload {
-            dlt::PayloadContent::Verbose(_) => "verbose".to_string(),
-            dlt::PayloadContent::NonVerbose(_, _) => "non_verbose".to_string(),
-            dlt::PayloadContent::ControlMsg(_, _) => "control".to_string(),
+            dlt::PayloadContent::Verbose(_) => "verbose",
+            dlt::PayloadContent::NonVerbose(_, _) => "non_verbose",
+            dlt::PayloadContent::ControlMsg(_, _) => "control",
         },
     }
 }
 
-pub fn dlt_message_to_event_attrs(msg: &dlt::Message) -> Vec<(AttrKey, AttrVal)> {
+/// Caches the dynamically constructed attribute keys (`event.payload.<name>`
+/// and `event.payload.<n>`), so converting a stream of similar
+/// messages doesn't format and allocate the same keys over and over.
+#[derive(Default)]
+pub struct AttrKeyInterner {
+    payload_by_index: Vec<AttrKey>,
+    payload_by_name: HashMap<String, AttrKey>,
+}
+
+impl AttrKeyInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The key for the unnamed payload argument at position `arg_id`
+    pub fn payload_index(&mut self, arg_id: usize) -> AttrKey {
+        while self.payload_by_index.len() <= arg_id {
+            let next_id = self.payload_by_index.len();
+            self.payload_by_index
+                .push(format!("event.payload.{next_id}").into());
+        }
+        self.payload_by_index[arg_id].clone()
+    }
+
+    /// The key for the payload argument called `name`
+    pub fn payload_name(&mut self, name: &str) -> AttrKey {
+        if let Some(key) = self.payload_by_name.get(name) {
+            return key.clone();
+        }
+
+        let key: AttrKey = format!("event.payload.{name}").into();
+        self.payload_by_name.insert(name.to_string(), key.clone());
+        key
+    }
+}
+
+pub fn dlt_message_to_event_attrs(
+    msg: &dlt::Message,
+    config: &CommonConfig,
+) -> Vec<(AttrKey, AttrVal)> {
+    dlt_message_to_event_attrs_interned(msg, config, &mut AttrKeyInterner::new())
+}
+
+/// Like [dlt_message_to_event_attrs], but reuses attribute keys from
+/// `interner` across calls.
+pub fn dlt_message_to_event_attrs_interned(
+    msg: &dlt::Message,
+    config: &CommonConfig,
+    interner: &mut AttrKeyInterner,
+) -> Vec<(AttrKey, AttrVal)> {
     let mut attrs: Vec<(AttrKey, AttrVal)> = vec![];
 
     gather_header_attrs(msg, &mut attrs);
@@ -101,7 +159,8 @@ pub fn dlt_message_to_event_attrs(msg: &dlt::Message) -> Vec<(AttrKey, AttrVal)>
         gather_extended_header_attrs(&mut attrs, extended_header);
     }
 
-    gather_payload(msg, &mut attrs);
+    gather_payload(msg, &mut attrs, config, interner);
+    network::gather_network_trace_attrs(msg, &mut attrs);
 
     attrs
 }
@@ -179,11 +238,27 @@ fn gather_message_type_attrs(
     }
 }
 
-fn gather_payload(msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
+fn gather_payload(
+    msg: &dlt::Message,
+    attrs: &mut Vec<(AttrKey, AttrVal)>,
+    config: &CommonConfig,
+    interner: &mut AttrKeyInterner,
+) {
     match &msg.payload {
         dlt::PayloadContent::Verbose(args) => {
             attrs.push(("event.payload_type".into(), "verbose".into()));
 
+            if config.render_message.unwrap_or(false) {
+                // All the arguments, separated by spaces, the way
+                // dlt-viewer shows them
+                let message = args
+                    .iter()
+                    .map(|arg| value_to_text(&arg.value))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                attrs.push(("event.message".into(), message.into()));
+            }
+
             // Special case a single non-named arg as "event.payload"
             if args.len() == 1 && args[0].name.is_none() {
                 if let Some(attr_val) = value_to_attr_val(args[0].value.clone()) {
@@ -195,22 +270,18 @@ fn gather_payload(msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
                         continue;
                     };
 
-                    let attr_key = if let Some(name) = &arg.name {
-                        if name.is_empty() {
-                            format!("event.payload.{arg_id}")
-                        } else {
-                            format!("event.payload.{name}")
-                        }
-                    } else {
-                        format!("event.payload.{arg_id}")
+                    let attr_key = match &arg.name {
+                        Some(name) if !name.is_empty() => interner.payload_name(name),
+                        _ => interner.payload_index(arg_id),
                     };
-                    attrs.push((attr_key.into(), attr_val));
+                    attrs.push((attr_key, attr_val));
                 }
             }
         }
 
         dlt::PayloadContent::NonVerbose(message_id, _payload) => {
-            // TODO interpret message_id and payload using an "external description"
+            // The payload is decoded by nonverbose::NonVerboseMessages,
+            // when there's a description for the message id
             attrs.push(("event.payload_type".into(), "non_verbose".into()));
             attrs.push(("event.message_id".into(), (*message_id).into()));
         }
@@ -221,11 +292,29 @@ fn gather_payload(msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
                 "event.control_type".into(),
                 control_type_to_str(control_type).into(),
             ));
+            gather_control_attrs(msg, attrs);
         }
     }
 }
 
-fn log_level_to_str(log_level: LogLevel) -> &'static str {
+fn gather_control_attrs(msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
+    let Some(payload) = control::control_payload(msg) else {
+        return;
+    };
+    let Some(service_id) = control::service_id(msg, &payload) else {
+        return;
+    };
+
+    attrs.push(("event.service_id".into(), (service_id as i64).into()));
+    if let Some(name) = control::service_name(service_id) {
+        attrs.push(("event.service_name".into(), name.into()));
+    }
+    if let Some(response) = ControlResponse::from_message(msg) {
+        attrs.push(("event.status".into(), response.status.as_str().into()));
+    }
+}
+
+pub(crate) fn log_level_to_str(log_level: LogLevel) -> &'static str {
     match log_level {
         LogLevel::Fatal => "fatal",
         LogLevel::Error => "error",
@@ -237,7 +326,7 @@ fn log_level_to_str(log_level: LogLevel) -> &'static str {
     }
 }
 
-fn control_type_to_str(control_type: &ControlType) -> &'static str {
+pub(crate) fn control_type_to_str(control_type: &ControlType) -> &'static str {
     match control_type {
         ControlType::Request => "request",
         ControlType::Response => "response",
@@ -245,7 +334,28 @@ fn control_type_to_str(control_type: &ControlType) -> &'static str {
     }
 }
 
-fn value_to_attr_val(value: dlt::Value) -> Option<AttrVal> {
+/// A value as text, for rendering messages. Raw values are shown as hex.
+pub(crate) fn value_to_text(value: &dlt::Value) -> String {
+    match value {
+        dlt::Value::Bool(x) => (*x != 0).to_string(),
+        dlt::Value::U8(x) => x.to_string(),
+        dlt::Value::U16(x) => x.to_string(),
+        dlt::Value::U32(x) => x.to_string(),
+        dlt::Value::U64(x) => x.to_string(),
+        dlt::Value::U128(x) => x.to_string(),
+        dlt::Value::I8(x) => x.to_string(),
+        dlt::Value::I16(x) => x.to_string(),
+        dlt::Value::I32(x) => x.to_string(),
+        dlt::Value::I64(x) => x.to_string(),
+        dlt::Value::I128(x) => x.to_string(),
+        dlt::Value::F32(x) => x.to_string(),
+        dlt::Value::F64(x) => x.to_string(),
+        dlt::Value::StringVal(x) => x.clone(),
+        dlt::Value::Raw(bytes) => network::hex(bytes),
+    }
+}
+
+pub(crate) fn value_to_attr_val(value: dlt::Value) -> Option<AttrVal> {
     match value {
         dlt::Value::Bool(x) => {
             if x == 0 {
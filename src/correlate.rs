@@ -0,0 +1,203 @@
+//! Linking events on different timelines through correlation ids which
+//! applications log on both sides of an interaction.
+
+use std::collections::{HashMap, VecDeque};
+
+use auxon_sdk::api::{AttrKey, AttrVal, TimelineId};
+use dlt_core::dlt;
+use serde::{Deserialize, Serialize};
+
+/// How many sends to remember per rule, while waiting for receives.
+/// Beyond this, the oldest are forgotten.
+pub const MAX_REMEMBERED_SENDS: usize = 4096;
+
+/// Describes a pair of log messages which are two sides of the same
+/// interaction, identified by a correlation id they both carry.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CorrelationRule {
+    /// Used in log messages about the rule
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// The message which starts the interaction
+    pub send: CorrelationEndpoint,
+
+    /// The message which is caused by it
+    pub receive: CorrelationEndpoint,
+}
+
+/// Which messages are one side of an interaction, and where their
+/// correlation id is. Unset ids match anything.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CorrelationEndpoint {
+    #[serde(default)]
+    pub ecu_id: Option<String>,
+
+    #[serde(default)]
+    pub application_id: Option<String>,
+
+    #[serde(default)]
+    pub context_id: Option<String>,
+
+    /// The verbose payload argument holding the correlation id: either
+    /// its name, or its position (starting from 0).
+    pub argument: String,
+}
+
+impl CorrelationEndpoint {
+    /// The correlation id in `msg`, if it's a message this endpoint
+    /// describes.
+    fn correlation_id(&self, msg: &dlt::Message) -> Option<String> {
+        if !id_matches(&self.ecu_id, msg.header.ecu_id.as_deref()) {
+            return None;
+        }
+
+        let extended_header = msg.extended_header.as_ref();
+        let application_id = extended_header.map(|eh| eh.application_id.as_str());
+        let context_id = extended_header.map(|eh| eh.context_id.as_str());
+        if !id_matches(&self.application_id, application_id)
+            || !id_matches(&self.context_id, context_id)
+        {
+            return None;
+        }
+
+        let dlt::PayloadContent::Verbose(args) = &msg.payload else {
+            return None;
+        };
+        let arg = args
+            .iter()
+            .find(|arg| arg.name.as_deref() == Some(self.argument.as_str()))
+            .or_else(|| args.get(self.argument.parse::<usize>().ok()?))?;
+        value_to_correlation_id(&arg.value)
+    }
+}
+
+pub(crate) fn id_matches(expected: &Option<String>, actual: Option<&str>) -> bool {
+    match expected {
+        Some(expected) => actual == Some(expected.as_str()),
+        None => true,
+    }
+}
+
+fn value_to_correlation_id(value: &dlt::Value) -> Option<String> {
+    Some(match value {
+        dlt::Value::Bool(x) => x.to_string(),
+        dlt::Value::U8(x) => x.to_string(),
+        dlt::Value::U16(x) => x.to_string(),
+        dlt::Value::U32(x) => x.to_string(),
+        dlt::Value::U64(x) => x.to_string(),
+        dlt::Value::U128(x) => x.to_string(),
+        dlt::Value::I8(x) => x.to_string(),
+        dlt::Value::I16(x) => x.to_string(),
+        dlt::Value::I32(x) => x.to_string(),
+        dlt::Value::I64(x) => x.to_string(),
+        dlt::Value::I128(x) => x.to_string(),
+        dlt::Value::StringVal(x) => x.clone(),
+        dlt::Value::Raw(bytes) => bytes.iter().map(|b| format!("{b:02x}")).collect(),
+        // Floats don't make for good identifiers
+        dlt::Value::F32(_) | dlt::Value::F64(_) => return None,
+    })
+}
+
+/// Applies [CorrelationRule]s to messages as they're converted,
+/// remembering each send so later receives can point back to it.
+#[derive(Default)]
+pub struct Correlator {
+    rules: Vec<CorrelationRule>,
+
+    /// Per rule: the timeline and nonce of each remembered send, by
+    /// correlation id, and the order they were seen in.
+    sends: Vec<(HashMap<String, (TimelineId, i64)>, VecDeque<String>)>,
+}
+
+impl Correlator {
+    pub fn new(rules: Vec<CorrelationRule>) -> Self {
+        let sends = rules.iter().map(|_| Default::default()).collect();
+        Correlator { rules, sends }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Add interaction attributes for `msg` (which is on `timeline_id`)
+    /// to `attrs`. A receive gets `event.interaction.remote_timeline_id`
+    /// and `event.interaction.remote_nonce`, pointing at the most recent
+    /// send with the same correlation id. A send gets an `event.nonce`,
+    /// from `next_nonce` unless it already has one.
+    pub fn correlate(
+        &mut self,
+        msg: &dlt::Message,
+        timeline_id: TimelineId,
+        attrs: &mut Vec<(AttrKey, AttrVal)>,
+        next_nonce: impl FnOnce() -> i64,
+    ) {
+        // Receives first, so a message which is both can't link to itself
+        let has_remote = attrs
+            .iter()
+            .any(|(k, _)| k.as_ref() == "event.interaction.remote_nonce");
+        if !has_remote {
+            let remote = self
+                .rules
+                .iter()
+                .zip(self.sends.iter())
+                .find_map(|(rule, (by_id, _))| {
+                    let id = rule.receive.correlation_id(msg)?;
+                    let remote = by_id.get(&id).copied()?;
+                    tracing::debug!(
+                        rule = ?rule.name,
+                        correlation_id = %id,
+                        "Correlated receive with send"
+                    );
+                    Some(remote)
+                });
+            if let Some((remote_timeline_id, remote_nonce)) = remote {
+                attrs.push((
+                    "event.interaction.remote_timeline_id".into(),
+                    remote_timeline_id.into(),
+                ));
+                attrs.push(("event.interaction.remote_nonce".into(), remote_nonce.into()));
+            }
+        }
+
+        let sent_ids: Vec<(usize, String)> = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, rule)| Some((idx, rule.send.correlation_id(msg)?)))
+            .collect();
+        if sent_ids.is_empty() {
+            return;
+        }
+
+        let existing_nonce = attrs
+            .iter()
+            .find(|(k, _)| k.as_ref() == "event.nonce")
+            .and_then(|(_, v)| match v {
+                AttrVal::Integer(n) => Some(*n),
+                _ => None,
+            });
+        let nonce = match existing_nonce {
+            Some(nonce) => nonce,
+            None => {
+                let nonce = next_nonce();
+                attrs.push(("event.nonce".into(), nonce.into()));
+                nonce
+            }
+        };
+
+        for (idx, id) in sent_ids {
+            let (by_id, order) = &mut self.sends[idx];
+            // A repeated id is now the most recent send
+            if by_id.insert(id.clone(), (timeline_id, nonce)).is_some() {
+                order.retain(|seen| *seen != id);
+            }
+            order.push_back(id);
+            if order.len() > MAX_REMEMBERED_SENDS {
+                if let Some(oldest) = order.pop_front() {
+                    by_id.remove(&oldest);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Decoding CAN signals using a DBC file.
+//!
+//! Only the parts of the format needed to decode signals are read:
+//! messages (`BO_`) and their signals (`SG_`). Multiplexed signals are
+//! decoded regardless of the multiplexer's value.
+
+use std::{collections::HashMap, path::Path};
+
+use auxon_sdk::api::{AttrKey, AttrVal};
+use dlt_core::dlt;
+
+use crate::{network, send::EventAnnotator};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DbcError {
+    #[error("Failed to read DBC file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid DBC file, line {line}: {reason}")]
+    Syntax { line: usize, reason: String },
+}
+
+/// The messages described by a DBC file, by CAN id
+#[derive(Debug, Default)]
+pub struct Dbc {
+    messages: HashMap<u32, DbcMessage>,
+}
+
+#[derive(Debug)]
+struct DbcMessage {
+    name: String,
+    signals: Vec<Signal>,
+}
+
+#[derive(Debug)]
+struct Signal {
+    name: String,
+    start_bit: u32,
+    size: u32,
+    little_endian: bool,
+    signed: bool,
+    factor: f64,
+    offset: f64,
+}
+
+/// In DBC files, bit 31 of a message id marks an extended id
+const DBC_EXTENDED_FLAG: u32 = 1 << 31;
+
+impl Dbc {
+    pub fn from_file(path: &Path) -> Result<Self, DbcError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, DbcError> {
+        let mut dbc = Dbc::default();
+        let mut current: Option<u32> = None;
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            let syntax = |reason: &str| DbcError::Syntax {
+                line: idx + 1,
+                reason: reason.to_owned(),
+            };
+
+            if let Some(rest) = line.strip_prefix("BO_ ") {
+                // BO_ <id> <name>: <dlc> <transmitter>
+                let mut parts = rest.split_whitespace();
+                let id: u32 = parts
+                    .next()
+                    .and_then(|id| id.parse().ok())
+                    .ok_or_else(|| syntax("invalid message id"))?;
+                let name = parts
+                    .next()
+                    .map(|name| name.trim_end_matches(':').to_owned())
+                    .ok_or_else(|| syntax("missing message name"))?;
+
+                let id = id & !DBC_EXTENDED_FLAG;
+                dbc.messages.insert(
+                    id,
+                    DbcMessage {
+                        name,
+                        signals: vec![],
+                    },
+                );
+                current = Some(id);
+            } else if let Some(rest) = line.strip_prefix("SG_ ") {
+                let Some(message) = current.and_then(|id| dbc.messages.get_mut(&id)) else {
+                    return Err(syntax("signal outside of a message"));
+                };
+                message
+                    .signals
+                    .push(parse_signal(rest).map_err(|r| syntax(&r))?);
+            } else if !line.is_empty() {
+                // Signals only follow their message directly
+                current = None;
+            }
+        }
+
+        Ok(dbc)
+    }
+
+    /// Decode the signals of a CAN frame, if its id is known.
+    /// Returns the message name, and each signal's name and value.
+    pub fn decode(&self, id: u32, data: &[u8]) -> Option<(&str, Vec<(&str, f64)>)> {
+        let message = self.messages.get(&id)?;
+        let values = message
+            .signals
+            .iter()
+            .filter_map(|signal| Some((signal.name.as_str(), signal.decode(data)?)))
+            .collect();
+        Some((message.name.as_str(), values))
+    }
+}
+
+/// Parse the part of a signal line after `SG_`:
+/// `<name> [<mux>] : <start>|<size>@<order><sign> (<factor>,<offset>) [<min>|<max>] "<unit>" <receivers>`
+fn parse_signal(rest: &str) -> Result<Signal, String> {
+    let (name_part, layout_part) = rest
+        .split_once(':')
+        .ok_or_else(|| "missing ':' in signal".to_owned())?;
+    let name = name_part
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| "missing signal name".to_owned())?
+        .to_owned();
+
+    let mut parts = layout_part.split_whitespace();
+    let layout = parts
+        .next()
+        .ok_or_else(|| format!("missing layout for signal {name}"))?;
+    let scaling = parts
+        .next()
+        .ok_or_else(|| format!("missing scaling for signal {name}"))?;
+
+    let invalid_layout = || format!("invalid layout '{layout}' for signal {name}");
+    let (start_bit, rest) = layout.split_once('|').ok_or_else(invalid_layout)?;
+    let (size, rest) = rest.split_once('@').ok_or_else(invalid_layout)?;
+    let mut flags = rest.chars();
+    let little_endian = match flags.next() {
+        Some('1') => true,
+        Some('0') => false,
+        _ => return Err(invalid_layout()),
+    };
+    let signed = match flags.next() {
+        Some('-') => true,
+        Some('+') => false,
+        _ => return Err(invalid_layout()),
+    };
+
+    let invalid_scaling = || format!("invalid scaling '{scaling}' for signal {name}");
+    let (factor, offset) = scaling
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split_once(',')
+        .ok_or_else(invalid_scaling)?;
+
+    let size: u32 = size.parse().map_err(|_| invalid_layout())?;
+    if size == 0 || size > 64 {
+        return Err(invalid_layout());
+    }
+
+    Ok(Signal {
+        start_bit: start_bit.parse().map_err(|_| invalid_layout())?,
+        size,
+        little_endian,
+        signed,
+        factor: factor.parse().map_err(|_| invalid_scaling())?,
+        offset: offset.parse().map_err(|_| invalid_scaling())?,
+        name,
+    })
+}
+
+impl Signal {
+    fn decode(&self, data: &[u8]) -> Option<f64> {
+        let raw = if self.little_endian {
+            // The start bit is the least significant bit; bits count up
+            // through each byte, and on into the next
+            let mut raw = 0u64;
+            for i in 0..self.size {
+                let bit = self.start_bit + i;
+                raw |= (bit_at(data, bit)? as u64) << i;
+            }
+            raw
+        } else {
+            // The start bit is the most significant bit; bits count down
+            // through each byte, then on to the top of the next
+            let mut raw = 0u64;
+            let mut bit = self.start_bit;
+            for _ in 0..self.size {
+                raw = (raw << 1) | bit_at(data, bit)? as u64;
+                bit = if bit % 8 == 0 { bit + 15 } else { bit - 1 };
+            }
+            raw
+        };
+
+        let value = if self.signed && self.size < 64 && raw & (1 << (self.size - 1)) != 0 {
+            (raw | (u64::MAX << self.size)) as i64 as f64
+        } else if self.signed {
+            raw as i64 as f64
+        } else {
+            raw as f64
+        };
+        Some(value * self.factor + self.offset)
+    }
+}
+
+fn bit_at(data: &[u8], bit: u32) -> Option<u8> {
+    let byte = data.get((bit / 8) as usize)?;
+    Some((byte >> (bit % 8)) & 1)
+}
+
+/// Adds `event.can.message` and `event.can.signal.<name>` attributes
+/// to CAN network trace events whose id is in the DBC file.
+impl EventAnnotator for Dbc {
+    fn annotate(&self, msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
+        let Some(frame) = network::can_frame(msg) else {
+            return;
+        };
+        let Some((message_name, signals)) = self.decode(frame.id, frame.data) else {
+            return;
+        };
+
+        attrs.push(("event.can.message".into(), message_name.to_owned().into()));
+        for (name, value) in signals {
+            let key = format!("event.can.signal.{name}");
+            // Keep whole numbers as integers
+            let value: AttrVal = if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+                (value as i64).into()
+            } else {
+                value.into()
+            };
+            attrs.push((key.into(), value));
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! Serializing DLT messages: the inverse of the reader in
+//! [stream](crate::stream).
+//!
+//! Messages are written as they are described, so a message which was
+//! read can be written back byte for byte. Header flags come from which
+//! optional fields are present, and the length field is computed, but
+//! the extended header's verbose flag and argument count are written as
+//! given; [dlt::Message::new] fills those in for new messages.
+//!
+//! Header fields are big endian, as the protocol requires; payloads use
+//! the message's own byte order.
+
+use dlt_core::dlt::{
+    self, ApplicationTraceType, DltTimeStamp, LogLevel, MessageType, NetworkTraceType,
+    StorageHeader,
+};
+
+use crate::{
+    control::id_bytes,
+    stream::{Framing, SERIAL_HEADER_PATTERN, STORAGE_HEADER_LEN, STORAGE_HEADER_PATTERN},
+};
+
+// Standard header type flags
+const UEH: u8 = 0x01;
+const MSBF: u8 = 0x02;
+const WEID: u8 = 0x04;
+const WSID: u8 = 0x08;
+const WTMS: u8 = 0x10;
+
+// Verbose argument type info flags
+const TYPE_BOOL: u32 = 0x10;
+const TYPE_SINT: u32 = 0x20;
+const TYPE_UINT: u32 = 0x40;
+const TYPE_FLOA: u32 = 0x80;
+const TYPE_STRG: u32 = 0x200;
+const TYPE_RAWD: u32 = 0x400;
+const TYPE_VARI: u32 = 0x800;
+const TYPE_FIXP: u32 = 0x1000;
+const TYPE_SCOD_SHIFT: u32 = 15;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeError {
+    #[error("Message is {size} bytes, more than a DLT header can describe")]
+    MessageTooLarge { size: usize },
+
+    #[error("Argument {field} is {len} bytes, more than DLT can describe")]
+    FieldTooLong { field: &'static str, len: usize },
+}
+
+/// Serialize `msg`, without any framing.
+pub fn encode(msg: &dlt::Message) -> Result<Vec<u8>, EncodeError> {
+    let mut buf = Vec::new();
+    encode_into(msg, &mut buf)?;
+    Ok(buf)
+}
+
+/// Serialize `msg`, without any framing, appending it to `buf`. If the
+/// message can't be encoded, `buf` is left as it was.
+pub fn encode_into(msg: &dlt::Message, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+    let start = buf.len();
+    let res = encode_message(msg, buf);
+    if res.is_err() {
+        buf.truncate(start);
+    }
+    res
+}
+
+/// Serialize `msg`, preceded by the framing header that `framing` calls
+/// for. With [Framing::StorageHeader], `storage_header` is written, or
+/// if not given, one with the message's ECU id and a zero timestamp.
+pub fn encode_framed(
+    msg: &dlt::Message,
+    framing: Framing,
+    storage_header: Option<&StorageHeader>,
+) -> Result<Vec<u8>, EncodeError> {
+    let mut buf = Vec::new();
+    match framing {
+        Framing::Raw => (),
+        Framing::StorageHeader => {
+            let default_header;
+            let storage_header = match storage_header {
+                Some(storage_header) => storage_header,
+                None => {
+                    default_header = StorageHeader {
+                        timestamp: DltTimeStamp {
+                            seconds: 0,
+                            microseconds: 0,
+                        },
+                        ecu_id: msg.header.ecu_id.clone().unwrap_or_default(),
+                    };
+                    &default_header
+                }
+            };
+            buf.extend_from_slice(&encode_storage_header(storage_header));
+        }
+        Framing::SerialHeader => buf.extend_from_slice(&SERIAL_HEADER_PATTERN),
+    }
+    encode_into(msg, &mut buf)?;
+    Ok(buf)
+}
+
+/// A verbose argument holding `value`, with the type info its value
+/// implies, named if `name` is given.
+pub fn verbose_argument(name: Option<String>, value: dlt::Value) -> dlt::Argument {
+    let kind = match &value {
+        dlt::Value::Bool(_) => dlt::TypeInfoKind::Bool,
+        dlt::Value::U8(_) => dlt::TypeInfoKind::Unsigned(dlt::TypeLength::BitLength8),
+        dlt::Value::U16(_) => dlt::TypeInfoKind::Unsigned(dlt::TypeLength::BitLength16),
+        dlt::Value::U32(_) => dlt::TypeInfoKind::Unsigned(dlt::TypeLength::BitLength32),
+        dlt::Value::U64(_) => dlt::TypeInfoKind::Unsigned(dlt::TypeLength::BitLength64),
+        dlt::Value::U128(_) => dlt::TypeInfoKind::Unsigned(dlt::TypeLength::BitLength128),
+        dlt::Value::I8(_) => dlt::TypeInfoKind::Signed(dlt::TypeLength::BitLength8),
+        dlt::Value::I16(_) => dlt::TypeInfoKind::Signed(dlt::TypeLength::BitLength16),
+        dlt::Value::I32(_) => dlt::TypeInfoKind::Signed(dlt::TypeLength::BitLength32),
+        dlt::Value::I64(_) => dlt::TypeInfoKind::Signed(dlt::TypeLength::BitLength64),
+        dlt::Value::I128(_) => dlt::TypeInfoKind::Signed(dlt::TypeLength::BitLength128),
+        dlt::Value::F32(_) => dlt::TypeInfoKind::Float(dlt::FloatWidth::Width32),
+        dlt::Value::F64(_) => dlt::TypeInfoKind::Float(dlt::FloatWidth::Width64),
+        dlt::Value::StringVal(_) => dlt::TypeInfoKind::StringType,
+        dlt::Value::Raw(_) => dlt::TypeInfoKind::Raw,
+    };
+    dlt::Argument {
+        type_info: dlt::TypeInfo {
+            kind,
+            coding: dlt::StringCoding::UTF8,
+            has_variable_info: name.is_some(),
+            has_trace_info: false,
+        },
+        name,
+        unit: None,
+        fixed_point: None,
+        value,
+    }
+}
+
+/// Serialize a storage header: the `DLT\x01` pattern, the seconds and
+/// microseconds of the timestamp (both little endian), and the ECU id.
+pub fn encode_storage_header(header: &StorageHeader) -> [u8; STORAGE_HEADER_LEN] {
+    let mut buf = [0u8; STORAGE_HEADER_LEN];
+    buf[0..4].copy_from_slice(&STORAGE_HEADER_PATTERN);
+    buf[4..8].copy_from_slice(&header.timestamp.seconds.to_le_bytes());
+    buf[8..12].copy_from_slice(&header.timestamp.microseconds.to_le_bytes());
+    buf[12..16].copy_from_slice(&id_bytes(&header.ecu_id));
+    buf
+}
+
+fn encode_message(msg: &dlt::Message, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+    let start = buf.len();
+    let header = &msg.header;
+
+    let mut header_type = (header.version & 0x07) << 5;
+    if msg.extended_header.is_some() {
+        header_type |= UEH;
+    }
+    if matches!(header.endianness, dlt::Endianness::Big) {
+        header_type |= MSBF;
+    }
+    if header.ecu_id.is_some() {
+        header_type |= WEID;
+    }
+    if header.session_id.is_some() {
+        header_type |= WSID;
+    }
+    if header.timestamp.is_some() {
+        header_type |= WTMS;
+    }
+
+    buf.push(header_type);
+    buf.push(header.message_counter);
+    // The length, filled in at the end
+    buf.extend_from_slice(&[0, 0]);
+    if let Some(ecu_id) = &header.ecu_id {
+        buf.extend_from_slice(&id_bytes(ecu_id));
+    }
+    if let Some(session_id) = header.session_id {
+        buf.extend_from_slice(&session_id.to_be_bytes());
+    }
+    if let Some(timestamp) = header.timestamp {
+        buf.extend_from_slice(&timestamp.to_be_bytes());
+    }
+
+    if let Some(extended_header) = &msg.extended_header {
+        let (message_type, message_type_info) = message_type_bits(&extended_header.message_type);
+        buf.push(
+            (message_type_info << 4) | ((message_type & 0x07) << 1) | extended_header.verbose as u8,
+        );
+        buf.push(extended_header.argument_count);
+        buf.extend_from_slice(&id_bytes(&extended_header.application_id));
+        buf.extend_from_slice(&id_bytes(&extended_header.context_id));
+    }
+
+    let mut out = Writer {
+        buf: &mut *buf,
+        endianness: header.endianness,
+    };
+    match &msg.payload {
+        dlt::PayloadContent::Verbose(args) => {
+            for arg in args {
+                encode_argument(arg, &mut out)?;
+            }
+        }
+        dlt::PayloadContent::NonVerbose(message_id, payload) => {
+            out.u32(*message_id);
+            out.buf.extend_from_slice(payload);
+        }
+        dlt::PayloadContent::ControlMsg(control_type, payload) => {
+            // dlt-core keeps the first payload byte apart, as a
+            // ControlType; see control::control_payload
+            out.buf.push(control_type.value());
+            out.buf.extend_from_slice(payload);
+        }
+    }
+
+    let size = buf.len() - start;
+    let len = u16::try_from(size).map_err(|_| EncodeError::MessageTooLarge { size })?;
+    buf[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+/// The message type (MSTP) and message type info (MTIN) fields
+fn message_type_bits(message_type: &MessageType) -> (u8, u8) {
+    match message_type {
+        MessageType::Log(level) => (
+            0,
+            match level {
+                LogLevel::Fatal => 1,
+                LogLevel::Error => 2,
+                LogLevel::Warn => 3,
+                LogLevel::Info => 4,
+                LogLevel::Debug => 5,
+                LogLevel::Verbose => 6,
+                LogLevel::Invalid(n) => *n,
+            },
+        ),
+        MessageType::ApplicationTrace(trace_type) => (
+            1,
+            match trace_type {
+                ApplicationTraceType::Variable => 1,
+                ApplicationTraceType::FunctionIn => 2,
+                ApplicationTraceType::FunctionOut => 3,
+                ApplicationTraceType::State => 4,
+                ApplicationTraceType::VFB => 5,
+                ApplicationTraceType::Invalid(n) => *n,
+            },
+        ),
+        MessageType::NetworkTrace(trace_type) => (
+            2,
+            match trace_type {
+                NetworkTraceType::Invalid => 0,
+                NetworkTraceType::Ipc => 1,
+                NetworkTraceType::Can => 2,
+                NetworkTraceType::Flexray => 3,
+                NetworkTraceType::Most => 4,
+                NetworkTraceType::Ethernet => 5,
+                NetworkTraceType::SomeIP => 6,
+                NetworkTraceType::UserDefined(n) => *n,
+            },
+        ),
+        MessageType::Control(control_type) => (3, control_type.value()),
+        MessageType::Unknown((message_type, message_type_info)) => {
+            (*message_type, *message_type_info)
+        }
+    }
+}
+
+/// Appends payload values in the message's byte order
+struct Writer<'a> {
+    buf: &'a mut Vec<u8>,
+    endianness: dlt::Endianness,
+}
+
+macro_rules! write_num {
+    ($($name:ident: $ty:ty),*) => {
+        $(fn $name(&mut self, x: $ty) {
+            match self.endianness {
+                dlt::Endianness::Big => self.buf.extend_from_slice(&x.to_be_bytes()),
+                dlt::Endianness::Little => self.buf.extend_from_slice(&x.to_le_bytes()),
+            }
+        })*
+    };
+}
+
+impl Writer<'_> {
+    write_num!(
+        u16: u16, u32: u32, u64: u64, u128: u128,
+        i16: i16, i32: i32, i64: i64, i128: i128,
+        f32: f32, f64: f64
+    );
+
+    /// A 16 bit length, then the text, null terminated
+    fn text(&mut self, field: &'static str, text: &str) -> Result<(), EncodeError> {
+        self.length(field, text.len() + 1)?;
+        self.buf.extend_from_slice(text.as_bytes());
+        self.buf.push(0);
+        Ok(())
+    }
+
+    fn length(&mut self, field: &'static str, len: usize) -> Result<(), EncodeError> {
+        let len = u16::try_from(len).map_err(|_| EncodeError::FieldTooLong { field, len })?;
+        self.u16(len);
+        Ok(())
+    }
+}
+
+/// The type length (TYLE) field for a value of `bytes` bytes
+fn type_length(bytes: u32) -> u32 {
+    match bytes {
+        1 => 1,
+        2 => 2,
+        4 => 3,
+        8 => 4,
+        16 => 5,
+        _ => 0,
+    }
+}
+
+fn encode_argument(arg: &dlt::Argument, out: &mut Writer) -> Result<(), EncodeError> {
+    let type_info = &arg.type_info;
+    let vari = type_info.has_variable_info;
+    let name = arg.name.as_deref().unwrap_or_default();
+    let unit = arg.unit.as_deref().unwrap_or_default();
+    let coding = match type_info.coding {
+        dlt::StringCoding::ASCII => 0,
+        dlt::StringCoding::UTF8 => 1,
+        dlt::StringCoding::Reserved(n) => n as u32,
+    };
+    let fixp = matches!(
+        type_info.kind,
+        dlt::TypeInfoKind::SignedFixedPoint(_) | dlt::TypeInfoKind::UnsignedFixedPoint(_)
+    ) && arg.fixed_point.is_some();
+
+    let (flags, size) = match &arg.value {
+        dlt::Value::Bool(_) => (TYPE_BOOL, 1),
+        dlt::Value::U8(_) => (TYPE_UINT, 1),
+        dlt::Value::U16(_) => (TYPE_UINT, 2),
+        dlt::Value::U32(_) => (TYPE_UINT, 4),
+        dlt::Value::U64(_) => (TYPE_UINT, 8),
+        dlt::Value::U128(_) => (TYPE_UINT, 16),
+        dlt::Value::I8(_) => (TYPE_SINT, 1),
+        dlt::Value::I16(_) => (TYPE_SINT, 2),
+        dlt::Value::I32(_) => (TYPE_SINT, 4),
+        dlt::Value::I64(_) => (TYPE_SINT, 8),
+        dlt::Value::I128(_) => (TYPE_SINT, 16),
+        dlt::Value::F32(_) => (TYPE_FLOA, 4),
+        dlt::Value::F64(_) => (TYPE_FLOA, 8),
+        dlt::Value::StringVal(_) => (TYPE_STRG | (coding << TYPE_SCOD_SHIFT), 0),
+        dlt::Value::Raw(_) => (TYPE_RAWD, 0),
+    };
+    let mut type_info_bits = flags | type_length(size);
+    if vari {
+        type_info_bits |= TYPE_VARI;
+    }
+    if fixp {
+        type_info_bits |= TYPE_FIXP;
+    }
+    out.u32(type_info_bits);
+
+    match &arg.value {
+        dlt::Value::StringVal(s) => {
+            // Strings are null terminated, and the length counts it
+            out.length("string", s.len() + 1)?;
+            if vari {
+                out.text("name", name)?;
+            }
+            out.buf.extend_from_slice(s.as_bytes());
+            out.buf.push(0);
+            return Ok(());
+        }
+        dlt::Value::Raw(bytes) => {
+            out.length("raw value", bytes.len())?;
+            if vari {
+                out.text("name", name)?;
+            }
+            out.buf.extend_from_slice(bytes);
+            return Ok(());
+        }
+        dlt::Value::Bool(_) => {
+            if vari {
+                out.text("name", name)?;
+            }
+        }
+        _ => {
+            // Numbers carry a unit as well as a name
+            if vari {
+                out.length("name", name.len() + 1)?;
+                out.length("unit", unit.len() + 1)?;
+                out.buf.extend_from_slice(name.as_bytes());
+                out.buf.push(0);
+                out.buf.extend_from_slice(unit.as_bytes());
+                out.buf.push(0);
+            }
+        }
+    }
+
+    if let (true, Some(fixed_point)) = (fixp, &arg.fixed_point) {
+        out.f32(fixed_point.quantization);
+        match fixed_point.offset {
+            dlt::FixedPointValue::I32(offset) => out.i32(offset),
+            dlt::FixedPointValue::I64(offset) => out.i64(offset),
+        }
+    }
+
+    match &arg.value {
+        dlt::Value::Bool(b) => out.buf.push(*b),
+        dlt::Value::U8(x) => out.buf.push(*x),
+        dlt::Value::U16(x) => out.u16(*x),
+        dlt::Value::U32(x) => out.u32(*x),
+        dlt::Value::U64(x) => out.u64(*x),
+        dlt::Value::U128(x) => out.u128(*x),
+        dlt::Value::I8(x) => out.buf.push(*x as u8),
+        dlt::Value::I16(x) => out.i16(*x),
+        dlt::Value::I32(x) => out.i32(*x),
+        dlt::Value::I64(x) => out.i64(*x),
+        dlt::Value::I128(x) => out.i128(*x),
+        dlt::Value::F32(x) => out.f32(*x),
+        dlt::Value::F64(x) => out.f64(*x),
+        // Written above
+        dlt::Value::StringVal(_) | dlt::Value::Raw(_) => (),
+    }
+    Ok(())
+}
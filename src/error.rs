@@ -0,0 +1,146 @@
+use thiserror::Error;
+
+/// Everything that can go wrong while reading DLT data or sending it
+/// to Modality.
+///
+/// Offsets are in bytes. Errors from the single-message readers (like
+/// [read_dlt_message](crate::read_dlt_message)) are relative to the
+/// start of the message; [DltStream](crate::stream::DltStream) makes
+/// them relative to the start of the stream.
+#[derive(Debug, Error)]
+pub enum DltPluginError {
+    /// The input ended cleanly, at a message boundary.
+    #[error("End of input")]
+    Eof,
+
+    /// The input ended in the middle of a message.
+    #[error(
+        "Truncated message at offset {offset}: expected {expected} bytes, \
+         but only {available} were available"
+    )]
+    Truncated {
+        offset: u64,
+        expected: usize,
+        available: usize,
+    },
+
+    /// The standard header couldn't be parsed, so the message size is
+    /// unknown.
+    #[error("Invalid DLT header at offset {offset} ({reason}): {header:02x?}")]
+    InvalidHeader {
+        offset: u64,
+        header: Vec<u8>,
+        reason: String,
+    },
+
+    #[error("Invalid DLT storage header at offset {offset}: {header:02x?}")]
+    InvalidStorageHeader { offset: u64, header: Vec<u8> },
+
+    #[error("Invalid DLT serial header at offset {offset}: {header:02x?}")]
+    InvalidSerialHeader { offset: u64, header: Vec<u8> },
+
+    /// The header announces a message larger than we're willing to
+    /// read. The message is skipped.
+    #[error(
+        "Message at offset {offset} is {size} bytes, more than the maximum of {max}: {header:02x?}"
+    )]
+    MessageTooLarge {
+        offset: u64,
+        size: usize,
+        max: usize,
+        header: Vec<u8>,
+    },
+
+    /// The message was read completely, but dlt-core couldn't parse it.
+    #[error(
+        "Failed to parse {size} byte DLT message at offset {offset} ({reason}): {header:02x?}"
+    )]
+    Parse {
+        offset: u64,
+        size: usize,
+        header: Vec<u8>,
+        reason: String,
+    },
+
+    /// The message was parsed, but didn't account for all of its bytes.
+    #[error(
+        "{remaining} bytes left over after parsing {size} byte DLT message at offset {offset}"
+    )]
+    TrailingData {
+        offset: u64,
+        size: usize,
+        remaining: usize,
+    },
+
+    /// dlt-daemon didn't answer a control request in time.
+    #[error("No response to {service} control request within {timeout:?}")]
+    ControlTimeout {
+        service: &'static str,
+        timeout: std::time::Duration,
+    },
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// Talking to the Modality backend failed.
+    #[error("Modality backend error: {0}")]
+    Backend(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl DltPluginError {
+    pub fn backend(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        DltPluginError::Backend(e.into())
+    }
+
+    /// Where in the input the error occurred, if it's related to a
+    /// particular position.
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            DltPluginError::Truncated { offset, .. }
+            | DltPluginError::InvalidHeader { offset, .. }
+            | DltPluginError::InvalidStorageHeader { offset, .. }
+            | DltPluginError::InvalidSerialHeader { offset, .. }
+            | DltPluginError::MessageTooLarge { offset, .. }
+            | DltPluginError::Parse { offset, .. }
+            | DltPluginError::TrailingData { offset, .. } => Some(*offset),
+            DltPluginError::Eof
+            | DltPluginError::ControlTimeout { .. }
+            | DltPluginError::Io(_)
+            | DltPluginError::Backend(_) => None,
+        }
+    }
+
+    /// Shift the error's offset by `base`, e.g. to make an offset
+    /// relative to a message into one relative to the whole stream.
+    pub fn at_offset(mut self, base: u64) -> Self {
+        match &mut self {
+            DltPluginError::Truncated { offset, .. }
+            | DltPluginError::InvalidHeader { offset, .. }
+            | DltPluginError::InvalidStorageHeader { offset, .. }
+            | DltPluginError::InvalidSerialHeader { offset, .. }
+            | DltPluginError::MessageTooLarge { offset, .. }
+            | DltPluginError::Parse { offset, .. }
+            | DltPluginError::TrailingData { offset, .. } => *offset += base,
+            DltPluginError::Eof
+            | DltPluginError::ControlTimeout { .. }
+            | DltPluginError::Io(_)
+            | DltPluginError::Backend(_) => (),
+        }
+        self
+    }
+
+    /// Was the offending message consumed completely, so reading can
+    /// carry on with the next one? If so, returns its size.
+    pub fn skipped_message_size(&self) -> Option<usize> {
+        match self {
+            DltPluginError::Parse { size, .. }
+            | DltPluginError::TrailingData { size, .. }
+            | DltPluginError::MessageTooLarge { size, .. } => Some(*size),
+            _ => None,
+        }
+    }
+
+    pub fn is_backend(&self) -> bool {
+        matches!(self, DltPluginError::Backend(_))
+    }
+}
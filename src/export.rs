@@ -0,0 +1,428 @@
+//! Rebuilding DLT messages from Modality event attributes, for writing
+//! `.dlt` files that tools like dlt-viewer can open. This is the inverse
+//! of [dlt_message_to_event_attrs](crate::convert::dlt_message_to_event_attrs),
+//! as far as the attributes allow:
+//!
+//! * Headers are rebuilt from `event.ecu_id`, `event.session_id`,
+//!   `event.timestamp`, `event.application_id`, `event.context_id` and
+//!   the message type attributes (`event.message_type`,
+//!   `event.log_level`, and so on).
+//! * Verbose payloads are rebuilt from the `event.payload` attributes,
+//!   in the order they're given. Raw arguments aren't converted to
+//!   attributes in the first place, so they can't come back. Attributes
+//!   don't keep the width of numbers, so integers come back as `I64`
+//!   and floats as `F64`.
+//! * Non-verbose messages keep only their message id, and control
+//!   messages only their service id and status, since that's all the
+//!   conversion records.
+//!
+//! Everything is written little endian.
+
+use std::{collections::HashMap, io::Write};
+
+use auxon_sdk::api::{AttrKey, AttrVal, Nanoseconds};
+use dlt_core::dlt::{
+    self, ApplicationTraceType, ControlType, DltTimeStamp, LogLevel, MessageType, NetworkTraceType,
+    StorageHeader,
+};
+
+use crate::{
+    control::ControlStatus,
+    convert::{control_type_to_str, log_level_to_str},
+    encode::{self, EncodeError},
+    stream::Framing,
+};
+
+/// DLT protocol version written in standard headers
+const DLT_VERSION: u8 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("Failed to write DLT file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to encode DLT message: {0}")]
+    Encode(#[from] EncodeError),
+
+    #[error("Invalid event JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Can't export '{key}': {reason}")]
+    InvalidAttr { key: String, reason: String },
+}
+
+impl ExportError {
+    fn invalid(key: &str, reason: impl Into<String>) -> Self {
+        ExportError::InvalidAttr {
+            key: key.to_owned(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Writes events as DLT messages, each preceded by a storage header,
+/// as in `.dlt` files.
+pub struct DltFileWriter<W> {
+    writer: W,
+    /// The next message counter for each ECU
+    message_counters: HashMap<Option<String>, u8>,
+}
+
+impl<W: Write> DltFileWriter<W> {
+    pub fn new(writer: W) -> Self {
+        DltFileWriter {
+            writer,
+            message_counters: HashMap::new(),
+        }
+    }
+
+    /// Rebuild the message described by an event's attributes, and
+    /// write it.
+    pub fn write_event(&mut self, attrs: &[(AttrKey, AttrVal)]) -> Result<(), ExportError> {
+        let mut msg = event_attrs_to_dlt_message(attrs)?;
+        let counter = self
+            .message_counters
+            .entry(msg.header.ecu_id.clone())
+            .or_default();
+        msg.header.message_counter = *counter;
+        *counter = counter.wrapping_add(1);
+
+        let bytes =
+            encode::encode_framed(&msg, Framing::StorageHeader, Some(&storage_header(&msg)))?;
+        self.writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), ExportError> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// The storage header for `msg`. Events only carry the ECU's uptime, so
+/// that's used in place of the time the message was logged.
+fn storage_header(msg: &dlt::Message) -> StorageHeader {
+    // Header timestamps count 0.1 milliseconds
+    let ticks = msg.header.timestamp.unwrap_or(0);
+    StorageHeader {
+        timestamp: DltTimeStamp {
+            seconds: ticks / 10_000,
+            microseconds: (ticks % 10_000) * 100,
+        },
+        ecu_id: msg.header.ecu_id.clone().unwrap_or_default(),
+    }
+}
+
+/// Rebuild the DLT message described by an event's attributes. The
+/// message counter is left at 0.
+pub fn event_attrs_to_dlt_message(
+    attrs: &[(AttrKey, AttrVal)],
+) -> Result<dlt::Message, ExportError> {
+    let application_id = string_attr(attrs, "event.application_id")?;
+    let context_id = string_attr(attrs, "event.context_id")?;
+
+    // The conversion adds application and context ids exactly when
+    // there's an extended header
+    let extended_header_info = match application_id {
+        Some(app_id) => Some(dlt::ExtendedHeaderConfig {
+            message_type: message_type(attrs)?,
+            app_id,
+            context_id: context_id.unwrap_or_default(),
+        }),
+        None => None,
+    };
+
+    let payload = payload(attrs, extended_header_info.as_ref())?;
+
+    Ok(dlt::Message::new(
+        dlt::MessageConfig {
+            version: DLT_VERSION,
+            counter: 0,
+            endianness: dlt::Endianness::Little,
+            ecu_id: string_attr(attrs, "event.ecu_id")?,
+            session_id: int_attr(attrs, "event.session_id")?,
+            timestamp: timestamp_attr(attrs)?,
+            payload,
+            extended_header_info,
+        },
+        None,
+    ))
+}
+
+fn message_type(attrs: &[(AttrKey, AttrVal)]) -> Result<MessageType, ExportError> {
+    let key = "event.message_type";
+    Ok(match string_attr(attrs, key)?.as_deref() {
+        Some("log") | None => MessageType::Log(log_level(attrs)?),
+        Some("application_trace") => MessageType::ApplicationTrace(by_name(
+            attrs,
+            "event.application_trace_type",
+            [
+                ApplicationTraceType::Variable,
+                ApplicationTraceType::FunctionIn,
+                ApplicationTraceType::FunctionOut,
+                ApplicationTraceType::State,
+                ApplicationTraceType::VFB,
+            ],
+            |t| t.as_ref().to_lowercase(),
+        )?),
+        Some("network_trace") => MessageType::NetworkTrace(by_name(
+            attrs,
+            "event.network_trace_type",
+            [
+                NetworkTraceType::Ipc,
+                NetworkTraceType::Can,
+                NetworkTraceType::Flexray,
+                NetworkTraceType::Most,
+                NetworkTraceType::Ethernet,
+                NetworkTraceType::SomeIP,
+            ],
+            |t| t.as_ref().to_lowercase(),
+        )?),
+        Some("control") => MessageType::Control(control_type(attrs)?),
+        Some(other) => {
+            return Err(ExportError::invalid(
+                key,
+                format!("unsupported message type '{other}'"),
+            ))
+        }
+    })
+}
+
+/// The log level, defaulting to info
+fn log_level(attrs: &[(AttrKey, AttrVal)]) -> Result<LogLevel, ExportError> {
+    if find(attrs, "event.log_level").is_none() {
+        return Ok(LogLevel::Info);
+    }
+    by_name(
+        attrs,
+        "event.log_level",
+        [
+            LogLevel::Fatal,
+            LogLevel::Error,
+            LogLevel::Warn,
+            LogLevel::Info,
+            LogLevel::Debug,
+            LogLevel::Verbose,
+        ],
+        |l| log_level_to_str(*l).to_owned(),
+    )
+}
+
+fn control_type(attrs: &[(AttrKey, AttrVal)]) -> Result<ControlType, ExportError> {
+    by_name(
+        attrs,
+        "event.control_type",
+        [ControlType::Request, ControlType::Response],
+        |t| control_type_to_str(t).to_owned(),
+    )
+}
+
+/// The one of `candidates` whose name matches the attribute `key`
+fn by_name<T, const N: usize>(
+    attrs: &[(AttrKey, AttrVal)],
+    key: &str,
+    candidates: [T; N],
+    name: impl Fn(&T) -> String,
+) -> Result<T, ExportError> {
+    let value = string_attr(attrs, key)?.ok_or_else(|| ExportError::invalid(key, "missing"))?;
+    candidates
+        .into_iter()
+        .find(|c| name(c) == value)
+        .ok_or_else(|| ExportError::invalid(key, format!("unknown value '{value}'")))
+}
+
+fn payload(
+    attrs: &[(AttrKey, AttrVal)],
+    extended_header: Option<&dlt::ExtendedHeaderConfig>,
+) -> Result<dlt::PayloadContent, ExportError> {
+    let key = "event.payload_type";
+    let payload_type = match string_attr(attrs, key)? {
+        Some(payload_type) => payload_type,
+        None if find(attrs, "event.message_id").is_some() => "non_verbose".to_owned(),
+        None => "verbose".to_owned(),
+    };
+
+    match payload_type.as_str() {
+        "verbose" if extended_header.is_none() => Err(ExportError::invalid(
+            key,
+            "verbose messages need event.application_id and event.context_id",
+        )),
+        "verbose" => Ok(dlt::PayloadContent::Verbose(verbose_arguments(attrs)?)),
+        "non_verbose" => {
+            let message_id = int_attr(attrs, "event.message_id")?
+                .ok_or_else(|| ExportError::invalid("event.message_id", "missing"))?;
+            Ok(dlt::PayloadContent::NonVerbose(message_id, vec![]))
+        }
+        "control" => control_payload(attrs),
+        other => Err(ExportError::invalid(
+            key,
+            format!("unknown payload type '{other}'"),
+        )),
+    }
+}
+
+/// The service id, followed by the status for responses
+fn control_payload(attrs: &[(AttrKey, AttrVal)]) -> Result<dlt::PayloadContent, ExportError> {
+    let service_id: u32 = int_attr(attrs, "event.service_id")?
+        .ok_or_else(|| ExportError::invalid("event.service_id", "missing"))?;
+    let mut payload = service_id.to_le_bytes().to_vec();
+
+    if matches!(control_type(attrs)?, ControlType::Response) {
+        let key = "event.status";
+        let status = match string_attr(attrs, key)?.as_deref() {
+            Some(status) => [
+                ControlStatus::Ok,
+                ControlStatus::NotSupported,
+                ControlStatus::Error,
+            ]
+            .into_iter()
+            .find(|s| s.as_str() == status)
+            .ok_or_else(|| ExportError::invalid(key, format!("unknown status '{status}'")))?,
+            None => ControlStatus::Ok,
+        };
+        payload.push(status.value());
+    }
+
+    // dlt-core keeps the first payload byte apart, as a ControlType; see
+    // control::control_payload
+    let first = payload.remove(0);
+    Ok(dlt::PayloadContent::ControlMsg(
+        ControlType::from_value(first),
+        payload,
+    ))
+}
+
+/// Verbose arguments, from `event.payload` (a single unnamed argument)
+/// or `event.payload.<name>`. Names which are just a number are
+/// positions, standing in for unnamed arguments. Integers are written
+/// as 64 bit signed values, and floats as 64 bit floats.
+fn verbose_arguments(attrs: &[(AttrKey, AttrVal)]) -> Result<Vec<dlt::Argument>, ExportError> {
+    let mut args = vec![];
+    for (key, val) in attrs {
+        let key = key.as_ref();
+        let name = if key == "event.payload" {
+            None
+        } else if let Some(name) = key.strip_prefix("event.payload.") {
+            let is_position = name.bytes().all(|b| b.is_ascii_digit());
+            (!is_position).then(|| name.to_owned())
+        } else {
+            continue;
+        };
+
+        let Some(value) = attr_val_to_value(val) else {
+            return Err(ExportError::invalid(key, "not a DLT argument type"));
+        };
+        args.push(encode::verbose_argument(name, value));
+    }
+    Ok(args)
+}
+
+/// The widest DLT value for an attribute value
+fn attr_val_to_value(val: &AttrVal) -> Option<dlt::Value> {
+    Some(match val {
+        AttrVal::Bool(b) => dlt::Value::Bool(*b as u8),
+        AttrVal::Integer(i) => dlt::Value::I64(*i),
+        AttrVal::Float(f) => dlt::Value::F64(f.0),
+        AttrVal::String(s) => dlt::Value::StringVal(s.to_string()),
+        AttrVal::Timestamp(ns) => dlt::Value::U64(ns.get_raw()),
+        _ => return None,
+    })
+}
+
+fn find<'a>(attrs: &'a [(AttrKey, AttrVal)], key: &str) -> Option<&'a AttrVal> {
+    attrs
+        .iter()
+        .find(|(k, _)| k.as_ref() == key)
+        .map(|(_, v)| v)
+}
+
+fn string_attr(attrs: &[(AttrKey, AttrVal)], key: &str) -> Result<Option<String>, ExportError> {
+    match find(attrs, key) {
+        None => Ok(None),
+        Some(AttrVal::String(s)) => Ok(Some(s.to_string())),
+        Some(_) => Err(ExportError::invalid(key, "expected a string")),
+    }
+}
+
+fn int_attr<T: TryFrom<i64>>(
+    attrs: &[(AttrKey, AttrVal)],
+    key: &str,
+) -> Result<Option<T>, ExportError> {
+    match find(attrs, key) {
+        None => Ok(None),
+        Some(AttrVal::Integer(i)) => T::try_from(*i)
+            .map(Some)
+            .map_err(|_| ExportError::invalid(key, format!("{i} is out of range"))),
+        Some(_) => Err(ExportError::invalid(key, "expected an integer")),
+    }
+}
+
+/// The header timestamp. The conversion stores it as is, in 0.1
+/// millisecond ticks, as `event.timestamp`.
+fn timestamp_attr(attrs: &[(AttrKey, AttrVal)]) -> Result<Option<u32>, ExportError> {
+    let key = "event.timestamp";
+    let ticks = match find(attrs, key) {
+        None => return Ok(None),
+        Some(AttrVal::Timestamp(ns)) => ns.get_raw(),
+        Some(AttrVal::Integer(i)) if *i >= 0 => *i as u64,
+        Some(_) => return Err(ExportError::invalid(key, "expected a timestamp")),
+    };
+    u32::try_from(ticks)
+        .map(Some)
+        .map_err(|_| ExportError::invalid(key, format!("{ticks} is out of range")))
+}
+
+/// Parse an event exported from Modality as JSON: an object of
+/// attribute values by key, or an object with such an object under
+/// `attrs`. Keys other than `event.*` are ignored, as are values which
+/// aren't scalars.
+pub fn event_attrs_from_json(json: &str) -> Result<Vec<(AttrKey, AttrVal)>, ExportError> {
+    let serde_json::Value::Object(mut object) = serde_json::from_str(json)? else {
+        return Err(ExportError::invalid("event", "expected a JSON object"));
+    };
+    if let Some(serde_json::Value::Object(attrs)) = object.remove("attrs") {
+        object = attrs;
+    }
+
+    let mut attrs = vec![];
+    for (key, value) in object {
+        if !key.starts_with("event.") {
+            continue;
+        }
+        let val: AttrVal = match value {
+            serde_json::Value::Bool(b) => b.into(),
+            serde_json::Value::String(s) if key == "event.timestamp" => {
+                let ns = s
+                    .trim()
+                    .trim_end_matches("ns")
+                    .parse::<u64>()
+                    .map_err(|_| ExportError::invalid(&key, format!("'{s}' is not a timestamp")))?;
+                Nanoseconds::from(ns).into()
+            }
+            serde_json::Value::String(s) => s.into(),
+            serde_json::Value::Number(n) if key == "event.timestamp" => {
+                let ns = n
+                    .as_u64()
+                    .ok_or_else(|| ExportError::invalid(&key, format!("{n} is not a timestamp")))?;
+                Nanoseconds::from(ns).into()
+            }
+            serde_json::Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    i.into()
+                } else if let Some(u) = n.as_u64() {
+                    (u as i128).into()
+                } else if let Some(f) = n.as_f64() {
+                    f.into()
+                } else {
+                    continue;
+                }
+            }
+            _ => continue,
+        };
+        attrs.push((key.into(), val));
+    }
+    Ok(attrs)
+}
@@ -0,0 +1,227 @@
+//! Reading SOME/IP service interfaces from FIBEX (ASAM MCD-2 NET) files.
+
+use std::collections::HashMap;
+
+use roxmltree::{Document, Node};
+
+use crate::{
+    payload::{DataType, Parameter},
+    someip::{Method, Service, SomeIpServices},
+    xml::{attr, child, child_text, children, descendants, parse_int},
+};
+
+/// How deeply data types may nest, to stop cyclic definitions
+const MAX_TYPE_DEPTH: usize = 32;
+
+/// Add the service interfaces in `doc` to `services`.
+pub(crate) fn parse_services(doc: &Document, services: &mut SomeIpServices) -> Result<(), String> {
+    let root = doc.root_element();
+    let types = TypeResolver::new(root);
+
+    for interface in descendants(root, "SERVICE-INTERFACE") {
+        let name = child_text(interface, "SHORT-NAME").unwrap_or_default();
+        let Some(service_id) = child_text(interface, "SERVICE-IDENTIFIER").and_then(parse_int)
+        else {
+            tracing::debug!(service = name, "Skipping service interface without an id");
+            continue;
+        };
+
+        let mut service = Service {
+            name: name.to_owned(),
+            methods: HashMap::new(),
+        };
+
+        for method in descendants(interface, "METHOD").chain(descendants(interface, "EVENT")) {
+            let Some(method_id) = method_identifier(method) else {
+                continue;
+            };
+            service.methods.insert(
+                method_id,
+                Method {
+                    name: child_text(method, "SHORT-NAME")
+                        .unwrap_or_default()
+                        .to_owned(),
+                    request: types.parameters(method, "INPUT-PARAMETERS")?,
+                    response: types.parameters(method, "RETURN-PARAMETERS")?,
+                },
+            );
+        }
+
+        for field in descendants(interface, "FIELD") {
+            let field_param = Parameter {
+                name: child_text(field, "SHORT-NAME")
+                    .unwrap_or_default()
+                    .to_owned(),
+                data_type: types.typed_element(field, 0)?,
+            };
+            let accessors: [(&str, fn(&Parameter) -> Method); 3] = [
+                ("GETTER", Method::field_getter),
+                ("SETTER", Method::field_setter),
+                ("NOTIFIER", Method::field_notifier),
+            ];
+            for (accessor, make_method) in accessors {
+                if let Some(method_id) = child(field, accessor).and_then(method_identifier) {
+                    service.methods.insert(method_id, make_method(&field_param));
+                }
+            }
+        }
+
+        services.add_service(service_id as u16, service);
+    }
+
+    Ok(())
+}
+
+fn method_identifier(node: Node) -> Option<u16> {
+    child_text(node, "METHOD-IDENTIFIER")
+        .or_else(|| child_text(node, "NOTIFICATION-IDENTIFIER"))
+        .and_then(parse_int)
+        .map(|id| id as u16)
+}
+
+/// Resolves `DATATYPE-REF`s, through the datatypes and codings they
+/// refer to.
+struct TypeResolver<'a, 'i> {
+    datatypes: HashMap<&'a str, Node<'a, 'i>>,
+    codings: HashMap<&'a str, Node<'a, 'i>>,
+}
+
+impl<'a, 'i> TypeResolver<'a, 'i> {
+    fn new(root: Node<'a, 'i>) -> Self {
+        let by_id = |name: &'a str| -> HashMap<&'a str, Node<'a, 'i>> {
+            descendants(root, name)
+                .filter_map(|node| Some((attr(node, "ID")?, node)))
+                .collect()
+        };
+        TypeResolver {
+            datatypes: by_id("DATATYPE"),
+            codings: by_id("CODING"),
+        }
+    }
+
+    /// The parameters in the `list` child of `method`, in order
+    fn parameters(&self, method: Node<'a, 'i>, list: &str) -> Result<Vec<Parameter>, String> {
+        let Some(list) = child(method, list) else {
+            return Ok(vec![]);
+        };
+        self.ordered_members(list, 0)
+    }
+
+    /// The child elements of `list` as parameters, ordered by their
+    /// `POSITION`s
+    fn ordered_members(&self, list: Node<'a, 'i>, depth: usize) -> Result<Vec<Parameter>, String> {
+        let mut members = vec![];
+        for member in list.children().filter(|n| n.is_element()) {
+            let position = child_text(member, "POSITION")
+                .and_then(parse_int)
+                .unwrap_or(members.len() as u64);
+            let param = Parameter {
+                name: child_text(member, "SHORT-NAME")
+                    .unwrap_or_default()
+                    .to_owned(),
+                data_type: self.typed_element(member, depth)?,
+            };
+            members.push((position, param));
+        }
+        members.sort_by_key(|(position, _)| *position);
+        Ok(members.into_iter().map(|(_, param)| param).collect())
+    }
+
+    /// The type of an element with a `DATATYPE-REF`, and possibly an
+    /// `ARRAY-DECLARATION`
+    fn typed_element(&self, node: Node<'a, 'i>, depth: usize) -> Result<DataType, String> {
+        let name = child_text(node, "SHORT-NAME").unwrap_or_default();
+        let type_id = child(node, "DATATYPE-REF")
+            .and_then(|r| attr(r, "ID-REF"))
+            .ok_or_else(|| format!("'{name}' has no DATATYPE-REF"))?;
+        let data_type = self.datatype(type_id, depth)?;
+
+        let Some(array) = child(node, "ARRAY-DECLARATION") else {
+            return Ok(data_type);
+        };
+        // Innermost dimension last, so wrap from the end
+        let dimensions: Vec<_> = children(array, "ARRAY-DIMENSION").collect();
+        Ok(dimensions.iter().rev().fold(data_type, |element, dim| {
+            let min = child_text(*dim, "MINIMUM-SIZE").and_then(parse_int);
+            let max = child_text(*dim, "MAXIMUM-SIZE").and_then(parse_int);
+            let fixed_len = match (min, max) {
+                (Some(min), Some(max)) if min == max => Some(min as usize),
+                _ => None,
+            };
+            DataType::Array {
+                element: Box::new(element),
+                fixed_len,
+            }
+        }))
+    }
+
+    fn datatype(&self, id: &str, depth: usize) -> Result<DataType, String> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(format!("datatype '{id}' is nested too deeply"));
+        }
+        let node = self
+            .datatypes
+            .get(id)
+            .ok_or_else(|| format!("unknown datatype '{id}'"))?;
+
+        // Common and enum datatypes are described by their coding
+        if let Some(coding_id) = child(*node, "CODING-REF").and_then(|r| attr(r, "ID-REF")) {
+            return self.coding(coding_id);
+        }
+
+        let class = child_text(*node, "COMPLEX-DATATYPE-CLASS").unwrap_or_default();
+        let members = child(*node, "MEMBERS");
+        match (class, members) {
+            ("STRUCTURE", Some(members)) => {
+                Ok(DataType::Struct(self.ordered_members(members, depth + 1)?))
+            }
+            ("TYPEDEF", Some(members)) => {
+                let member = members
+                    .children()
+                    .find(|n| n.is_element())
+                    .ok_or_else(|| format!("typedef '{id}' has no member"))?;
+                self.typed_element(member, depth + 1)
+            }
+            _ => Err(format!(
+                "datatype '{id}' has an unsupported class '{class}'"
+            )),
+        }
+    }
+
+    fn coding(&self, id: &str) -> Result<DataType, String> {
+        let coded_type = self
+            .codings
+            .get(id)
+            .and_then(|coding| child(*coding, "CODED-TYPE"))
+            .ok_or_else(|| format!("unknown coding '{id}'"))?;
+
+        let base_type = attr(coded_type, "BASE-DATA-TYPE").unwrap_or_default();
+        let encoding = attr(coded_type, "ENCODING").unwrap_or_default();
+        let bit_length = child_text(coded_type, "BIT-LENGTH").and_then(parse_int);
+        let fixed_len = match attr(coded_type, "CATEGORY") {
+            Some("STANDARD-LENGTH-TYPE") => bit_length.map(|bits| (bits / 8) as usize),
+            _ => None,
+        };
+
+        Ok(match base_type {
+            _ if encoding == "BOOLEAN" => DataType::Bool,
+            "A_UINT8" => DataType::Unsigned(1),
+            "A_UINT16" => DataType::Unsigned(2),
+            "A_UINT32" => DataType::Unsigned(4),
+            "A_UINT64" => DataType::Unsigned(8),
+            "A_INT8" => DataType::Signed(1),
+            "A_INT16" => DataType::Signed(2),
+            "A_INT32" => DataType::Signed(4),
+            "A_INT64" => DataType::Signed(8),
+            "A_FLOAT32" => DataType::Float32,
+            "A_FLOAT64" => DataType::Float64,
+            "A_ASCIISTRING" | "A_UNICODE2STRING" => DataType::String { fixed_len },
+            "A_BYTEFIELD" | "A_BITFIELD" => DataType::Bytes { fixed_len },
+            _ => {
+                return Err(format!(
+                    "coding '{id}' has an unsupported base data type '{base_type}'"
+                ))
+            }
+        })
+    }
+}
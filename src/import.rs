@@ -0,0 +1,323 @@
+//! The importer's pipeline: reading a .dlt file (or the selected part
+//! of it), converting messages to events and sending them to an
+//! [IngestSink].
+
+use std::{path::Path, pin::pin, sync::Arc, time::Duration};
+
+use auxon_sdk::plugin_utils::{ingest::Config, serde::from_str};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::BufReader;
+use tracing::{info, warn};
+
+use crate::{
+    metrics::{CountingReader, Metrics},
+    range::{Bounds, ImportRange, Selection},
+    send::{message_channel, HasCommonConfig, Sender},
+    shutdown::{Shutdown, DEFAULT_SHUTDOWN_TIMEOUT},
+    sink::IngestSink,
+    stream::{DltStream, Framing},
+    CommonConfig, DltPluginError, DEFAULT_MAX_MESSAGE_SIZE,
+};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Serialize, Deserialize)]
+pub struct ImporterConfig {
+    /// If the file ends in the middle of a message, send a
+    /// `truncated_message` event describing it.
+    ///
+    /// If not given, defaults to false; the truncation is only logged.
+    #[serde(default, deserialize_with = "from_str")]
+    pub truncated_message_event: Option<bool>,
+
+    /// Only import messages whose storage header time is at or after
+    /// this, in seconds since the Unix epoch.
+    #[serde(default, deserialize_with = "from_str")]
+    pub start_time: Option<f64>,
+
+    /// Only import messages whose storage header time is before this,
+    /// in seconds since the Unix epoch.
+    #[serde(default, deserialize_with = "from_str")]
+    pub end_time: Option<f64>,
+
+    /// Only import messages whose header timestamp (the ECU's uptime)
+    /// is at or after this, in seconds.
+    #[serde(default, deserialize_with = "from_str")]
+    pub start_uptime: Option<f64>,
+
+    /// Only import messages whose header timestamp (the ECU's uptime)
+    /// is before this, in seconds.
+    #[serde(default, deserialize_with = "from_str")]
+    pub end_uptime: Option<f64>,
+
+    /// Only import messages from this one on, counting from 0.
+    #[serde(default, deserialize_with = "from_str")]
+    pub start_index: Option<u64>,
+
+    /// Only import messages before this one, counting from 0.
+    #[serde(default, deserialize_with = "from_str")]
+    pub end_index: Option<u64>,
+
+    /// Only import messages starting at or after this byte offset.
+    #[serde(default, deserialize_with = "from_str")]
+    pub start_offset: Option<u64>,
+
+    /// Only import messages starting before this byte offset.
+    #[serde(default, deserialize_with = "from_str")]
+    pub end_offset: Option<u64>,
+
+    #[serde(flatten)]
+    pub common: CommonConfig,
+}
+
+impl ImporterConfig {
+    /// The part of the file to import
+    pub fn import_range(&self) -> ImportRange {
+        // Storage header times have microsecond resolution, and header
+        // timestamps count 0.1 milliseconds
+        let micros = |seconds: f64| (seconds * 1e6) as u64;
+        let ticks = |seconds: f64| (seconds * 1e4) as u32;
+        ImportRange {
+            storage_time: Bounds::new(self.start_time.map(micros), self.end_time.map(micros)),
+            uptime: Bounds::new(self.start_uptime.map(ticks), self.end_uptime.map(ticks)),
+            index: Bounds::new(self.start_index, self.end_index),
+            offset: Bounds::new(self.start_offset, self.end_offset),
+        }
+    }
+}
+
+impl HasCommonConfig for ImporterConfig {
+    fn common_config(&self) -> &CommonConfig {
+        &self.common
+    }
+}
+
+/// Where the file was cut off
+struct Truncation {
+    offset: u64,
+    size: usize,
+    expected_size: usize,
+}
+
+impl Truncation {
+    /// Queue a `truncated_message` event on the timeline of the last
+    /// message read, which is most likely where the truncated one came
+    /// from.
+    fn send<C: HasCommonConfig, S: IngestSink>(&self, sender: &mut Sender<C, S>) {
+        let queued = sender.push_to_last_timeline(
+            "truncated_message",
+            vec![
+                ("event.offset".into(), (self.offset as i64).into()),
+                ("event.size".into(), (self.size as i64).into()),
+                (
+                    "event.expected_size".into(),
+                    (self.expected_size as i64).into(),
+                ),
+            ],
+        );
+        if !queued {
+            warn!("No messages were read, so there's no timeline for the truncated_message event");
+        }
+    }
+}
+
+/// Imports a single .dlt file
+pub struct Importer<S: IngestSink> {
+    config: Config<ImporterConfig>,
+    sink: S,
+    metrics: Arc<Metrics>,
+    shutdown: Option<Shutdown>,
+}
+
+impl<S: IngestSink + 'static> Importer<S> {
+    pub fn new(config: Config<ImporterConfig>, sink: S) -> Self {
+        Importer {
+            config,
+            sink,
+            metrics: Metrics::new(""),
+            shutdown: None,
+        }
+    }
+
+    /// Record progress in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Stop when `shutdown` says, rather than on SIGINT or SIGTERM.
+    /// Its timeout is used instead of `shutdown_timeout_ms`.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Import the file at `dlt_file_path`.
+    pub async fn run(self, dlt_file_path: &Path) -> Result<(), BoxError> {
+        let Importer {
+            config,
+            sink,
+            metrics,
+            shutdown,
+        } = self;
+
+        let max_message_size = config
+            .plugin
+            .common
+            .max_message_size
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+        let truncated_message_event = config.plugin.truncated_message_event.unwrap_or(false);
+        let range = config.plugin.import_range();
+        let shutdown = shutdown.unwrap_or_else(|| {
+            Shutdown::listen(
+                config
+                    .plugin
+                    .common
+                    .shutdown_timeout_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            )
+        });
+        let (tx, mut rx) = message_channel(&config.plugin.common);
+        let dbc = config.plugin.common.load_dbc()?;
+        let someip_services = config.plugin.common.load_someip_services()?;
+        let non_verbose_decoder = config.plugin.common.load_non_verbose_decoder()?;
+        let mut sender = Sender::new(sink, config).with_metrics(metrics.clone());
+        if let Some(dbc) = dbc {
+            sender = sender.with_annotator(Arc::new(dbc));
+        }
+        if let Some(someip_services) = someip_services {
+            sender = sender.with_annotator(Arc::new(someip_services));
+        }
+        if let Some(non_verbose_decoder) = non_verbose_decoder {
+            sender = sender.with_annotator(Arc::new(non_verbose_decoder));
+        }
+
+        info!(
+            file = %dlt_file_path.display(),
+            "Importing DLT messages from file"
+        );
+        let mut dlt_file = std::fs::File::open(dlt_file_path)?;
+        let start = range.seek(&mut dlt_file)?;
+        if !range.is_unbounded() {
+            info!(offset = start.offset, "Found the start of the import range");
+        }
+        let dlt_file = tokio::fs::File::from_std(dlt_file);
+        let mut records = DltStream::new(
+            BufReader::new(CountingReader::new(dlt_file, metrics.clone())),
+            Framing::StorageHeader,
+        )
+        .with_max_message_size(max_message_size);
+
+        let read_metrics = metrics.clone();
+        let read_shutdown = shutdown.clone();
+        let read_task = tokio::spawn(async move {
+            let mut shutdown = pin!(read_shutdown.requested());
+            let mut index = start.index;
+            loop {
+                let record = tokio::select! {
+                    record = records.next() => match record {
+                        Some(record) => record,
+                        None => break,
+                    },
+                    signal = &mut shutdown => {
+                        info!(%signal, "Import interrupted");
+                        break;
+                    }
+                };
+
+                let record = match record {
+                    Ok(record) => record,
+                    // A file which ends mid-message, typically because the
+                    // logger lost power. Everything before it is fine.
+                    Err(DltPluginError::Truncated {
+                        offset,
+                        expected,
+                        available,
+                    }) => {
+                        let offset = start.offset + offset;
+                        warn!(
+                            offset,
+                            size = available,
+                            expected_size = expected,
+                            "File ends with a truncated message"
+                        );
+                        read_metrics.record_parse_error();
+                        return Ok(Some(Truncation {
+                            offset,
+                            size: available,
+                            expected_size: expected,
+                        }));
+                    }
+                    Err(e) if e.skipped_message_size().is_some() => {
+                        let offset = start.offset + e.offset().unwrap_or_default();
+                        if range.is_past(index, offset) {
+                            break;
+                        }
+                        index = index.map(|i| i + 1);
+                        warn!(offset, err = %e, "Skipping unparseable DLT message");
+                        read_metrics.record_parse_error();
+                        continue;
+                    }
+                    Err(e) => {
+                        read_metrics.record_parse_error();
+                        return Err(e);
+                    }
+                };
+                let selection = range.select(index, start.offset + record.offset, &record);
+                index = index.map(|i| i + 1);
+                match selection {
+                    Selection::Keep => read_metrics.record_message(&record.message),
+                    Selection::Skip => {
+                        read_metrics.record_message(&record.message);
+                        read_metrics.record_messages_dropped(1);
+                        continue;
+                    }
+                    Selection::Done => break,
+                }
+
+                if tx.send(record.message).await.is_err() {
+                    break;
+                }
+            }
+            Ok::<_, DltPluginError>(None)
+        });
+
+        let read_abort = read_task.abort_handle();
+        let send_and_finish = async {
+            let send_res = sender.run(&mut rx).await;
+            read_task.abort();
+            let read_res = match read_task.await {
+                Ok(res) => res,
+                Err(e) if e.is_cancelled() => Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            if send_res.is_ok() {
+                if let Ok(Some(truncation)) = &read_res {
+                    if truncated_message_event {
+                        truncation.send(&mut sender);
+                    }
+                }
+                sender.finish().await?;
+            }
+
+            send_res?;
+            read_res?;
+            Ok::<_, BoxError>(())
+        };
+
+        // Being asked to stop again, or taking too long about it, gives up
+        // on sending what's left
+        let res = tokio::select! {
+            res = send_and_finish => res,
+            () = shutdown.abandoned() => {
+                read_abort.abort();
+                Ok(())
+            }
+        };
+        metrics.summary().log("Finished importing");
+        res
+    }
+}
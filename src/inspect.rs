@@ -0,0 +1,371 @@
+//! Statistics about a sequence of DLT messages, to see what's in a
+//! file before importing it.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Write},
+};
+
+use dlt_core::{
+    dlt::{self, LogLevel},
+    parse::ParsedMessage,
+};
+
+use crate::{
+    convert::{dlt_message_to_event_name, log_level_to_str},
+    stream::{DltRecord, DltStream},
+    DltPluginError,
+};
+
+/// How many parse errors are kept for the report, if not configured
+pub const DEFAULT_MAX_ERRORS: usize = 100;
+
+/// Log levels in the order they're reported
+const LOG_LEVELS: [LogLevel; 7] = [
+    LogLevel::Fatal,
+    LogLevel::Error,
+    LogLevel::Warn,
+    LogLevel::Info,
+    LogLevel::Debug,
+    LogLevel::Verbose,
+    LogLevel::Invalid(0),
+];
+
+/// What was seen in a sequence of DLT records; add them with
+/// [Inspection::add_record] and [Inspection::add_error].
+#[derive(Debug, Default)]
+pub struct Inspection {
+    pub messages: u64,
+
+    /// The size of the records read, including any framing
+    pub bytes: u64,
+    pub verbose: u64,
+    pub non_verbose: u64,
+    pub control: u64,
+    pub invalid: u64,
+    pub filtered_out: u64,
+
+    /// Per ECU id; messages without one are under the empty string
+    pub ecus: BTreeMap<String, EcuStats>,
+
+    /// Message counts by ECU, application and context id
+    pub contexts: BTreeMap<(String, String, String), u64>,
+
+    /// Message counts by event name (`log`, `network_trace`, ...)
+    pub message_types: BTreeMap<&'static str, u64>,
+
+    /// Non-verbose message counts by message id
+    pub message_ids: HashMap<u32, u64>,
+
+    /// The earliest and latest storage header times, in microseconds
+    /// since the Unix epoch
+    pub storage_time_range: Option<(u64, u64)>,
+
+    /// The first parse errors, up to the configured maximum
+    pub parse_errors: Vec<ParseErrorInfo>,
+    pub parse_error_count: u64,
+
+    /// The error which stopped reading early, if any
+    pub fatal_error: Option<ParseErrorInfo>,
+
+    max_errors: Option<usize>,
+
+    /// The last message counter seen from each ECU and session
+    last_counters: HashMap<(String, Option<u32>), u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct EcuStats {
+    pub messages: u64,
+
+    /// Log message counts, from fatal to verbose, then invalid
+    pub log_levels: [u64; LOG_LEVELS.len()],
+
+    /// The earliest and latest header timestamps (ECU uptime), in 0.1ms
+    /// ticks
+    pub timestamp_range: Option<(u32, u32)>,
+
+    /// How many times a session's message counter skipped ahead
+    pub counter_gaps: u64,
+
+    /// How many messages the counter gaps add up to
+    pub missing_messages: u64,
+}
+
+#[derive(Debug)]
+pub struct ParseErrorInfo {
+    pub offset: Option<u64>,
+    pub error: String,
+}
+
+impl Inspection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep at most `max_errors` parse errors for the report; the rest
+    /// are only counted.
+    pub fn with_max_errors(mut self, max_errors: usize) -> Self {
+        self.max_errors = Some(max_errors);
+        self
+    }
+
+    /// Inspect everything `stream` yields.
+    pub fn inspect<R: Read>(mut self, stream: DltStream<R>) -> Self {
+        for res in stream {
+            match res {
+                Ok(record) => self.add_record(&record),
+                Err(e) => self.add_error(&e),
+            }
+        }
+        self
+    }
+
+    pub fn add_record(&mut self, record: &DltRecord) {
+        self.bytes += record.size;
+        if let Some(storage_header) = &record.storage_header {
+            let micros = storage_header.timestamp.seconds as u64 * 1_000_000
+                + storage_header.timestamp.microseconds as u64;
+            self.storage_time_range = Some(match self.storage_time_range {
+                Some((first, last)) => (first.min(micros), last.max(micros)),
+                None => (micros, micros),
+            });
+        }
+
+        let msg = match &record.message {
+            ParsedMessage::Item(msg) => msg,
+            ParsedMessage::Invalid => {
+                self.invalid += 1;
+                return;
+            }
+            ParsedMessage::FilteredOut(_) => {
+                self.filtered_out += 1;
+                return;
+            }
+        };
+        self.messages += 1;
+
+        match &msg.payload {
+            dlt::PayloadContent::Verbose(_) => self.verbose += 1,
+            dlt::PayloadContent::NonVerbose(message_id, _) => {
+                self.non_verbose += 1;
+                *self.message_ids.entry(*message_id).or_default() += 1;
+            }
+            dlt::PayloadContent::ControlMsg(_, _) => self.control += 1,
+        }
+        *self
+            .message_types
+            .entry(dlt_message_to_event_name(msg))
+            .or_default() += 1;
+
+        let ecu_id = msg.header.ecu_id.clone().unwrap_or_default();
+        if let Some(eh) = &msg.extended_header {
+            *self
+                .contexts
+                .entry((
+                    ecu_id.clone(),
+                    eh.application_id.clone(),
+                    eh.context_id.clone(),
+                ))
+                .or_default() += 1;
+        }
+
+        let counter = msg.header.message_counter;
+        let expected = self
+            .last_counters
+            .insert((ecu_id.clone(), msg.header.session_id), counter)
+            .map(|last| last.wrapping_add(1));
+
+        let ecu = self.ecus.entry(ecu_id).or_default();
+        ecu.messages += 1;
+        if let Some(dlt::MessageType::Log(level)) =
+            msg.extended_header.as_ref().map(|eh| &eh.message_type)
+        {
+            ecu.log_levels[log_level_index(*level)] += 1;
+        }
+        if let Some(ts) = msg.header.timestamp {
+            ecu.timestamp_range = Some(match ecu.timestamp_range {
+                Some((first, last)) => (first.min(ts), last.max(ts)),
+                None => (ts, ts),
+            });
+        }
+        if let Some(expected) = expected.filter(|expected| *expected != counter) {
+            ecu.counter_gaps += 1;
+            ecu.missing_messages += counter.wrapping_sub(expected) as u64;
+        }
+    }
+
+    /// Record an error from the stream. Errors which don't end the
+    /// stream (see [DltPluginError::skipped_message_size]) are counted
+    /// as parse errors; any other is kept as the fatal error.
+    pub fn add_error(&mut self, err: &DltPluginError) {
+        let info = ParseErrorInfo {
+            offset: err.offset(),
+            error: err.to_string(),
+        };
+        if err.skipped_message_size().is_some() {
+            self.parse_error_count += 1;
+            if self.parse_errors.len() < self.max_errors.unwrap_or(DEFAULT_MAX_ERRORS) {
+                self.parse_errors.push(info);
+            }
+        } else {
+            self.fatal_error = Some(info);
+        }
+    }
+
+    /// Non-verbose message ids and their counts, most frequent first
+    pub fn message_ids_by_frequency(&self) -> Vec<(u32, u64)> {
+        let mut ids: Vec<(u32, u64)> = self.message_ids.iter().map(|(k, v)| (*k, *v)).collect();
+        ids.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ids
+    }
+
+    /// Write a human readable report, listing at most `top` message
+    /// ids.
+    pub fn write_report(&self, out: &mut impl Write, top: usize) -> io::Result<()> {
+        writeln!(out, "Messages: {} ({} bytes)", self.messages, self.bytes)?;
+        writeln!(
+            out,
+            "  verbose {}, non-verbose {}, control {}",
+            with_percent(self.verbose, self.messages),
+            with_percent(self.non_verbose, self.messages),
+            with_percent(self.control, self.messages),
+        )?;
+        writeln!(
+            out,
+            "  parse errors {}, invalid {}, filtered out {}",
+            self.parse_error_count, self.invalid, self.filtered_out
+        )?;
+        if let Some((first, last)) = self.storage_time_range {
+            writeln!(
+                out,
+                "Storage header time: {} to {} ({:.6}s)",
+                format_micros(first),
+                format_micros(last),
+                (last - first) as f64 / 1e6
+            )?;
+        }
+
+        writeln!(out, "\nECUs")?;
+        for (ecu_id, ecu) in &self.ecus {
+            write!(
+                out,
+                "  {:<4}  {} messages",
+                display_id(ecu_id),
+                ecu.messages
+            )?;
+            if let Some((first, last)) = ecu.timestamp_range {
+                write!(
+                    out,
+                    ", uptime {:.4}s to {:.4}s",
+                    first as f64 / 1e4,
+                    last as f64 / 1e4
+                )?;
+            }
+            writeln!(
+                out,
+                ", {} counter gaps ({} messages missing)",
+                ecu.counter_gaps, ecu.missing_messages
+            )?;
+
+            if ecu.log_levels.iter().any(|n| *n > 0) {
+                let levels: Vec<String> = LOG_LEVELS
+                    .iter()
+                    .zip(ecu.log_levels)
+                    .filter(|(_, n)| *n > 0)
+                    .map(|(level, n)| format!("{} {n}", log_level_to_str(*level)))
+                    .collect();
+                writeln!(out, "        log levels: {}", levels.join(", "))?;
+            }
+        }
+
+        if !self.contexts.is_empty() {
+            writeln!(out, "\nECU / application / context")?;
+            for ((ecu_id, application_id, context_id), n) in &self.contexts {
+                writeln!(
+                    out,
+                    "  {:<4}  {:<4}  {:<4}  {n}",
+                    display_id(ecu_id),
+                    application_id,
+                    context_id
+                )?;
+            }
+        }
+
+        writeln!(out, "\nMessage types")?;
+        for (name, n) in &self.message_types {
+            writeln!(out, "  {name:<18} {}", with_percent(*n, self.messages))?;
+        }
+
+        if !self.message_ids.is_empty() {
+            let ids = self.message_ids_by_frequency();
+            writeln!(
+                out,
+                "\nNon-verbose message ids (top {} of {})",
+                top.min(ids.len()),
+                ids.len()
+            )?;
+            for (id, n) in ids.into_iter().take(top) {
+                writeln!(out, "  {id:>10} (0x{id:08x})  {n}")?;
+            }
+        }
+
+        if self.parse_error_count > 0 {
+            writeln!(
+                out,
+                "\nParse errors (first {} of {})",
+                self.parse_errors.len(),
+                self.parse_error_count
+            )?;
+            for err in &self.parse_errors {
+                writeln!(out, "  {err}")?;
+            }
+        }
+
+        if let Some(err) = &self.fatal_error {
+            writeln!(out, "\nReading stopped early: {err}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for ParseErrorInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "offset {offset}: {}", self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+fn log_level_index(level: LogLevel) -> usize {
+    match level {
+        LogLevel::Fatal => 0,
+        LogLevel::Error => 1,
+        LogLevel::Warn => 2,
+        LogLevel::Info => 3,
+        LogLevel::Debug => 4,
+        LogLevel::Verbose => 5,
+        LogLevel::Invalid(_) => 6,
+    }
+}
+
+fn with_percent(n: u64, total: u64) -> String {
+    if total == 0 {
+        return n.to_string();
+    }
+    format!("{n} ({:.1}%)", n as f64 * 100.0 / total as f64)
+}
+
+fn format_micros(micros: u64) -> String {
+    format!("{}.{:06}", micros / 1_000_000, micros % 1_000_000)
+}
+
+fn display_id(id: &str) -> &str {
+    if id.is_empty() {
+        "-"
+    } else {
+        id
+    }
+}
@@ -1,12 +1,40 @@
+pub mod arxml;
+pub mod collect;
+pub mod control;
 pub mod convert;
+pub mod correlate;
+pub mod dbc;
+pub mod encode;
+pub mod error;
+pub mod export;
+pub mod fibex;
+pub mod import;
+pub mod inspect;
+pub mod metrics;
+pub mod mock;
+pub mod mutator;
+pub mod network;
+pub mod nonverbose;
+pub mod payload;
+pub mod range;
+pub mod ratelimit;
 pub mod send;
+pub mod shutdown;
+pub mod sink;
+pub mod someip;
+pub mod spool;
+pub mod stream;
+mod xml;
+
+use std::path::PathBuf;
 
-use anyhow::anyhow;
 use auxon_sdk::plugin_utils::serde::from_str;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt as _};
 
-#[derive(Serialize, Deserialize)]
+pub use error::DltPluginError;
+
+#[derive(Serialize, Deserialize, Default)]
 pub struct CommonConfig {
     /// Should the ecu id be used as part of timeline identity and naming? Defaults to true.
     #[serde(default, deserialize_with = "from_str")]
@@ -23,12 +51,183 @@ pub struct CommonConfig {
     /// Should the context id field be used as part of timeline identity and naming? Defaults to false.
     #[serde(default, deserialize_with = "from_str")]
     pub timeline_from_context_id: Option<bool>,
+
+    /// How many events to gather before sending them to Modality. Defaults to 1024.
+    #[serde(default, deserialize_with = "from_str")]
+    pub batch_size: Option<usize>,
+
+    /// How many parsed messages may be queued up while waiting to be sent. Defaults to 8192.
+    #[serde(default, deserialize_with = "from_str")]
+    pub channel_capacity: Option<usize>,
+
+    /// The largest DLT message (headers and payload, but not any
+    /// storage header) to accept, in bytes. Defaults to 65535, the
+    /// largest the protocol allows.
+    #[serde(default, deserialize_with = "from_str")]
+    pub max_message_size: Option<usize>,
+
+    /// Add an `event.message` attribute with each message rendered as
+    /// text. Defaults to false.
+    #[serde(default, deserialize_with = "from_str")]
+    pub render_message: Option<bool>,
+
+    /// Rules for linking events on different timelines through
+    /// correlation ids carried in their payloads. Only settable from
+    /// the reflector's TOML configuration.
+    #[serde(default)]
+    pub correlation: Vec<correlate::CorrelationRule>,
+
+    /// A DBC file describing the signals in CAN frames. If given, CAN
+    /// network trace events get the values of those signals.
+    #[serde(default)]
+    pub dbc_file: Option<PathBuf>,
+
+    /// An ARXML or FIBEX file describing SOME/IP service interfaces, or
+    /// a directory of them. If given, SOME/IP network trace events are
+    /// named after their method, and get its decoded parameters.
+    #[serde(default)]
+    pub someip_services: Option<PathBuf>,
+
+    /// An ARXML file describing non-verbose DLT messages, or a directory
+    /// of them. If given, non-verbose events get their message's name
+    /// and decoded arguments.
+    #[serde(default)]
+    pub non_verbose_messages: Option<PathBuf>,
+
+    /// Dictionaries of non-verbose message descriptions for particular
+    /// ECUs and software versions. Only settable from the reflector's
+    /// TOML configuration.
+    #[serde(default)]
+    pub non_verbose_dictionaries: Vec<nonverbose::NonVerboseDictionaryConfig>,
+
+    /// Instead of connecting to Modality, write the timelines and
+    /// events that would have been sent to this file as JSON Lines, or
+    /// to stdout if it's "-".
+    #[serde(default)]
+    pub dry_run_output: Option<PathBuf>,
+
+    /// Rules for sampling and rate limiting messages from chatty
+    /// applications and contexts. Only settable from the reflector's
+    /// TOML configuration.
+    #[serde(default)]
+    pub rate_limits: Vec<ratelimit::RateLimitRule>,
+
+    /// How often to send a `suppressed_messages` event for messages
+    /// left out by `rate_limits`, in seconds. Defaults to 10.
+    #[serde(default, deserialize_with = "from_str")]
+    pub suppressed_messages_interval: Option<f64>,
+
+    /// After being asked to stop by SIGINT or SIGTERM, how long to keep
+    /// sending what was already read before giving up on it, in
+    /// milliseconds. Defaults to 10000.
+    #[serde(default, deserialize_with = "from_str")]
+    pub shutdown_timeout_ms: Option<u64>,
+}
+
+impl CommonConfig {
+    /// Load the configured DBC file, if there is one.
+    pub fn load_dbc(&self) -> Result<Option<dbc::Dbc>, dbc::DbcError> {
+        let Some(path) = &self.dbc_file else {
+            return Ok(None);
+        };
+        let dbc = dbc::Dbc::from_file(path)?;
+        tracing::info!(dbc_file = %path.display(), "Loaded DBC file");
+        Ok(Some(dbc))
+    }
+
+    /// Load the configured SOME/IP service interfaces, if there are any.
+    pub fn load_someip_services(
+        &self,
+    ) -> Result<Option<someip::SomeIpServices>, payload::DescriptionError> {
+        let Some(path) = &self.someip_services else {
+            return Ok(None);
+        };
+        let services = someip::SomeIpServices::load(path)?;
+        if services.is_empty() {
+            tracing::warn!(
+                someip_services = %path.display(),
+                "No SOME/IP service interfaces found"
+            );
+        } else {
+            tracing::info!(someip_services = %path.display(), "Loaded SOME/IP service interfaces");
+        }
+        Ok(Some(services))
+    }
+
+    /// Load the configured non-verbose message dictionaries, if there
+    /// are any. `non_verbose_messages` is used as a dictionary for any
+    /// ECU, after those in `non_verbose_dictionaries`.
+    pub fn load_non_verbose_decoder(
+        &self,
+    ) -> Result<Option<nonverbose::NonVerboseDecoder>, payload::DescriptionError> {
+        let default_dictionary =
+            self.non_verbose_messages
+                .as_ref()
+                .map(|path| nonverbose::NonVerboseDictionaryConfig {
+                    name: None,
+                    path: path.clone(),
+                    ecu_id: None,
+                    software_version: None,
+                });
+
+        let mut decoder = nonverbose::NonVerboseDecoder::new()
+            .with_message_rendering(self.render_message.unwrap_or(false));
+        for dictionary in self
+            .non_verbose_dictionaries
+            .iter()
+            .chain(default_dictionary.as_ref())
+        {
+            decoder.load_dictionary(dictionary)?;
+        }
+        Ok((!decoder.is_empty()).then_some(decoder))
+    }
 }
 
+/// The largest message the DLT protocol can describe: the length field
+/// of the standard header is 16 bits.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
 /// Read a single, complete DLT message from `stream`, and parse it.
+///
+/// Returns [DltPluginError::Eof] if the stream ends before the first
+/// byte of the message.
 pub async fn read_dlt_message<S>(
     stream: &mut S,
-) -> Result<dlt_core::parse::ParsedMessage, anyhow::Error>
+) -> Result<dlt_core::parse::ParsedMessage, DltPluginError>
+where
+    S: AsyncRead + Unpin,
+{
+    // Read the first byte, from which we can calculate the header size
+    let header_type_byte = match stream.read_u8().await {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(DltPluginError::Eof),
+        Err(e) => return Err(e.into()),
+    };
+    let (dlt_msg, _) = read_dlt_message_after_type_byte(
+        stream,
+        header_type_byte,
+        &mut Vec::new(),
+        DEFAULT_MAX_MESSAGE_SIZE,
+    )
+    .await?;
+    Ok(dlt_msg)
+}
+
+/// Read the rest of a DLT message whose first byte (the header type)
+/// has already been read, and parse it. Returns the message, along with
+/// its total size in bytes.
+///
+/// The message is read into `buf`, which is reused from one message to
+/// the next, so reading doesn't allocate once it's grown to the size of
+/// the largest message. Messages larger than `max_message_size` are
+/// skipped without being buffered, and reported as
+/// [DltPluginError::MessageTooLarge].
+pub(crate) async fn read_dlt_message_after_type_byte<S>(
+    stream: &mut S,
+    header_type_byte: u8,
+    buf: &mut Vec<u8>,
+    max_message_size: usize,
+) -> Result<(dlt_core::parse::ParsedMessage, usize), DltPluginError>
 where
     S: AsyncRead + Unpin,
 {
@@ -36,67 +235,91 @@ where
     // framing part of the protocol just enough to determine the
     // message size, then read it into a buffer, and pass that down to
     // dlt_core.
-
-    // Read the first byte, from which we can calculate the header size
-    let header_type_byte = stream.read_u8().await?;
     let headers_len = dlt_core::dlt::calculate_all_headers_length(header_type_byte) as usize;
 
     // Read the whole header
-    let mut header_buf = vec![0u8; headers_len];
-    header_buf[0] = header_type_byte;
-    stream.read_exact(&mut header_buf[1..]).await?;
-    let (_, header) = dlt_core::parse::dlt_standard_header(&header_buf)?;
-
-    // Read the payload. Put it in a single buffer together with the header
-    let total_message_size = headers_len + header.payload_length as usize;
-    let mut msg_buf = vec![0u8; total_message_size];
-    msg_buf[0..headers_len].copy_from_slice(&header_buf);
-    stream.read_exact(&mut msg_buf[headers_len..]).await?;
-
-    let (remaining_data, dlt_msg) = dlt_core::parse::dlt_message(&msg_buf, None, false)?;
-    if !remaining_data.is_empty() {
-        return Err(anyhow!(
-            "Remaining data after loading DLT message: {remaining_data:?}"
-        ));
-    }
+    buf.clear();
+    buf.resize(headers_len, 0);
+    buf[0] = header_type_byte;
+    let n = read_fully(stream, &mut buf[1..]).await?;
+    check_complete(headers_len, 1 + n)?;
+    let total_message_size = match message_size(buf, max_message_size) {
+        Ok(size) => size,
+        Err(e @ DltPluginError::MessageTooLarge { size, .. }) => {
+            // Skip the payload without buffering it, so reading can
+            // carry on with the next message
+            let mut payload = (&mut *stream).take((size - headers_len) as u64);
+            let n = tokio::io::copy(&mut payload, &mut tokio::io::sink()).await?;
+            check_complete(size, headers_len + n as usize)?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
 
-    Ok(dlt_msg)
+    // Read the payload, into the same buffer as the header
+    buf.resize(total_message_size, 0);
+    let n = read_fully(stream, &mut buf[headers_len..]).await?;
+    check_complete(total_message_size, headers_len + n)?;
+
+    let dlt_msg = parse_message(buf, headers_len)?;
+    Ok((dlt_msg, total_message_size))
 }
 
 /// A non-async version of read_dlt_message. Does the same thing, just
 /// against a Read instead of an AsyncRead
 pub fn read_dlt_message_sync(
     mut stream: impl std::io::Read,
-) -> Result<dlt_core::parse::ParsedMessage, anyhow::Error> {
+) -> Result<dlt_core::parse::ParsedMessage, DltPluginError> {
     let mut header_type_byte_buf = [0u8; 1];
-    stream.read_exact(&mut header_type_byte_buf)?;
-    let header_type_byte = header_type_byte_buf[0];
+    if read_fully_sync(&mut stream, &mut header_type_byte_buf)? == 0 {
+        return Err(DltPluginError::Eof);
+    }
+    let (dlt_msg, _) = read_dlt_message_after_type_byte_sync(
+        stream,
+        header_type_byte_buf[0],
+        &mut Vec::new(),
+        DEFAULT_MAX_MESSAGE_SIZE,
+    )?;
+    Ok(dlt_msg)
+}
+
+/// A non-async version of read_dlt_message_after_type_byte.
+pub(crate) fn read_dlt_message_after_type_byte_sync(
+    mut stream: impl std::io::Read,
+    header_type_byte: u8,
+    buf: &mut Vec<u8>,
+    max_message_size: usize,
+) -> Result<(dlt_core::parse::ParsedMessage, usize), DltPluginError> {
     let headers_len = dlt_core::dlt::calculate_all_headers_length(header_type_byte) as usize;
 
     // Read the whole header
-    let mut header_buf = vec![0u8; headers_len];
-    header_buf[0] = header_type_byte;
-    stream.read_exact(&mut header_buf[1..])?;
-    let (_, header) = dlt_core::parse::dlt_standard_header(&header_buf)?;
-
-    // Read the payload. Put it in a single buffer together with the header
-    let total_message_size = headers_len + header.payload_length as usize;
-    let mut msg_buf = vec![0u8; total_message_size];
-    msg_buf[0..headers_len].copy_from_slice(&header_buf);
-    stream.read_exact(&mut msg_buf[headers_len..])?;
-
-    let (remaining_data, dlt_msg) = dlt_core::parse::dlt_message(&msg_buf, None, false)?;
-    if !remaining_data.is_empty() {
-        return Err(anyhow!(
-            "Remaining data after loading DLT message: {remaining_data:?}"
-        ));
-    }
+    buf.clear();
+    buf.resize(headers_len, 0);
+    buf[0] = header_type_byte;
+    let n = read_fully_sync(&mut stream, &mut buf[1..])?;
+    check_complete(headers_len, 1 + n)?;
+    let total_message_size = match message_size(buf, max_message_size) {
+        Ok(size) => size,
+        Err(e @ DltPluginError::MessageTooLarge { size, .. }) => {
+            let mut payload = std::io::Read::take(&mut stream, (size - headers_len) as u64);
+            let n = std::io::copy(&mut payload, &mut std::io::sink())?;
+            check_complete(size, headers_len + n as usize)?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
 
-    Ok(dlt_msg)
+    // Read the payload, into the same buffer as the header
+    buf.resize(total_message_size, 0);
+    let n = read_fully_sync(&mut stream, &mut buf[headers_len..])?;
+    check_complete(total_message_size, headers_len + n)?;
+
+    let dlt_msg = parse_message(buf, headers_len)?;
+    Ok((dlt_msg, total_message_size))
 }
 
 /// Try to read DLT storage header from `stream`. Return an error if we couldn't.
-pub async fn consume_dlt_storage_header<S>(stream: &mut S) -> Result<(), anyhow::Error>
+pub async fn consume_dlt_storage_header<S>(stream: &mut S) -> Result<(), DltPluginError>
 where
     S: AsyncRead + Unpin,
 {
@@ -104,33 +327,153 @@ where
     // a storage-header's worth of data from the stream, then use the library
     // to verify it has the right shape.
     let mut storage_header_buf = [0u8; 16];
-    stream
-        .read_buf(&mut storage_header_buf.as_mut_slice())
-        .await?;
-
-    let (remaining_data, read_size) = dlt_core::parse::skip_storage_header(&storage_header_buf)?;
-    if !remaining_data.is_empty() || read_size != 16 {
-        return Err(anyhow!("Invalid DLT storage header"));
+    let n = read_fully(stream, storage_header_buf.as_mut_slice()).await?;
+    if n == 0 {
+        return Err(DltPluginError::Eof);
     }
+    check_complete(storage_header_buf.len(), n)?;
 
-    Ok(())
+    check_storage_header(&storage_header_buf)
 }
 
 /// A non-async version of consume_dlt_storage_header. Does the same
 /// thing, just against a Read instead of an AsyncRead
 pub fn consume_dlt_storage_header_sync(
     mut stream: impl std::io::Read,
-) -> Result<(), anyhow::Error> {
+) -> Result<(), DltPluginError> {
     // dlt_core only works on a byte buffer. So, we're going to read
     // a storage-header's worth of data from the stream, then use the library
     // to verify it has the right shape.
     let mut storage_header_buf = [0u8; 16];
-    stream.read_exact(storage_header_buf.as_mut_slice())?;
+    let n = read_fully_sync(&mut stream, storage_header_buf.as_mut_slice())?;
+    if n == 0 {
+        return Err(DltPluginError::Eof);
+    }
+    check_complete(storage_header_buf.len(), n)?;
+
+    check_storage_header(&storage_header_buf)
+}
 
-    let (remaining_data, read_size) = dlt_core::parse::skip_storage_header(&storage_header_buf)?;
+fn check_storage_header(storage_header_buf: &[u8; 16]) -> Result<(), DltPluginError> {
+    let invalid = || DltPluginError::InvalidStorageHeader {
+        offset: 0,
+        header: storage_header_buf.to_vec(),
+    };
+    let (remaining_data, read_size) =
+        dlt_core::parse::skip_storage_header(storage_header_buf).map_err(|_| invalid())?;
     if !remaining_data.is_empty() || read_size != 16 {
-        return Err(anyhow!("Invalid DLT storage header"));
+        return Err(invalid());
     }
 
     Ok(())
 }
+
+/// Work out the total size of a message from its headers, and check
+/// it's acceptable.
+pub(crate) fn message_size(
+    header_buf: &[u8],
+    max_message_size: usize,
+) -> Result<usize, DltPluginError> {
+    let invalid = |reason: String| DltPluginError::InvalidHeader {
+        offset: 0,
+        header: header_buf.to_vec(),
+        reason,
+    };
+
+    // The length field covers everything from the standard header on,
+    // so it can't be shorter than the headers themselves. Check that
+    // here rather than relying on dlt_core to.
+    let len = u16::from_be_bytes([header_buf[2], header_buf[3]]) as usize;
+    if len < header_buf.len() {
+        return Err(invalid(format!(
+            "length {len} is shorter than the {} byte headers",
+            header_buf.len()
+        )));
+    }
+
+    let header = match dlt_core::parse::dlt_standard_header(header_buf) {
+        Ok((_, header)) => header,
+        Err(e) => return Err(invalid(e.to_string())),
+    };
+
+    let total_message_size = header_buf.len() + header.payload_length as usize;
+    if total_message_size > max_message_size {
+        return Err(DltPluginError::MessageTooLarge {
+            offset: 0,
+            size: total_message_size,
+            max: max_message_size,
+            header: header_buf.to_vec(),
+        });
+    }
+
+    Ok(total_message_size)
+}
+
+pub(crate) fn parse_message(
+    msg_buf: &[u8],
+    headers_len: usize,
+) -> Result<dlt_core::parse::ParsedMessage, DltPluginError> {
+    let (remaining_data, dlt_msg) =
+        dlt_core::parse::dlt_message(msg_buf, None, false).map_err(|e| DltPluginError::Parse {
+            offset: 0,
+            size: msg_buf.len(),
+            header: msg_buf[..headers_len].to_vec(),
+            reason: e.to_string(),
+        })?;
+
+    if !remaining_data.is_empty() {
+        return Err(DltPluginError::TrailingData {
+            offset: 0,
+            size: msg_buf.len(),
+            remaining: remaining_data.len(),
+        });
+    }
+
+    Ok(dlt_msg)
+}
+
+fn check_complete(expected: usize, available: usize) -> Result<(), DltPluginError> {
+    if available < expected {
+        return Err(DltPluginError::Truncated {
+            offset: 0,
+            expected,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Like read_exact, but stops at the end of the stream instead of
+/// failing. Returns the number of bytes read.
+pub(crate) async fn read_fully<S>(stream: &mut S, buf: &mut [u8]) -> std::io::Result<usize>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..]).await {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// A non-async version of read_fully
+pub(crate) fn read_fully_sync(
+    stream: &mut impl std::io::Read,
+    buf: &mut [u8],
+) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
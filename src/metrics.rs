@@ -0,0 +1,357 @@
+//! Counters describing the plugin's progress, and a minimal HTTP
+//! endpoint which serves them in the Prometheus text exposition format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use dlt_core::parse::ParsedMessage;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf},
+    net::TcpListener,
+};
+use tracing::{debug, info, warn};
+
+/// Counters which are tracked separately for each ECU
+#[derive(Default, Clone, Copy)]
+struct EcuCounters {
+    messages_read: u64,
+    events_sent: u64,
+    timelines_created: u64,
+    messages_suppressed: u64,
+}
+
+pub struct Metrics {
+    source: String,
+    bytes_read: AtomicU64,
+    parse_errors: AtomicU64,
+    invalid_messages: AtomicU64,
+    filtered_messages: AtomicU64,
+    dropped_messages: AtomicU64,
+    reconnects: AtomicU64,
+
+    /// Keyed by ECU id; messages without one are counted under ""
+    by_ecu: Mutex<BTreeMap<String, EcuCounters>>,
+}
+
+impl Metrics {
+    /// `source` identifies where the DLT data comes from (a host and
+    /// port, or a file name), and is used as a label on all metrics.
+    pub fn new(source: impl Into<String>) -> Arc<Self> {
+        Arc::new(Metrics {
+            source: source.into(),
+            bytes_read: Default::default(),
+            parse_errors: Default::default(),
+            invalid_messages: Default::default(),
+            filtered_messages: Default::default(),
+            dropped_messages: Default::default(),
+            reconnects: Default::default(),
+            by_ecu: Default::default(),
+        })
+    }
+
+    /// Record the outcome of reading a single message.
+    pub fn record_read<E>(&self, res: &Result<ParsedMessage, E>) {
+        match res {
+            Ok(parsed_msg) => self.record_message(parsed_msg),
+            Err(_) => self.record_parse_error(),
+        }
+    }
+
+    /// Record a message that was read successfully.
+    pub fn record_message(&self, parsed_msg: &ParsedMessage) {
+        match parsed_msg {
+            ParsedMessage::Item(msg) => {
+                self.update_ecu(msg.header.ecu_id.as_deref(), |c| c.messages_read += 1)
+            }
+            ParsedMessage::Invalid => {
+                self.update_ecu(None, |c| c.messages_read += 1);
+                self.invalid_messages.fetch_add(1, Ordering::Relaxed);
+            }
+            ParsedMessage::FilteredOut(_) => {
+                self.update_ecu(None, |c| c.messages_read += 1);
+                self.filtered_messages.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn record_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bytes_read(&self, n: usize) {
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Events made from DLT messages were sent; events the plugin adds
+    /// itself aren't counted, so this can be compared with messages read.
+    pub fn record_events_sent(&self, ecu_id: Option<&str>, n: usize) {
+        self.update_ecu(ecu_id, |c| c.events_sent += n as u64);
+    }
+
+    pub fn record_timeline_created(&self, ecu_id: Option<&str>) {
+        self.update_ecu(ecu_id, |c| c.timelines_created += 1);
+    }
+
+    /// A message was left out by sampling or a rate limit.
+    pub fn record_message_suppressed(&self, ecu_id: Option<&str>) {
+        self.update_ecu(ecu_id, |c| c.messages_suppressed += 1);
+    }
+
+    /// Messages which were read, but deliberately not sent: outside
+    /// the import range, or discarded by a full spool.
+    pub fn record_messages_dropped(&self, n: u64) {
+        self.dropped_messages.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn update_ecu(&self, ecu_id: Option<&str>, f: impl FnOnce(&mut EcuCounters)) {
+        let mut by_ecu = self.by_ecu.lock().unwrap();
+        let ecu_id = ecu_id.unwrap_or_default();
+        match by_ecu.get_mut(ecu_id) {
+            Some(counters) => f(counters),
+            None => {
+                let mut counters = EcuCounters::default();
+                f(&mut counters);
+                by_ecu.insert(ecu_id.to_string(), counters);
+            }
+        }
+    }
+
+    /// A snapshot of the totals, across all ECUs
+    pub fn summary(&self) -> Summary {
+        let by_ecu = self.by_ecu.lock().unwrap();
+        Summary {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            messages_read: by_ecu.values().map(|c| c.messages_read).sum(),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+            invalid_messages: self.invalid_messages.load(Ordering::Relaxed),
+            filtered_messages: self.filtered_messages.load(Ordering::Relaxed),
+            dropped_messages: self.dropped_messages.load(Ordering::Relaxed),
+            events_sent: by_ecu.values().map(|c| c.events_sent).sum(),
+            timelines_created: by_ecu.values().map(|c| c.timelines_created).sum(),
+            messages_suppressed: by_ecu.values().map(|c| c.messages_suppressed).sum(),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Render all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let by_ecu = self.by_ecu.lock().unwrap().clone();
+        let source = escape_label_value(&self.source);
+        let mut out = String::new();
+
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name}{{source=\"{source}\"}} {value}");
+        };
+        counter(
+            "modality_dlt_bytes_read_total",
+            "Bytes read from the DLT source.",
+            self.bytes_read.load(Ordering::Relaxed),
+        );
+        counter(
+            "modality_dlt_parse_errors_total",
+            "Messages which could not be read or parsed.",
+            self.parse_errors.load(Ordering::Relaxed),
+        );
+        counter(
+            "modality_dlt_invalid_messages_total",
+            "Messages dropped because dlt-core considered them invalid.",
+            self.invalid_messages.load(Ordering::Relaxed),
+        );
+        counter(
+            "modality_dlt_filtered_messages_total",
+            "Messages dropped by filtering.",
+            self.filtered_messages.load(Ordering::Relaxed),
+        );
+        counter(
+            "modality_dlt_dropped_messages_total",
+            "Messages outside the import range, or discarded by a full spool.",
+            self.dropped_messages.load(Ordering::Relaxed),
+        );
+        counter(
+            "modality_dlt_reconnects_total",
+            "Successful reconnections to the Modality backend.",
+            self.reconnects.load(Ordering::Relaxed),
+        );
+
+        let per_ecu: [(&str, &str, fn(&EcuCounters) -> u64); 4] = [
+            (
+                "modality_dlt_messages_read_total",
+                "DLT messages read.",
+                |c| c.messages_read,
+            ),
+            (
+                "modality_dlt_events_sent_total",
+                "Events made from DLT messages sent to Modality.",
+                |c| c.events_sent,
+            ),
+            (
+                "modality_dlt_timelines_created_total",
+                "Timelines created in Modality.",
+                |c| c.timelines_created,
+            ),
+            (
+                "modality_dlt_messages_suppressed_total",
+                "DLT messages left out by sampling or rate limits.",
+                |c| c.messages_suppressed,
+            ),
+        ];
+        for (name, help, get) in per_ecu {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (ecu_id, counters) in by_ecu.iter() {
+                let ecu_id = escape_label_value(ecu_id);
+                let _ = writeln!(
+                    out,
+                    "{name}{{source=\"{source}\",ecu_id=\"{ecu_id}\"}} {}",
+                    get(counters)
+                );
+            }
+        }
+
+        // Anything read which hasn't been sent or dropped is still on
+        // its way: queued, batched, or spooled.
+        let read: u64 = by_ecu.values().map(|c| c.messages_read).sum();
+        let sent: u64 = by_ecu.values().map(|c| c.events_sent).sum();
+        let suppressed: u64 = by_ecu.values().map(|c| c.messages_suppressed).sum();
+        let dropped = self.invalid_messages.load(Ordering::Relaxed)
+            + self.filtered_messages.load(Ordering::Relaxed)
+            + self.dropped_messages.load(Ordering::Relaxed)
+            + suppressed;
+        let lag = read.saturating_sub(sent + dropped);
+        let name = "modality_dlt_ingest_lag_messages";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Messages read but not yet sent to Modality."
+        );
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name}{{source=\"{source}\"}} {lag}");
+
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub bytes_read: u64,
+    pub messages_read: u64,
+    pub parse_errors: u64,
+    pub invalid_messages: u64,
+    pub filtered_messages: u64,
+    pub dropped_messages: u64,
+    pub events_sent: u64,
+    pub timelines_created: u64,
+    pub messages_suppressed: u64,
+    pub reconnects: u64,
+}
+
+impl Summary {
+    /// Log the summary at info level, with `what` as the message.
+    pub fn log(&self, what: &str) {
+        info!(
+            messages_read = self.messages_read,
+            bytes_read = self.bytes_read,
+            events_sent = self.events_sent,
+            timelines_created = self.timelines_created,
+            parse_errors = self.parse_errors,
+            invalid_messages = self.invalid_messages,
+            filtered_messages = self.filtered_messages,
+            dropped_messages = self.dropped_messages,
+            messages_suppressed = self.messages_suppressed,
+            reconnects = self.reconnects,
+            "{what}"
+        );
+    }
+}
+
+fn escape_label_value(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `metrics` over HTTP at `addr`, on a background task. Every
+/// request gets the current metrics, regardless of its path.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "Serving metrics");
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!(err = %e, "Failed to accept metrics connection");
+                    continue;
+                }
+            };
+
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                // We don't care what was asked for, but wait for the
+                // request before answering it.
+                let mut request = [0u8; 1024];
+                if let Err(e) = stream.read(&mut request).await {
+                    debug!(%peer, err = %e, "Failed to read metrics request");
+                    return;
+                }
+
+                let body = metrics.render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     Content-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\n\
+                     Connection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                if let Err(e) = stream.write_all(response.as_bytes()).await {
+                    debug!(%peer, err = %e, "Failed to write metrics response");
+                }
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Wraps a reader, counting the bytes read through it.
+pub struct CountingReader<R> {
+    inner: R,
+    metrics: Arc<Metrics>,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R, metrics: Arc<Metrics>) -> Self {
+        CountingReader { inner, metrics }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = &res {
+            self.metrics.record_bytes_read(buf.filled().len() - before);
+        }
+        res
+    }
+}
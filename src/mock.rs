@@ -0,0 +1,644 @@
+//! Stand-ins for dlt-daemon and the Modality backend, so the collector's
+//! pipeline can be tested without either.
+//!
+//! [MockDaemon] serves a [Script] of DLT messages over TCP, the way
+//! dlt-daemon does, optionally at a fixed rate, dropping connections
+//! partway through, or corrupting some of the messages. It answers
+//! control requests, and keeps them for inspection.
+//!
+//! [MockIngest] records the timelines and events which would have been
+//! sent to Modality. It's an [IngestSink], so a
+//! [Sender](crate::send::Sender) can send to it, or it can take the
+//! batches an [EventBatcher] produces directly.
+
+use std::{
+    future, io,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use auxon_sdk::api::{AttrKey, AttrVal, TimelineId};
+use dlt_core::{
+    dlt::{self, ControlType, LogLevel, MessageType},
+    parse::ParsedMessage,
+};
+use futures::StreamExt;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+    time::Interval,
+};
+use tracing::debug;
+
+use crate::{
+    control::{self, ControlStatus, SERVICE_GET_SOFTWARE_VERSION},
+    encode::{self, EncodeError},
+    send::{EventBatcher, TimelineBatch},
+    sink::IngestSink,
+    stream::{DltStream, Framing, STORAGE_HEADER_LEN},
+    DltPluginError,
+};
+
+/// The application and context ids dlt-daemon sends its own messages
+/// (like control responses) with
+const DAEMON_APPLICATION_ID: &str = "DA1";
+const DAEMON_CONTEXT_ID: &str = "DC1";
+
+/// The messages a [MockDaemon] sends, in order, already encoded.
+#[derive(Clone, Debug, Default)]
+pub struct Script {
+    messages: Vec<Vec<u8>>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every message in a `.dlt` file, as it was stored. Messages which
+    /// can't be parsed are kept, as long as they can be framed.
+    pub fn from_dlt_file(path: &Path) -> Result<Self, DltPluginError> {
+        let data = std::fs::read(path)?;
+        let mut script = Script::new();
+        for record in DltStream::new(data.as_slice(), Framing::StorageHeader) {
+            let (start, end) = match record {
+                Ok(record) => (
+                    record.offset as usize + STORAGE_HEADER_LEN,
+                    (record.offset + record.size) as usize,
+                ),
+                Err(e) => match (e.offset(), e.skipped_message_size()) {
+                    // The offset is the message's, after its storage header
+                    (Some(offset), Some(size)) => (offset as usize, offset as usize + size),
+                    _ => return Err(e),
+                },
+            };
+            script.push_bytes(data[start..end].to_vec());
+        }
+        Ok(script)
+    }
+
+    /// Add a message to the end of the script.
+    pub fn push(&mut self, msg: &dlt::Message) -> Result<(), EncodeError> {
+        self.messages.push(encode::encode(msg)?);
+        Ok(())
+    }
+
+    /// Add an already encoded message (or anything else) to the end of
+    /// the script. It's sent as is.
+    pub fn push_bytes(&mut self, bytes: Vec<u8>) {
+        self.messages.push(bytes);
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+/// A verbose log message, as an application would send it through
+/// dlt-daemon.
+pub fn log_message(
+    ecu_id: &str,
+    application_id: &str,
+    context_id: &str,
+    level: LogLevel,
+    args: Vec<dlt::Argument>,
+) -> dlt::Message {
+    dlt::Message::new(
+        dlt::MessageConfig {
+            version: 1,
+            counter: 0,
+            endianness: dlt::Endianness::Little,
+            ecu_id: Some(ecu_id.to_owned()),
+            session_id: None,
+            timestamp: None,
+            payload: dlt::PayloadContent::Verbose(args),
+            extended_header_info: Some(dlt::ExtendedHeaderConfig {
+                message_type: MessageType::Log(level),
+                app_id: application_id.to_owned(),
+                context_id: context_id.to_owned(),
+            }),
+        },
+        None,
+    )
+}
+
+/// A network trace message, as a bus logger would send it through
+/// dlt-daemon: the frame's `header`, then its `payload`, each as a raw
+/// argument.
+pub fn network_trace_message(
+    ecu_id: &str,
+    application_id: &str,
+    context_id: &str,
+    trace_type: dlt::NetworkTraceType,
+    header: &[u8],
+    payload: &[u8],
+) -> dlt::Message {
+    let args = [header, payload]
+        .into_iter()
+        .map(|bytes| encode::verbose_argument(None, dlt::Value::Raw(bytes.to_vec())))
+        .collect();
+    dlt::Message::new(
+        dlt::MessageConfig {
+            version: 1,
+            counter: 0,
+            endianness: dlt::Endianness::Little,
+            ecu_id: Some(ecu_id.to_owned()),
+            session_id: None,
+            timestamp: None,
+            payload: dlt::PayloadContent::Verbose(args),
+            extended_header_info: Some(dlt::ExtendedHeaderConfig {
+                message_type: MessageType::NetworkTrace(trace_type),
+                app_id: application_id.to_owned(),
+                context_id: context_id.to_owned(),
+            }),
+        },
+        None,
+    )
+}
+
+/// A control response from the daemon: the service id, the status, and
+/// then `data`.
+pub fn control_response(
+    ecu_id: &str,
+    service_id: u32,
+    status: ControlStatus,
+    data: &[u8],
+) -> dlt::Message {
+    let mut payload = service_id.to_le_bytes().to_vec();
+    payload.push(status.value());
+    payload.extend_from_slice(data);
+    // dlt-core keeps the first payload byte apart, as a ControlType;
+    // see control::control_payload
+    let first = payload.remove(0);
+
+    dlt::Message::new(
+        dlt::MessageConfig {
+            version: 1,
+            counter: 0,
+            endianness: dlt::Endianness::Little,
+            ecu_id: Some(ecu_id.to_owned()),
+            session_id: None,
+            timestamp: None,
+            payload: dlt::PayloadContent::ControlMsg(ControlType::from_value(first), payload),
+            extended_header_info: Some(dlt::ExtendedHeaderConfig {
+                message_type: MessageType::Control(ControlType::Response),
+                app_id: DAEMON_APPLICATION_ID.to_owned(),
+                context_id: DAEMON_CONTEXT_ID.to_owned(),
+            }),
+        },
+        None,
+    )
+}
+
+/// How a [MockDaemon] damages the messages it's told to corrupt
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Corruption {
+    /// Overwrite the payload with `0xff` bytes. The message still has
+    /// a valid length, so it can be skipped, but a verbose payload
+    /// can't be parsed.
+    Payload,
+
+    /// Send the first half of the message, then drop the connection.
+    Truncate,
+}
+
+/// A fake dlt-daemon, serving a [Script] to clients over TCP. Clients
+/// are served one at a time. Each carries on from where the last one
+/// left the script, so a collector which reconnects after a
+/// disconnect gets the rest of the messages.
+#[derive(Clone, Debug)]
+pub struct MockDaemon {
+    script: Arc<Script>,
+    rate: Option<f64>,
+    disconnect_after: Option<usize>,
+    corruption: Option<(usize, Corruption)>,
+    software_version: Option<String>,
+    hold_open: bool,
+}
+
+impl MockDaemon {
+    pub fn new(script: Script) -> Self {
+        MockDaemon {
+            script: Arc::new(script),
+            rate: None,
+            disconnect_after: None,
+            corruption: None,
+            software_version: None,
+            hold_open: false,
+        }
+    }
+
+    /// Send `messages_per_second` messages a second, rather than as
+    /// fast as the client will take them.
+    pub fn with_rate(mut self, messages_per_second: f64) -> Self {
+        self.rate = Some(messages_per_second);
+        self
+    }
+
+    /// Drop each connection after sending it `messages` messages.
+    pub fn with_disconnect_after(mut self, messages: usize) -> Self {
+        self.disconnect_after = Some(messages.max(1));
+        self
+    }
+
+    /// Damage every `every`th message of the script (counting from 1)
+    /// with `corruption`.
+    pub fn with_corruption(mut self, every: usize, corruption: Corruption) -> Self {
+        self.corruption = Some((every.max(1), corruption));
+        self
+    }
+
+    /// Answer get_software_version requests with `version`. Without
+    /// one, they're answered as not supported.
+    pub fn with_software_version(mut self, version: impl Into<String>) -> Self {
+        self.software_version = Some(version.into());
+        self
+    }
+
+    /// Keep connections open after the end of the script, answering
+    /// control requests, until the client disconnects. By default, the
+    /// connection is closed once the script is done.
+    pub fn with_hold_open(mut self) -> Self {
+        self.hold_open = true;
+        self
+    }
+
+    /// Start serving on `addr`, on a background task. Use port 0 to
+    /// pick any free port; [MockDaemonHandle::local_addr] says which.
+    pub async fn start(self, addr: SocketAddr) -> io::Result<MockDaemonHandle> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(DaemonState::default());
+
+        let task_state = state.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        debug!(err = %e, "Mock daemon failed to accept connection");
+                        continue;
+                    }
+                };
+                task_state.connections.fetch_add(1, Ordering::SeqCst);
+                debug!(%peer, "Mock daemon accepted connection");
+                if let Err(e) = self.serve(stream, &task_state).await {
+                    debug!(%peer, err = %e, "Mock daemon connection failed");
+                }
+            }
+        });
+
+        Ok(MockDaemonHandle { addr, state, task })
+    }
+
+    async fn serve(&self, stream: TcpStream, state: &Arc<DaemonState>) -> io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_requests(
+            read,
+            state.clone(),
+            self.software_version.clone(),
+            response_tx,
+        ));
+
+        let mut interval = self
+            .rate
+            .map(|rate| tokio::time::interval(Duration::from_secs_f64(1.0 / rate)));
+        let mut sent = 0;
+        let res = async {
+            loop {
+                let next = state.next_message.load(Ordering::SeqCst);
+                if self.disconnect_after == Some(sent) {
+                    debug!(sent, "Mock daemon disconnecting");
+                    return Ok(());
+                }
+                let done = next >= self.script.len();
+                if done && !self.hold_open {
+                    return Ok(());
+                }
+
+                tokio::select! {
+                    biased;
+                    response = response_rx.recv() => match response {
+                        Some(response) => write.write_all(&response).await?,
+                        // The client went away
+                        None => return Ok(()),
+                    },
+                    _ = tick(&mut interval), if !done => {
+                        let mut bytes = self.script.messages[next].clone();
+                        state.next_message.fetch_add(1, Ordering::SeqCst);
+                        sent += 1;
+
+                        let drop_connection = match self.corruption {
+                            Some((every, corruption)) if (next + 1) % every == 0 => {
+                                corrupt(&mut bytes, corruption)
+                            }
+                            _ => false,
+                        };
+                        write.write_all(&bytes).await?;
+                        if drop_connection {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+        .await;
+
+        reader.abort();
+        let _ = write.shutdown().await;
+        res
+    }
+}
+
+/// Wait for the next tick, if sending is rate limited
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => future::ready(()).await,
+    }
+}
+
+/// Damage `bytes`. Returns true if the connection should be dropped
+/// after sending them.
+fn corrupt(bytes: &mut Vec<u8>, corruption: Corruption) -> bool {
+    match corruption {
+        Corruption::Payload => {
+            let headers_len = match bytes.first() {
+                Some(header_type) => dlt::calculate_all_headers_length(*header_type) as usize,
+                None => return false,
+            };
+            for b in bytes.iter_mut().skip(headers_len) {
+                *b = 0xff;
+            }
+            false
+        }
+        Corruption::Truncate => {
+            bytes.truncate(bytes.len() / 2);
+            true
+        }
+    }
+}
+
+/// Read control requests from the client, and queue their responses.
+async fn read_requests(
+    read: OwnedReadHalf,
+    state: Arc<DaemonState>,
+    software_version: Option<String>,
+    response_tx: mpsc::UnboundedSender<Vec<u8>>,
+) {
+    let mut requests = DltStream::new(read, Framing::Raw);
+    while let Some(request) = requests.next().await {
+        let msg = match request {
+            Ok(record) => match record.message {
+                ParsedMessage::Item(msg) => msg,
+                _ => continue,
+            },
+            Err(e) if e.skipped_message_size().is_some() => continue,
+            Err(_) => break,
+        };
+
+        let Some(service_id) = control::request_service_id(&msg) else {
+            continue;
+        };
+        let ecu_id = msg.header.ecu_id.clone().unwrap_or_default();
+        state.control_requests.lock().unwrap().push(msg);
+
+        let response = match (service_id, &software_version) {
+            (SERVICE_GET_SOFTWARE_VERSION, Some(version)) => {
+                let mut data = (version.len() as u32).to_le_bytes().to_vec();
+                data.extend_from_slice(version.as_bytes());
+                control_response(&ecu_id, service_id, ControlStatus::Ok, &data)
+            }
+            (SERVICE_GET_SOFTWARE_VERSION, None) => {
+                control_response(&ecu_id, service_id, ControlStatus::NotSupported, &[])
+            }
+            _ => control_response(&ecu_id, service_id, ControlStatus::Ok, &[]),
+        };
+        let Ok(bytes) = encode::encode(&response) else {
+            continue;
+        };
+        if response_tx.send(bytes).is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(Default)]
+struct DaemonState {
+    /// The position in the script of the next message to send
+    next_message: AtomicUsize,
+    connections: AtomicUsize,
+    control_requests: Mutex<Vec<dlt::Message>>,
+}
+
+/// A running [MockDaemon]. Dropping it stops the daemon.
+pub struct MockDaemonHandle {
+    addr: SocketAddr,
+    state: Arc<DaemonState>,
+    task: JoinHandle<()>,
+}
+
+impl MockDaemonHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// How many messages of the script have been sent
+    pub fn messages_sent(&self) -> usize {
+        self.state.next_message.load(Ordering::SeqCst)
+    }
+
+    /// How many connections have been accepted
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    /// The control requests received so far, oldest first
+    pub fn control_requests(&self) -> Vec<dlt::Message> {
+        self.state.control_requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockDaemonHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A timeline, as it would have been created in Modality
+#[derive(Clone, Debug)]
+pub struct IngestedTimeline {
+    pub id: TimelineId,
+    pub name: String,
+    pub attrs: Vec<(String, AttrVal)>,
+}
+
+/// An event, as it would have been sent to Modality
+#[derive(Clone, Debug)]
+pub struct IngestedEvent {
+    pub timeline_id: TimelineId,
+    pub name: String,
+    pub ordering: u128,
+    pub attrs: Vec<(String, AttrVal)>,
+}
+
+impl IngestedEvent {
+    pub fn attr(&self, key: &str) -> Option<&AttrVal> {
+        self.attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+}
+
+/// Records what would have been sent to Modality, in the order it
+/// would have been sent.
+#[derive(Debug, Default)]
+pub struct MockIngest {
+    pub timelines: Vec<IngestedTimeline>,
+    pub events: Vec<IngestedEvent>,
+
+    /// How many times the timeline was switched, as an [IngestSink]
+    pub timeline_switches: usize,
+
+    current_timeline: Option<TimelineId>,
+    fail_after_events: Option<usize>,
+}
+
+impl MockIngest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// As an [IngestSink], fail to send any more events once `events`
+    /// have been recorded, or stop failing if `None`.
+    pub fn set_fail_after(&mut self, events: Option<usize>) {
+        self.fail_after_events = events;
+    }
+
+    /// Take everything `batcher` has pending, as
+    /// [Sender::flush](crate::send::Sender::flush) would.
+    pub fn ingest_from(&mut self, batcher: &mut EventBatcher) {
+        for batch in batcher.take_batches() {
+            self.ingest(batch);
+        }
+    }
+
+    pub fn ingest(&mut self, batch: TimelineBatch) {
+        if let Some(new_timeline) = batch.new_timeline {
+            self.timelines.push(IngestedTimeline {
+                id: batch.timeline_id,
+                name: new_timeline.name,
+                attrs: new_timeline
+                    .attrs
+                    .into_iter()
+                    .map(|(k, v)| (k.to_owned(), v))
+                    .collect(),
+            });
+        }
+        self.events.extend(batch.events.into_iter().map(|ev| {
+            IngestedEvent {
+                timeline_id: batch.timeline_id,
+                name: ev.name.into_owned(),
+                ordering: ev.ordering,
+                attrs: ev
+                    .attrs
+                    .into_iter()
+                    .map(|(k, v)| (k.as_ref().to_owned(), v))
+                    .collect(),
+            }
+        }));
+    }
+
+    pub fn timeline(&self, id: TimelineId) -> Option<&IngestedTimeline> {
+        self.timelines.iter().find(|t| t.id == id)
+    }
+
+    pub fn timeline_named(&self, name: &str) -> Option<&IngestedTimeline> {
+        self.timelines.iter().find(|t| t.name == name)
+    }
+
+    pub fn events_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a IngestedEvent> {
+        self.events.iter().filter(move |ev| ev.name == name)
+    }
+
+    /// The events on `timeline_id`, in order
+    pub fn events_on(&self, timeline_id: TimelineId) -> impl Iterator<Item = &IngestedEvent> {
+        self.events
+            .iter()
+            .filter(move |ev| ev.timeline_id == timeline_id)
+    }
+
+    fn current_timeline(&self) -> Result<TimelineId, DltPluginError> {
+        self.current_timeline
+            .ok_or_else(|| DltPluginError::backend("No timeline has been switched to"))
+    }
+}
+
+#[async_trait]
+impl IngestSink for MockIngest {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), DltPluginError> {
+        self.current_timeline = Some(id);
+        self.timeline_switches += 1;
+        Ok(())
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: &[(&'static str, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        let id = self.current_timeline()?;
+        let attrs = attrs.iter().map(|(k, v)| (k.to_string(), v.clone()));
+        match self.timelines.iter_mut().find(|t| t.id == id) {
+            Some(timeline) => {
+                timeline.name = name.to_owned();
+                timeline.attrs.extend(attrs);
+            }
+            None => self.timelines.push(IngestedTimeline {
+                id,
+                name: name.to_owned(),
+                attrs: attrs.collect(),
+            }),
+        }
+        Ok(())
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: &[(AttrKey, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        let timeline_id = self.current_timeline()?;
+        if self.fail_after_events == Some(self.events.len()) {
+            return Err(DltPluginError::backend("Mock ingest failure"));
+        }
+        self.events.push(IngestedEvent {
+            timeline_id,
+            name: name.to_owned(),
+            ordering,
+            attrs: attrs
+                .iter()
+                .map(|(k, v)| (k.as_ref().to_owned(), v.clone()))
+                .collect(),
+        });
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), DltPluginError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,657 @@
+//! Modality mutators which change dlt-daemon's log levels and trace
+//! status at runtime, by sending control requests over the same TCP
+//! connection the collector reads from.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use auxon_sdk::{
+    api::{AttrKey, AttrType, AttrVal, TimelineId},
+    mutation_plane::types::MutationId,
+    mutator_protocol::descriptor::owned::{
+        MutatorOperation, OwnedMutatorDescriptor, OwnedMutatorParamDescriptor,
+    },
+    plugin_utils::{
+        ingest::{Client, Config},
+        mutation::Mutator,
+    },
+};
+use dlt_core::{dlt, parse::ParsedMessage};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{broadcast, Mutex},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    control::{
+        service_name, ControlLogLevel, ControlRequest, ControlResponse, ControlStatus,
+        ControlTraceStatus, SERVICE_INJECTION_MIN,
+    },
+    send::EventAnnotator,
+    DltPluginError,
+};
+
+/// How long to wait for the daemon to answer a control request, if not configured
+pub const DEFAULT_CONTROL_RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The most data an injection message can carry: what's left of the
+/// largest possible message after its headers, service id and length.
+const MAX_INJECTION_DATA_LEN: usize = u16::MAX as usize - 18 - 8;
+
+/// The ECU id control requests are addressed to, if not configured.
+/// This is dlt-daemon's default.
+pub const DEFAULT_CONTROL_ECU_ID: &str = "ECU1";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The write side of the connection to dlt-daemon, shared by all
+/// mutators, along with the responses seen on the read side.
+#[derive(Clone)]
+pub struct ControlConnection {
+    writer: Arc<Mutex<ControlWriter>>,
+    responses: broadcast::Sender<ControlResponse>,
+    response_timeout: Duration,
+    interactions: Arc<std::sync::Mutex<VecDeque<PendingInteraction>>>,
+}
+
+/// A request recorded as an event, whose response should link back to it
+struct PendingInteraction {
+    ecu_id: String,
+    service_id: u32,
+    timeline_id: TimelineId,
+    nonce: i64,
+}
+
+struct ControlWriter {
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    message_counter: u8,
+}
+
+impl ControlConnection {
+    pub fn new(writer: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        let (responses, _) = broadcast::channel(64);
+        ControlConnection {
+            writer: Arc::new(Mutex::new(ControlWriter {
+                writer: Box::new(writer),
+                message_counter: 0,
+            })),
+            responses,
+            response_timeout: DEFAULT_CONTROL_RESPONSE_TIMEOUT,
+            interactions: Default::default(),
+        }
+    }
+
+    pub fn with_response_timeout(mut self, response_timeout: Duration) -> Self {
+        self.response_timeout = response_timeout;
+        self
+    }
+
+    /// Look at a message read from the daemon, in case it's a response
+    /// to one of our requests. Call this for every message read.
+    pub fn observe(&self, parsed_msg: &ParsedMessage) {
+        let ParsedMessage::Item(msg) = parsed_msg else {
+            return;
+        };
+        if let Some(response) = ControlResponse::from_message(msg) {
+            // No receivers just means nobody's waiting
+            let _ = self.responses.send(response);
+        }
+    }
+
+    /// Link the next response to `service_id` from `ecu_id` to the event
+    /// with `nonce` on `timeline_id`; see [EventAnnotator].
+    pub fn expect_interaction(
+        &self,
+        ecu_id: &str,
+        service_id: u32,
+        timeline_id: TimelineId,
+        nonce: i64,
+    ) {
+        self.interactions
+            .lock()
+            .unwrap()
+            .push_back(PendingInteraction {
+                ecu_id: ecu_id.to_owned(),
+                service_id,
+                timeline_id,
+                nonce,
+            });
+    }
+
+    /// Stop waiting for the response to the event with `nonce`.
+    fn forget_interaction(&self, nonce: i64) {
+        self.interactions
+            .lock()
+            .unwrap()
+            .retain(|i| i.nonce != nonce);
+    }
+
+    /// Send `request` to `ecu_id`, without waiting for a response.
+    pub async fn send(&self, ecu_id: &str, request: &ControlRequest) -> Result<(), DltPluginError> {
+        let mut writer = self.writer.lock().await;
+        let bytes = request.to_bytes(ecu_id, writer.message_counter);
+        writer.message_counter = writer.message_counter.wrapping_add(1);
+        writer.writer.write_all(&bytes).await?;
+        writer.writer.flush().await?;
+        debug!(ecu_id, ?request, "Sent control request");
+        Ok(())
+    }
+
+    /// Send `request` to `ecu_id`, and wait for the daemon's response.
+    pub async fn request(
+        &self,
+        ecu_id: &str,
+        request: &ControlRequest,
+    ) -> Result<ControlStatus, DltPluginError> {
+        // Subscribe first, so the response can't slip past
+        let mut responses = self.responses.subscribe();
+        self.send(ecu_id, request).await?;
+
+        let service_id = request.service_id();
+        let wait_for_response = async {
+            loop {
+                match responses.recv().await {
+                    Ok(response)
+                        if response.service_id == service_id
+                            && response.ecu_id.as_deref().map_or(true, |id| id == ecu_id) =>
+                    {
+                        return Ok(response.status);
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(DltPluginError::Io(std::io::ErrorKind::BrokenPipe.into()));
+                    }
+                }
+            }
+        };
+
+        match tokio::time::timeout(self.response_timeout, wait_for_response).await {
+            Ok(res) => res,
+            Err(_) => Err(DltPluginError::ControlTimeout {
+                service: service_name(service_id).unwrap_or("unknown"),
+                timeout: self.response_timeout,
+            }),
+        }
+    }
+}
+
+/// Responses to recorded requests get `event.interaction.remote_timeline_id`
+/// and `event.interaction.remote_nonce` attributes, so Modality links
+/// them causally to the request's event. A response which is already
+/// linked to something is left alone, and doesn't use up a request.
+impl EventAnnotator for ControlConnection {
+    fn annotate(&self, msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
+        let Some(response) = ControlResponse::from_message(msg) else {
+            return;
+        };
+        let has_remote = attrs
+            .iter()
+            .any(|(k, _)| k.as_ref() == "event.interaction.remote_nonce");
+        if has_remote {
+            return;
+        }
+
+        let mut interactions = self.interactions.lock().unwrap();
+        let Some(idx) = interactions.iter().position(|i| {
+            i.service_id == response.service_id
+                && response.ecu_id.as_deref().map_or(true, |id| id == i.ecu_id)
+        }) else {
+            return;
+        };
+        let interaction = interactions.remove(idx).unwrap();
+
+        attrs.push((
+            "event.interaction.remote_timeline_id".into(),
+            interaction.timeline_id.into(),
+        ));
+        attrs.push((
+            "event.interaction.remote_nonce".into(),
+            interaction.nonce.into(),
+        ));
+    }
+}
+
+/// Records injected messages as events on a timeline of their own.
+pub struct InjectionRecorder {
+    client: Client,
+    timeline_id: TimelineId,
+    timeline_created: bool,
+    ordering: u128,
+    next_nonce: i64,
+}
+
+impl InjectionRecorder {
+    pub const TIMELINE_NAME: &'static str = "modality-dlt-control";
+
+    pub fn new(client: Client) -> Self {
+        InjectionRecorder {
+            client,
+            timeline_id: TimelineId::allocate(),
+            timeline_created: false,
+            ordering: 0,
+            next_nonce: 0,
+        }
+    }
+
+    /// Send an `injected_message` event with `attrs`, and return the
+    /// timeline and nonce a response should refer to.
+    async fn record(
+        &mut self,
+        attrs: Vec<(&'static str, AttrVal)>,
+    ) -> Result<(TimelineId, i64), DltPluginError> {
+        if !self.timeline_created {
+            self.client
+                .switch_timeline(self.timeline_id)
+                .await
+                .map_err(DltPluginError::backend)?;
+            self.client
+                .send_timeline_attrs(Self::TIMELINE_NAME, std::iter::empty::<(&str, AttrVal)>())
+                .await
+                .map_err(DltPluginError::backend)?;
+            self.timeline_created = true;
+        }
+
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.client
+            .send_event(
+                "injected_message",
+                self.ordering,
+                attrs
+                    .into_iter()
+                    .chain(std::iter::once(("event.nonce", nonce.into()))),
+            )
+            .await
+            .map_err(DltPluginError::backend)?;
+        self.ordering += 1;
+        self.client.flush().await.map_err(DltPluginError::backend)?;
+
+        Ok((self.timeline_id, nonce))
+    }
+}
+
+/// Which control request a [ControlMutator] sends
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ControlMutatorKind {
+    SetLogLevel,
+    SetDefaultLogLevel,
+    SetTraceStatus,
+    InjectMessage,
+}
+
+/// A mutator which sends one kind of control request. Clearing a
+/// mutation puts the affected application and context back to the
+/// default log level or trace status.
+pub struct ControlMutator {
+    kind: ControlMutatorKind,
+    connection: ControlConnection,
+    default_ecu_id: String,
+    recorder: Option<Arc<Mutex<InjectionRecorder>>>,
+
+    /// For each active mutation, where it was sent and how to undo it
+    active: BTreeMap<MutationId, (String, ControlRequest)>,
+}
+
+impl ControlMutator {
+    pub fn new(
+        kind: ControlMutatorKind,
+        connection: ControlConnection,
+        default_ecu_id: impl Into<String>,
+    ) -> Self {
+        ControlMutator {
+            kind,
+            connection,
+            default_ecu_id: default_ecu_id.into(),
+            recorder: None,
+            active: Default::default(),
+        }
+    }
+
+    /// Record injected messages with `recorder`.
+    pub fn with_recorder(mut self, recorder: Arc<Mutex<InjectionRecorder>>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// One mutator of each kind, sharing `connection`
+    pub fn all(connection: &ControlConnection, default_ecu_id: &str) -> Vec<Self> {
+        [
+            ControlMutatorKind::SetLogLevel,
+            ControlMutatorKind::SetDefaultLogLevel,
+            ControlMutatorKind::SetTraceStatus,
+            ControlMutatorKind::InjectMessage,
+        ]
+        .into_iter()
+        .map(|kind| ControlMutator::new(kind, connection.clone(), default_ecu_id))
+        .collect()
+    }
+
+    fn name(&self) -> &'static str {
+        match self.kind {
+            ControlMutatorKind::SetLogLevel => "dlt_set_log_level",
+            ControlMutatorKind::SetDefaultLogLevel => "dlt_set_default_log_level",
+            ControlMutatorKind::SetTraceStatus => "dlt_set_trace_status",
+            ControlMutatorKind::InjectMessage => "dlt_inject_message",
+        }
+    }
+
+    /// Build the request for a mutation, and the one which undoes it
+    /// (if there is one).
+    fn requests(
+        &self,
+        params: &BTreeMap<AttrKey, AttrVal>,
+    ) -> Result<(ControlRequest, Option<ControlRequest>), String> {
+        Ok(match self.kind {
+            ControlMutatorKind::SetLogLevel => {
+                let application_id = required_param(params, "application_id")?;
+                let context_id = required_param(params, "context_id")?;
+                let log_level = required_param(params, "log_level")?.parse()?;
+                (
+                    ControlRequest::SetLogLevel {
+                        application_id: application_id.clone(),
+                        context_id: context_id.clone(),
+                        log_level,
+                    },
+                    Some(ControlRequest::SetLogLevel {
+                        application_id,
+                        context_id,
+                        log_level: ControlLogLevel::Default,
+                    }),
+                )
+            }
+            ControlMutatorKind::SetTraceStatus => {
+                let application_id = required_param(params, "application_id")?;
+                let context_id = required_param(params, "context_id")?;
+                let trace_status = required_param(params, "trace_status")?.parse()?;
+                (
+                    ControlRequest::SetTraceStatus {
+                        application_id: application_id.clone(),
+                        context_id: context_id.clone(),
+                        trace_status,
+                    },
+                    Some(ControlRequest::SetTraceStatus {
+                        application_id,
+                        context_id,
+                        trace_status: ControlTraceStatus::Default,
+                    }),
+                )
+            }
+            ControlMutatorKind::SetDefaultLogLevel => {
+                let log_level = required_param(params, "log_level")?.parse()?;
+                // The previous default isn't known, so this can't be undone
+                (ControlRequest::SetDefaultLogLevel { log_level }, None)
+            }
+            ControlMutatorKind::InjectMessage => {
+                let service_id = match int_param(params, "service_id") {
+                    Some(id) if id >= SERVICE_INJECTION_MIN as i64 && id <= u32::MAX as i64 => {
+                        id as u32
+                    }
+                    Some(id) => {
+                        return Err(format!(
+                        "Injection service id {id} must be between {SERVICE_INJECTION_MIN} and {}",
+                        u32::MAX
+                    ))
+                    }
+                    None => return Err("Missing integer parameter 'service_id'".to_owned()),
+                };
+                let data = match (param(params, "payload"), param(params, "payload_hex")) {
+                    (Some(payload), None) => payload.into_bytes(),
+                    (None, Some(hex)) => decode_hex(&hex)?,
+                    (None, None) => vec![],
+                    (Some(_), Some(_)) => {
+                        return Err("Give only one of 'payload' and 'payload_hex'".to_owned())
+                    }
+                };
+                if data.len() > MAX_INJECTION_DATA_LEN {
+                    return Err(format!(
+                        "Injection payload is {} bytes, more than the maximum of {MAX_INJECTION_DATA_LEN}",
+                        data.len()
+                    ));
+                }
+
+                // Injections can't be taken back
+                (
+                    ControlRequest::Inject {
+                        application_id: required_param(params, "application_id")?,
+                        context_id: required_param(params, "context_id")?,
+                        service_id,
+                        data,
+                    },
+                    None,
+                )
+            }
+        })
+    }
+
+    /// Record an injection as an event, and have the daemon's response
+    /// link back to it. Returns the event's nonce.
+    async fn record_injection(
+        &self,
+        ecu_id: &str,
+        request: &ControlRequest,
+    ) -> Result<Option<i64>, DltPluginError> {
+        let (
+            Some(recorder),
+            ControlRequest::Inject {
+                application_id,
+                context_id,
+                service_id,
+                data,
+            },
+        ) = (&self.recorder, request)
+        else {
+            return Ok(None);
+        };
+
+        let mut attrs = vec![
+            ("event.ecu_id", ecu_id.to_owned().into()),
+            ("event.application_id", application_id.clone().into()),
+            ("event.context_id", context_id.clone().into()),
+            ("event.service_id", (*service_id as i64).into()),
+            ("event.payload_size", (data.len() as i64).into()),
+        ];
+        if let Ok(payload) = std::str::from_utf8(data) {
+            attrs.push(("event.payload", payload.to_owned().into()));
+        }
+
+        let (timeline_id, nonce) = recorder.lock().await.record(attrs).await?;
+        self.connection
+            .expect_interaction(ecu_id, *service_id, timeline_id, nonce);
+        Ok(Some(nonce))
+    }
+
+    async fn send(&self, ecu_id: &str, request: &ControlRequest) -> Result<(), BoxError> {
+        let status = self.connection.request(ecu_id, request).await?;
+        info!(
+            mutator = self.name(),
+            ecu_id,
+            status = status.as_str(),
+            "Control request answered"
+        );
+        if status != ControlStatus::Ok {
+            return Err(format!("dlt-daemon answered '{}'", status.as_str()).into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Mutator for ControlMutator {
+    fn describe(&self) -> OwnedMutatorDescriptor {
+        let string_param = |name: &str, description: &str| {
+            OwnedMutatorParamDescriptor::new(AttrType::String, name.to_owned())
+                .unwrap()
+                .with_description(description)
+        };
+        let ecu_id = string_param(
+            "ecu_id",
+            "The ECU to send the request to. Defaults to the collector's control_ecu_id.",
+        );
+        let application_id = string_param("application_id", "The DLT application id");
+        let context_id = string_param("context_id", "The DLT context id");
+        let log_level = string_param(
+            "log_level",
+            "One of off, fatal, error, warn, info, debug, verbose or default",
+        );
+
+        let (description, params) = match self.kind {
+            ControlMutatorKind::InjectMessage => (
+                "Send an injection message to a DLT application context",
+                vec![
+                    ecu_id,
+                    application_id,
+                    context_id,
+                    OwnedMutatorParamDescriptor::new(AttrType::Integer, "service_id".to_owned())
+                        .unwrap()
+                        .with_description("The injection service id, 4095 (0xFFF) or more"),
+                    string_param("payload", "The data to inject, as text"),
+                    string_param("payload_hex", "The data to inject, as hex"),
+                ],
+            ),
+            ControlMutatorKind::SetLogLevel => (
+                "Set the log level of a DLT application context",
+                vec![ecu_id, application_id, context_id, log_level],
+            ),
+            ControlMutatorKind::SetDefaultLogLevel => {
+                ("Set the default DLT log level", vec![ecu_id, log_level])
+            }
+            ControlMutatorKind::SetTraceStatus => (
+                "Turn tracing of a DLT application context on or off",
+                vec![
+                    ecu_id,
+                    application_id,
+                    context_id,
+                    string_param("trace_status", "One of on, off or default"),
+                ],
+            ),
+        };
+
+        OwnedMutatorDescriptor {
+            name: Some(self.name().to_owned()),
+            description: Some(description.to_owned()),
+            layer: None,
+            group: Some("dlt".to_owned()),
+            operation: Some(MutatorOperation::SetToValue),
+            statefulness: None,
+            organization_custom_metadata: None,
+            params,
+        }
+    }
+
+    async fn inject(
+        &mut self,
+        mutation_id: MutationId,
+        params: BTreeMap<AttrKey, AttrVal>,
+    ) -> Result<(), BoxError> {
+        let ecu_id = param(&params, "ecu_id").unwrap_or_else(|| self.default_ecu_id.clone());
+        let (request, undo) = self.requests(&params)?;
+        let nonce = self.record_injection(&ecu_id, &request).await?;
+        let res = self.send(&ecu_id, &request).await;
+        if let (Err(_), Some(nonce)) = (&res, nonce) {
+            self.connection.forget_interaction(nonce);
+        }
+        res?;
+        if let Some(undo) = undo {
+            self.active.insert(mutation_id, (ecu_id, undo));
+        }
+        Ok(())
+    }
+
+    async fn clear_mutation(&mut self, mutation_id: &MutationId) -> Result<(), BoxError> {
+        if let Some((ecu_id, undo)) = self.active.remove(mutation_id) {
+            self.send(&ecu_id, &undo).await?;
+        }
+        Ok(())
+    }
+
+    async fn reset(&mut self) -> Result<(), BoxError> {
+        for (_, (ecu_id, undo)) in std::mem::take(&mut self.active) {
+            if let Err(e) = self.send(&ecu_id, &undo).await {
+                warn!(mutator = self.name(), err = %e, "Failed to undo mutation");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Connect to the Modality mutation plane with `config`, and serve
+/// `mutators` on a background task until the process exits.
+pub async fn serve<C>(
+    config: &Config<C>,
+    mutators: Vec<ControlMutator>,
+) -> Result<(), DltPluginError>
+where
+    C: Serialize + DeserializeOwned,
+{
+    let mut host = config
+        .connect_and_authenticate_mutation()
+        .await
+        .map_err(DltPluginError::backend)?;
+
+    // Injections are recorded over a separate ingest connection, so
+    // they don't have to wait behind the collector's batches
+    let client = config
+        .connect_and_authenticate()
+        .await
+        .map_err(DltPluginError::backend)?;
+    let recorder = Arc::new(Mutex::new(InjectionRecorder::new(client)));
+
+    for mutator in mutators {
+        let mutator = mutator.with_recorder(recorder.clone());
+        info!(mutator = mutator.name(), "Registering mutator");
+        host.register_mutator(Box::new(mutator))
+            .map_err(DltPluginError::backend)?;
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = host.run().await {
+            warn!(err = %e, "Mutation plane connection failed");
+        }
+    });
+    Ok(())
+}
+
+fn param(params: &BTreeMap<AttrKey, AttrVal>, name: &str) -> Option<String> {
+    params
+        .iter()
+        .find(|(k, _)| k.as_ref() == name)
+        .and_then(|(_, v)| match v {
+            AttrVal::String(s) => Some(s.to_string()),
+            _ => None,
+        })
+}
+
+fn int_param(params: &BTreeMap<AttrKey, AttrVal>, name: &str) -> Option<i64> {
+    params
+        .iter()
+        .find(|(k, _)| k.as_ref() == name)
+        .and_then(|(_, v)| match v {
+            AttrVal::Integer(i) => Some(*i),
+            _ => None,
+        })
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let hex: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if hex.len() % 2 != 0 {
+        return Err("Hex payload has an odd number of digits".to_owned());
+    }
+    hex.chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                .ok_or_else(|| format!("Invalid hex digits '{}'", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+fn required_param(params: &BTreeMap<AttrKey, AttrVal>, name: &str) -> Result<String, String> {
+    param(params, name).ok_or_else(|| format!("Missing string parameter '{name}'"))
+}
@@ -0,0 +1,242 @@
+//! Decoding the payloads of network trace messages.
+//!
+//! Network trace messages carry two raw arguments: a header, whose
+//! layout depends on the bus, and the frame's payload.
+
+use auxon_sdk::api::{AttrKey, AttrVal};
+use dlt_core::dlt;
+
+/// A CAN frame, as found in a network trace message
+pub struct CanFrame<'a> {
+    /// The CAN id, without the extended frame flag
+    pub id: u32,
+    pub extended: bool,
+    pub data: &'a [u8],
+}
+
+/// Bit 31 of the CAN id in the header marks an extended (29 bit) id
+const CAN_EXTENDED_FLAG: u32 = 1 << 31;
+
+/// The fixed size header at the start of every SOME/IP message
+pub struct SomeIpHeader {
+    pub service_id: u16,
+    pub method_id: u16,
+    pub length: u32,
+    pub client_id: u16,
+    pub session_id: u16,
+    pub protocol_version: u8,
+    pub interface_version: u8,
+    pub message_type: u8,
+    pub return_code: u8,
+}
+
+pub const SOMEIP_HEADER_LEN: usize = 16;
+
+/// The only SOME/IP protocol version there has been
+pub const SOMEIP_PROTOCOL_VERSION: u8 = 0x01;
+
+impl SomeIpHeader {
+    /// Parse the header at the start of `bytes`. SOME/IP is always big
+    /// endian.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let b = bytes.get(..SOMEIP_HEADER_LEN)?;
+        Some(SomeIpHeader {
+            service_id: u16::from_be_bytes([b[0], b[1]]),
+            method_id: u16::from_be_bytes([b[2], b[3]]),
+            length: u32::from_be_bytes([b[4], b[5], b[6], b[7]]),
+            client_id: u16::from_be_bytes([b[8], b[9]]),
+            session_id: u16::from_be_bytes([b[10], b[11]]),
+            protocol_version: b[12],
+            interface_version: b[13],
+            message_type: b[14],
+            return_code: b[15],
+        })
+    }
+
+    pub fn message_type_name(&self) -> &'static str {
+        match self.message_type {
+            0x00 => "request",
+            0x01 => "request_no_return",
+            0x02 => "notification",
+            0x80 => "response",
+            0x81 => "error",
+            0x20 => "tp_request",
+            0x21 => "tp_request_no_return",
+            0x22 => "tp_notification",
+            0xA0 => "tp_response",
+            0xA1 => "tp_error",
+            _ => "unknown",
+        }
+    }
+
+    pub fn return_code_name(&self) -> &'static str {
+        match self.return_code {
+            0x00 => "ok",
+            0x01 => "not_ok",
+            0x02 => "unknown_service",
+            0x03 => "unknown_method",
+            0x04 => "not_ready",
+            0x05 => "not_reachable",
+            0x06 => "timeout",
+            0x07 => "wrong_protocol_version",
+            0x08 => "wrong_interface_version",
+            0x09 => "malformed_message",
+            0x0A => "wrong_message_type",
+            _ => "unknown",
+        }
+    }
+}
+
+/// The network trace type of `msg` (like "can" or "someip"), if it is
+/// a network trace message.
+pub fn network_trace_type(msg: &dlt::Message) -> Option<String> {
+    match &msg.extended_header.as_ref()?.message_type {
+        dlt::MessageType::NetworkTrace(trace_type) => Some(trace_type.as_ref().to_lowercase()),
+        _ => None,
+    }
+}
+
+/// The header and payload arguments of a network trace message
+pub fn header_and_payload(msg: &dlt::Message) -> Option<(&[u8], &[u8])> {
+    let dlt::PayloadContent::Verbose(args) = &msg.payload else {
+        return None;
+    };
+    let raw = |idx: usize| match args.get(idx).map(|arg| &arg.value) {
+        Some(dlt::Value::Raw(bytes)) => Some(bytes.as_slice()),
+        _ => None,
+    };
+    Some((raw(0)?, raw(1).unwrap_or_default()))
+}
+
+/// The CAN frame in `msg`, if it's a CAN network trace message. The
+/// header holds the CAN id, in the message's byte order.
+pub fn can_frame(msg: &dlt::Message) -> Option<CanFrame<'_>> {
+    if network_trace_type(msg)? != "can" {
+        return None;
+    }
+    let (header, data) = header_and_payload(msg)?;
+    let id_bytes: [u8; 4] = header.get(..4)?.try_into().ok()?;
+    let raw_id = match msg.header.endianness {
+        dlt::Endianness::Big => u32::from_be_bytes(id_bytes),
+        dlt::Endianness::Little => u32::from_le_bytes(id_bytes),
+    };
+    Some(CanFrame {
+        id: raw_id & !CAN_EXTENDED_FLAG,
+        extended: raw_id & CAN_EXTENDED_FLAG != 0,
+        data,
+    })
+}
+
+/// The SOME/IP header and payload in `msg`, if it's a SOME/IP network
+/// trace message. Ethernet traces either carry the SOME/IP header as
+/// their header argument, or start with an Ethernet II header; only a
+/// 16 byte header with the SOME/IP protocol version is taken as SOME/IP.
+pub fn someip_message(msg: &dlt::Message) -> Option<(SomeIpHeader, &[u8])> {
+    let trace_type = network_trace_type(msg)?;
+    let (header, payload) = header_and_payload(msg)?;
+    let someip = SomeIpHeader::parse(header)?;
+    let is_someip = trace_type == "someip"
+        || (trace_type == "ethernet"
+            && header.len() == SOMEIP_HEADER_LEN
+            && someip.protocol_version == SOMEIP_PROTOCOL_VERSION);
+    if !is_someip {
+        return None;
+    }
+    Some((someip, payload))
+}
+
+/// Add structured attributes for the frame in a network trace message.
+pub fn gather_network_trace_attrs(msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
+    let Some(trace_type) = network_trace_type(msg) else {
+        return;
+    };
+
+    match trace_type.as_str() {
+        "can" => {
+            let Some(frame) = can_frame(msg) else {
+                return;
+            };
+            attrs.push(("event.can.id".into(), (frame.id as i64).into()));
+            attrs.push(("event.can.extended".into(), frame.extended.into()));
+            attrs.push(("event.can.dlc".into(), (frame.data.len() as i64).into()));
+            attrs.push(("event.can.data".into(), hex(frame.data).into()));
+        }
+        "someip" | "ethernet" => {
+            if let Some((someip, payload)) = someip_message(msg) {
+                gather_someip_attrs(&someip, payload, attrs);
+                return;
+            }
+            let Some((header, payload)) = header_and_payload(msg) else {
+                return;
+            };
+            if let Some(b) = header.get(..14) {
+                attrs.push(("event.ethernet.destination".into(), mac(&b[0..6]).into()));
+                attrs.push(("event.ethernet.source".into(), mac(&b[6..12]).into()));
+                attrs.push((
+                    "event.ethernet.ethertype".into(),
+                    (u16::from_be_bytes([b[12], b[13]]) as i64).into(),
+                ));
+                attrs.push((
+                    "event.ethernet.payload_size".into(),
+                    (payload.len() as i64).into(),
+                ));
+            }
+        }
+        // FlexRay, MOST and the rest have no common header layout, so
+        // just pass the bytes on
+        _ => {
+            let Some((header, payload)) = header_and_payload(msg) else {
+                return;
+            };
+            attrs.push(("event.network.header".into(), hex(header).into()));
+            attrs.push(("event.network.payload".into(), hex(payload).into()));
+        }
+    }
+}
+
+fn gather_someip_attrs(someip: &SomeIpHeader, payload: &[u8], attrs: &mut Vec<(AttrKey, AttrVal)>) {
+    attrs.push((
+        "event.someip.service_id".into(),
+        (someip.service_id as i64).into(),
+    ));
+    attrs.push((
+        "event.someip.method_id".into(),
+        (someip.method_id as i64).into(),
+    ));
+    attrs.push((
+        "event.someip.client_id".into(),
+        (someip.client_id as i64).into(),
+    ));
+    attrs.push((
+        "event.someip.session_id".into(),
+        (someip.session_id as i64).into(),
+    ));
+    attrs.push((
+        "event.someip.interface_version".into(),
+        (someip.interface_version as i64).into(),
+    ));
+    attrs.push((
+        "event.someip.message_type".into(),
+        someip.message_type_name().into(),
+    ));
+    attrs.push((
+        "event.someip.return_code".into(),
+        someip.return_code_name().into(),
+    ));
+    attrs.push((
+        "event.someip.payload_size".into(),
+        (payload.len() as i64).into(),
+    ));
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn mac(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
@@ -0,0 +1,558 @@
+//! Decoding non-verbose DLT messages, using message descriptions from
+//! an AUTOSAR Log and Trace extract (`DLT-MESSAGE` elements in ARXML).
+//!
+//! Descriptions are grouped into dictionaries, each of which may be for
+//! a particular ECU, and a particular software version of it.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use auxon_sdk::api::{AttrKey, AttrVal};
+use dlt_core::dlt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arxml, control,
+    convert::{value_to_attr_val, value_to_text},
+    payload::{self, DescriptionError, Encoding, Parameter},
+    send::EventAnnotator,
+};
+
+/// Non-verbose message descriptions, by message id
+#[derive(Debug, Default)]
+pub struct NonVerboseMessages {
+    messages: HashMap<u32, NonVerboseMessage>,
+}
+
+#[derive(Debug, Default)]
+pub struct NonVerboseMessage {
+    pub name: String,
+    /// The application and context the message is logged from, which
+    /// non-verbose messages don't necessarily carry themselves
+    pub application_id: Option<String>,
+    pub context_id: Option<String>,
+    pub source_file: Option<String>,
+    pub source_line: Option<i64>,
+    pub arguments: Vec<Parameter>,
+    /// How to present decoded argument values, by argument name
+    pub conversions: HashMap<String, Conversion>,
+    /// Arguments with fixed text, which isn't in the payload
+    pub predefined_text: Vec<PredefinedText>,
+}
+
+/// An argument with fixed text. This is often the message's format
+/// string, or a piece of it.
+#[derive(Clone, Debug)]
+pub struct PredefinedText {
+    pub name: String,
+    pub text: String,
+    /// How many of the message's (payload) arguments come before it
+    pub position: usize,
+}
+
+/// How to turn an argument's raw value into the value it represents
+#[derive(Clone, Debug, Default)]
+pub struct Conversion {
+    pub unit: Option<String>,
+    /// Scale the raw value as `(offset + factor * raw) / divisor`
+    pub linear: Option<Linear>,
+    /// Symbolic names for raw values, by inclusive range
+    pub text_table: Vec<(i64, i64, String)>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Linear {
+    pub offset: f64,
+    pub factor: f64,
+    pub divisor: f64,
+}
+
+impl Conversion {
+    /// The converted value as text, if the value is converted at all
+    fn apply_text(&self, value: &dlt::Value) -> Option<String> {
+        let raw = integer_value(value)?;
+        let text = self
+            .text_table
+            .iter()
+            .find(|(lower, upper, _)| (*lower..=*upper).contains(&raw));
+        if let Some((_, _, text)) = text {
+            return Some(text.clone());
+        }
+        match self.linear {
+            Some(linear) if linear.divisor != 0.0 => {
+                Some(((linear.offset + linear.factor * raw as f64) / linear.divisor).to_string())
+            }
+            _ => None,
+        }
+    }
+
+    fn apply(&self, value: dlt::Value) -> Option<AttrVal> {
+        let raw = integer_value(&value);
+        if let Some(raw) = raw {
+            let text = self
+                .text_table
+                .iter()
+                .find(|(lower, upper, _)| (*lower..=*upper).contains(&raw));
+            if let Some((_, _, text)) = text {
+                return Some(text.clone().into());
+            }
+        }
+
+        match (self.linear, raw) {
+            (Some(linear), Some(raw)) if linear.divisor != 0.0 => {
+                Some(((linear.offset + linear.factor * raw as f64) / linear.divisor).into())
+            }
+            _ => value_to_attr_val(value),
+        }
+    }
+}
+
+fn integer_value(value: &dlt::Value) -> Option<i64> {
+    Some(match value {
+        dlt::Value::Bool(x) | dlt::Value::U8(x) => *x as i64,
+        dlt::Value::U16(x) => *x as i64,
+        dlt::Value::U32(x) => *x as i64,
+        dlt::Value::U64(x) => i64::try_from(*x).ok()?,
+        dlt::Value::I8(x) => *x as i64,
+        dlt::Value::I16(x) => *x as i64,
+        dlt::Value::I32(x) => *x as i64,
+        dlt::Value::I64(x) => *x,
+        _ => return None,
+    })
+}
+
+impl NonVerboseMessages {
+    /// Load message descriptions from an ARXML file, or from every such
+    /// file in a directory.
+    pub fn load(path: &Path) -> Result<Self, DescriptionError> {
+        let mut messages = NonVerboseMessages::default();
+        for (path, content) in payload::read_description_files(path)? {
+            let doc =
+                roxmltree::Document::parse(&content).map_err(|source| DescriptionError::Xml {
+                    path: path.clone(),
+                    source,
+                })?;
+            arxml::parse_dlt_messages(&doc, &mut messages)
+                .map_err(|reason| DescriptionError::Invalid { path, reason })?;
+        }
+        Ok(messages)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Add a message description, replacing any with the same id
+    pub fn add_message(&mut self, message_id: u32, message: NonVerboseMessage) {
+        self.messages.insert(message_id, message);
+    }
+
+    pub fn message(&self, message_id: u32) -> Option<&NonVerboseMessage> {
+        self.messages.get(&message_id)
+    }
+
+    /// Add attributes for the non-verbose message `msg`, with
+    /// `message_id` and `payload`: the described message's name, source
+    /// location, and decoded arguments, along with the application and
+    /// context ids if the message has no extended header to carry them.
+    /// If `render_message` is set, `event.message` is added too; see
+    /// [render].
+    ///
+    /// Returns why, if the message can't be decoded.
+    fn decode(
+        &self,
+        msg: &dlt::Message,
+        message_id: u32,
+        payload: &[u8],
+        render_message: bool,
+        attrs: &mut Vec<(AttrKey, AttrVal)>,
+    ) -> Result<(), String> {
+        let message = self
+            .message(message_id)
+            .ok_or_else(|| format!("Unknown message id {message_id}"))?;
+
+        attrs.push(("event.message_name".into(), message.name.clone().into()));
+        if msg.extended_header.is_none() {
+            if let Some(application_id) = &message.application_id {
+                attrs.push(("event.application_id".into(), application_id.clone().into()));
+            }
+            if let Some(context_id) = &message.context_id {
+                attrs.push(("event.context_id".into(), context_id.clone().into()));
+            }
+        }
+        if let Some(source_file) = &message.source_file {
+            attrs.push(("event.source_file".into(), source_file.clone().into()));
+        }
+        if let Some(source_line) = message.source_line {
+            attrs.push(("event.source_line".into(), source_line.into()));
+        }
+
+        for predefined in message.predefined_text.iter() {
+            attrs.push((
+                format!("event.payload.{}", predefined.name).into(),
+                predefined.text.clone().into(),
+            ));
+        }
+
+        let encoding = Encoding::dlt(msg.header.endianness);
+        let values = payload::decode_values(&message.arguments, payload, encoding)
+            .map_err(|e| e.to_string())?;
+        if render_message {
+            let text = render(message, &values);
+            attrs.push(("event.message".into(), text.into()));
+        }
+        for (name, value) in values {
+            let conversion = message.conversions.get(&name);
+            let val = match conversion {
+                Some(conversion) => conversion.apply(value),
+                None => value_to_attr_val(value),
+            };
+            if let Some(val) = val {
+                attrs.push((format!("event.payload.{name}").into(), val));
+            }
+            if let Some(unit) = conversion.and_then(|c| c.unit.as_ref()) {
+                attrs.push((
+                    format!("event.payload.{name}.unit").into(),
+                    unit.clone().into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where to find a dictionary of non-verbose message descriptions, and
+/// which messages it's for.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NonVerboseDictionaryConfig {
+    /// Identifies the dictionary in `event.decoder.dictionary`. Defaults
+    /// to its path.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// An ARXML file, or a directory of them
+    pub path: PathBuf,
+
+    /// The ECU whose messages it describes. If not given, it's for any
+    /// ECU.
+    #[serde(default)]
+    pub ecu_id: Option<String>,
+
+    /// Only use the dictionary once the ECU has reported this software
+    /// version, in a get_software_version control response. A trailing
+    /// `*` matches any version starting with what comes before it.
+    #[serde(default)]
+    pub software_version: Option<String>,
+}
+
+struct Dictionary {
+    name: String,
+    ecu_id: Option<String>,
+    software_version: Option<String>,
+    messages: NonVerboseMessages,
+}
+
+/// Decodes non-verbose messages with whichever dictionary fits the
+/// message's ECU, and its software version if known.
+#[derive(Default)]
+pub struct NonVerboseDecoder {
+    dictionaries: Vec<Dictionary>,
+
+    /// The software version each ECU reported most recently
+    software_versions: Mutex<HashMap<Option<String>, String>>,
+
+    render_message: bool,
+}
+
+impl NonVerboseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an `event.message` attribute to decoded messages, with their
+    /// text rendered; see [render].
+    pub fn with_message_rendering(mut self, render_message: bool) -> Self {
+        self.render_message = render_message;
+        self
+    }
+
+    /// Load the dictionary described by `config`.
+    pub fn load_dictionary(
+        &mut self,
+        config: &NonVerboseDictionaryConfig,
+    ) -> Result<(), DescriptionError> {
+        let messages = NonVerboseMessages::load(&config.path)?;
+        self.add_dictionary(config, messages);
+        Ok(())
+    }
+
+    /// Add a dictionary of `messages`, described by `config` (whose path
+    /// isn't read).
+    pub fn add_dictionary(
+        &mut self,
+        config: &NonVerboseDictionaryConfig,
+        messages: NonVerboseMessages,
+    ) {
+        let name = config
+            .name
+            .clone()
+            .unwrap_or_else(|| config.path.display().to_string());
+        if messages.is_empty() {
+            tracing::warn!(dictionary = %name, "No non-verbose message descriptions found");
+        } else {
+            tracing::info!(dictionary = %name, "Loaded non-verbose message descriptions");
+        }
+        self.dictionaries.push(Dictionary {
+            name,
+            ecu_id: config.ecu_id.clone(),
+            software_version: config.software_version.clone(),
+            messages,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dictionaries.is_empty()
+    }
+
+    /// Whether any dictionary is for a particular software version, so
+    /// it's worth asking ECUs for theirs.
+    pub fn needs_software_version(&self) -> bool {
+        self.dictionaries
+            .iter()
+            .any(|d| d.software_version.is_some())
+    }
+
+    /// The dictionary for messages from `ecu_id`. Dictionaries for the
+    /// ECU are preferred over ones for any ECU, and ones for its software
+    /// version over ones for any version; the first configured wins ties.
+    fn dictionary(&self, ecu_id: &Option<String>) -> Option<&Dictionary> {
+        let version = self.software_versions.lock().unwrap().get(ecu_id).cloned();
+        self.dictionaries
+            .iter()
+            .filter(|d| d.ecu_id.is_none() || d.ecu_id == *ecu_id)
+            .filter(|d| match (&d.software_version, &version) {
+                (Some(wanted), Some(version)) => version_matches(wanted, version),
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .min_by_key(|d| (d.ecu_id.is_none(), d.software_version.is_none()))
+    }
+}
+
+/// Whether `version` is the `wanted` software version: the same, or
+/// starting with `wanted` up to a trailing `*`.
+fn version_matches(wanted: &str, version: &str) -> bool {
+    match wanted.strip_suffix('*') {
+        Some(prefix) => version.starts_with(prefix),
+        None => version == wanted,
+    }
+}
+
+/// Adds decoded arguments to non-verbose events (see
+/// [NonVerboseMessages]), with `event.decoder.dictionary` saying which
+/// dictionary was used, or `event.decode_error` saying why the message
+/// couldn't be decoded.
+impl EventAnnotator for NonVerboseDecoder {
+    fn annotate(&self, msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
+        if let Some(version) = control::software_version(msg) {
+            tracing::info!(ecu_id = ?msg.header.ecu_id, %version, "ECU reported its software version");
+            self.software_versions
+                .lock()
+                .unwrap()
+                .insert(msg.header.ecu_id.clone(), version);
+            return;
+        }
+
+        let dlt::PayloadContent::NonVerbose(message_id, payload) = &msg.payload else {
+            return;
+        };
+
+        let ecu_id = &msg.header.ecu_id;
+        let Some(dictionary) = self.dictionary(ecu_id) else {
+            let err = match ecu_id {
+                Some(ecu_id) => format!("No dictionary for ECU '{ecu_id}'"),
+                None => "No dictionary for messages without an ECU id".to_owned(),
+            };
+            attrs.push(("event.decode_error".into(), err.into()));
+            return;
+        };
+
+        attrs.push((
+            "event.decoder.dictionary".into(),
+            dictionary.name.clone().into(),
+        ));
+        if let Err(err) =
+            dictionary
+                .messages
+                .decode(msg, *message_id, payload, self.render_message, attrs)
+        {
+            attrs.push(("event.decode_error".into(), err.into()));
+        }
+    }
+}
+
+/// A piece of a message, for rendering
+enum Segment<'a> {
+    Text(&'a str),
+    Value {
+        raw: &'a dlt::Value,
+        /// The value after conversion (by text table, or scaling)
+        converted: Option<String>,
+    },
+}
+
+/// Render `message`'s text, from its decoded argument `values`. The
+/// predefined text and argument values are put together in the order
+/// they're described in. Predefined text may be a printf-style format
+/// string, in which case the values which follow are substituted into
+/// it; any values left over are added after it, separated by spaces.
+fn render(message: &NonVerboseMessage, values: &[(String, dlt::Value)]) -> String {
+    let mut segments = vec![];
+    let mut values = values.iter().peekable();
+    for (position, arg) in message.arguments.iter().enumerate() {
+        segments.extend(
+            message
+                .predefined_text
+                .iter()
+                .filter(|p| p.position == position)
+                .map(|p| Segment::Text(&p.text)),
+        );
+        // Struct members and array elements follow their argument
+        let member_prefix = format!("{}.", arg.name);
+        while let Some((name, raw)) =
+            values.next_if(|(name, _)| *name == arg.name || name.starts_with(&member_prefix))
+        {
+            let converted = message
+                .conversions
+                .get(name)
+                .and_then(|c| c.apply_text(raw));
+            segments.push(Segment::Value { raw, converted });
+        }
+    }
+    segments.extend(
+        message
+            .predefined_text
+            .iter()
+            .filter(|p| p.position >= message.arguments.len())
+            .map(|p| Segment::Text(&p.text)),
+    );
+
+    let mut consumed = vec![false; segments.len()];
+    let mut pieces = vec![];
+    for (idx, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Text(format) => {
+                let mut cursor = idx + 1;
+                let mut next_value = || {
+                    while let Some(segment) = segments.get(cursor) {
+                        cursor += 1;
+                        if let Segment::Value { raw, converted } = segment {
+                            if !consumed[cursor - 1] {
+                                consumed[cursor - 1] = true;
+                                return Some((*raw, converted.as_deref()));
+                            }
+                        }
+                    }
+                    None
+                };
+                pieces.push(substitute(format, &mut next_value));
+            }
+            Segment::Value { raw, converted } if !consumed[idx] => {
+                pieces.push(converted.clone().unwrap_or_else(|| value_to_text(raw)));
+            }
+            Segment::Value { .. } => (),
+        }
+    }
+    pieces.join(" ")
+}
+
+/// Substitute values into the printf-style conversion specifications
+/// in `format`. Specifications with no value left are kept as they are.
+fn substitute<'v>(
+    format: &str,
+    next_value: &mut dyn FnMut() -> Option<(&'v dlt::Value, Option<&'v str>)>,
+) -> String {
+    let mut out = String::with_capacity(format.len());
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        if chars.next_if_eq(&'%').is_some() {
+            out.push('%');
+            continue;
+        }
+
+        let mut spec = String::from("%");
+        let mut flags = String::new();
+        while let Some(flag) = chars.next_if(|c| "-+ #0".contains(*c)) {
+            flags.push(flag);
+        }
+        let mut width = String::new();
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            width.push(digit);
+        }
+        let mut precision = None;
+        if chars.next_if_eq(&'.').is_some() {
+            let mut digits = String::new();
+            while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                digits.push(digit);
+            }
+            precision = Some(digits.parse::<usize>().unwrap_or(0));
+        }
+        // Length modifiers don't matter, since values carry their type
+        while chars.next_if(|c| "hlLqjzt".contains(*c)).is_some() {}
+        let Some(conversion) = chars.next_if(char::is_ascii_alphabetic) else {
+            spec.push_str(&flags);
+            spec.push_str(&width);
+            out.push_str(&spec);
+            continue;
+        };
+
+        let Some((raw, converted)) = next_value() else {
+            spec.push_str(&flags);
+            spec.push_str(&width);
+            if let Some(precision) = precision {
+                spec.push_str(&format!(".{precision}"));
+            }
+            spec.push(conversion);
+            out.push_str(&spec);
+            continue;
+        };
+
+        let text = match (conversion, converted) {
+            (_, Some(converted)) => converted.to_owned(),
+            ('x', None) => integer_value(raw).map(|x| format!("{x:x}")),
+            ('X', None) => integer_value(raw).map(|x| format!("{x:X}")),
+            ('o', None) => integer_value(raw).map(|x| format!("{x:o}")),
+            ('f' | 'F' | 'e' | 'E' | 'g' | 'G', None) => {
+                float_value(raw).map(|x| format!("{x:.*}", precision.unwrap_or(6)))
+            }
+            _ => None,
+        }
+        .unwrap_or_else(|| value_to_text(raw));
+
+        let width = width.parse::<usize>().unwrap_or(0);
+        let padded = if flags.contains('-') {
+            format!("{text:<width$}")
+        } else if flags.contains('0') && text.parse::<f64>().is_ok() {
+            format!("{text:0>width$}")
+        } else {
+            format!("{text:>width$}")
+        };
+        out.push_str(&padded);
+    }
+    out
+}
+
+fn float_value(value: &dlt::Value) -> Option<f64> {
+    match value {
+        dlt::Value::F32(x) => Some(*x as f64),
+        dlt::Value::F64(x) => Some(*x),
+        other => integer_value(other).map(|x| x as f64),
+    }
+}
@@ -0,0 +1,330 @@
+//! Decoding payloads whose layout isn't carried in the message itself,
+//! but comes from an external description (like ARXML or FIBEX).
+
+use std::path::{Path, PathBuf};
+
+use auxon_sdk::api::{AttrKey, AttrVal};
+use dlt_core::dlt;
+
+use crate::convert::value_to_attr_val;
+
+/// The type of a value in a described payload
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataType {
+    Bool,
+    /// An unsigned integer, of the given number of bytes
+    Unsigned(u8),
+    /// A two's complement signed integer, of the given number of bytes
+    Signed(u8),
+    Float32,
+    Float64,
+    /// A string, either of a fixed size in bytes, or preceded by a
+    /// length field
+    String {
+        fixed_len: Option<usize>,
+    },
+    /// Raw bytes, either of a fixed size, or preceded by a length field
+    Bytes {
+        fixed_len: Option<usize>,
+    },
+    Struct(Vec<Parameter>),
+    /// Either a fixed number of elements, or preceded by a length field
+    /// giving the size of the elements in bytes
+    Array {
+        element: Box<DataType>,
+        fixed_len: Option<usize>,
+    },
+}
+
+/// A named value in a described payload
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub data_type: DataType,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DescriptionError {
+    #[error("Failed to read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("{} is not valid XML: {source}", path.display())]
+    Xml {
+        path: PathBuf,
+        source: roxmltree::Error,
+    },
+
+    #[error("Invalid description in {}: {reason}", path.display())]
+    Invalid { path: PathBuf, reason: String },
+}
+
+/// Read the description file at `path`, or every description file (by
+/// extension: `.arxml`, `.xml` or `.fibex`) if it's a directory.
+/// Returns each file's path and contents.
+pub fn read_description_files(path: &Path) -> Result<Vec<(PathBuf, String)>, DescriptionError> {
+    let io_err = |path: &Path| {
+        let path = path.to_owned();
+        move |source| DescriptionError::Io { path, source }
+    };
+
+    let mut paths = vec![];
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path).map_err(io_err(path))? {
+            let entry_path = entry.map_err(io_err(path))?.path();
+            let is_description = entry_path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    ["arxml", "xml", "fibex"].contains(&ext.to_lowercase().as_str())
+                });
+            if is_description {
+                paths.push(entry_path);
+            }
+        }
+        // Load in a predictable order, since later definitions win
+        paths.sort();
+    } else {
+        paths.push(path.to_owned());
+    }
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let content = std::fs::read_to_string(&path).map_err(io_err(&path))?;
+            Ok((path, content))
+        })
+        .collect()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PayloadDecodeError {
+    #[error("Payload ended in the middle of '{name}'")]
+    Truncated { name: String },
+
+    #[error("'{name}' is not a valid string")]
+    InvalidString { name: String },
+
+    #[error("The elements of '{name}' don't fit its length field")]
+    LengthMismatch { name: String },
+}
+
+/// How a payload is serialized: the byte order of its values, and the
+/// size of the length fields in front of dynamically sized ones.
+#[derive(Copy, Clone, Debug)]
+pub struct Encoding {
+    pub endianness: dlt::Endianness,
+    pub length_field_size: usize,
+}
+
+impl Encoding {
+    /// SOME/IP: big endian, with 32 bit length fields
+    pub const SOMEIP: Encoding = Encoding {
+        endianness: dlt::Endianness::Big,
+        length_field_size: 4,
+    };
+
+    /// DLT non-verbose arguments, in the message's byte order, with 16
+    /// bit length fields
+    pub fn dlt(endianness: dlt::Endianness) -> Self {
+        Encoding {
+            endianness,
+            length_field_size: 2,
+        }
+    }
+}
+
+/// Decode `bytes` as a sequence of `params`, adding an
+/// `event.payload.<name>` attribute for each value. Struct members and
+/// array elements are named `<parent>.<member>` and `<parent>.<index>`.
+///
+/// Nothing is added to `attrs` if the payload doesn't match `params`.
+pub fn decode_payload(
+    params: &[Parameter],
+    bytes: &[u8],
+    encoding: Encoding,
+    attrs: &mut Vec<(AttrKey, AttrVal)>,
+) -> Result<(), PayloadDecodeError> {
+    for (name, value) in decode_values(params, bytes, encoding)? {
+        if let Some(val) = value_to_attr_val(value) {
+            attrs.push((format!("event.payload.{name}").into(), val));
+        }
+    }
+    Ok(())
+}
+
+/// Decode `bytes` as a sequence of `params`, returning each value with
+/// its name, as described for [decode_payload].
+pub fn decode_values(
+    params: &[Parameter],
+    bytes: &[u8],
+    encoding: Encoding,
+) -> Result<Vec<(String, dlt::Value)>, PayloadDecodeError> {
+    let mut decoder = Decoder {
+        bytes,
+        pos: 0,
+        encoding,
+        values: vec![],
+    };
+    for param in params {
+        decoder.decode(&param.name, &param.data_type)?;
+    }
+    Ok(decoder.values)
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    encoding: Encoding,
+    values: Vec<(String, dlt::Value)>,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, name: &str, len: usize) -> Result<&'a [u8], PayloadDecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| PayloadDecodeError::Truncated {
+                name: name.to_owned(),
+            })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Read an unsigned integer of `size` bytes (at most 8)
+    fn uint(&mut self, name: &str, size: usize) -> Result<u64, PayloadDecodeError> {
+        let bytes = self.take(name, size)?;
+        let mut buf = [0u8; 8];
+        Ok(match self.encoding.endianness {
+            dlt::Endianness::Big => {
+                buf[8 - size..].copy_from_slice(bytes);
+                u64::from_be_bytes(buf)
+            }
+            dlt::Endianness::Little => {
+                buf[..size].copy_from_slice(bytes);
+                u64::from_le_bytes(buf)
+            }
+        })
+    }
+
+    /// The contents of a value with a length field in front of it, or
+    /// of `fixed_len` bytes
+    fn sized(
+        &mut self,
+        name: &str,
+        fixed_len: Option<usize>,
+    ) -> Result<&'a [u8], PayloadDecodeError> {
+        let len = match fixed_len {
+            Some(len) => len,
+            None => self.uint(name, self.encoding.length_field_size)? as usize,
+        };
+        self.take(name, len)
+    }
+
+    fn decode(&mut self, name: &str, data_type: &DataType) -> Result<(), PayloadDecodeError> {
+        let value = match data_type {
+            DataType::Bool => dlt::Value::Bool(self.take(name, 1)?[0]),
+            DataType::Unsigned(size) => {
+                let size = (*size).min(8) as usize;
+                let x = self.uint(name, size)?;
+                match size {
+                    1 => dlt::Value::U8(x as u8),
+                    2 => dlt::Value::U16(x as u16),
+                    3 | 4 => dlt::Value::U32(x as u32),
+                    _ => dlt::Value::U64(x),
+                }
+            }
+            DataType::Signed(size) => {
+                let size = (*size).clamp(1, 8) as usize;
+                let x = self.uint(name, size)?;
+                // Sign extend
+                let shift = 64 - size * 8;
+                let x = ((x << shift) as i64) >> shift;
+                match size {
+                    1 => dlt::Value::I8(x as i8),
+                    2 => dlt::Value::I16(x as i16),
+                    3 | 4 => dlt::Value::I32(x as i32),
+                    _ => dlt::Value::I64(x),
+                }
+            }
+            DataType::Float32 => dlt::Value::F32(f32::from_bits(self.uint(name, 4)? as u32)),
+            DataType::Float64 => dlt::Value::F64(f64::from_bits(self.uint(name, 8)?)),
+            DataType::String { fixed_len } => {
+                let bytes = self.sized(name, *fixed_len)?;
+                let s = decode_string(bytes).ok_or_else(|| PayloadDecodeError::InvalidString {
+                    name: name.to_owned(),
+                })?;
+                dlt::Value::StringVal(s)
+            }
+            DataType::Bytes { fixed_len } => {
+                let bytes = self.sized(name, *fixed_len)?;
+                dlt::Value::StringVal(crate::network::hex(bytes))
+            }
+            DataType::Struct(members) => {
+                for member in members {
+                    self.decode(&format!("{name}.{}", member.name), &member.data_type)?;
+                }
+                return Ok(());
+            }
+            DataType::Array { element, fixed_len } => {
+                match fixed_len {
+                    Some(count) => {
+                        for idx in 0..*count {
+                            self.decode(&format!("{name}.{idx}"), element)?;
+                        }
+                    }
+                    None => {
+                        // The length field gives the size in bytes, so
+                        // decode elements until it's used up
+                        let len = self.uint(name, self.encoding.length_field_size)? as usize;
+                        let end = self.pos.saturating_add(len);
+                        if end > self.bytes.len() {
+                            return Err(PayloadDecodeError::Truncated {
+                                name: name.to_owned(),
+                            });
+                        }
+                        let mut idx = 0;
+                        while self.pos < end {
+                            let start = self.pos;
+                            self.decode(&format!("{name}.{idx}"), element)?;
+                            if self.pos == start || self.pos > end {
+                                // Elements which take no space, or which
+                                // overrun the array, can't be right
+                                return Err(PayloadDecodeError::LengthMismatch {
+                                    name: name.to_owned(),
+                                });
+                            }
+                            idx += 1;
+                        }
+                    }
+                }
+                return Ok(());
+            }
+        };
+        self.values.push((name.to_owned(), value));
+        Ok(())
+    }
+}
+
+/// Strings may start with a byte order mark, which says whether they're
+/// UTF-8 or UTF-16, and end with a null terminator. Without a byte
+/// order mark, they're taken to be UTF-8.
+fn decode_string(bytes: &[u8]) -> Option<String> {
+    let s = match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => std::str::from_utf8(rest).ok()?.to_owned(),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes)?,
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes)?,
+        _ => std::str::from_utf8(bytes).ok()?.to_owned(),
+    };
+    Some(s.trim_end_matches('\0').to_owned())
+}
+
+fn utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> Option<String> {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| from_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16(&units).ok()
+}
@@ -0,0 +1,339 @@
+//! Importing part of a `.dlt` file: finding where the part of interest
+//! starts without parsing everything before it, and deciding which of
+//! the messages read from there are in it.
+//!
+//! Seeking only looks at storage headers and the length field of each
+//! message's standard header. Seeking by time is a binary search, so it
+//! relies on storage header times increasing through the file, as they
+//! do when a single logger writes it; for the same reason, reading stops
+//! at the first message at or after the end time.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use dlt_core::parse::ParsedMessage;
+
+use crate::stream::{DltRecord, STORAGE_HEADER_LEN, STORAGE_HEADER_PATTERN};
+
+/// How much is read at a time while looking for the next storage header
+const SCAN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A storage header and the start of the standard header, up to its
+/// length field
+const RECORD_PREFIX_LEN: usize = STORAGE_HEADER_LEN + 4;
+
+/// A half-open range of values: `start` is included, `end` isn't.
+/// Either may be left open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bounds<T> {
+    pub start: Option<T>,
+    pub end: Option<T>,
+}
+
+impl<T: PartialOrd + Copy> Bounds<T> {
+    pub fn new(start: Option<T>, end: Option<T>) -> Self {
+        Self { start, end }
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.start.is_none() && self.end.is_none()
+    }
+
+    pub fn contains(&self, value: T) -> bool {
+        !self.start.is_some_and(|start| value < start) && !self.is_past(value)
+    }
+
+    /// Is `value` at or after the end?
+    pub fn is_past(&self, value: T) -> bool {
+        self.end.is_some_and(|end| value >= end)
+    }
+}
+
+/// Which messages of a `.dlt` file to import. A message has to be
+/// within all of the bounds which are given.
+#[derive(Clone, Debug, Default)]
+pub struct ImportRange {
+    /// Storage header time, in microseconds since the Unix epoch
+    pub storage_time: Bounds<u64>,
+
+    /// Standard header timestamp (the ECU's uptime), in 0.1ms ticks.
+    /// Messages without one are left out when this is bounded.
+    pub uptime: Bounds<u32>,
+
+    /// The message's position in the file, counting from 0. Messages
+    /// which can't be parsed count too.
+    pub index: Bounds<u64>,
+
+    /// The byte offset of the message's storage header in the file
+    pub offset: Bounds<u64>,
+}
+
+/// Where reading should start
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StartPosition {
+    /// The byte offset of the first record to read
+    pub offset: u64,
+
+    /// The index of that record, if it's needed and known
+    pub index: Option<u64>,
+}
+
+/// What to do with a message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Selection {
+    Keep,
+    Skip,
+    /// This message, and everything after it, is past the end.
+    Done,
+}
+
+/// The start of a record, as found by looking at its headers
+struct RecordInfo {
+    offset: u64,
+    len: u64,
+    /// Storage header time, in microseconds since the Unix epoch
+    time: u64,
+}
+
+impl ImportRange {
+    pub fn is_unbounded(&self) -> bool {
+        self.storage_time.is_unbounded()
+            && self.uptime.is_unbounded()
+            && self.index.is_unbounded()
+            && self.offset.is_unbounded()
+    }
+
+    /// Find where to start reading `file`, a sequence of records with
+    /// storage headers, and leave it positioned there.
+    ///
+    /// A start index is found by stepping over that many records, a
+    /// start offset by looking for the first storage header at or after
+    /// it, and a start time by a binary search over storage headers.
+    /// When more than one is given, reading starts at the latest of
+    /// them. If the headers stop making sense before a start is found,
+    /// reading starts there, so the problem is reported as usual.
+    pub fn seek<R: Read + Seek>(&self, file: &mut R) -> io::Result<StartPosition> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        let track_index = !self.index.is_unbounded();
+        let mut pos = StartPosition {
+            offset: 0,
+            index: track_index.then_some(0),
+        };
+
+        if let Some(start_index) = self.index.start {
+            let (offset, skipped) = step_over_records(file, 0, file_len, |_, n| n < start_index)?;
+            pos = StartPosition {
+                offset,
+                index: Some(skipped),
+            };
+        }
+
+        if let Some(start_offset) = self.offset.start.filter(|o| *o > pos.offset) {
+            let offset = match find_record(file, start_offset, file_len)? {
+                Some(record) => record.offset,
+                None => file_len,
+            };
+            pos = self.advance(file, pos, offset, file_len)?;
+        }
+
+        if let Some(start_time) = self.storage_time.start {
+            let offset = search_time(file, pos.offset, file_len, start_time)?;
+            pos = self.advance(file, pos, offset, file_len)?;
+        }
+
+        file.seek(SeekFrom::Start(pos.offset))?;
+        Ok(pos)
+    }
+
+    /// Move `pos` forward to `offset`, counting the records on the way
+    /// if the index is needed.
+    fn advance<R: Read + Seek>(
+        &self,
+        file: &mut R,
+        pos: StartPosition,
+        offset: u64,
+        file_len: u64,
+    ) -> io::Result<StartPosition> {
+        if offset <= pos.offset {
+            return Ok(pos);
+        }
+        let Some(index) = pos.index else {
+            return Ok(StartPosition {
+                offset,
+                index: None,
+            });
+        };
+
+        let (offset, skipped) = step_over_records(file, pos.offset, file_len, |at, _| at < offset)?;
+        Ok(StartPosition {
+            offset,
+            index: Some(index + skipped),
+        })
+    }
+
+    /// Whether to import `record`, found at byte `offset` of the file
+    /// with the given `index` (which is only needed when the index is
+    /// bounded).
+    pub fn select(&self, index: Option<u64>, offset: u64, record: &DltRecord) -> Selection {
+        let storage_time = record
+            .storage_header
+            .as_ref()
+            .map(|sh| sh.timestamp.seconds as u64 * 1_000_000 + sh.timestamp.microseconds as u64);
+        if self.is_past(index, offset) || storage_time.is_some_and(|t| self.storage_time.is_past(t))
+        {
+            return Selection::Done;
+        }
+
+        let out_of_range = index.is_some_and(|i| !self.index.contains(i))
+            || !self.offset.contains(offset)
+            || storage_time.is_some_and(|t| !self.storage_time.contains(t));
+        if out_of_range {
+            return Selection::Skip;
+        }
+
+        if !self.uptime.is_unbounded() {
+            let uptime = match &record.message {
+                ParsedMessage::Item(msg) => msg.header.timestamp,
+                _ => None,
+            };
+            if !uptime.is_some_and(|t| self.uptime.contains(t)) {
+                return Selection::Skip;
+            }
+        }
+
+        Selection::Keep
+    }
+
+    /// Is a message at `index` and `offset` past the end of the range?
+    /// For messages which couldn't be read, where that's all there is
+    /// to go on.
+    pub fn is_past(&self, index: Option<u64>, offset: u64) -> bool {
+        index.is_some_and(|i| self.index.is_past(i)) || self.offset.is_past(offset)
+    }
+}
+
+/// Read exactly `buf.len()` bytes at `offset`, or return false if the
+/// file ends first.
+fn read_at<R: Read + Seek>(file: &mut R, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The record starting at `offset`, if its headers look right: a
+/// storage header, then a standard header of a supported version whose
+/// message fits in the file.
+fn record_at<R: Read + Seek>(
+    file: &mut R,
+    offset: u64,
+    file_len: u64,
+) -> io::Result<Option<RecordInfo>> {
+    let mut buf = [0u8; RECORD_PREFIX_LEN];
+    if !read_at(file, offset, &mut buf)? || buf[0..4] != STORAGE_HEADER_PATTERN {
+        return Ok(None);
+    }
+
+    let header_type = buf[STORAGE_HEADER_LEN];
+    let version = (header_type >> 5) & 0x07;
+    let message_len = u16::from_be_bytes([buf[18], buf[19]]) as u64;
+    let len = STORAGE_HEADER_LEN as u64 + message_len;
+    if version != 1 || message_len < 4 || offset + len > file_len {
+        return Ok(None);
+    }
+
+    let seconds = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as u64;
+    let microseconds = u32::from_le_bytes(buf[8..12].try_into().unwrap()) as u64;
+    Ok(Some(RecordInfo {
+        offset,
+        len,
+        time: seconds * 1_000_000 + microseconds,
+    }))
+}
+
+/// The first record at or after `from`. Since the storage header
+/// pattern could turn up inside a message, a candidate only counts if
+/// it's followed by another storage header, or the end of the file.
+fn find_record<R: Read + Seek>(
+    file: &mut R,
+    from: u64,
+    file_len: u64,
+) -> io::Result<Option<RecordInfo>> {
+    let pattern_len = STORAGE_HEADER_PATTERN.len();
+    let mut chunk = vec![0u8; SCAN_CHUNK_SIZE];
+    let mut chunk_start = from;
+    while chunk_start < file_len {
+        let len = (file_len - chunk_start).min(SCAN_CHUNK_SIZE as u64) as usize;
+        if !read_at(file, chunk_start, &mut chunk[..len])? {
+            break;
+        }
+
+        for i in 0..len.saturating_sub(pattern_len - 1) {
+            if chunk[i..i + pattern_len] != STORAGE_HEADER_PATTERN {
+                continue;
+            }
+            let Some(record) = record_at(file, chunk_start + i as u64, file_len)? else {
+                continue;
+            };
+            let next = record.offset + record.len;
+            if next == file_len || record_at(file, next, file_len)?.is_some() {
+                return Ok(Some(record));
+            }
+        }
+
+        // Overlap chunks, in case the pattern straddles two
+        if len < SCAN_CHUNK_SIZE {
+            break;
+        }
+        chunk_start += (len - (pattern_len - 1)) as u64;
+    }
+    Ok(None)
+}
+
+/// Step over records from `from` (a record boundary) while
+/// `keep_going(offset, records_stepped_over)` holds. Returns where that
+/// stopped and how many records were stepped over. Stops early at the
+/// end of the file, or where the headers stop making sense.
+fn step_over_records<R: Read + Seek>(
+    file: &mut R,
+    from: u64,
+    file_len: u64,
+    mut keep_going: impl FnMut(u64, u64) -> bool,
+) -> io::Result<(u64, u64)> {
+    let mut offset = from;
+    let mut n = 0;
+    while keep_going(offset, n) {
+        let Some(record) = record_at(file, offset, file_len)? else {
+            break;
+        };
+        offset += record.len;
+        n += 1;
+    }
+    Ok((offset, n))
+}
+
+/// The offset of the first record at or after `from` whose storage
+/// header time is at least `start`, or the end of the file if there
+/// isn't one.
+fn search_time<R: Read + Seek>(
+    file: &mut R,
+    from: u64,
+    file_len: u64,
+    start: u64,
+) -> io::Result<u64> {
+    // Every record before lo is earlier than start, and the first
+    // record at or after hi isn't. lo is always a record boundary.
+    let mut lo = from;
+    let mut hi = file_len;
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match find_record(file, mid, file_len)? {
+            Some(record) if record.offset < hi && record.time < start => {
+                lo = record.offset + record.len;
+            }
+            _ => hi = mid,
+        }
+    }
+    Ok(lo)
+}
@@ -0,0 +1,334 @@
+//! Sampling and rate limiting of chatty applications and contexts, and
+//! summaries of the messages which were left out, so the suppression
+//! itself shows up in Modality.
+
+use std::{collections::BTreeMap, time::Instant};
+
+use auxon_sdk::api::{AttrKey, AttrVal, TimelineId};
+use dlt_core::dlt;
+use serde::{Deserialize, Serialize};
+
+use crate::correlate::id_matches;
+
+/// The name of the events which summarize suppressed messages
+pub const SUPPRESSED_MESSAGES_EVENT: &str = "suppressed_messages";
+
+/// How often suppressed messages are summarized, in seconds, if not configured.
+pub const DEFAULT_SUPPRESSED_MESSAGES_INTERVAL: f64 = 10.0;
+
+/// Limits how many of the messages with the given ids are kept. Unset
+/// ids match anything.
+///
+/// Matching messages are limited separately for each combination of
+/// ECU, application, context and message id, so a rule without any ids
+/// limits every context on its own.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RateLimitRule {
+    /// Given as `event.rule` on summary events
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub ecu_id: Option<String>,
+
+    #[serde(default)]
+    pub application_id: Option<String>,
+
+    #[serde(default)]
+    pub context_id: Option<String>,
+
+    /// Only applies to non-verbose messages
+    #[serde(default)]
+    pub message_id: Option<u32>,
+
+    /// Keep the first of every `keep_one_in` messages.
+    #[serde(default)]
+    pub keep_one_in: Option<u64>,
+
+    /// Keep at most this many messages per second, on average.
+    #[serde(default)]
+    pub max_per_second: Option<f64>,
+
+    /// How many messages may be kept in a burst before
+    /// `max_per_second` applies. Defaults to `max_per_second`, and is
+    /// at least 1.
+    #[serde(default)]
+    pub burst: Option<f64>,
+}
+
+impl RateLimitRule {
+    fn matches(&self, key: &LimitKey) -> bool {
+        id_matches(&self.ecu_id, key.ecu_id.as_deref())
+            && id_matches(&self.application_id, key.application_id.as_deref())
+            && id_matches(&self.context_id, key.context_id.as_deref())
+            && (self.message_id.is_none() || self.message_id == key.message_id)
+    }
+
+    fn burst(&self, max_per_second: f64) -> f64 {
+        self.burst.unwrap_or(max_per_second).max(1.0)
+    }
+}
+
+/// What messages are limited by, within a rule
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct LimitKey {
+    ecu_id: Option<String>,
+    application_id: Option<String>,
+    context_id: Option<String>,
+    message_id: Option<u32>,
+}
+
+impl LimitKey {
+    fn for_message(msg: &dlt::Message) -> Self {
+        let extended_header = msg.extended_header.as_ref();
+        LimitKey {
+            ecu_id: msg.header.ecu_id.clone(),
+            application_id: extended_header.map(|eh| eh.application_id.clone()),
+            context_id: extended_header.map(|eh| eh.context_id.clone()),
+            message_id: match &msg.payload {
+                dlt::PayloadContent::NonVerbose(id, _) => Some(*id),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The limiting state of one key of one rule. Times are in seconds,
+/// on the key's clock (see [RateLimiter::check]).
+struct LimitState {
+    seen: u64,
+    tokens: f64,
+    last_refill: f64,
+
+    /// Since the last summary
+    kept: u64,
+    suppressed: u64,
+    last_report: f64,
+
+    /// Where the key's most recent kept message went, which is where
+    /// its summaries go; see [RateLimiter::kept_on]
+    timeline_id: Option<TimelineId>,
+}
+
+/// A summary of the messages a rule left out for one ECU, application,
+/// context and message id, since the previous summary.
+#[derive(Clone, Debug)]
+pub struct SuppressionReport {
+    pub rule: Option<String>,
+    pub timeline_id: TimelineId,
+    pub ecu_id: Option<String>,
+    pub application_id: Option<String>,
+    pub context_id: Option<String>,
+    pub message_id: Option<u32>,
+    pub suppressed: u64,
+    pub kept: u64,
+}
+
+impl SuppressionReport {
+    /// The attrs of a [SUPPRESSED_MESSAGES_EVENT] event
+    pub fn event_attrs(&self) -> Vec<(AttrKey, AttrVal)> {
+        let mut attrs: Vec<(AttrKey, AttrVal)> = vec![
+            (
+                "event.suppressed_count".into(),
+                (self.suppressed as i64).into(),
+            ),
+            ("event.kept_count".into(), (self.kept as i64).into()),
+        ];
+        let ids = [
+            ("event.rule", &self.rule),
+            ("event.ecu_id", &self.ecu_id),
+            ("event.application_id", &self.application_id),
+            ("event.context_id", &self.context_id),
+        ];
+        for (key, id) in ids {
+            if let Some(id) = id {
+                attrs.push((key.into(), id.clone().into()));
+            }
+        }
+        if let Some(message_id) = self.message_id {
+            attrs.push(("event.message_id".into(), message_id.into()));
+        }
+        attrs
+    }
+}
+
+/// What to do with a message, and a summary which is due, if any
+pub struct Decision {
+    pub keep: bool,
+    pub report: Option<SuppressionReport>,
+
+    /// The rule and key which limited the message, if any
+    limited_by: Option<(usize, LimitKey)>,
+}
+
+/// Applies [RateLimitRule]s to messages. A message is limited by the
+/// first rule it matches; one matching none is always kept.
+pub struct RateLimiter {
+    rules: Vec<RateLimitRule>,
+
+    /// Per rule
+    states: Vec<BTreeMap<LimitKey, LimitState>>,
+
+    /// Seconds between summaries
+    report_interval: f64,
+
+    /// The clock for messages without a timestamp
+    started: Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(vec![], DEFAULT_SUPPRESSED_MESSAGES_INTERVAL)
+    }
+}
+
+impl RateLimiter {
+    /// Summarize suppressed messages every `report_interval` seconds.
+    pub fn new(rules: Vec<RateLimitRule>, report_interval: f64) -> Self {
+        let states = rules.iter().map(|_| Default::default()).collect();
+        RateLimiter {
+            rules,
+            states,
+            report_interval,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Decide whether to keep `msg`. This comes before working out the
+    /// message's timeline, so if it's kept, say where it went with
+    /// [RateLimiter::kept_on].
+    ///
+    /// Time is measured by the message's header timestamp (the ECU's
+    /// uptime), so files are limited the same way however fast they're
+    /// imported; messages without one go by the time they're checked.
+    /// A summary is due once a key has suppressed something and
+    /// `report_interval` has passed since its previous summary.
+    pub fn check(&mut self, msg: &dlt::Message) -> Decision {
+        let key = LimitKey::for_message(msg);
+        let Some(rule_idx) = self.rules.iter().position(|rule| rule.matches(&key)) else {
+            return Decision {
+                keep: true,
+                report: None,
+                limited_by: None,
+            };
+        };
+        let rule = &self.rules[rule_idx];
+        let now = match msg.header.timestamp {
+            Some(ticks) => ticks as f64 / 1e4,
+            None => self.started.elapsed().as_secs_f64(),
+        };
+
+        let states = &mut self.states[rule_idx];
+        if !states.contains_key(&key) {
+            let state = LimitState {
+                seen: 0,
+                tokens: rule.max_per_second.map_or(0.0, |rate| rule.burst(rate)),
+                last_refill: now,
+                kept: 0,
+                suppressed: 0,
+                last_report: now,
+                timeline_id: None,
+            };
+            states.insert(key.clone(), state);
+        }
+        let state = states.get_mut(&key).unwrap();
+
+        // The clock went backwards, most likely because the ECU restarted
+        if now < state.last_refill {
+            state.last_refill = now;
+        }
+        if now < state.last_report {
+            state.last_report = now;
+        }
+
+        let sampled = state.seen % rule.keep_one_in.unwrap_or(1).max(1) == 0;
+        state.seen += 1;
+        let keep = sampled
+            && match rule.max_per_second {
+                Some(rate) => {
+                    state.tokens =
+                        (state.tokens + (now - state.last_refill) * rate).min(rule.burst(rate));
+                    state.last_refill = now;
+                    if state.tokens >= 1.0 {
+                        state.tokens -= 1.0;
+                        true
+                    } else {
+                        false
+                    }
+                }
+                None => true,
+            };
+
+        let report = if now - state.last_report >= self.report_interval {
+            state.last_report = now;
+            take_report(rule, &key, state)
+        } else {
+            None
+        };
+        if keep {
+            state.kept += 1;
+        } else {
+            state.suppressed += 1;
+        }
+
+        Decision {
+            keep,
+            report,
+            limited_by: Some((rule_idx, key)),
+        }
+    }
+
+    /// Note that the message `decision` kept went to `timeline_id`, so
+    /// that's where the summaries of its key go from now on.
+    pub fn kept_on(&mut self, decision: &Decision, timeline_id: TimelineId) {
+        let Some((rule_idx, key)) = &decision.limited_by else {
+            return;
+        };
+        if let Some(state) = self.states[*rule_idx].get_mut(key) {
+            state.timeline_id = Some(timeline_id);
+        }
+    }
+
+    /// Summaries of everything suppressed since the previous ones,
+    /// whether or not they're due yet; e.g. before stopping.
+    pub fn take_reports(&mut self) -> Vec<SuppressionReport> {
+        let mut reports = vec![];
+        for (rule, states) in self.rules.iter().zip(self.states.iter_mut()) {
+            for (key, state) in states.iter_mut() {
+                reports.extend(take_report(rule, key, state));
+            }
+        }
+        reports
+    }
+}
+
+/// A summary of `state`'s counts, if anything was suppressed, starting
+/// the counts over. Counts are held on to until a message of the key
+/// has been kept somewhere, so the summary has a timeline to go on.
+fn take_report(
+    rule: &RateLimitRule,
+    key: &LimitKey,
+    state: &mut LimitState,
+) -> Option<SuppressionReport> {
+    let timeline_id = state.timeline_id?;
+    let kept = std::mem::take(&mut state.kept);
+    let suppressed = std::mem::take(&mut state.suppressed);
+    if suppressed == 0 {
+        return None;
+    }
+
+    Some(SuppressionReport {
+        rule: rule.name.clone(),
+        timeline_id,
+        ecu_id: key.ecu_id.clone(),
+        application_id: key.application_id.clone(),
+        context_id: key.context_id.clone(),
+        message_id: key.message_id,
+        suppressed,
+        kept,
+    })
+}
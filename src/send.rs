@@ -1,87 +1,733 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
 
-use auxon_sdk::{api::TimelineId, plugin_utils::ingest::Config};
-use dlt_core::parse::ParsedMessage;
+use auxon_sdk::{
+    api::{AttrKey, AttrVal, TimelineId},
+    plugin_utils::ingest::Config,
+};
+use dlt_core::{dlt, parse::ParsedMessage};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::mpsc;
 use tracing::warn;
 
 use crate::{
-    convert::{dlt_message_to_event_attrs, dlt_message_to_event_name, TimelineKey},
-    CommonConfig,
+    control::{self, ControlResponse},
+    convert::{
+        dlt_message_to_event_attrs_interned, dlt_message_to_event_name, AttrKeyInterner,
+        TimelineKey,
+    },
+    correlate::{CorrelationRule, Correlator},
+    metrics::Metrics,
+    ratelimit::{
+        RateLimiter, SuppressionReport, DEFAULT_SUPPRESSED_MESSAGES_INTERVAL,
+        SUPPRESSED_MESSAGES_EVENT,
+    },
+    sink::IngestSink,
+    CommonConfig, DltPluginError,
 };
 
-pub struct Sender<C: HasCommonConfig> {
-    client: auxon_sdk::plugin_utils::ingest::Client,
+/// How many events are buffered before they're sent to the backend, if not configured.
+pub const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// How many control requests to remember while waiting for their
+/// responses. Beyond this, the oldest are forgotten.
+const MAX_PENDING_CONTROL_REQUESTS: usize = 256;
+
+/// How many parsed messages can be queued between the reader and the sender, if not configured.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 8192;
+
+/// Converts messages to events and sends them to an [IngestSink]; the
+/// Modality ingest client, unless otherwise specified.
+pub struct Sender<C: HasCommonConfig, S: IngestSink = auxon_sdk::plugin_utils::ingest::Client> {
+    sink: S,
     config: Config<C>,
-    known_timelines: HashMap<TimelineKey, TimelineId>,
+    batcher: EventBatcher,
+    batch_size: usize,
     current_timeline: Option<TimelineId>,
-    event_ordering: u128,
+    metrics: Arc<Metrics>,
 }
 
 pub trait HasCommonConfig {
     fn common_config(&self) -> &CommonConfig;
 }
 
-impl<C: HasCommonConfig> Sender<C> {
-    pub fn new(client: auxon_sdk::plugin_utils::ingest::Client, config: Config<C>) -> Self {
+impl<C: HasCommonConfig, S: IngestSink> Sender<C, S> {
+    pub fn new(sink: S, config: Config<C>) -> Self {
+        let batch_size = config
+            .plugin
+            .common_config()
+            .batch_size
+            .unwrap_or(DEFAULT_BATCH_SIZE)
+            .max(1);
+        let common = config.plugin.common_config();
+        let batcher = EventBatcher::new()
+            .with_correlation_rules(common.correlation.clone())
+            .with_rate_limiter(RateLimiter::new(
+                common.rate_limits.clone(),
+                common
+                    .suppressed_messages_interval
+                    .unwrap_or(DEFAULT_SUPPRESSED_MESSAGES_INTERVAL),
+            ));
+
         Self {
-            client,
+            sink,
             config,
-            known_timelines: Default::default(),
+            batcher,
+            batch_size,
             current_timeline: None,
-            event_ordering: 0,
+            metrics: Metrics::new(""),
         }
     }
 
+    /// Record sent events and created timelines in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Annotate events with `annotator` as they're converted.
+    pub fn with_annotator(mut self, annotator: Arc<dyn EventAnnotator>) -> Self {
+        self.batcher.add_annotator(annotator);
+        self
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    /// Convert `parsed_msg` and queue it for sending. The pending batch
+    /// is sent once it reaches the configured batch size; call [Sender::flush]
+    /// to send it before that.
     pub async fn handle_message(
         &mut self,
         parsed_msg: ParsedMessage,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), DltPluginError> {
+        let pushed = self
+            .batcher
+            .push(parsed_msg, self.config.plugin.common_config());
+        if let Pushed::Suppressed { ecu_id } = pushed {
+            self.metrics.record_message_suppressed(ecu_id.as_deref());
+        }
+
+        if self.batcher.len() >= self.batch_size {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send all pending events to the backend, one timeline at a time.
+    ///
+    /// If sending fails, the events that didn't make it are kept, and
+    /// will be sent by the next flush.
+    pub async fn flush(&mut self) -> Result<(), DltPluginError> {
+        let mut batches = self.batcher.take_batches().into_iter();
+        while let Some(mut batch) = batches.next() {
+            if let Err(e) = self.send_batch(&mut batch).await {
+                self.current_timeline = None;
+                self.batcher.restore(std::iter::once(batch).chain(batches));
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send everything that's pending, including summaries of any
+    /// suppressed messages which haven't been reported yet, and make
+    /// sure the sink has passed it all on to the backend.
+    pub async fn finish(&mut self) -> Result<(), DltPluginError> {
+        self.batcher.push_suppression_reports();
+        self.flush().await?;
+        self.sink.flush().await
+    }
+
+    /// Send a final `event_name` event to every timeline seen so far,
+    /// with `reason` as its `event.reason` attribute, then
+    /// [finish](Sender::finish).
+    pub async fn stop(
+        &mut self,
+        event_name: &'static str,
+        reason: &str,
+    ) -> Result<(), DltPluginError> {
+        self.batcher.push_suppression_reports();
+        self.batcher.push_to_all_timelines(
+            event_name,
+            vec![("event.reason".into(), reason.to_string().into())],
+        );
+        self.finish().await
+    }
+
+    /// Queue an event on the timeline of the most recently handled
+    /// message; see [EventBatcher::push_to_last_timeline].
+    pub fn push_to_last_timeline(
+        &mut self,
+        event_name: &'static str,
+        attrs: Vec<(AttrKey, AttrVal)>,
+    ) -> bool {
+        self.batcher.push_to_last_timeline(event_name, attrs)
+    }
+
+    /// Are there any events which haven't been sent yet?
+    pub fn has_pending_events(&self) -> bool {
+        !self.batcher.is_empty()
+    }
+
+    /// Replace the backend connection, e.g. after the previous one was lost.
+    pub fn set_client(&mut self, sink: S) {
+        self.sink = sink;
+        self.current_timeline = None;
+    }
+
+    async fn send_batch(&mut self, batch: &mut TimelineBatch) -> Result<(), DltPluginError> {
+        if self.current_timeline != Some(batch.timeline_id) {
+            self.sink.switch_timeline(batch.timeline_id).await?;
+            self.current_timeline = Some(batch.timeline_id);
+        }
+
+        if let Some(new_timeline) = &batch.new_timeline {
+            self.sink
+                .send_timeline_attrs(new_timeline.name.as_str(), &new_timeline.attrs)
+                .await?;
+            self.metrics
+                .record_timeline_created(new_timeline.ecu_id.as_deref());
+            batch.new_timeline = None;
+        }
+
+        let mut sent = 0;
+        while let Some(ev) = batch.events.get(sent) {
+            let res = self
+                .sink
+                .send_event(ev.name.as_ref(), ev.ordering, &ev.attrs)
+                .await;
+
+            if let Err(e) = res {
+                batch.events.drain(..sent);
+                return Err(e);
+            }
+            if ev.from_message {
+                self.metrics.record_events_sent(ev.ecu_id.as_deref(), 1);
+            }
+            sent += 1;
+        }
+        batch.events.clear();
+
+        Ok(())
+    }
+
+    /// Receive messages from `rx` until it is closed, sending them in
+    /// batches. Whenever the channel runs dry, whatever has been
+    /// gathered so far is flushed, so a slow trickle of messages still
+    /// gets through promptly.
+    ///
+    /// With rate limits, suppressed messages are also summarized every
+    /// `suppressed_messages_interval`, so a burst which stops is still
+    /// reported without waiting for more messages.
+    pub async fn run(
+        &mut self,
+        rx: &mut mpsc::Receiver<ParsedMessage>,
+    ) -> Result<(), DltPluginError> {
+        let mut report_timer = self
+            .report_period()
+            .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+        let mut buf = Vec::with_capacity(self.batch_size);
+        loop {
+            let received = tokio::select! {
+                received = rx.recv_many(&mut buf, self.batch_size) => received,
+                _ = tick(&mut report_timer) => {
+                    self.batcher.push_suppression_reports();
+                    self.flush().await?;
+                    continue;
+                }
+            };
+            if received == 0 {
+                break;
+            }
+
+            for parsed_msg in buf.drain(..) {
+                self.handle_message(parsed_msg).await?;
+            }
+
+            if rx.is_empty() {
+                self.flush().await?;
+            }
+        }
+
+        self.flush().await
+    }
+
+    /// How often [Sender::run] summarizes suppressed messages, if
+    /// there are rate limits
+    fn report_period(&self) -> Option<Duration> {
+        let common = self.config.plugin.common_config();
+        if common.rate_limits.is_empty() {
+            return None;
+        }
+        let interval = common
+            .suppressed_messages_interval
+            .unwrap_or(DEFAULT_SUPPRESSED_MESSAGES_INTERVAL);
+        Duration::try_from_secs_f64(interval)
+            .ok()
+            .filter(|period| !period.is_zero())
+    }
+}
+
+/// Wait for the next tick of `timer`, or forever if there isn't one
+async fn tick(timer: &mut Option<tokio::time::Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+impl<C, S> Sender<C, S>
+where
+    C: HasCommonConfig + Serialize + DeserializeOwned,
+    S: IngestSink,
+{
+    /// Open a new connection to the backend, using the same
+    /// configuration as the original one.
+    pub async fn connect(&self) -> Result<auxon_sdk::plugin_utils::ingest::Client, DltPluginError> {
+        self.config
+            .connect_and_authenticate()
+            .await
+            .map_err(DltPluginError::backend)
+    }
+}
+
+/// Create the bounded channel used to pass messages from a reader task
+/// to [Sender::run].
+pub fn message_channel(
+    config: &CommonConfig,
+) -> (mpsc::Sender<ParsedMessage>, mpsc::Receiver<ParsedMessage>) {
+    mpsc::channel(
+        config
+            .channel_capacity
+            .unwrap_or(DEFAULT_CHANNEL_CAPACITY)
+            .max(1),
+    )
+}
+
+/// A timeline seen for the first time in the current batch; its attrs
+/// have to be sent before any of its events.
+pub struct NewTimeline {
+    pub name: String,
+    pub ecu_id: Option<String>,
+    pub attrs: Vec<(&'static str, AttrVal)>,
+}
+
+/// A converted event, waiting to be sent.
+pub struct PendingEvent {
+    pub name: Cow<'static, str>,
+
+    /// For metrics; shared between all the events from the same ECU
+    pub ecu_id: Option<Arc<str>>,
+
+    /// Made from a DLT message, rather than added by the plugin (like
+    /// `suppressed_messages`); only these count as sent messages
+    pub from_message: bool,
+    pub ordering: u128,
+    pub attrs: Vec<(AttrKey, AttrVal)>,
+}
+
+/// The pending events for a single timeline.
+pub struct TimelineBatch {
+    pub timeline_id: TimelineId,
+    pub new_timeline: Option<NewTimeline>,
+    pub events: Vec<PendingEvent>,
+}
+
+/// Adds attributes to events as messages are converted, based on
+/// state outside the message itself.
+pub trait EventAnnotator: Send + Sync {
+    fn annotate(&self, msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>);
+
+    /// A more specific name for the event than its message type, if
+    /// there is one. The first annotator to give a name wins.
+    fn event_name(&self, _msg: &dlt::Message) -> Option<String> {
+        None
+    }
+}
+
+/// What [EventBatcher::push] did with a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+
+    /// Left out by a rate limit or sampling
+    Suppressed {
+        ecu_id: Option<String>,
+    },
+
+    /// Invalid, or filtered out by the parser
+    Dropped,
+}
+
+/// The backend-independent part of the send path: assigns messages to
+/// timelines, converts them to events, and groups the results by
+/// timeline so each timeline only has to be switched to once per batch.
+#[derive(Default)]
+pub struct EventBatcher {
+    known_timelines: HashMap<TimelineKey, TimelineId>,
+    interner: AttrKeyInterner,
+    event_ordering: u128,
+
+    /// Batches in the order their timelines first appeared
+    batches: Vec<TimelineBatch>,
+    batch_index: HashMap<TimelineId, usize>,
+    pending_events: usize,
+
+    /// The timeline of the most recently pushed message, and its ECU id
+    last_timeline: Option<(TimelineId, Option<Arc<str>>)>,
+
+    /// Every ECU id seen so far, so events can share them rather than
+    /// each having a copy
+    ecu_ids: HashSet<Arc<str>>,
+
+    annotators: Vec<Arc<dyn EventAnnotator>>,
+
+    /// Control requests waiting to be paired with their responses,
+    /// oldest first
+    pending_control_requests: VecDeque<PendingControlRequest>,
+    next_nonce: i64,
+
+    correlator: Correlator,
+    rate_limiter: RateLimiter,
+}
+
+/// A control request event which a later response should link back to
+struct PendingControlRequest {
+    ecu_id: Option<String>,
+    service_id: u32,
+    application_id: Option<String>,
+    context_id: Option<String>,
+    timeline_id: TimelineId,
+    nonce: i64,
+}
+
+impl EventBatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Link events on different timelines according to `rules`; see
+    /// [Correlator].
+    pub fn with_correlation_rules(mut self, rules: Vec<CorrelationRule>) -> Self {
+        self.correlator = Correlator::new(rules);
+        self
+    }
+
+    /// Leave out messages according to `rate_limiter`, summarizing
+    /// them in `suppressed_messages` events.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    pub fn add_annotator(&mut self, annotator: Arc<dyn EventAnnotator>) {
+        self.annotators.push(annotator);
+    }
+
+    /// The number of events waiting to be sent
+    pub fn len(&self) -> usize {
+        self.pending_events
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending_events == 0
+    }
+
+    pub fn push(&mut self, parsed_msg: ParsedMessage, config: &CommonConfig) -> Pushed {
         let msg = match parsed_msg {
             ParsedMessage::Item(msg) => msg,
             ParsedMessage::Invalid => {
                 warn!("Dropping invalid message");
-                return Ok(());
+                return Pushed::Dropped;
             }
             ParsedMessage::FilteredOut(_) => {
-                return Ok(());
+                return Pushed::Dropped;
             }
         };
 
-        let tl_key = TimelineKey::for_message(&msg, self.config.plugin.common_config());
-        match self.known_timelines.get(&tl_key) {
-            Some(tl_id) => {
-                // It's a known timeline; switch to it if necessary
-                if self.current_timeline != Some(*tl_id) {
-                    self.client.switch_timeline(*tl_id).await?;
-                    self.current_timeline = Some(*tl_id);
-                }
+        // Before anything else, so suppressed messages cost as little as
+        // possible, and don't leave timelines behind
+        let decision = if self.rate_limiter.is_empty() {
+            None
+        } else {
+            let mut decision = self.rate_limiter.check(&msg);
+            if let Some(report) = decision.report.take() {
+                self.push_suppression_report(report);
+            }
+            if !decision.keep {
+                return Pushed::Suppressed {
+                    ecu_id: msg.header.ecu_id,
+                };
             }
+            Some(decision)
+        };
+
+        let tl_key = TimelineKey::for_message(&msg, config);
+        let (tl_id, batch) = match self.known_timelines.get(&tl_key) {
+            Some(tl_id) => (*tl_id, self.batch_for(*tl_id, None)),
             None => {
                 // We've never seen this timeline before; allocate an
-                // id, and send its attrs.
+                // id, and queue up its attrs.
                 let tl_id = TimelineId::allocate();
-
-                self.client.switch_timeline(tl_id).await?;
-                self.current_timeline = Some(tl_id);
-
-                self.client
-                    .send_timeline_attrs(tl_key.timeline_name().as_str(), tl_key.timeline_attrs())
-                    .await?;
+                let new_timeline = NewTimeline {
+                    name: tl_key.timeline_name(),
+                    ecu_id: tl_key.ecu_id().map(ToOwned::to_owned),
+                    attrs: tl_key.timeline_attrs(),
+                };
                 self.known_timelines.insert(tl_key, tl_id);
+                (tl_id, self.batch_for(tl_id, Some(new_timeline)))
             }
         };
+        let ecu_id = self.shared_ecu_id(msg.header.ecu_id.as_deref());
+        self.last_timeline = Some((tl_id, ecu_id.clone()));
+        if let Some(decision) = &decision {
+            self.rate_limiter.kept_on(decision, tl_id);
+        }
 
-        let ev_name = dlt_message_to_event_name(&msg);
-        let ev_attrs = dlt_message_to_event_attrs(&msg);
-        self.client
-            .send_event(
-                &ev_name,
-                self.event_ordering,
-                ev_attrs.iter().map(|(k, v)| (k.as_ref(), v.clone())),
-            )
-            .await?;
+        let mut attrs = dlt_message_to_event_attrs_interned(&msg, config, &mut self.interner);
+        self.link_control_interaction(&msg, tl_id, &mut attrs);
+        if !self.correlator.is_empty() {
+            let next_nonce = &mut self.next_nonce;
+            self.correlator.correlate(&msg, tl_id, &mut attrs, || {
+                let nonce = *next_nonce;
+                *next_nonce += 1;
+                nonce
+            });
+        }
+        for annotator in self.annotators.iter() {
+            annotator.annotate(&msg, &mut attrs);
+        }
+
+        let name = self
+            .annotators
+            .iter()
+            .find_map(|annotator| annotator.event_name(&msg))
+            .map(Cow::Owned)
+            .unwrap_or(Cow::Borrowed(dlt_message_to_event_name(&msg)));
 
+        let ev = PendingEvent {
+            name,
+            ecu_id,
+            from_message: true,
+            ordering: self.event_ordering,
+            attrs,
+        };
+        self.batches[batch].events.push(ev);
+        self.pending_events += 1;
         self.event_ordering += 1;
-        Ok(())
+        Pushed::Queued
+    }
+
+    /// Queue `suppressed_messages` events for everything the rate
+    /// limiter has left out since its last summaries, whether or not
+    /// they're due yet.
+    pub fn push_suppression_reports(&mut self) {
+        for report in self.rate_limiter.take_reports() {
+            self.push_suppression_report(report);
+        }
+    }
+
+    fn push_suppression_report(&mut self, report: SuppressionReport) {
+        let batch = self.batch_for(report.timeline_id, None);
+        let ecu_id = self.shared_ecu_id(report.ecu_id.as_deref());
+        self.batches[batch].events.push(PendingEvent {
+            name: Cow::Borrowed(SUPPRESSED_MESSAGES_EVENT),
+            ecu_id,
+            from_message: false,
+            ordering: self.event_ordering,
+            attrs: report.event_attrs(),
+        });
+        self.pending_events += 1;
+        self.event_ordering += 1;
+    }
+
+    /// Queue an event with the given name and attrs on every timeline
+    /// seen so far.
+    pub fn push_to_all_timelines(
+        &mut self,
+        event_name: &'static str,
+        attrs: Vec<(AttrKey, AttrVal)>,
+    ) {
+        let timelines: Vec<(TimelineId, Option<Arc<str>>)> = self
+            .known_timelines
+            .iter()
+            .map(|(tl_key, tl_id)| (*tl_id, tl_key.ecu_id().map(Arc::from)))
+            .collect();
+
+        for (tl_id, ecu_id) in timelines {
+            let batch = self.batch_for(tl_id, None);
+            self.batches[batch].events.push(PendingEvent {
+                name: Cow::Borrowed(event_name),
+                ecu_id,
+                from_message: false,
+                ordering: self.event_ordering,
+                attrs: attrs.clone(),
+            });
+            self.pending_events += 1;
+            self.event_ordering += 1;
+        }
+    }
+
+    /// Queue an event with the given name and attrs on the timeline of
+    /// the most recently pushed message. Returns false (and queues
+    /// nothing) if no message has been pushed yet.
+    pub fn push_to_last_timeline(
+        &mut self,
+        event_name: &'static str,
+        attrs: Vec<(AttrKey, AttrVal)>,
+    ) -> bool {
+        let Some((tl_id, ecu_id)) = self.last_timeline.clone() else {
+            return false;
+        };
+
+        let batch = self.batch_for(tl_id, None);
+        self.batches[batch].events.push(PendingEvent {
+            name: Cow::Borrowed(event_name),
+            ecu_id,
+            from_message: false,
+            ordering: self.event_ordering,
+            attrs,
+        });
+        self.pending_events += 1;
+        self.event_ordering += 1;
+        true
+    }
+
+    /// Take all pending batches, leaving the batcher empty.
+    pub fn take_batches(&mut self) -> Vec<TimelineBatch> {
+        self.batch_index.clear();
+        self.pending_events = 0;
+        std::mem::take(&mut self.batches)
+    }
+
+    /// Put back batches which were taken, but couldn't be sent. They go
+    /// ahead of anything that was pushed in the meantime.
+    pub fn restore(&mut self, unsent: impl IntoIterator<Item = TimelineBatch>) {
+        let newer = self.take_batches();
+        for batch in unsent.into_iter().chain(newer) {
+            if batch.events.is_empty() && batch.new_timeline.is_none() {
+                continue;
+            }
+
+            let idx = self.batch_for(batch.timeline_id, None);
+            let existing = &mut self.batches[idx];
+            if existing.new_timeline.is_none() {
+                existing.new_timeline = batch.new_timeline;
+            }
+            self.pending_events += batch.events.len();
+            existing.events.extend(batch.events);
+        }
+    }
+
+    /// Give control requests a nonce, and point their responses back
+    /// at them, so Modality sees the request as causing the response.
+    ///
+    /// A response is paired with the oldest unanswered request for the
+    /// same service on the same ECU, preferring one with the same
+    /// application and context ids. (dlt-daemon answers with its own
+    /// ids, so these usually differ.)
+    fn link_control_interaction(
+        &mut self,
+        msg: &dlt::Message,
+        timeline_id: TimelineId,
+        attrs: &mut Vec<(AttrKey, AttrVal)>,
+    ) {
+        let application_id = msg.extended_header.as_ref().map(|eh| &eh.application_id);
+        let context_id = msg.extended_header.as_ref().map(|eh| &eh.context_id);
+
+        if let Some(service_id) = control::request_service_id(msg) {
+            let nonce = self.next_nonce;
+            self.next_nonce += 1;
+            attrs.push(("event.nonce".into(), nonce.into()));
+
+            if self.pending_control_requests.len() >= MAX_PENDING_CONTROL_REQUESTS {
+                self.pending_control_requests.pop_front();
+            }
+            self.pending_control_requests
+                .push_back(PendingControlRequest {
+                    ecu_id: msg.header.ecu_id.clone(),
+                    service_id,
+                    application_id: application_id.cloned(),
+                    context_id: context_id.cloned(),
+                    timeline_id,
+                    nonce,
+                });
+            return;
+        }
+
+        let Some(response) = ControlResponse::from_message(msg) else {
+            return;
+        };
+        let candidates = || {
+            self.pending_control_requests
+                .iter()
+                .enumerate()
+                .filter(|(_, req)| {
+                    req.service_id == response.service_id && req.ecu_id == response.ecu_id
+                })
+        };
+        let same_ids = candidates()
+            .find(|(_, req)| {
+                req.application_id.as_ref() == application_id
+                    && req.context_id.as_ref() == context_id
+            })
+            .map(|(idx, _)| idx);
+        let Some(idx) = same_ids.or_else(|| candidates().next().map(|(idx, _)| idx)) else {
+            return;
+        };
+
+        let request = self.pending_control_requests.remove(idx).unwrap();
+        attrs.push((
+            "event.interaction.remote_timeline_id".into(),
+            request.timeline_id.into(),
+        ));
+        attrs.push((
+            "event.interaction.remote_nonce".into(),
+            request.nonce.into(),
+        ));
+    }
+
+    /// `ecu_id`, shared with every other event from the same ECU
+    fn shared_ecu_id(&mut self, ecu_id: Option<&str>) -> Option<Arc<str>> {
+        let ecu_id = ecu_id?;
+        if let Some(shared) = self.ecu_ids.get(ecu_id) {
+            return Some(shared.clone());
+        }
+        let shared: Arc<str> = Arc::from(ecu_id);
+        self.ecu_ids.insert(shared.clone());
+        Some(shared)
+    }
+
+    fn batch_for(&mut self, timeline_id: TimelineId, new_timeline: Option<NewTimeline>) -> usize {
+        if let Some(idx) = self.batch_index.get(&timeline_id) {
+            return *idx;
+        }
+
+        let idx = self.batches.len();
+        self.batches.push(TimelineBatch {
+            timeline_id,
+            new_timeline,
+            events: vec![],
+        });
+        self.batch_index.insert(timeline_id, idx);
+        idx
     }
 }
@@ -0,0 +1,139 @@
+//! Handling of termination signals.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Wait until the process is asked to stop, via SIGINT (ctrl-c) or
+/// SIGTERM, and return the name of the signal.
+pub async fn shutdown_signal() -> &'static str {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            // Couldn't install the handler; never fire.
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => "SIGINT",
+        _ = terminate => "SIGTERM",
+    }
+}
+
+/// How long to keep sending what was read before being asked to stop,
+/// if not configured.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The termination signals received so far
+#[derive(Clone, Copy, Default)]
+struct Signals {
+    first: Option<(&'static str, Instant)>,
+    count: usize,
+}
+
+/// Listens for termination signals for as long as it's alive. The
+/// first signal asks for a graceful stop: stop reading, but send
+/// everything read so far. A second signal, or the first one being
+/// `timeout` old, abandons that.
+#[derive(Clone)]
+pub struct Shutdown {
+    signals: watch::Receiver<Signals>,
+    timeout: Duration,
+}
+
+/// Delivers signals to a [Shutdown] made with [Shutdown::manual]
+#[derive(Clone)]
+pub struct ShutdownTrigger {
+    signals: Arc<watch::Sender<Signals>>,
+}
+
+impl ShutdownTrigger {
+    /// Act as if the process had received `signal`
+    pub fn signal(&self, signal: &'static str) {
+        self.signals.send_modify(|signals| {
+            signals.first.get_or_insert((signal, Instant::now()));
+            signals.count += 1;
+        });
+    }
+}
+
+impl Shutdown {
+    /// Listen for SIGINT and SIGTERM.
+    pub fn listen(timeout: Duration) -> Self {
+        let (shutdown, trigger) = Shutdown::manual(timeout);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    signal = shutdown_signal() => trigger.signal(signal),
+                    _ = trigger.signals.closed() => break,
+                }
+            }
+        });
+        shutdown
+    }
+
+    /// Only listen for the signals given to the returned trigger, e.g.
+    /// when something other than the process decides when to stop.
+    pub fn manual(timeout: Duration) -> (Self, ShutdownTrigger) {
+        let (tx, signals) = watch::channel(Signals::default());
+        let trigger = ShutdownTrigger {
+            signals: Arc::new(tx),
+        };
+        (Shutdown { signals, timeout }, trigger)
+    }
+
+    /// Wait until stopping was requested, and return the name of the
+    /// signal that asked for it.
+    pub async fn requested(&self) -> &'static str {
+        let mut signals = self.signals.clone();
+        let first = signals
+            .wait_for(|signals| signals.first.is_some())
+            .await
+            .ok()
+            .and_then(|signals| signals.first);
+        match first {
+            Some((signal, _)) => signal,
+            // Stopped listening, so no signal will come
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Wait until a graceful stop should be abandoned: once a second
+    /// signal arrives, or `timeout` after the first.
+    pub async fn abandoned(&self) {
+        self.requested().await;
+        let first_at = self.signals.borrow().first.expect("stop was requested").1;
+
+        let mut signals = self.signals.clone();
+        let second_signal = async move {
+            if signals.wait_for(|signals| signals.count > 1).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        };
+        tokio::select! {
+            _ = second_signal => info!("Asked to stop again; abandoning unsent events"),
+            _ = tokio::time::sleep_until((first_at + self.timeout).into()) => warn!(
+                timeout = ?self.timeout,
+                "Timed out sending events after being asked to stop; abandoning the rest"
+            ),
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Where [Sender](crate::send::Sender) sends timelines and events.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use async_trait::async_trait;
+use auxon_sdk::{
+    api::{AttrKey, AttrVal, TimelineId},
+    plugin_utils::ingest::{Client, Config},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use tracing::info;
+
+use crate::{send::HasCommonConfig, DltPluginError};
+
+/// The destination of the send path. The Modality ingest client is the
+/// usual one; [MockIngest](crate::mock::MockIngest) records everything
+/// in memory instead.
+///
+/// Timeline attrs and events apply to the timeline most recently
+/// switched to.
+#[async_trait]
+pub trait IngestSink: Send {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), DltPluginError>;
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: &[(&'static str, AttrVal)],
+    ) -> Result<(), DltPluginError>;
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: &[(AttrKey, AttrVal)],
+    ) -> Result<(), DltPluginError>;
+
+    /// Make sure everything sent so far has been passed on.
+    async fn flush(&mut self) -> Result<(), DltPluginError>;
+}
+
+#[async_trait]
+impl IngestSink for Client {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), DltPluginError> {
+        Client::switch_timeline(self, id)
+            .await
+            .map_err(DltPluginError::backend)
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: &[(&'static str, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        Client::send_timeline_attrs(self, name, attrs.iter().map(|(k, v)| (*k, v.clone())))
+            .await
+            .map_err(DltPluginError::backend)
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: &[(AttrKey, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        Client::send_event(
+            self,
+            name,
+            ordering,
+            attrs.iter().map(|(k, v)| (k.as_ref(), v.clone())),
+        )
+        .await
+        .map_err(DltPluginError::backend)
+    }
+
+    async fn flush(&mut self) -> Result<(), DltPluginError> {
+        Client::flush(self).await.map_err(DltPluginError::backend)
+    }
+}
+
+/// Writes timelines and events as JSON Lines, to see what the
+/// conversion produces without sending anything to Modality.
+///
+/// A timeline's line is written when its attrs are sent, before any of
+/// its events:
+///
+/// ```text
+/// {"type":"timeline","timeline_id":"…","name":"ECU1","attrs":{"timeline.ecu_id":"ECU1",…}}
+/// {"type":"event","timeline_id":"…","name":"log","ordering":0,"attrs":{"event.ecu_id":"ECU1",…}}
+/// ```
+///
+/// Timestamps are written as integer nanoseconds. Event lines can be
+/// fed to `modality-dlt-exporter`.
+pub struct JsonLinesSink<W> {
+    out: W,
+    current_timeline: Option<TimelineId>,
+}
+
+impl<W: Write + Send> JsonLinesSink<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            current_timeline: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn current_timeline(&self) -> Result<String, DltPluginError> {
+        match &self.current_timeline {
+            Some(id) => Ok(id.to_string()),
+            None => Err(io::Error::other("No timeline has been switched to").into()),
+        }
+    }
+
+    fn write_line(&mut self, line: &serde_json::Value) -> Result<(), DltPluginError> {
+        serde_json::to_writer(&mut self.out, line).map_err(io::Error::from)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<W: Write + Send> IngestSink for JsonLinesSink<W> {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), DltPluginError> {
+        self.current_timeline = Some(id);
+        Ok(())
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: &[(&'static str, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        let line = json!({
+            "type": "timeline",
+            "timeline_id": self.current_timeline()?,
+            "name": name,
+            "attrs": attrs_to_json(attrs.iter().map(|(k, v)| (*k, v))),
+        });
+        self.write_line(&line)
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: &[(AttrKey, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        // Orderings count up from 0, so this would take a very long run
+        let ordering = u64::try_from(ordering).map_err(|_| {
+            io::Error::other(format!("Event ordering {ordering} doesn't fit in 64 bits"))
+        })?;
+        let line = json!({
+            "type": "event",
+            "timeline_id": self.current_timeline()?,
+            "name": name,
+            "ordering": ordering,
+            "attrs": attrs_to_json(attrs.iter().map(|(k, v)| (k.as_ref(), v))),
+        });
+        self.write_line(&line)
+    }
+
+    async fn flush(&mut self) -> Result<(), DltPluginError> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+fn attrs_to_json<'a>(
+    attrs: impl Iterator<Item = (&'a str, &'a AttrVal)>,
+) -> serde_json::Map<String, serde_json::Value> {
+    attrs
+        .map(|(k, v)| (k.to_owned(), attr_val_to_json(v)))
+        .collect()
+}
+
+fn attr_val_to_json(val: &AttrVal) -> serde_json::Value {
+    match val {
+        AttrVal::String(s) => s.to_string().into(),
+        AttrVal::Integer(i) => (*i).into(),
+        AttrVal::Float(f) => serde_json::Number::from_f64(f.0)
+            .map(serde_json::Value::Number)
+            .unwrap_or_else(|| f.0.to_string().into()),
+        AttrVal::Bool(b) => (*b).into(),
+        AttrVal::Timestamp(ns) => ns.get_raw().into(),
+        other => other.to_string().into(),
+    }
+}
+
+/// Where the plugins' output goes: Modality, or for a dry run (when
+/// `dry_run_output` is configured) JSON Lines.
+pub enum PluginSink {
+    Modality(Client),
+    DryRun(JsonLinesSink<Box<dyn Write + Send>>),
+}
+
+impl PluginSink {
+    /// Open the configured dry run output, or if there isn't one,
+    /// connect to Modality.
+    pub async fn for_config<C>(config: &Config<C>) -> Result<Self, DltPluginError>
+    where
+        C: HasCommonConfig + Serialize + DeserializeOwned,
+    {
+        let Some(path) = &config.plugin.common_config().dry_run_output else {
+            let client = config
+                .connect_and_authenticate()
+                .await
+                .map_err(DltPluginError::backend)?;
+            info!("Connected to Modality");
+            return Ok(PluginSink::Modality(client));
+        };
+
+        let out: Box<dyn Write + Send> = if path.as_os_str() == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        info!(output = %path.display(), "Dry run; writing events as JSON Lines");
+        Ok(PluginSink::DryRun(JsonLinesSink::new(out)))
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        matches!(self, PluginSink::DryRun(_))
+    }
+}
+
+impl From<Client> for PluginSink {
+    fn from(client: Client) -> Self {
+        PluginSink::Modality(client)
+    }
+}
+
+#[async_trait]
+impl IngestSink for PluginSink {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), DltPluginError> {
+        match self {
+            PluginSink::Modality(client) => IngestSink::switch_timeline(client, id).await,
+            PluginSink::DryRun(sink) => sink.switch_timeline(id).await,
+        }
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: &[(&'static str, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        match self {
+            PluginSink::Modality(client) => {
+                IngestSink::send_timeline_attrs(client, name, attrs).await
+            }
+            PluginSink::DryRun(sink) => sink.send_timeline_attrs(name, attrs).await,
+        }
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: &[(AttrKey, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        match self {
+            PluginSink::Modality(client) => {
+                IngestSink::send_event(client, name, ordering, attrs).await
+            }
+            PluginSink::DryRun(sink) => sink.send_event(name, ordering, attrs).await,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), DltPluginError> {
+        match self {
+            PluginSink::Modality(client) => IngestSink::flush(client).await,
+            PluginSink::DryRun(sink) => sink.flush().await,
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Decoding the payloads of SOME/IP network trace messages, using
+//! service interface descriptions from ARXML or FIBEX files.
+
+use std::{collections::HashMap, path::Path};
+
+use auxon_sdk::api::{AttrKey, AttrVal};
+use dlt_core::dlt;
+
+use crate::{
+    arxml, fibex, network,
+    payload::{self, DescriptionError, Encoding, Parameter},
+    send::EventAnnotator,
+};
+
+/// Service interfaces, by SOME/IP service id
+#[derive(Debug, Default)]
+pub struct SomeIpServices {
+    services: HashMap<u16, Service>,
+}
+
+#[derive(Debug, Default)]
+pub struct Service {
+    pub name: String,
+    /// Methods, events and field accessors, by method (or event) id
+    pub methods: HashMap<u16, Method>,
+}
+
+/// A method, or anything else which is addressed by a method id (events,
+/// and field getters, setters and notifiers)
+#[derive(Debug, Default)]
+pub struct Method {
+    pub name: String,
+    /// The payload of requests, or of notifications for events
+    pub request: Vec<Parameter>,
+    /// The payload of responses
+    pub response: Vec<Parameter>,
+}
+
+impl Method {
+    /// The getter of a field: an empty request, answered with the value
+    pub(crate) fn field_getter(field: &Parameter) -> Self {
+        Method {
+            name: format!("{}_get", field.name),
+            request: vec![],
+            response: vec![field.clone()],
+        }
+    }
+
+    /// The setter of a field: the new value, answered with the value
+    /// which was actually set
+    pub(crate) fn field_setter(field: &Parameter) -> Self {
+        Method {
+            name: format!("{}_set", field.name),
+            request: vec![field.clone()],
+            response: vec![field.clone()],
+        }
+    }
+
+    /// The notifier of a field: an event carrying the value
+    pub(crate) fn field_notifier(field: &Parameter) -> Self {
+        Method {
+            name: format!("{}_notify", field.name),
+            request: vec![field.clone()],
+            response: vec![],
+        }
+    }
+}
+
+impl SomeIpServices {
+    /// Load service interfaces from an ARXML or FIBEX file, or from every
+    /// such file in a directory.
+    pub fn load(path: &Path) -> Result<Self, DescriptionError> {
+        let mut services = SomeIpServices::default();
+        for (path, content) in payload::read_description_files(path)? {
+            let doc =
+                roxmltree::Document::parse(&content).map_err(|source| DescriptionError::Xml {
+                    path: path.clone(),
+                    source,
+                })?;
+            let res = match doc.root_element().tag_name().name() {
+                "FIBEX" => fibex::parse_services(&doc, &mut services),
+                "AUTOSAR" => arxml::parse_services(&doc, &mut services),
+                other => Err(format!(
+                    "unknown root element '{other}', expected AUTOSAR or FIBEX"
+                )),
+            };
+            res.map_err(|reason| DescriptionError::Invalid { path, reason })?;
+        }
+        Ok(services)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    /// Add a service. If one with the same id is already known, methods
+    /// are merged into it, replacing any with the same id.
+    pub fn add_service(&mut self, service_id: u16, service: Service) {
+        let existing = self.services.entry(service_id).or_default();
+        if existing.name.is_empty() {
+            existing.name = service.name;
+        }
+        existing.methods.extend(service.methods);
+    }
+
+    pub fn method(&self, service_id: u16, method_id: u16) -> Option<(&Service, &Method)> {
+        let service = self.services.get(&service_id)?;
+        Some((service, service.methods.get(&method_id)?))
+    }
+
+    fn lookup<'m>(
+        &self,
+        msg: &'m dlt::Message,
+    ) -> Option<(&Service, &Method, network::SomeIpHeader, &'m [u8])> {
+        let (header, payload) = network::someip_message(msg)?;
+        let (service, method) = self.method(header.service_id, header.method_id)?;
+        Some((service, method, header, payload))
+    }
+}
+
+/// Names SOME/IP network trace events after the method they're for, and
+/// adds `event.someip.service_name`, `event.someip.method_name`, and an
+/// `event.payload.<name>` attribute for each decoded parameter.
+impl EventAnnotator for SomeIpServices {
+    fn annotate(&self, msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
+        let Some((service, method, header, payload)) = self.lookup(msg) else {
+            return;
+        };
+
+        attrs.push((
+            "event.someip.service_name".into(),
+            service.name.clone().into(),
+        ));
+        attrs.push((
+            "event.someip.method_name".into(),
+            method.name.clone().into(),
+        ));
+
+        let params = match header.message_type {
+            // Request, request without return, and notification
+            0x00 | 0x01 | 0x02 => &method.request,
+            // Response
+            0x80 => &method.response,
+            // Errors carry no defined payload, and segmented (TP)
+            // messages would have to be reassembled first
+            _ => return,
+        };
+
+        if let Err(e) = payload::decode_payload(params, payload, Encoding::SOMEIP, attrs) {
+            tracing::debug!(
+                service = %service.name,
+                method = %method.name,
+                err = %e,
+                "Failed to decode SOME/IP payload"
+            );
+        }
+    }
+
+    fn event_name(&self, msg: &dlt::Message) -> Option<String> {
+        let (_, method, _, _) = self.lookup(msg)?;
+        Some(method.name.clone())
+    }
+}
@@ -0,0 +1,703 @@
+//! A bounded, on-disk queue of DLT messages, used to ride out periods
+//! where the Modality backend can't be reached.
+//!
+//! The spool is a directory of segment files. Each segment is a
+//! sequence of records, each of which is a little-endian `u32` length
+//! followed by a single serialized DLT message (without storage
+//! header). Segments are written in order, and consumed (and deleted)
+//! oldest first.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use auxon_sdk::plugin_utils::{ingest::Client, serde::from_str};
+use dlt_core::parse::ParsedMessage;
+use futures::future::LocalBoxFuture;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{
+    metrics::Metrics,
+    send::{HasCommonConfig, Sender},
+    sink::IngestSink,
+    DltPluginError,
+};
+
+const SEGMENT_EXTENSION: &str = "spool";
+
+/// Spool size limit, if not configured (256 MiB)
+pub const DEFAULT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Segment size, if not configured (4 MiB)
+pub const DEFAULT_SEGMENT_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Time between reconnection attempts, if not configured
+pub const DEFAULT_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many messages to take from the channel at once
+const RECV_BATCH: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SpoolConfig {
+    /// Directory to spool messages to while the Modality backend is
+    /// unreachable. If not given, spooling is disabled, and losing the
+    /// backend connection is an error.
+    pub spool_dir: Option<PathBuf>,
+
+    /// The maximum total size of the spool, in bytes. Defaults to 256 MiB.
+    #[serde(default, deserialize_with = "from_str")]
+    pub spool_max_bytes: Option<u64>,
+
+    /// The size at which a new spool segment file is started, in bytes. Defaults to 4 MiB.
+    #[serde(default, deserialize_with = "from_str")]
+    pub spool_segment_bytes: Option<u64>,
+
+    /// What to do with messages when the spool is full. Defaults to `drop_newest`.
+    #[serde(default, deserialize_with = "from_str")]
+    pub spool_drop_policy: Option<DropPolicy>,
+
+    /// How long to wait between attempts to reconnect to the backend, in milliseconds. Defaults to 1000.
+    #[serde(default, deserialize_with = "from_str")]
+    pub reconnect_interval_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// Discard incoming messages, keeping what's already spooled.
+    #[default]
+    DropNewest,
+
+    /// Discard the oldest spooled segment to make room.
+    DropOldest,
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_newest" => Ok(DropPolicy::DropNewest),
+            "drop_oldest" => Ok(DropPolicy::DropOldest),
+            _ => Err(format!(
+                "Invalid spool drop policy '{s}'; expected 'drop_newest' or 'drop_oldest'"
+            )),
+        }
+    }
+}
+
+struct Segment {
+    seq: u64,
+    size: u64,
+
+    /// How many messages it holds; unknown for segments left over from
+    /// a previous run, which this run's metrics haven't counted
+    records: Option<u64>,
+}
+
+struct ActiveSegment {
+    seq: u64,
+    writer: BufWriter<File>,
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    drop_policy: DropPolicy,
+
+    /// All segments on disk, oldest first. The active segment, if any, is last.
+    segments: VecDeque<Segment>,
+    active: Option<ActiveSegment>,
+    total_bytes: u64,
+    next_seq: u64,
+
+    /// Messages discarded since the spool last became empty
+    dropped: u64,
+
+    /// Records at the start of the oldest segment which have already
+    /// been handed to the sender, or couldn't be
+    consumed: usize,
+
+    /// How many records each message from the last [Spool::read_oldest]
+    /// accounts for: itself, and any unparseable records before it
+    read_records: VecDeque<usize>,
+
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Spool {
+    /// Open the spool in `dir`, creating it if necessary. Segments left
+    /// over from a previous run are kept, and will be drained first.
+    pub fn open(dir: &Path, config: &SpoolConfig) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut segments = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let Some(seq) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            segments.push(Segment {
+                seq,
+                size: fs::metadata(&path)?.len(),
+                records: None,
+            });
+        }
+        segments.sort_by_key(|s| s.seq);
+
+        let total_bytes = segments.iter().map(|s| s.size).sum();
+        let next_seq = segments.last().map(|s| s.seq + 1).unwrap_or(0);
+        if !segments.is_empty() {
+            info!(
+                dir = %dir.display(),
+                segments = segments.len(),
+                bytes = total_bytes,
+                "Found existing spooled messages"
+            );
+        }
+
+        Ok(Spool {
+            dir: dir.to_owned(),
+            max_bytes: config.spool_max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            segment_bytes: config
+                .spool_segment_bytes
+                .unwrap_or(DEFAULT_SEGMENT_BYTES)
+                .max(1),
+            drop_policy: config.spool_drop_policy.unwrap_or_default(),
+            segments: segments.into(),
+            active: None,
+            total_bytes,
+            next_seq,
+            dropped: 0,
+            consumed: 0,
+            read_records: VecDeque::new(),
+            metrics: None,
+        })
+    }
+
+    /// Record discarded messages in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The total size of all spooled segments, in bytes
+    pub fn len_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Was the oldest segment left over from a previous run?
+    pub fn oldest_is_leftover(&self) -> bool {
+        self.segments
+            .front()
+            .is_some_and(|segment| segment.records.is_none())
+    }
+
+    /// Append a message to the spool. Returns false if the message
+    /// was discarded because the spool is full.
+    pub fn push(&mut self, parsed_msg: ParsedMessage) -> io::Result<bool> {
+        let ParsedMessage::Item(msg) = parsed_msg else {
+            // Nothing would be sent for these anyway
+            return Ok(true);
+        };
+
+        let bytes = msg.as_bytes();
+        let record_size = 4 + bytes.len() as u64;
+
+        while self.total_bytes + record_size > self.max_bytes {
+            if self.drop_policy == DropPolicy::DropNewest || self.segments.is_empty() {
+                self.note_dropped(1);
+                return Ok(false);
+            }
+
+            let discarded = self.discard_oldest()?;
+            self.note_dropped(discarded);
+        }
+
+        let seq = self.active_segment()?;
+        let active = self
+            .active
+            .as_mut()
+            .expect("active segment was just opened");
+        active
+            .writer
+            .write_all(&(bytes.len() as u32).to_le_bytes())?;
+        active.writer.write_all(&bytes)?;
+
+        let segment = self.segments.back_mut().expect("active segment is tracked");
+        debug_assert_eq!(segment.seq, seq);
+        segment.size += record_size;
+        if let Some(records) = &mut segment.records {
+            *records += 1;
+        }
+        self.total_bytes += record_size;
+
+        if segment.size >= self.segment_bytes {
+            self.close_active()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Read the messages from the oldest segment which haven't been
+    /// [consumed](Spool::mark_consumed) yet. The segment stays on disk
+    /// until [Spool::remove_oldest] is called, so if sending them fails
+    /// the rest of them will be read again.
+    pub fn read_oldest(&mut self) -> io::Result<Option<Vec<ParsedMessage>>> {
+        let Some(oldest) = self.segments.front() else {
+            return Ok(None);
+        };
+        let seq = oldest.seq;
+        if self.active.as_ref().map(|a| a.seq) == Some(seq) {
+            self.close_active()?;
+        }
+
+        let path = self.segment_path(seq);
+        let mut buf = vec![];
+        File::open(&path)?.read_to_end(&mut buf)?;
+
+        let mut messages = vec![];
+        self.read_records.clear();
+        let mut records = 0;
+        let mut rest = buf.as_slice();
+        let mut skip = self.consumed;
+        while !rest.is_empty() {
+            let Some((record, remaining)) = split_record(rest) else {
+                warn!(
+                    segment = %path.display(),
+                    offset = buf.len() - rest.len(),
+                    "Ignoring incomplete record at the end of spool segment"
+                );
+                break;
+            };
+            rest = remaining;
+            if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+
+            records += 1;
+            match dlt_core::parse::dlt_message(record, None, false) {
+                Ok((_, parsed_msg)) => {
+                    messages.push(parsed_msg);
+                    self.read_records.push_back(std::mem::take(&mut records));
+                }
+                Err(e) => warn!(
+                    segment = %path.display(),
+                    err = %e,
+                    "Ignoring unparseable spooled message"
+                ),
+            }
+        }
+
+        Ok(Some(messages))
+    }
+
+    /// Note that the next message from the oldest segment has been
+    /// handed to the sender, which is then responsible for it, so it
+    /// isn't read again. Neither are any unparseable records before it.
+    pub fn mark_consumed(&mut self) {
+        self.consumed += self.read_records.pop_front().unwrap_or(1);
+    }
+
+    /// Delete the oldest segment, once its messages have been sent.
+    pub fn remove_oldest(&mut self) -> io::Result<()> {
+        self.discard_oldest()?;
+        if self.is_empty() && self.dropped > 0 {
+            warn!(
+                dropped = self.dropped,
+                "Spool drained; messages were discarded while it was full"
+            );
+            self.dropped = 0;
+        }
+        Ok(())
+    }
+
+    /// Delete the oldest segment, returning how many of its messages
+    /// hadn't been consumed yet, as far as this run knows.
+    fn discard_oldest(&mut self) -> io::Result<u64> {
+        let Some(oldest) = self.segments.front() else {
+            return Ok(0);
+        };
+        let seq = oldest.seq;
+        if self.active.as_ref().map(|a| a.seq) == Some(seq) {
+            self.close_active()?;
+        }
+
+        let oldest = self.segments.pop_front().expect("checked above");
+        self.total_bytes -= oldest.size;
+        let unconsumed = oldest
+            .records
+            .map_or(0, |records| records.saturating_sub(self.consumed as u64));
+        self.consumed = 0;
+        self.read_records.clear();
+        fs::remove_file(self.segment_path(seq))?;
+        Ok(unconsumed)
+    }
+
+    fn note_dropped(&mut self, count: u64) {
+        if self.dropped == 0 {
+            let discarding = match self.drop_policy {
+                DropPolicy::DropNewest => "new",
+                DropPolicy::DropOldest => "the oldest",
+            };
+            warn!(
+                max_bytes = self.max_bytes,
+                "Spool is full; discarding {discarding} messages"
+            );
+        }
+        self.dropped += count;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_messages_dropped(count);
+        }
+    }
+
+    fn active_segment(&mut self) -> io::Result<u64> {
+        if let Some(active) = &self.active {
+            return Ok(active.seq);
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(self.segment_path(seq))?;
+        self.active = Some(ActiveSegment {
+            seq,
+            writer: BufWriter::new(file),
+        });
+        self.segments.push_back(Segment {
+            seq,
+            size: 0,
+            records: Some(0),
+        });
+        Ok(seq)
+    }
+
+    fn close_active(&mut self) -> io::Result<()> {
+        if let Some(mut active) = self.active.take() {
+            active.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn segment_path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{seq:010}.{SEGMENT_EXTENSION}"))
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = self.close_active();
+    }
+}
+
+fn split_record(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len_bytes: [u8; 4] = buf.get(0..4)?.try_into().ok()?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    let record = buf.get(4..4 + len)?;
+    Some((record, &buf[4 + len..]))
+}
+
+/// Reconnects a [Sender] to the backend
+pub(crate) type Connector<C, S> =
+    Box<dyn for<'a> Fn(&'a Sender<C, S>) -> LocalBoxFuture<'a, Result<S, DltPluginError>>>;
+
+/// Wraps a [Sender], diverting messages to a [Spool] whenever the
+/// backend connection is lost. Reconnection is attempted in the
+/// background while messages keep being read and spooled; once it
+/// succeeds, the spool is drained in order before live messages are
+/// sent directly again.
+///
+/// Spooled messages are only read once: if the connection drops while
+/// a segment is being sent, the sender keeps whatever it couldn't send
+/// and the rest of the segment is read after reconnecting. Delivery is
+/// still at-least-once across runs, since a segment which was only
+/// partly sent when the process exited is sent again in full.
+///
+/// The spool's file I/O runs on tokio's blocking thread pool.
+pub struct SpoolingSender<C: HasCommonConfig, S: IngestSink = Client> {
+    sender: Sender<C, S>,
+    spool: Arc<Mutex<Spool>>,
+    reconnect_interval: Duration,
+    connect: Connector<C, S>,
+}
+
+impl<C, S> SpoolingSender<C, S>
+where
+    C: HasCommonConfig + Serialize + DeserializeOwned + 'static,
+    S: IngestSink + From<Client> + 'static,
+{
+    /// Spool to `spool_dir`, reconnecting with the sender's configuration.
+    pub async fn new(
+        sender: Sender<C, S>,
+        spool_dir: &Path,
+        config: &SpoolConfig,
+    ) -> io::Result<Self> {
+        Self::with_connector(sender, spool_dir, config, |sender| {
+            Box::pin(async move { Ok(sender.connect().await?.into()) })
+        })
+        .await
+    }
+}
+
+impl<C: HasCommonConfig, S: IngestSink> SpoolingSender<C, S> {
+    /// Spool to `spool_dir`, reconnecting with `connect`; e.g. to test
+    /// against a [MockIngest](crate::mock::MockIngest).
+    pub async fn with_connector<F>(
+        sender: Sender<C, S>,
+        spool_dir: &Path,
+        config: &SpoolConfig,
+        connect: F,
+    ) -> io::Result<Self>
+    where
+        F: for<'a> Fn(&'a Sender<C, S>) -> LocalBoxFuture<'a, Result<S, DltPluginError>> + 'static,
+    {
+        Self::with_boxed_connector(sender, spool_dir, config, Box::new(connect)).await
+    }
+
+    pub(crate) async fn with_boxed_connector(
+        sender: Sender<C, S>,
+        spool_dir: &Path,
+        config: &SpoolConfig,
+        connect: Connector<C, S>,
+    ) -> io::Result<Self> {
+        let dir = spool_dir.to_owned();
+        let spool_config = config.clone();
+        let spool = tokio::task::spawn_blocking(move || Spool::open(&dir, &spool_config))
+            .await
+            .map_err(io::Error::other)??
+            .with_metrics(sender.metrics().clone());
+        Ok(SpoolingSender {
+            sender,
+            spool: Arc::new(Mutex::new(spool)),
+            reconnect_interval: config
+                .reconnect_interval_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_RECONNECT_INTERVAL),
+            connect,
+        })
+    }
+
+    pub fn into_inner(self) -> Sender<C, S> {
+        self.sender
+    }
+
+    pub fn sender(&self) -> &Sender<C, S> {
+        &self.sender
+    }
+
+    /// Like [Sender::run], but survives losing the backend connection.
+    /// Backend errors are retried; anything else (like failing to
+    /// write to the spool) is returned.
+    pub async fn run(
+        &mut self,
+        rx: &mut mpsc::Receiver<ParsedMessage>,
+    ) -> Result<(), DltPluginError> {
+        let mut buf = vec![];
+        let mut connected = true;
+        loop {
+            if !connected {
+                if !self.reconnect(rx).await? {
+                    break;
+                }
+                connected = true;
+            }
+
+            if !self.spool().is_empty() || self.sender.has_pending_events() {
+                match self.drain_spool(rx).await {
+                    Ok(()) => (),
+                    Err(e) if e.is_backend() => {
+                        warn!(err = %e, "Lost connection to Modality while draining spool");
+                        connected = false;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if rx.recv_many(&mut buf, RECV_BATCH).await == 0 {
+                break;
+            }
+
+            let mut unsent = vec![];
+            for parsed_msg in buf.drain(..) {
+                if !connected {
+                    unsent.push(parsed_msg);
+                    continue;
+                }
+
+                match self.sender.handle_message(parsed_msg).await {
+                    Ok(()) => (),
+                    Err(e) if e.is_backend() => {
+                        warn!(err = %e, "Lost connection to Modality; spooling messages");
+                        connected = false;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            spool_messages(&self.spool, unsent).await?;
+
+            if connected && rx.is_empty() {
+                match self.sender.flush().await {
+                    Ok(()) => (),
+                    Err(e) if e.is_backend() => {
+                        warn!(err = %e, "Lost connection to Modality; spooling messages");
+                        connected = false;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        if connected {
+            self.sender.flush().await?;
+        } else if !self.spool().is_empty() {
+            warn!(
+                bytes = self.spool().len_bytes(),
+                "Exiting with spooled messages; they will be sent on the next run"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Keep spooling incoming messages while trying to reconnect.
+    /// Returns false if `rx` was closed before that succeeded.
+    async fn reconnect(
+        &mut self,
+        rx: &mut mpsc::Receiver<ParsedMessage>,
+    ) -> Result<bool, DltPluginError> {
+        let sender = &self.sender;
+        let connect = &self.connect;
+        let interval = self.reconnect_interval;
+
+        let mut buf = vec![];
+        let mut attempt = connect_after(sender, connect, interval);
+        let sink = loop {
+            tokio::select! {
+                res = &mut attempt => match res {
+                    Ok(sink) => break sink,
+                    Err(e) => {
+                        warn!(err = %e, "Failed to reconnect to Modality");
+                        attempt = connect_after(sender, connect, interval);
+                    }
+                },
+                n = rx.recv_many(&mut buf, RECV_BATCH) => {
+                    if n == 0 {
+                        return Ok(false);
+                    }
+                    spool_messages(&self.spool, buf.drain(..).collect()).await?;
+                }
+            }
+        };
+        drop(attempt);
+
+        info!("Reconnected to Modality");
+        self.sender.set_client(sink);
+        self.sender.metrics().record_reconnect();
+        Ok(true)
+    }
+
+    /// Send any events left over from a failed flush, followed by the
+    /// spooled messages, oldest first. Messages arriving meanwhile are
+    /// added to the end of the spool, to keep everything in order.
+    async fn drain_spool(
+        &mut self,
+        rx: &mut mpsc::Receiver<ParsedMessage>,
+    ) -> Result<(), DltPluginError> {
+        self.sender.flush().await?;
+
+        while let Some(messages) = spool_io(&self.spool, Spool::read_oldest).await? {
+            // Messages spooled by a previous run haven't been counted
+            // as read by this one, but are about to be counted as sent
+            let leftover = self.spool().oldest_is_leftover();
+            for parsed_msg in messages {
+                if leftover {
+                    self.sender.metrics().record_message(&parsed_msg);
+                }
+                // If sending fails, the sender keeps the message's
+                // event, so the message mustn't be read again.
+                self.spool().mark_consumed();
+                self.sender.handle_message(parsed_msg).await?;
+            }
+            self.sender.flush().await?;
+            spool_io(&self.spool, Spool::remove_oldest).await?;
+
+            let mut incoming = vec![];
+            while let Ok(parsed_msg) = rx.try_recv() {
+                incoming.push(parsed_msg);
+            }
+            spool_messages(&self.spool, incoming).await?;
+        }
+
+        Ok(())
+    }
+
+    /// For quick, in-memory access; anything touching the disk goes
+    /// through [spool_io].
+    fn spool(&self) -> MutexGuard<'_, Spool> {
+        self.spool.lock().unwrap()
+    }
+}
+
+/// Run `f` on the spool, on the blocking thread pool
+async fn spool_io<T, F>(spool: &Arc<Mutex<Spool>>, f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Spool) -> io::Result<T> + Send + 'static,
+{
+    let spool = spool.clone();
+    tokio::task::spawn_blocking(move || f(&mut *spool.lock().unwrap()))
+        .await
+        .map_err(io::Error::other)?
+}
+
+async fn spool_messages(spool: &Arc<Mutex<Spool>>, messages: Vec<ParsedMessage>) -> io::Result<()> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    spool_io(spool, move |spool| {
+        for parsed_msg in messages {
+            spool.push(parsed_msg)?;
+        }
+        Ok(())
+    })
+    .await
+}
+
+fn connect_after<'a, C, S>(
+    sender: &'a Sender<C, S>,
+    connect: &'a Connector<C, S>,
+    delay: Duration,
+) -> LocalBoxFuture<'a, Result<S, DltPluginError>>
+where
+    C: HasCommonConfig,
+    S: IngestSink,
+{
+    Box::pin(async move {
+        tokio::time::sleep(delay).await;
+        connect(sender).await
+    })
+}
@@ -0,0 +1,554 @@
+//! A reusable reader for sequences of DLT messages, as found in files
+//! or on the wire.
+
+use std::{
+    io::{self, Read},
+    pin::Pin,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use dlt_core::{
+    dlt::{self, DltTimeStamp, StorageHeader},
+    parse::ParsedMessage,
+};
+use futures::{ready, Stream};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{message_size, parse_message, DltPluginError, DEFAULT_MAX_MESSAGE_SIZE};
+
+/// The magic bytes at the start of every storage header
+pub const STORAGE_HEADER_PATTERN: [u8; 4] = [b'D', b'L', b'T', 0x01];
+pub const STORAGE_HEADER_LEN: usize = 16;
+
+/// The magic bytes of the serial header
+pub const SERIAL_HEADER_PATTERN: [u8; 4] = [b'D', b'L', b'S', 0x01];
+
+/// What precedes each DLT message in the stream
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Nothing; messages follow each other directly. This is what
+    /// dlt-daemon sends over TCP.
+    #[default]
+    Raw,
+
+    /// Each message is preceded by a 16 byte storage header, as in
+    /// `.dlt` files.
+    StorageHeader,
+
+    /// Each message is preceded by the 4 byte serial header `DLS\x01`.
+    SerialHeader,
+}
+
+impl Framing {
+    /// The length of the header preceding each message
+    fn header_len(self) -> usize {
+        match self {
+            Framing::Raw => 0,
+            Framing::StorageHeader => STORAGE_HEADER_LEN,
+            Framing::SerialHeader => SERIAL_HEADER_PATTERN.len(),
+        }
+    }
+}
+
+/// A message read from a [DltStream], along with where and when it
+/// was found.
+#[derive(Debug)]
+pub struct DltRecord {
+    pub message: ParsedMessage,
+
+    /// Where the record (including any framing header) starts in the
+    /// stream, counting from where the [DltStream] started reading.
+    pub offset: u64,
+
+    /// The size of the record in bytes, including any framing header
+    pub size: u64,
+
+    /// The storage header, when reading with [Framing::StorageHeader]
+    pub storage_header: Option<StorageHeader>,
+
+    /// When the record was finished being read
+    pub received_at: SystemTime,
+}
+
+/// A sequence of DLT messages read from `R`.
+///
+/// When `R` implements [AsyncRead], this is a [Stream]; when it
+/// implements [Read], it's an [Iterator]. Either way, a clean end of
+/// input (at a record boundary) ends the sequence.
+///
+/// If a message was read completely but couldn't be parsed (see
+/// [DltPluginError::skipped_message_size]), the error is yielded and
+/// reading carries on with the next record. Any other error ends the
+/// sequence after being yielded, since framing can't be trusted after
+/// it.
+///
+/// Records are read into a single buffer which is reused from one to
+/// the next, and which never grows beyond the maximum message size (see
+/// [DltStream::with_max_message_size]) plus the framing header. Reading
+/// picks up where it left off whenever more input arrives, so nothing
+/// is allocated per record beyond the parsed message itself.
+pub struct DltStream<R> {
+    reader: RecordReader<R>,
+    done: bool,
+}
+
+impl<R> DltStream<R> {
+    pub fn new(reader: R, framing: Framing) -> Self {
+        DltStream {
+            reader: RecordReader {
+                reader,
+                framing,
+                offset: 0,
+                buf: Vec::new(),
+                filled: 0,
+                phase: Phase::Framing,
+                max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            },
+            done: false,
+        }
+    }
+
+    /// Skip messages (not counting any framing header) larger than
+    /// `max_message_size` bytes, reporting them as
+    /// [DltPluginError::MessageTooLarge]. Defaults to
+    /// [DEFAULT_MAX_MESSAGE_SIZE].
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.reader.max_message_size = max_message_size;
+        self
+    }
+
+    /// What to yield for the outcome of reading a record, ending the
+    /// sequence unless reading can carry on after it
+    fn yield_record(
+        &mut self,
+        res: Result<Option<DltRecord>, DltPluginError>,
+    ) -> Option<Result<DltRecord, DltPluginError>> {
+        match res {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) if e.skipped_message_size().is_some() => Some(Err(e)),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for DltStream<R> {
+    type Item = Result<DltRecord, DltPluginError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.reader.next_record_sync();
+        self.yield_record(res)
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for DltStream<R> {
+    type Item = Result<DltRecord, DltPluginError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        let res = ready!(this.reader.poll_record(cx));
+        Poll::Ready(this.yield_record(res))
+    }
+}
+
+/// Reads records one step at a time, so reading can stop whenever the
+/// input has nothing more yet and pick up from there later.
+struct RecordReader<R> {
+    reader: R,
+    framing: Framing,
+    offset: u64,
+    /// The record being read: its framing header, then the message
+    buf: Vec<u8>,
+    /// How much of `buf` has been read
+    filled: usize,
+    phase: Phase,
+    max_message_size: usize,
+}
+
+/// How far reading the current record has got
+enum Phase {
+    /// Reading the framing header, if there is one
+    Framing,
+    /// Reading the first byte of the message, which says how long its
+    /// headers are
+    HeaderType {
+        storage_header: Option<StorageHeader>,
+    },
+    /// Reading the message's headers, which say how long it is
+    Headers {
+        storage_header: Option<StorageHeader>,
+        headers_len: usize,
+    },
+    /// Reading the rest of the message
+    Payload {
+        storage_header: Option<StorageHeader>,
+        headers_len: usize,
+        size: usize,
+    },
+    /// Discarding a message which is too large to buffer. `skipped`
+    /// bytes of it, including its headers, have been read.
+    Skipping {
+        error: DltPluginError,
+        size: usize,
+        skipped: usize,
+    },
+}
+
+/// What a [RecordReader] needs from the input to make progress
+enum Want {
+    /// Fill the buffer up to this length
+    Fill(usize),
+    /// Read and discard this many bytes
+    Discard(usize),
+}
+
+/// How much to discard per read, when skipping a message
+const DISCARD_CHUNK_LEN: usize = 4096;
+
+impl<R: AsyncRead + Unpin> RecordReader<R> {
+    fn poll_record(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<DltRecord>, DltPluginError>> {
+        loop {
+            let eof = match self.want() {
+                Want::Fill(len) => ready!(self.poll_fill(cx, len))?,
+                Want::Discard(len) => ready!(self.poll_discard(cx, len))?,
+            };
+            if let Some(res) = self.advance(eof) {
+                return Poll::Ready(res);
+            }
+        }
+    }
+
+    /// Read until the buffer holds `len` bytes. Returns whether the
+    /// input ended first.
+    fn poll_fill(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<io::Result<bool>> {
+        if self.buf.len() < len {
+            self.buf.resize(len, 0);
+        }
+        while self.filled < len {
+            let mut read_buf = ReadBuf::new(&mut self.buf[self.filled..len]);
+            match ready!(Pin::new(&mut self.reader).poll_read(cx, &mut read_buf)) {
+                Ok(()) if read_buf.filled().is_empty() => return Poll::Ready(Ok(true)),
+                Ok(()) => self.filled += read_buf.filled().len(),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(false))
+    }
+
+    /// Read and discard `len` bytes. Returns whether the input ended
+    /// first.
+    fn poll_discard(&mut self, cx: &mut Context<'_>, mut len: usize) -> Poll<io::Result<bool>> {
+        let mut chunk = [0u8; DISCARD_CHUNK_LEN];
+        while len > 0 {
+            let mut read_buf = ReadBuf::new(&mut chunk[..len.min(DISCARD_CHUNK_LEN)]);
+            match ready!(Pin::new(&mut self.reader).poll_read(cx, &mut read_buf)) {
+                Ok(()) if read_buf.filled().is_empty() => return Poll::Ready(Ok(true)),
+                Ok(()) => {
+                    let n = read_buf.filled().len();
+                    self.skipped(n);
+                    len -= n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl<R: Read> RecordReader<R> {
+    fn next_record_sync(&mut self) -> Result<Option<DltRecord>, DltPluginError> {
+        loop {
+            let eof = match self.want() {
+                Want::Fill(len) => self.fill_sync(len)?,
+                Want::Discard(len) => self.discard_sync(len)?,
+            };
+            if let Some(res) = self.advance(eof) {
+                return res;
+            }
+        }
+    }
+
+    /// A blocking version of [poll_fill](RecordReader::poll_fill)
+    fn fill_sync(&mut self, len: usize) -> io::Result<bool> {
+        if self.buf.len() < len {
+            self.buf.resize(len, 0);
+        }
+        while self.filled < len {
+            match self.reader.read(&mut self.buf[self.filled..len]) {
+                Ok(0) => return Ok(true),
+                Ok(n) => self.filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    }
+
+    /// A blocking version of [poll_discard](RecordReader::poll_discard)
+    fn discard_sync(&mut self, mut len: usize) -> io::Result<bool> {
+        let mut chunk = [0u8; DISCARD_CHUNK_LEN];
+        while len > 0 {
+            match self.reader.read(&mut chunk[..len.min(DISCARD_CHUNK_LEN)]) {
+                Ok(0) => return Ok(true),
+                Ok(n) => {
+                    self.skipped(n);
+                    len -= n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<R> RecordReader<R> {
+    fn want(&self) -> Want {
+        let framing_len = self.framing.header_len();
+        match &self.phase {
+            Phase::Framing => Want::Fill(framing_len),
+            Phase::HeaderType { .. } => Want::Fill(framing_len + 1),
+            Phase::Headers { headers_len, .. } => Want::Fill(framing_len + headers_len),
+            Phase::Payload { size, .. } => Want::Fill(framing_len + size),
+            Phase::Skipping { size, skipped, .. } => Want::Discard(size - skipped),
+        }
+    }
+
+    fn skipped(&mut self, n: usize) {
+        if let Phase::Skipping { skipped, .. } = &mut self.phase {
+            *skipped += n;
+        }
+    }
+
+    /// Move on, once what [want](RecordReader::want) asked for has been
+    /// read or the input has ended. Returns the outcome once the record
+    /// is done with, and gets ready to read the next one.
+    fn advance(&mut self, eof: bool) -> Option<Result<Option<DltRecord>, DltPluginError>> {
+        let res = match self.step(eof) {
+            Ok(None) => return None,
+            Ok(Some(record)) => Ok(record),
+            Err(e) => Err(e),
+        };
+        self.phase = Phase::Framing;
+        self.filled = 0;
+        Some(res)
+    }
+
+    /// Take one step through the record. Returns `Ok(None)` if there's
+    /// more to read, and `Ok(Some(None))` at a clean end of input.
+    fn step(&mut self, eof: bool) -> Result<Option<Option<DltRecord>>, DltPluginError> {
+        let framing_len = self.framing.header_len();
+        match std::mem::replace(&mut self.phase, Phase::Framing) {
+            Phase::Framing => {
+                if eof && self.filled == 0 {
+                    return Ok(Some(None));
+                }
+                let storage_header = match self.framing {
+                    Framing::Raw => None,
+                    Framing::StorageHeader => Some(self.storage_header()?),
+                    Framing::SerialHeader => {
+                        self.serial_header()?;
+                        None
+                    }
+                };
+                self.phase = Phase::HeaderType { storage_header };
+            }
+            Phase::HeaderType { storage_header } => {
+                if eof {
+                    // Without framing, nothing has been read yet
+                    if self.filled == 0 {
+                        return Ok(Some(None));
+                    }
+                    return Err(self.truncated_after_framing(framing_len));
+                }
+                let headers_len = dlt::calculate_all_headers_length(self.buf[framing_len]) as usize;
+                self.phase = Phase::Headers {
+                    storage_header,
+                    headers_len,
+                };
+            }
+            Phase::Headers {
+                storage_header,
+                headers_len,
+            } => {
+                let headers = &self.buf[framing_len..self.filled];
+                let res = if eof {
+                    Err(truncated(headers_len, headers.len()))
+                } else {
+                    message_size(headers, self.max_message_size)
+                };
+                match res {
+                    Ok(size) => {
+                        self.phase = Phase::Payload {
+                            storage_header,
+                            headers_len,
+                            size,
+                        }
+                    }
+                    Err(error @ DltPluginError::MessageTooLarge { size, .. }) => {
+                        // Skip the payload without buffering it, so
+                        // reading can carry on with the next message
+                        self.phase = Phase::Skipping {
+                            error,
+                            size,
+                            skipped: headers_len,
+                        }
+                    }
+                    Err(e) => return Err(self.message_error(e, framing_len)),
+                }
+            }
+            Phase::Payload {
+                storage_header,
+                headers_len,
+                size,
+            } => {
+                let message = &self.buf[framing_len..self.filled];
+                let res = if eof {
+                    Err(truncated(size, message.len()))
+                } else {
+                    parse_message(message, headers_len).map(|message| (message, size))
+                };
+                return self
+                    .record(res, storage_header, framing_len)
+                    .map(|record| Some(Some(record)));
+            }
+            Phase::Skipping {
+                error,
+                size,
+                skipped,
+            } => {
+                let error = if eof { truncated(size, skipped) } else { error };
+                return Err(self.message_error(error, framing_len));
+            }
+        }
+        Ok(None)
+    }
+
+    fn storage_header(&self) -> Result<StorageHeader, DltPluginError> {
+        let Ok(buf) = self.buf[..self.filled].try_into() else {
+            return Err(DltPluginError::Truncated {
+                offset: self.offset,
+                expected: STORAGE_HEADER_LEN,
+                available: self.filled,
+            });
+        };
+        parse_storage_header(buf).map_err(|e| e.at_offset(self.offset))
+    }
+
+    fn serial_header(&self) -> Result<(), DltPluginError> {
+        let buf = &self.buf[..self.filled];
+        if buf.len() < SERIAL_HEADER_PATTERN.len() {
+            return Err(DltPluginError::Truncated {
+                offset: self.offset,
+                expected: SERIAL_HEADER_PATTERN.len(),
+                available: buf.len(),
+            });
+        }
+        if *buf != SERIAL_HEADER_PATTERN {
+            return Err(DltPluginError::InvalidSerialHeader {
+                offset: self.offset,
+                header: buf.to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The input ended right after a framing header, before the message
+    fn truncated_after_framing(&self, framing_len: usize) -> DltPluginError {
+        DltPluginError::Truncated {
+            offset: self.offset,
+            expected: framing_len + 1,
+            available: framing_len,
+        }
+    }
+
+    /// Turn the outcome of reading a message into a record, keeping
+    /// track of the offset.
+    fn record(
+        &mut self,
+        res: Result<(ParsedMessage, usize), DltPluginError>,
+        storage_header: Option<StorageHeader>,
+        framing_len: usize,
+    ) -> Result<DltRecord, DltPluginError> {
+        let (message, message_len) = match res {
+            Ok(res) => res,
+            Err(e) => return Err(self.message_error(e, framing_len)),
+        };
+
+        let size = (framing_len + message_len) as u64;
+        let record = DltRecord {
+            message,
+            offset: self.offset,
+            size,
+            storage_header,
+            received_at: SystemTime::now(),
+        };
+        self.offset += size;
+        Ok(record)
+    }
+
+    /// Make `e`, from reading a message, relative to the stream rather
+    /// than the message, and move past the message if it was skipped.
+    fn message_error(&mut self, e: DltPluginError, framing_len: usize) -> DltPluginError {
+        let e = e.at_offset(self.offset + framing_len as u64);
+        if let Some(size) = e.skipped_message_size() {
+            self.offset += (framing_len + size) as u64;
+        }
+        e
+    }
+}
+
+/// The message ended after `available` of its `expected` bytes
+fn truncated(expected: usize, available: usize) -> DltPluginError {
+    DltPluginError::Truncated {
+        offset: 0,
+        expected,
+        available,
+    }
+}
+
+/// Parse a complete storage header: the `DLT\x01` pattern, followed by
+/// the seconds and microseconds of the timestamp (both little endian)
+/// and the ECU id.
+pub fn parse_storage_header(
+    buf: &[u8; STORAGE_HEADER_LEN],
+) -> Result<StorageHeader, DltPluginError> {
+    if buf[0..4] != STORAGE_HEADER_PATTERN {
+        return Err(DltPluginError::InvalidStorageHeader {
+            offset: 0,
+            header: buf.to_vec(),
+        });
+    }
+
+    let seconds = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    let microseconds = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+    let ecu_id = String::from_utf8_lossy(&buf[12..16])
+        .trim_end_matches('\0')
+        .to_string();
+
+    Ok(StorageHeader {
+        timestamp: DltTimeStamp {
+            seconds,
+            microseconds,
+        },
+        ecu_id,
+    })
+}
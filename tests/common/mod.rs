@@ -0,0 +1,135 @@
+//! Fixtures shared by the integration tests. Not every test uses all
+//! of them.
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use auxon_sdk::{
+    api::{AttrKey, AttrVal, TimelineId},
+    plugin_utils::ingest::Config,
+};
+use dlt_core::{
+    dlt::{self, LogLevel},
+    parse::ParsedMessage,
+};
+use modality_dlt::{
+    collect::CollectorConfig,
+    encode::verbose_argument,
+    import::ImporterConfig,
+    mock::{log_message, MockIngest},
+    send::HasCommonConfig,
+    sink::IngestSink,
+    CommonConfig, DltPluginError,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize)]
+pub struct TestConfig {
+    #[serde(flatten)]
+    pub common: CommonConfig,
+}
+
+impl HasCommonConfig for TestConfig {
+    fn common_config(&self) -> &CommonConfig {
+        &self.common
+    }
+}
+
+/// The default configuration, apart from what `configure` changes.
+///
+/// Options are set on the loaded config rather than through
+/// environment variables: tests run in parallel, and setting the
+/// environment while another thread reads it isn't safe. Nothing uses
+/// this prefix, so loading only picks up the defaults.
+pub fn config(configure: impl FnOnce(&mut CommonConfig)) -> Config<TestConfig> {
+    let mut config = Config::<TestConfig>::load("MODALITY_DLT_TEST_UNUSED_").unwrap();
+    configure(&mut config.plugin.common);
+    config
+}
+
+/// The collector's default configuration, apart from what `configure`
+/// changes; see [config].
+pub fn collector_config(configure: impl FnOnce(&mut CollectorConfig)) -> Config<CollectorConfig> {
+    let mut config = Config::<CollectorConfig>::load("MODALITY_DLT_TEST_UNUSED_").unwrap();
+    configure(&mut config.plugin);
+    config
+}
+
+/// The importer's default configuration, apart from what `configure`
+/// changes; see [config].
+pub fn importer_config(configure: impl FnOnce(&mut ImporterConfig)) -> Config<ImporterConfig> {
+    let mut config = Config::<ImporterConfig>::load("MODALITY_DLT_TEST_UNUSED_").unwrap();
+    configure(&mut config.plugin);
+    config
+}
+
+/// An info message with `text` as its only argument, named "text"
+pub fn text_message(
+    ecu_id: &str,
+    application_id: &str,
+    context_id: &str,
+    text: &str,
+) -> dlt::Message {
+    log_message(
+        ecu_id,
+        application_id,
+        context_id,
+        LogLevel::Info,
+        vec![verbose_argument(
+            Some("text".to_owned()),
+            dlt::Value::StringVal(text.to_owned()),
+        )],
+    )
+}
+
+/// [text_message] from context CTX1
+pub fn log(ecu_id: &str, application_id: &str, text: &str) -> ParsedMessage {
+    ParsedMessage::Item(text_message(ecu_id, application_id, "CTX1", text))
+}
+
+/// The text of every event made from a [text_message], in order
+pub fn texts(ingest: &MockIngest) -> Vec<String> {
+    ingest
+        .events
+        .iter()
+        .filter_map(|ev| match ev.attr("event.payload.text") {
+            Some(AttrVal::String(s)) => Some(s.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A [MockIngest] which can be looked at while something else owns
+/// it as a sink, and which outlives reconnections
+#[derive(Clone, Default)]
+pub struct SharedIngest(pub Arc<Mutex<MockIngest>>);
+
+#[async_trait]
+impl IngestSink for SharedIngest {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), DltPluginError> {
+        self.0.lock().await.switch_timeline(id).await
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: &[(&'static str, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        self.0.lock().await.send_timeline_attrs(name, attrs).await
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: &[(AttrKey, AttrVal)],
+    ) -> Result<(), DltPluginError> {
+        self.0.lock().await.send_event(name, ordering, attrs).await
+    }
+
+    async fn flush(&mut self) -> Result<(), DltPluginError> {
+        self.0.lock().await.flush().await
+    }
+}
@@ -0,0 +1,120 @@
+//! Control requests, as encoded for the daemon and parsed back.
+
+use dlt_core::{
+    dlt::{self, ControlType},
+    parse::{dlt_message, ParsedMessage},
+};
+use modality_dlt::control::{
+    self, ControlLogLevel, ControlRequest, ControlTraceStatus, SERVICE_INJECTION_MIN,
+};
+
+/// Encode `request`, parse it again, and check everything a request
+/// carries in its headers. Returns its payload.
+fn round_trip(request: &ControlRequest, application_id: &str, context_id: &str) -> Vec<u8> {
+    let bytes = request.to_bytes("ECU9", 42);
+    let (rest, parsed) = dlt_message(&bytes, None, false).unwrap();
+    assert!(rest.is_empty());
+    let ParsedMessage::Item(msg) = parsed else {
+        panic!("unexpected message {parsed:?}");
+    };
+
+    assert_eq!(msg.header.ecu_id.as_deref(), Some("ECU9"));
+    assert_eq!(msg.header.message_counter, 42);
+    assert!(matches!(msg.header.endianness, dlt::Endianness::Little));
+    let extended_header = msg.extended_header.as_ref().unwrap();
+    assert!(matches!(
+        extended_header.message_type,
+        dlt::MessageType::Control(ControlType::Request)
+    ));
+    assert!(!extended_header.verbose);
+    assert_eq!(extended_header.argument_count, 1);
+    assert_eq!(extended_header.application_id, application_id);
+    assert_eq!(extended_header.context_id, context_id);
+
+    assert_eq!(
+        control::request_service_id(&msg),
+        Some(request.service_id())
+    );
+    // Nothing is lost in parsing
+    assert_eq!(msg.as_bytes(), bytes);
+    control::control_payload(&msg).unwrap()
+}
+
+fn payload(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+}
+
+#[test]
+fn set_log_level() {
+    let request = ControlRequest::SetLogLevel {
+        application_id: "APP1".to_owned(),
+        context_id: "CTX1".to_owned(),
+        log_level: ControlLogLevel::Debug,
+    };
+    assert_eq!(
+        round_trip(&request, "MDLT", "CTRL"),
+        payload(&[&[0x01, 0, 0, 0], b"APP1", b"CTX1", &[5], b"remo"])
+    );
+}
+
+#[test]
+fn set_trace_status() {
+    let request = ControlRequest::SetTraceStatus {
+        application_id: "APP1".to_owned(),
+        context_id: "CTX1".to_owned(),
+        trace_status: ControlTraceStatus::On,
+    };
+    assert_eq!(
+        round_trip(&request, "MDLT", "CTRL"),
+        payload(&[&[0x02, 0, 0, 0], b"APP1", b"CTX1", &[1], b"remo"])
+    );
+}
+
+#[test]
+fn set_default_log_level() {
+    let request = ControlRequest::SetDefaultLogLevel {
+        log_level: ControlLogLevel::Default,
+    };
+    assert_eq!(
+        round_trip(&request, "MDLT", "CTRL"),
+        payload(&[&[0x11, 0, 0, 0], &[0xff], b"remo"])
+    );
+}
+
+#[test]
+fn get_software_version() {
+    assert_eq!(
+        round_trip(&ControlRequest::GetSoftwareVersion, "MDLT", "CTRL"),
+        vec![0x13, 0, 0, 0]
+    );
+}
+
+#[test]
+fn inject() {
+    let request = ControlRequest::Inject {
+        application_id: "APP1".to_owned(),
+        context_id: "CTX1".to_owned(),
+        service_id: SERVICE_INJECTION_MIN + 1,
+        data: vec![1, 2, 3],
+    };
+    assert_eq!(
+        round_trip(&request, "APP1", "CTX1"),
+        payload(&[&[0x00, 0x10, 0, 0], &[3, 0, 0, 0], &[1, 2, 3]])
+    );
+}
+
+#[test]
+fn short_ids_are_padded() {
+    let request = ControlRequest::SetLogLevel {
+        application_id: "A".to_owned(),
+        context_id: "CT".to_owned(),
+        log_level: ControlLogLevel::Off,
+    };
+    let bytes = request.to_bytes("E", 0);
+    // After the standard header, and the message info and argument count
+    assert_eq!(&bytes[4..8], b"E\0\0\0");
+    assert_eq!(
+        &bytes[18..],
+        payload(&[&[0x01, 0, 0, 0], b"A\0\0\0", b"CT\0\0", &[0], b"remo"])
+    );
+}
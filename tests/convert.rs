@@ -0,0 +1,64 @@
+//! Rendering verbose messages as text, in `event.message`.
+
+use auxon_sdk::api::AttrVal;
+use dlt_core::dlt::{self, LogLevel};
+use modality_dlt::{
+    convert::dlt_message_to_event_attrs, encode::verbose_argument, mock::log_message, CommonConfig,
+};
+
+/// The `event.message` attribute of a log message with `args`, if any
+fn rendered(args: Vec<dlt::Argument>, render_message: bool) -> Option<AttrVal> {
+    let config = CommonConfig {
+        render_message: Some(render_message),
+        ..Default::default()
+    };
+    let msg = log_message("ECU1", "APP1", "CTX1", LogLevel::Info, args);
+    dlt_message_to_event_attrs(&msg, &config)
+        .into_iter()
+        .find_map(|(k, v)| (k.as_ref() == "event.message").then_some(v))
+}
+
+fn args(values: Vec<dlt::Value>) -> Vec<dlt::Argument> {
+    values
+        .into_iter()
+        .map(|value| verbose_argument(None, value))
+        .collect()
+}
+
+#[test]
+fn arguments_are_separated_by_single_spaces() {
+    // Names aren't shown, only values
+    let mut args = args(vec![
+        dlt::Value::StringVal("Engine".to_owned()),
+        dlt::Value::U16(2000),
+        dlt::Value::StringVal("rpm".to_owned()),
+    ]);
+    args[1].name = Some("speed".to_owned());
+    assert_eq!(rendered(args, true), Some(AttrVal::from("Engine 2000 rpm")));
+}
+
+#[test]
+fn values_are_shown_the_way_dlt_viewer_does() {
+    let values = vec![
+        dlt::Value::Bool(1),
+        dlt::Value::Bool(0),
+        dlt::Value::I8(-3),
+        dlt::Value::U64(u64::MAX),
+        dlt::Value::F32(1.5),
+        dlt::Value::F64(-0.25),
+        dlt::Value::Raw(vec![0x01, 0xab, 0x00]),
+    ];
+    assert_eq!(
+        rendered(args(values), true),
+        Some(AttrVal::from(
+            "true false -3 18446744073709551615 1.5 -0.25 01ab00"
+        ))
+    );
+}
+
+#[test]
+fn messages_are_only_rendered_when_asked() {
+    let values = vec![dlt::Value::StringVal("hello".to_owned())];
+    assert_eq!(rendered(args(values.clone()), false), None);
+    assert_eq!(rendered(args(values), true), Some(AttrVal::from("hello")));
+}
@@ -0,0 +1,183 @@
+//! Linking sends and receives through the correlation ids they carry.
+
+use auxon_sdk::api::{AttrKey, AttrVal, TimelineId};
+use dlt_core::dlt::{self, LogLevel};
+use modality_dlt::{
+    correlate::{CorrelationEndpoint, CorrelationRule, Correlator, MAX_REMEMBERED_SENDS},
+    encode::verbose_argument,
+    mock::log_message,
+};
+
+fn endpoint(application_id: Option<&str>, argument: &str) -> CorrelationEndpoint {
+    CorrelationEndpoint {
+        ecu_id: None,
+        application_id: application_id.map(str::to_owned),
+        context_id: None,
+        argument: argument.to_owned(),
+    }
+}
+
+fn rule(send: CorrelationEndpoint, receive: CorrelationEndpoint) -> CorrelationRule {
+    CorrelationRule {
+        name: None,
+        send,
+        receive,
+    }
+}
+
+/// A message from `application_id` on ECU1, with a named `request_id`
+/// argument after an unnamed one
+fn message(application_id: &str, request_id: u32) -> dlt::Message {
+    log_message(
+        "ECU1",
+        application_id,
+        "CTX1",
+        LogLevel::Info,
+        vec![
+            verbose_argument(None, dlt::Value::StringVal("request".to_owned())),
+            verbose_argument(Some("request_id".to_owned()), dlt::Value::U32(request_id)),
+        ],
+    )
+}
+
+/// Runs messages through a [Correlator], all on one timeline, handing
+/// out nonces in order
+struct Harness {
+    correlator: Correlator,
+    timeline_id: TimelineId,
+    next_nonce: i64,
+}
+
+impl Harness {
+    fn new(rules: Vec<CorrelationRule>) -> Self {
+        Harness {
+            correlator: Correlator::new(rules),
+            timeline_id: TimelineId::allocate(),
+            next_nonce: 0,
+        }
+    }
+
+    /// The nonce `msg` got as a send, and the nonce of the send it was
+    /// linked to as a receive
+    fn correlate(&mut self, msg: &dlt::Message) -> (Option<i64>, Option<i64>) {
+        let mut attrs = vec![];
+        let next_nonce = &mut self.next_nonce;
+        self.correlator
+            .correlate(msg, self.timeline_id, &mut attrs, || {
+                *next_nonce += 1;
+                *next_nonce
+            });
+        (
+            integer(&attrs, "event.nonce"),
+            integer(&attrs, "event.interaction.remote_nonce"),
+        )
+    }
+}
+
+fn integer(attrs: &[(AttrKey, AttrVal)], key: &str) -> Option<i64> {
+    attrs.iter().find_map(|(k, v)| match v {
+        AttrVal::Integer(n) if k.as_ref() == key => Some(*n),
+        _ => None,
+    })
+}
+
+#[test]
+fn matches_by_argument_name() {
+    let mut harness = Harness::new(vec![rule(
+        endpoint(Some("CLNT"), "request_id"),
+        endpoint(Some("SRVR"), "request_id"),
+    )]);
+    assert_eq!(harness.correlate(&message("CLNT", 1)), (Some(1), None));
+    assert_eq!(harness.correlate(&message("CLNT", 2)), (Some(2), None));
+    assert_eq!(harness.correlate(&message("SRVR", 2)), (None, Some(2)));
+    assert_eq!(harness.correlate(&message("SRVR", 1)), (None, Some(1)));
+    // Never sent
+    assert_eq!(harness.correlate(&message("SRVR", 3)), (None, None));
+    // Neither side
+    assert_eq!(harness.correlate(&message("OTHR", 1)), (None, None));
+}
+
+#[test]
+fn matches_by_argument_position() {
+    let mut harness = Harness::new(vec![rule(
+        endpoint(Some("CLNT"), "1"),
+        endpoint(Some("SRVR"), "request_id"),
+    )]);
+    assert_eq!(harness.correlate(&message("CLNT", 7)), (Some(1), None));
+    assert_eq!(harness.correlate(&message("SRVR", 7)), (None, Some(1)));
+
+    // The first argument is the same for every message
+    let mut harness = Harness::new(vec![rule(
+        endpoint(Some("CLNT"), "0"),
+        endpoint(Some("SRVR"), "0"),
+    )]);
+    assert_eq!(harness.correlate(&message("CLNT", 7)), (Some(1), None));
+    assert_eq!(harness.correlate(&message("SRVR", 8)), (None, Some(1)));
+
+    // Out of range
+    let mut harness = Harness::new(vec![rule(
+        endpoint(Some("CLNT"), "2"),
+        endpoint(Some("SRVR"), "1"),
+    )]);
+    assert_eq!(harness.correlate(&message("CLNT", 7)), (None, None));
+}
+
+#[test]
+fn unset_ids_match_anything() {
+    let mut harness = Harness::new(vec![rule(
+        endpoint(Some("CLNT"), "request_id"),
+        endpoint(None, "request_id"),
+    )]);
+    assert_eq!(harness.correlate(&message("CLNT", 1)), (Some(1), None));
+    assert_eq!(harness.correlate(&message("SRVR", 1)), (None, Some(1)));
+    assert_eq!(harness.correlate(&message("OTHR", 1)), (None, Some(1)));
+
+    // ...but set ones must match
+    let mut wrong_ecu = endpoint(None, "request_id");
+    wrong_ecu.ecu_id = Some("ECU2".to_owned());
+    let mut harness = Harness::new(vec![rule(wrong_ecu, endpoint(None, "request_id"))]);
+    assert_eq!(harness.correlate(&message("CLNT", 1)), (None, None));
+}
+
+#[test]
+fn messages_cannot_link_to_themselves() {
+    // Every message is both a send and a receive
+    let mut harness = Harness::new(vec![rule(
+        endpoint(None, "request_id"),
+        endpoint(None, "request_id"),
+    )]);
+    assert_eq!(harness.correlate(&message("APP1", 1)), (Some(1), None));
+    assert_eq!(harness.correlate(&message("APP2", 1)), (Some(2), Some(1)));
+    assert_eq!(harness.correlate(&message("APP3", 1)), (Some(3), Some(2)));
+}
+
+#[test]
+fn oldest_sends_are_forgotten() {
+    let mut harness = Harness::new(vec![rule(
+        endpoint(Some("CLNT"), "request_id"),
+        endpoint(Some("SRVR"), "request_id"),
+    )]);
+    for request_id in 0..=MAX_REMEMBERED_SENDS as u32 {
+        harness.correlate(&message("CLNT", request_id));
+    }
+    assert_eq!(harness.correlate(&message("SRVR", 0)), (None, None));
+    assert_eq!(harness.correlate(&message("SRVR", 1)), (None, Some(2)));
+}
+
+#[test]
+fn repeated_sends_are_remembered_as_the_latest() {
+    let mut harness = Harness::new(vec![rule(
+        endpoint(Some("CLNT"), "request_id"),
+        endpoint(Some("SRVR"), "request_id"),
+    )]);
+    for request_id in 0..MAX_REMEMBERED_SENDS as u32 {
+        harness.correlate(&message("CLNT", request_id));
+    }
+    // Sent again, so now it's the newest rather than the oldest
+    let (nonce, _) = harness.correlate(&message("CLNT", 0));
+    harness.correlate(&message("CLNT", MAX_REMEMBERED_SENDS as u32));
+
+    assert_eq!(harness.correlate(&message("SRVR", 0)), (None, nonce));
+    assert_eq!(harness.correlate(&message("SRVR", 1)), (None, None));
+    assert_eq!(harness.correlate(&message("SRVR", 2)), (None, Some(3)));
+}
@@ -0,0 +1,75 @@
+//! Decoding CAN frames against a DBC file.
+
+use modality_dlt::dbc::{Dbc, DbcError};
+
+const DBC: &str = r#"
+VERSION ""
+
+BU_: ECU GW
+
+BO_ 100 EngineData: 8 ECU
+ SG_ EngineSpeed : 0|16@1+ (0.25,0) [0|16383.75] "rpm" GW
+ SG_ Temperature : 16|8@1- (0.5,0) [-64|63.5] "degC" GW
+ SG_ Pressure : 31|12@0+ (1,0) [0|4095] "kPa" GW
+ SG_ Torque : 43|12@0- (0.5,0) [-1024|1023.5] "Nm" GW
+
+BO_ 2147484672 Extended: 2 GW
+ SG_ Mode M : 0|4@1+ (1,0) [0|15] "" ECU
+ SG_ Level m1 : 4|4@1+ (1,10) [10|25] "" ECU
+"#;
+
+fn dbc() -> Dbc {
+    Dbc::parse(DBC).unwrap()
+}
+
+#[test]
+fn decodes_intel_and_motorola_signals() {
+    let data = [0x10, 0x27, 0xf6, 0x12, 0x3f, 0xaf, 0x9c, 0x00];
+    let (name, signals) = dbc().decode(100, &data).unwrap();
+    assert_eq!(name, "EngineData");
+    assert_eq!(
+        signals,
+        vec![
+            // Little endian, across bytes 0 and 1: 0x2710
+            ("EngineSpeed", 2500.0),
+            // 0xf6 is -10
+            ("Temperature", -5.0),
+            // Big endian, from the top of byte 3 into the top of byte 4: 0x123
+            ("Pressure", 291.0),
+            // Big endian, from bit 3 of byte 5 into byte 6: 0xf9c is -100
+            ("Torque", -50.0),
+        ]
+    );
+}
+
+#[test]
+fn signals_beyond_the_frame_are_left_out() {
+    let (_, signals) = dbc().decode(100, &[0x10, 0x27, 0xf6, 0x12]).unwrap();
+    assert_eq!(
+        signals,
+        vec![("EngineSpeed", 2500.0), ("Temperature", -5.0)]
+    );
+}
+
+#[test]
+fn extended_ids_lose_their_flag() {
+    let (name, signals) = dbc().decode(1024, &[0x51]).unwrap();
+    assert_eq!(name, "Extended");
+    assert_eq!(signals, vec![("Mode", 1.0), ("Level", 15.0)]);
+}
+
+#[test]
+fn unknown_ids_are_not_decoded() {
+    assert!(dbc().decode(101, &[0; 8]).is_none());
+}
+
+#[test]
+fn invalid_signals_are_reported_with_their_line() {
+    let err =
+        Dbc::parse("BO_ 100 EngineData: 8 ECU\n SG_ EngineSpeed : 0|65@1+ (1,0) [0|0] \"\" GW\n")
+            .unwrap_err();
+    assert!(matches!(err, DbcError::Syntax { line: 2, .. }), "{err}");
+
+    let err = Dbc::parse(" SG_ EngineSpeed : 0|16@1+ (1,0) [0|0] \"\" GW\n").unwrap_err();
+    assert!(matches!(err, DbcError::Syntax { line: 1, .. }), "{err}");
+}
@@ -0,0 +1,85 @@
+//! The JSON Lines output written instead of sending to Modality.
+
+mod common;
+
+use auxon_sdk::api::{AttrVal, TimelineId};
+use dlt_core::parse::ParsedMessage;
+use modality_dlt::{
+    export::event_attrs_from_json,
+    send::Sender,
+    sink::{IngestSink, JsonLinesSink},
+};
+use serde_json::Value;
+
+use common::config;
+
+fn log(ecu_id: &str, text: &str) -> ParsedMessage {
+    common::log(ecu_id, "APP1", text)
+}
+
+async fn dry_run(messages: Vec<ParsedMessage>) -> Vec<String> {
+    let mut sender = Sender::new(JsonLinesSink::new(Vec::new()), config(|_| ()));
+    for msg in messages {
+        sender.handle_message(msg).await.unwrap();
+    }
+    sender.finish().await.unwrap();
+
+    let out = String::from_utf8(sender.sink().get_ref().clone()).unwrap();
+    out.lines().map(ToOwned::to_owned).collect()
+}
+
+#[tokio::test]
+async fn writes_timelines_before_their_events() {
+    let lines = dry_run(vec![log("ECU1", "one"), log("ECU2", "two")]).await;
+    let lines: Vec<Value> = lines
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let types: Vec<&str> = lines.iter().map(|l| l["type"].as_str().unwrap()).collect();
+    assert_eq!(types, vec!["timeline", "event", "timeline", "event"]);
+
+    let timeline = &lines[0];
+    assert_eq!(timeline["name"], "ECU1");
+    assert_eq!(timeline["attrs"]["timeline.ecu_id"], "ECU1");
+
+    let event = &lines[1];
+    assert_eq!(event["timeline_id"], timeline["timeline_id"]);
+    assert_eq!(event["name"], "log");
+    assert_eq!(event["ordering"], 0);
+    assert_eq!(event["attrs"]["event.payload.text"], "one");
+    assert_eq!(event["attrs"]["event.log_level"], "info");
+
+    assert_ne!(lines[2]["timeline_id"], timeline["timeline_id"]);
+    assert_eq!(lines[3]["ordering"], 1);
+}
+
+#[tokio::test]
+async fn event_lines_can_be_exported() {
+    let lines = dry_run(vec![log("ECU1", "one")]).await;
+    let attrs = event_attrs_from_json(&lines[1]).unwrap();
+    let text = attrs
+        .iter()
+        .find(|(k, _)| k.as_ref() == "event.payload.text")
+        .map(|(_, v)| v);
+    assert_eq!(text, Some(&AttrVal::from("one")));
+}
+
+#[tokio::test]
+async fn orderings_beyond_64_bits_are_an_error() {
+    let mut sink = JsonLinesSink::new(Vec::new());
+    sink.switch_timeline(TimelineId::allocate()).await.unwrap();
+    sink.send_event("log", u64::MAX as u128, &[]).await.unwrap();
+    assert!(sink
+        .send_event("log", u64::MAX as u128 + 1, &[])
+        .await
+        .is_err());
+
+    // Rather than being written truncated
+    let out = String::from_utf8(sink.into_inner()).unwrap();
+    let lines: Vec<Value> = out
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["ordering"], u64::MAX);
+}
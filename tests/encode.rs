@@ -0,0 +1,361 @@
+//! Encoding messages, and reading them back as they were.
+
+use dlt_core::{
+    dlt::{self, ApplicationTraceType, ControlType, LogLevel, MessageType, NetworkTraceType},
+    parse::ParsedMessage,
+};
+use modality_dlt::{
+    control::{ControlStatus, SERVICE_GET_SOFTWARE_VERSION},
+    encode::{encode, encode_framed, verbose_argument},
+    mock::control_response,
+    read_dlt_message_sync,
+    stream::{DltStream, Framing},
+};
+use proptest::{collection::vec, option, prelude::*, sample::select};
+
+/// Check that `msg` reads back as itself, both without framing and
+/// with each kind of framing
+fn round_trip(msg: &dlt::Message) {
+    let bytes = encode(msg).unwrap();
+    let ParsedMessage::Item(reread) = read_dlt_message_sync(bytes.as_slice()).unwrap() else {
+        panic!("encoded message didn't read back as a message");
+    };
+    assert_eq!(&reread, msg);
+
+    for framing in [Framing::Raw, Framing::StorageHeader, Framing::SerialHeader] {
+        let framed = encode_framed(msg, framing, None).unwrap();
+        let mut records = DltStream::new(framed.as_slice(), framing);
+        let record = records.next().unwrap().unwrap();
+        assert!(records.next().is_none());
+        assert_eq!(record.size, framed.len() as u64);
+        let ParsedMessage::Item(reread) = record.message else {
+            panic!("encoded message didn't read back as a message");
+        };
+        assert_eq!(&reread, msg, "{framing:?}");
+    }
+}
+
+fn message(
+    endianness: dlt::Endianness,
+    payload: dlt::PayloadContent,
+    message_type: Option<MessageType>,
+) -> dlt::Message {
+    dlt::Message::new(
+        dlt::MessageConfig {
+            version: 1,
+            counter: 7,
+            endianness,
+            ecu_id: Some("ECU1".to_owned()),
+            session_id: Some(1234),
+            timestamp: Some(56789),
+            payload,
+            extended_header_info: message_type.map(|message_type| dlt::ExtendedHeaderConfig {
+                message_type,
+                app_id: "APP1".to_owned(),
+                context_id: "CTX1".to_owned(),
+            }),
+        },
+        None,
+    )
+}
+
+/// A verbose argument holding `value`. Numbers named `name` get `unit`.
+fn argument(name: Option<&str>, value: dlt::Value) -> dlt::Argument {
+    let is_number = !matches!(
+        value,
+        dlt::Value::Bool(_) | dlt::Value::StringVal(_) | dlt::Value::Raw(_)
+    );
+    let mut arg = verbose_argument(name.map(str::to_owned), value);
+    if is_number {
+        // Only strings say how they're coded
+        arg.type_info.coding = dlt::StringCoding::ASCII;
+        if name.is_some() {
+            arg.unit = Some("unit".to_owned());
+        }
+    }
+    arg
+}
+
+/// Every kind of verbose argument, with and without names
+fn verbose_arguments() -> Vec<dlt::Argument> {
+    let values = [
+        dlt::Value::Bool(1),
+        dlt::Value::U8(u8::MAX),
+        dlt::Value::U16(0x1234),
+        dlt::Value::U32(0x1234_5678),
+        dlt::Value::U64(0x1234_5678_9abc_def0),
+        dlt::Value::U128(u128::MAX - 1),
+        dlt::Value::I8(-8),
+        dlt::Value::I16(-16),
+        dlt::Value::I32(-32),
+        dlt::Value::I64(i64::MIN),
+        dlt::Value::I128(i128::MIN + 1),
+        dlt::Value::F32(-1.5),
+        dlt::Value::F64(1e300),
+        dlt::Value::StringVal("héllo".to_owned()),
+        dlt::Value::Raw(vec![0, 1, 2, 0xff]),
+    ];
+    let mut args = vec![];
+    for (idx, value) in values.into_iter().enumerate() {
+        args.push(argument(None, value.clone()));
+        args.push(argument(Some(&format!("arg{idx}")), value));
+    }
+
+    let mut ascii = argument(Some("ascii"), dlt::Value::StringVal("plain".to_owned()));
+    ascii.type_info.coding = dlt::StringCoding::ASCII;
+    args.push(ascii);
+    args.push(argument(None, dlt::Value::StringVal(String::new())));
+    args
+}
+
+/// Fixed-point arguments, in both widths
+fn fixed_point_arguments() -> Vec<dlt::Argument> {
+    let mut signed = argument(Some("signed"), dlt::Value::I32(-1000));
+    signed.type_info.kind = dlt::TypeInfoKind::SignedFixedPoint(dlt::FloatWidth::Width32);
+    signed.fixed_point = Some(dlt::FixedPoint {
+        quantization: 0.25,
+        offset: dlt::FixedPointValue::I32(-40),
+    });
+
+    let mut unsigned = argument(None, dlt::Value::U64(1 << 40));
+    unsigned.type_info.kind = dlt::TypeInfoKind::UnsignedFixedPoint(dlt::FloatWidth::Width64);
+    unsigned.fixed_point = Some(dlt::FixedPoint {
+        quantization: 0.001,
+        offset: dlt::FixedPointValue::I64(1 << 33),
+    });
+
+    vec![signed, unsigned]
+}
+
+#[test]
+fn verbose_messages() {
+    for endianness in [dlt::Endianness::Little, dlt::Endianness::Big] {
+        round_trip(&message(
+            endianness,
+            dlt::PayloadContent::Verbose(verbose_arguments()),
+            Some(MessageType::Log(LogLevel::Warn)),
+        ));
+    }
+}
+
+#[test]
+fn fixed_point_arguments_round_trip() {
+    for endianness in [dlt::Endianness::Little, dlt::Endianness::Big] {
+        round_trip(&message(
+            endianness,
+            dlt::PayloadContent::Verbose(fixed_point_arguments()),
+            Some(MessageType::Log(LogLevel::Info)),
+        ));
+    }
+}
+
+#[test]
+fn verbose_messages_without_optional_headers() {
+    for args in [vec![], verbose_arguments()] {
+        round_trip(&dlt::Message::new(
+            dlt::MessageConfig {
+                version: 1,
+                counter: 0,
+                endianness: dlt::Endianness::Little,
+                ecu_id: None,
+                session_id: None,
+                timestamp: None,
+                payload: dlt::PayloadContent::Verbose(args),
+                extended_header_info: Some(dlt::ExtendedHeaderConfig {
+                    message_type: MessageType::Log(LogLevel::Fatal),
+                    app_id: "APP1".to_owned(),
+                    context_id: "CTX1".to_owned(),
+                }),
+            },
+            None,
+        ));
+    }
+}
+
+#[test]
+fn non_verbose_messages() {
+    for endianness in [dlt::Endianness::Little, dlt::Endianness::Big] {
+        // With and without an extended header
+        for message_type in [None, Some(MessageType::Log(LogLevel::Debug))] {
+            round_trip(&message(
+                endianness,
+                dlt::PayloadContent::NonVerbose(0x1234_5678, vec![1, 2, 3, 4, 5]),
+                message_type,
+            ));
+        }
+    }
+}
+
+#[test]
+fn control_messages() {
+    round_trip(&control_response(
+        "ECU1",
+        SERVICE_GET_SOFTWARE_VERSION,
+        ControlStatus::Ok,
+        &[5, 0, 0, 0, b'1', b'.', b'2', b'.', b'3'],
+    ));
+
+    // A software version request, with its service id split off the
+    // way dlt-core does
+    for (endianness, first, rest) in [
+        (dlt::Endianness::Little, 0x13, vec![0, 0, 0]),
+        (dlt::Endianness::Big, 0, vec![0, 0, 0x13]),
+    ] {
+        round_trip(&message(
+            endianness,
+            dlt::PayloadContent::ControlMsg(ControlType::from_value(first), rest),
+            Some(MessageType::Control(ControlType::Request)),
+        ));
+    }
+}
+
+fn any_endianness() -> impl Strategy<Value = dlt::Endianness> {
+    prop_oneof![Just(dlt::Endianness::Little), Just(dlt::Endianness::Big)]
+}
+
+/// An ECU, application or context id
+fn any_id() -> impl Strategy<Value = String> {
+    "[A-Z0-9]{1,4}"
+}
+
+fn any_message_type() -> impl Strategy<Value = MessageType> {
+    prop_oneof![
+        select(vec![
+            LogLevel::Fatal,
+            LogLevel::Error,
+            LogLevel::Warn,
+            LogLevel::Info,
+            LogLevel::Debug,
+            LogLevel::Verbose,
+        ])
+        .prop_map(MessageType::Log),
+        select(vec![
+            ApplicationTraceType::Variable,
+            ApplicationTraceType::FunctionIn,
+            ApplicationTraceType::FunctionOut,
+            ApplicationTraceType::State,
+            ApplicationTraceType::VFB,
+        ])
+        .prop_map(MessageType::ApplicationTrace),
+        select(vec![
+            NetworkTraceType::Ipc,
+            NetworkTraceType::Can,
+            NetworkTraceType::Flexray,
+            NetworkTraceType::Most,
+            NetworkTraceType::Ethernet,
+            NetworkTraceType::SomeIP,
+        ])
+        .prop_map(MessageType::NetworkTrace),
+    ]
+}
+
+/// Any verbose value. NaN is left out, since it never reads back as
+/// equal to itself.
+fn any_value() -> impl Strategy<Value = dlt::Value> {
+    let unsigned = prop_oneof![
+        any::<u8>().prop_map(dlt::Value::U8),
+        any::<u16>().prop_map(dlt::Value::U16),
+        any::<u32>().prop_map(dlt::Value::U32),
+        any::<u64>().prop_map(dlt::Value::U64),
+        any::<u128>().prop_map(dlt::Value::U128),
+    ];
+    let signed = prop_oneof![
+        any::<i8>().prop_map(dlt::Value::I8),
+        any::<i16>().prop_map(dlt::Value::I16),
+        any::<i32>().prop_map(dlt::Value::I32),
+        any::<i64>().prop_map(dlt::Value::I64),
+        any::<i128>().prop_map(dlt::Value::I128),
+    ];
+    let float = prop_oneof![
+        any::<f32>()
+            .prop_filter("not NaN", |x| !x.is_nan())
+            .prop_map(dlt::Value::F32),
+        any::<f64>()
+            .prop_filter("not NaN", |x| !x.is_nan())
+            .prop_map(dlt::Value::F64),
+    ];
+    prop_oneof![
+        any::<bool>().prop_map(|b| dlt::Value::Bool(b as u8)),
+        unsigned,
+        signed,
+        float,
+        "\\PC{0,20}".prop_map(dlt::Value::StringVal),
+        vec(any::<u8>(), 0..32).prop_map(dlt::Value::Raw),
+    ]
+}
+
+/// Any verbose argument, named or not. Strings are either UTF-8, or
+/// ASCII when they only hold ASCII.
+fn any_argument() -> impl Strategy<Value = dlt::Argument> {
+    let name = || option::of("[a-z][a-z0-9_]{0,10}");
+    prop_oneof![
+        (name(), any_value()).prop_map(|(name, value)| argument(name.as_deref(), value)),
+        (name(), "[ -~]{0,20}").prop_map(|(name, text)| {
+            let mut arg = argument(name.as_deref(), dlt::Value::StringVal(text));
+            arg.type_info.coding = dlt::StringCoding::ASCII;
+            arg
+        }),
+    ]
+}
+
+/// Any verbose or non-verbose message, with or without each optional
+/// header field. Verbose messages need an extended header to say
+/// they're verbose; non-verbose ones may or may not have one.
+fn any_message() -> impl Strategy<Value = dlt::Message> {
+    let payload = prop_oneof![
+        (any_message_type(), vec(any_argument(), 0..8)).prop_map(|(message_type, args)| (
+            Some(message_type),
+            dlt::PayloadContent::Verbose(args)
+        )),
+        (
+            option::of(any_message_type()),
+            any::<u32>(),
+            vec(any::<u8>(), 0..32)
+        )
+            .prop_map(|(message_type, message_id, bytes)| (
+                message_type,
+                dlt::PayloadContent::NonVerbose(message_id, bytes)
+            )),
+    ];
+    (
+        any_endianness(),
+        any::<u8>(),
+        option::of(any_id()),
+        option::of(any::<u32>()),
+        option::of(any::<u32>()),
+        any_id(),
+        any_id(),
+        payload,
+    )
+        .prop_map(
+            |(endianness, counter, ecu_id, session_id, timestamp, app_id, context_id, payload)| {
+                let (message_type, payload) = payload;
+                dlt::Message::new(
+                    dlt::MessageConfig {
+                        version: 1,
+                        counter,
+                        endianness,
+                        ecu_id,
+                        session_id,
+                        timestamp,
+                        payload,
+                        extended_header_info: message_type.map(|message_type| {
+                            dlt::ExtendedHeaderConfig {
+                                message_type,
+                                app_id,
+                                context_id,
+                            }
+                        }),
+                    },
+                    None,
+                )
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn generated_messages_round_trip(msg in any_message()) {
+        round_trip(&msg);
+    }
+}
@@ -0,0 +1,167 @@
+//! Exporting events converted from foo.dlt back to DLT messages, and
+//! reading those again.
+
+use std::path::Path;
+
+use auxon_sdk::api::{AttrKey, AttrVal};
+use dlt_core::{
+    dlt::{self, LogLevel},
+    parse::ParsedMessage,
+};
+use modality_dlt::{
+    convert::dlt_message_to_event_attrs,
+    encode::{encode, verbose_argument},
+    export::{event_attrs_to_dlt_message, DltFileWriter},
+    mock::log_message,
+    read_dlt_message_sync,
+    stream::{DltStream, Framing},
+    CommonConfig,
+};
+
+/// How many records foo.dlt has
+const RECORDS: usize = 15;
+
+/// The messages in `data`, which is in .dlt file format
+fn messages(data: &[u8]) -> Vec<dlt::Message> {
+    Iterator::map(
+        DltStream::new(data, Framing::StorageHeader),
+        |record| match record.unwrap().message {
+            ParsedMessage::Item(msg) => msg,
+            parsed => panic!("unexpected message {parsed:?}"),
+        },
+    )
+    .collect()
+}
+
+fn foo_dlt() -> Vec<dlt::Message> {
+    let data = std::fs::read(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("integration-test/test-data/foo.dlt"),
+    )
+    .unwrap();
+    let messages = messages(&data);
+    assert_eq!(messages.len(), RECORDS);
+    messages
+}
+
+/// The attributes which the export rebuilds messages from, as text.
+/// Numbers are compared as attribute values, which don't keep their
+/// width; see [numbers_are_widened].
+fn exported_attrs(attrs: &[(AttrKey, AttrVal)]) -> Vec<String> {
+    const KEYS: &[&str] = &[
+        "event.ecu_id",
+        "event.session_id",
+        "event.timestamp",
+        "event.application_id",
+        "event.context_id",
+        "event.message_type",
+        "event.log_level",
+        "event.application_trace_type",
+        "event.network_trace_type",
+        "event.control_type",
+        "event.payload_type",
+        "event.message_id",
+        "event.service_id",
+        "event.status",
+    ];
+    attrs
+        .iter()
+        .filter(|(k, _)| KEYS.contains(&k.as_ref()) || k.as_ref().starts_with("event.payload"))
+        .map(|(k, v)| format!("{}={v:?}", k.as_ref()))
+        .collect()
+}
+
+#[test]
+fn exported_messages_convert_to_the_same_attrs() {
+    let config = CommonConfig::default();
+    for msg in foo_dlt() {
+        let attrs = dlt_message_to_event_attrs(&msg, &config);
+        let exported = event_attrs_to_dlt_message(&attrs).unwrap();
+        let bytes = encode(&exported).unwrap();
+        let ParsedMessage::Item(reparsed) = read_dlt_message_sync(bytes.as_slice()).unwrap() else {
+            panic!("exported message didn't read back as a message");
+        };
+        assert_eq!(
+            exported_attrs(&dlt_message_to_event_attrs(&reparsed, &config)),
+            exported_attrs(&attrs)
+        );
+    }
+}
+
+#[test]
+fn exported_files_convert_to_the_same_attrs() {
+    let config = CommonConfig::default();
+    let messages = foo_dlt();
+    let mut writer = DltFileWriter::new(vec![]);
+    for msg in &messages {
+        writer
+            .write_event(&dlt_message_to_event_attrs(msg, &config))
+            .unwrap();
+    }
+    let exported = messages(&writer.into_inner());
+
+    assert_eq!(exported.len(), messages.len());
+    for (idx, (msg, exported)) in messages.iter().zip(&exported).enumerate() {
+        assert_eq!(
+            exported_attrs(&dlt_message_to_event_attrs(exported, &config)),
+            exported_attrs(&dlt_message_to_event_attrs(msg, &config)),
+            "message {idx}"
+        );
+    }
+
+    // Numbered per ECU, from 0
+    let counters: Vec<u8> = exported
+        .iter()
+        .map(|msg| msg.header.message_counter)
+        .collect();
+    assert_eq!(counters, (0..RECORDS as u8).collect::<Vec<_>>());
+}
+
+/// Attributes don't say how wide a number was, so the export writes the
+/// widest DLT types: values survive the round trip, but their types
+/// don't.
+#[test]
+fn numbers_are_widened() {
+    let config = CommonConfig::default();
+    let args = [
+        dlt::Value::U8(7),
+        dlt::Value::I16(-300),
+        dlt::Value::U32(70000),
+        dlt::Value::I64(-1),
+        dlt::Value::F32(1.5),
+        dlt::Value::F64(0.25),
+        dlt::Value::Bool(1),
+        dlt::Value::StringVal("text".to_owned()),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(idx, value)| verbose_argument(Some(format!("arg{idx}")), value))
+    .collect();
+    let msg = log_message("ECU1", "APP1", "CTX1", LogLevel::Info, args);
+
+    let attrs = dlt_message_to_event_attrs(&msg, &config);
+    let exported = event_attrs_to_dlt_message(&attrs).unwrap();
+    let dlt::PayloadContent::Verbose(args) = &exported.payload else {
+        panic!("exported message isn't verbose");
+    };
+    let values: Vec<&dlt::Value> = args.iter().map(|arg| &arg.value).collect();
+    assert_eq!(
+        values,
+        vec![
+            &dlt::Value::I64(7),
+            &dlt::Value::I64(-300),
+            &dlt::Value::I64(70000),
+            &dlt::Value::I64(-1),
+            &dlt::Value::F64(1.5),
+            &dlt::Value::F64(0.25),
+            &dlt::Value::Bool(1),
+            &dlt::Value::StringVal("text".to_owned()),
+        ]
+    );
+    assert_ne!(exported.payload, msg.payload);
+
+    // Which makes no difference once it's converted again
+    assert_eq!(
+        exported_attrs(&dlt_message_to_event_attrs(&exported, &config)),
+        exported_attrs(&attrs)
+    );
+}
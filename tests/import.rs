@@ -0,0 +1,145 @@
+//! Importing .dlt files, including ones cut off partway through the
+//! last record.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use auxon_sdk::api::AttrVal;
+use futures::StreamExt;
+use modality_dlt::{
+    import::Importer,
+    metrics::{Metrics, Summary},
+    stream::{DltStream, Framing},
+    DltPluginError,
+};
+
+use common::{importer_config, SharedIngest};
+
+/// How many records foo.dlt has
+const RECORDS: usize = 15;
+/// Where its last record's storage header starts
+const LAST_RECORD_OFFSET: u64 = 1167;
+/// Where its last message starts, after the storage header
+const LAST_MESSAGE_OFFSET: u64 = LAST_RECORD_OFFSET + 16;
+const LAST_MESSAGE_SIZE: usize = 56;
+
+/// A copy of foo.dlt, cut off after `len` bytes, named `name`
+fn truncated_foo_dlt(name: &str, len: usize) -> PathBuf {
+    let data = std::fs::read(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("integration-test/test-data/foo.dlt"),
+    )
+    .unwrap();
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{name}.dlt"));
+    std::fs::write(&path, &data[..len]).unwrap();
+    path
+}
+
+/// Read every record of `path`, checking everything but the last is a
+/// message, and return the last.
+async fn last_record(path: &Path) -> DltPluginError {
+    let file = tokio::fs::File::open(path).await.unwrap();
+    let records: Vec<_> = DltStream::new(file, Framing::StorageHeader).collect().await;
+    assert_eq!(records.len(), RECORDS);
+    let mut records = records.into_iter();
+    let last = records.next_back().unwrap();
+    for record in records {
+        record.unwrap();
+    }
+    last.unwrap_err()
+}
+
+/// Import `path`, with a truncated_message event, and return the
+/// events' names and attributes along with the metrics
+async fn import(path: &Path) -> (Vec<(String, Vec<(String, AttrVal)>)>, Summary) {
+    let ingest = SharedIngest::default();
+    let metrics = Metrics::new("");
+    let config = importer_config(|c| c.truncated_message_event = Some(true));
+    Importer::new(config, ingest.clone())
+        .with_metrics(metrics.clone())
+        .run(path)
+        .await
+        .unwrap();
+
+    let events = ingest
+        .0
+        .lock()
+        .await
+        .events
+        .iter()
+        .map(|ev| (ev.name.clone(), ev.attrs.clone()))
+        .collect();
+    (events, metrics.summary())
+}
+
+/// Check that importing `path` sends every complete message in it, the
+/// same way as importing foo.dlt without its last record does, followed
+/// by a truncated_message event with `offset`, `size` and
+/// `expected_size`
+async fn check_import(path: &Path, offset: u64, size: usize, expected_size: usize) {
+    let name = path.file_stem().unwrap().to_str().unwrap();
+    let without_last_record =
+        truncated_foo_dlt(&format!("{name}-complete"), LAST_RECORD_OFFSET as usize);
+    let (complete, summary) = import(&without_last_record).await;
+    assert_eq!(summary.messages_read, RECORDS as u64 - 1);
+    assert_eq!(summary.parse_errors, 0);
+
+    let (mut events, summary) = import(path).await;
+    let (name, attrs) = events.pop().unwrap();
+    assert_eq!(events, complete);
+    assert_eq!(summary.messages_read, RECORDS as u64 - 1);
+    assert_eq!(summary.parse_errors, 1);
+
+    assert_eq!(name, "truncated_message");
+    let attr = |key: &str| {
+        attrs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+            .unwrap()
+    };
+    assert_eq!(attr("event.offset"), AttrVal::from(offset as i64));
+    assert_eq!(attr("event.size"), AttrVal::from(size as i64));
+    assert_eq!(
+        attr("event.expected_size"),
+        AttrVal::from(expected_size as i64)
+    );
+}
+
+#[tokio::test]
+async fn truncated_storage_header() {
+    let path = truncated_foo_dlt("truncated-storage-header", 1175);
+    let err = last_record(&path).await;
+    assert!(
+        matches!(
+            err,
+            DltPluginError::Truncated {
+                offset: LAST_RECORD_OFFSET,
+                available: 8,
+                expected: 16,
+            }
+        ),
+        "{err:?}"
+    );
+
+    check_import(&path, LAST_RECORD_OFFSET, 8, 16).await;
+}
+
+#[tokio::test]
+async fn truncated_message() {
+    let path = truncated_foo_dlt("truncated-message", 1215);
+    let err = last_record(&path).await;
+    assert!(
+        matches!(
+            err,
+            DltPluginError::Truncated {
+                offset: LAST_MESSAGE_OFFSET,
+                available: 32,
+                expected: LAST_MESSAGE_SIZE,
+            }
+        ),
+        "{err:?}"
+    );
+
+    check_import(&path, LAST_MESSAGE_OFFSET, 32, LAST_MESSAGE_SIZE).await;
+}
@@ -0,0 +1,212 @@
+//! Statistics gathered by `modality-dlt-inspect`.
+
+use std::path::Path;
+
+use dlt_core::dlt::{self, DltTimeStamp, LogLevel, StorageHeader};
+use modality_dlt::{
+    encode::{encode_framed, verbose_argument},
+    inspect::Inspection,
+    mock::log_message,
+    stream::{DltStream, Framing},
+};
+
+fn storage_header(seconds: u32, ecu_id: &str) -> StorageHeader {
+    StorageHeader {
+        timestamp: DltTimeStamp {
+            seconds,
+            microseconds: 500,
+        },
+        ecu_id: ecu_id.to_owned(),
+    }
+}
+
+fn log(counter: u8, timestamp: u32, level: LogLevel) -> dlt::Message {
+    let mut msg = log_message(
+        "ECU1",
+        "APP1",
+        if matches!(level, LogLevel::Error) {
+            "ERRS"
+        } else {
+            "CTX1"
+        },
+        level,
+        vec![verbose_argument(None, dlt::Value::U32(counter as u32))],
+    );
+    msg.header.message_counter = counter;
+    msg.header.timestamp = Some(timestamp);
+    msg
+}
+
+fn non_verbose(counter: u8, message_id: u32) -> dlt::Message {
+    let mut msg = dlt::Message::new(
+        dlt::MessageConfig {
+            version: 1,
+            counter,
+            endianness: dlt::Endianness::Little,
+            ecu_id: Some("ECU2".to_owned()),
+            session_id: Some(7),
+            timestamp: None,
+            payload: dlt::PayloadContent::NonVerbose(message_id, vec![1, 2, 3]),
+            extended_header_info: None,
+        },
+        None,
+    );
+    msg.header.message_counter = counter;
+    msg
+}
+
+fn file(messages: &[(u32, dlt::Message)]) -> Vec<u8> {
+    let mut file = vec![];
+    for (seconds, msg) in messages {
+        let ecu_id = msg.header.ecu_id.as_deref().unwrap_or_default();
+        let header = storage_header(*seconds, ecu_id);
+        file.extend(encode_framed(msg, Framing::StorageHeader, Some(&header)).unwrap());
+    }
+    file
+}
+
+fn inspect(file: &[u8]) -> Inspection {
+    Inspection::new().inspect(DltStream::new(file, Framing::StorageHeader))
+}
+
+#[test]
+fn counts_messages_by_kind() {
+    let file = file(&[
+        (100, log(0, 10, LogLevel::Info)),
+        (101, non_verbose(0, 42)),
+        (102, log(1, 20, LogLevel::Warn)),
+        (103, non_verbose(1, 42)),
+        (104, log(2, 5, LogLevel::Error)),
+        (99, non_verbose(2, 7)),
+    ]);
+    let inspection = inspect(&file);
+
+    assert_eq!(inspection.messages, 6);
+    assert_eq!(inspection.bytes, file.len() as u64);
+    assert_eq!(inspection.verbose, 3);
+    assert_eq!(inspection.non_verbose, 3);
+    assert_eq!(inspection.control, 0);
+    assert_eq!(inspection.message_ids_by_frequency(), vec![(42, 2), (7, 1)]);
+    assert_eq!(inspection.message_types.get("log"), Some(&3));
+    assert_eq!(inspection.message_types.get("non_verbose"), Some(&3));
+
+    let contexts: Vec<_> = inspection
+        .contexts
+        .iter()
+        .map(|((ecu, app, ctx), n)| (ecu.as_str(), app.as_str(), ctx.as_str(), *n))
+        .collect();
+    assert_eq!(
+        contexts,
+        vec![("ECU1", "APP1", "CTX1", 2), ("ECU1", "APP1", "ERRS", 1)]
+    );
+
+    let ecu1 = &inspection.ecus["ECU1"];
+    assert_eq!(ecu1.messages, 3);
+    assert_eq!(ecu1.log_levels, [0, 1, 1, 1, 0, 0, 0]);
+    assert_eq!(ecu1.timestamp_range, Some((5, 20)));
+    assert_eq!(inspection.ecus["ECU2"].messages, 3);
+    assert_eq!(inspection.ecus["ECU2"].timestamp_range, None);
+
+    assert_eq!(
+        inspection.storage_time_range,
+        Some((99_000_500, 104_000_500))
+    );
+}
+
+#[test]
+fn finds_counter_gaps() {
+    let file = file(&[
+        (1, log(0, 1, LogLevel::Info)),
+        (1, log(1, 2, LogLevel::Info)),
+        (1, log(4, 3, LogLevel::Info)),
+        (1, log(255, 4, LogLevel::Info)),
+        (1, log(0, 5, LogLevel::Info)),
+        (1, log(2, 6, LogLevel::Info)),
+        // Counters are per session, so these don't interleave with ECU1
+        (1, non_verbose(10, 1)),
+        (1, non_verbose(11, 1)),
+    ]);
+    let inspection = inspect(&file);
+
+    let ecu1 = &inspection.ecus["ECU1"];
+    assert_eq!(ecu1.counter_gaps, 3);
+    // 2 and 3, 5 through 254, and 1
+    assert_eq!(ecu1.missing_messages, 2 + 250 + 1);
+    assert_eq!(inspection.ecus["ECU2"].counter_gaps, 0);
+}
+
+#[test]
+fn reports_parse_errors_with_offsets() {
+    let good = file(&[(1, log(0, 1, LogLevel::Info))]);
+    let mut bad = file(&[(1, log(1, 1, LogLevel::Info))]);
+    // Overwrite the argument's type info, after the storage and message
+    // headers
+    let headers_len = 16 + dlt::calculate_all_headers_length(bad[16]) as usize;
+    for b in bad.iter_mut().skip(headers_len) {
+        *b = 0xff;
+    }
+
+    let mut file = good.clone();
+    file.extend(&bad);
+    file.extend(&good);
+    // Cut off partway through a message
+    file.extend(&good[..good.len() - 2]);
+
+    let inspection = Inspection::new()
+        .with_max_errors(10)
+        .inspect(DltStream::new(file.as_slice(), Framing::StorageHeader));
+    assert_eq!(inspection.messages, 2);
+    assert_eq!(inspection.parse_error_count, 1);
+    // Offsets are of the message itself, after its storage header
+    let offset = good.len() as u64 + 16;
+    assert_eq!(inspection.parse_errors[0].offset, Some(offset));
+
+    let fatal = inspection.fatal_error.as_ref().unwrap();
+    assert!(fatal.offset.unwrap() >= (2 * good.len() + bad.len()) as u64);
+
+    let mut report = vec![];
+    inspection.write_report(&mut report, 10).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.contains(&format!("offset {offset}: ")));
+    assert!(report.contains("Reading stopped early"));
+}
+
+#[test]
+fn keeps_only_the_first_errors() {
+    let mut bad = file(&[(1, log(1, 1, LogLevel::Info))]);
+    let headers_len = 16 + dlt::calculate_all_headers_length(bad[16]) as usize;
+    for b in bad.iter_mut().skip(headers_len) {
+        *b = 0xff;
+    }
+    let file = bad.repeat(5);
+
+    let inspection = Inspection::new()
+        .with_max_errors(2)
+        .inspect(DltStream::new(file.as_slice(), Framing::StorageHeader));
+    assert_eq!(inspection.parse_error_count, 5);
+    assert_eq!(inspection.parse_errors.len(), 2);
+    assert!(inspection.fatal_error.is_none());
+}
+
+#[test]
+fn inspects_recorded_files() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("integration-test/test-data/foo.dlt");
+    let file = std::fs::read(path).unwrap();
+    let inspection = inspect(&file);
+
+    assert!(inspection.messages > 0);
+    assert!(inspection.fatal_error.is_none());
+    if inspection.parse_error_count == 0 {
+        assert_eq!(inspection.bytes, file.len() as u64);
+    }
+    assert_eq!(
+        inspection.verbose + inspection.non_verbose + inspection.control,
+        inspection.messages
+    );
+    let per_ecu: u64 = inspection.ecus.values().map(|ecu| ecu.messages).sum();
+    assert_eq!(per_ecu, inspection.messages);
+
+    let mut report = vec![];
+    inspection.write_report(&mut report, 20).unwrap();
+    assert!(String::from_utf8(report).unwrap().starts_with("Messages: "));
+}
@@ -0,0 +1,132 @@
+//! Rendering metrics in the Prometheus text format, and serving them.
+
+mod common;
+
+use modality_dlt::{
+    metrics::{self, Metrics},
+    mock::MockIngest,
+    send::Sender,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use common::{config, log};
+
+const EXPECTED: &str = r#"# HELP modality_dlt_bytes_read_total Bytes read from the DLT source.
+# TYPE modality_dlt_bytes_read_total counter
+modality_dlt_bytes_read_total{source="dlt \"daemon\"\\1\n"} 100
+# HELP modality_dlt_parse_errors_total Messages which could not be read or parsed.
+# TYPE modality_dlt_parse_errors_total counter
+modality_dlt_parse_errors_total{source="dlt \"daemon\"\\1\n"} 1
+# HELP modality_dlt_invalid_messages_total Messages dropped because dlt-core considered them invalid.
+# TYPE modality_dlt_invalid_messages_total counter
+modality_dlt_invalid_messages_total{source="dlt \"daemon\"\\1\n"} 0
+# HELP modality_dlt_filtered_messages_total Messages dropped by filtering.
+# TYPE modality_dlt_filtered_messages_total counter
+modality_dlt_filtered_messages_total{source="dlt \"daemon\"\\1\n"} 0
+# HELP modality_dlt_dropped_messages_total Messages outside the import range, or discarded by a full spool.
+# TYPE modality_dlt_dropped_messages_total counter
+modality_dlt_dropped_messages_total{source="dlt \"daemon\"\\1\n"} 0
+# HELP modality_dlt_reconnects_total Successful reconnections to the Modality backend.
+# TYPE modality_dlt_reconnects_total counter
+modality_dlt_reconnects_total{source="dlt \"daemon\"\\1\n"} 1
+# HELP modality_dlt_messages_read_total DLT messages read.
+# TYPE modality_dlt_messages_read_total counter
+modality_dlt_messages_read_total{source="dlt \"daemon\"\\1\n",ecu_id="E\"2"} 1
+modality_dlt_messages_read_total{source="dlt \"daemon\"\\1\n",ecu_id="ECU1"} 2
+# HELP modality_dlt_events_sent_total Events made from DLT messages sent to Modality.
+# TYPE modality_dlt_events_sent_total counter
+modality_dlt_events_sent_total{source="dlt \"daemon\"\\1\n",ecu_id="E\"2"} 0
+modality_dlt_events_sent_total{source="dlt \"daemon\"\\1\n",ecu_id="ECU1"} 2
+# HELP modality_dlt_timelines_created_total Timelines created in Modality.
+# TYPE modality_dlt_timelines_created_total counter
+modality_dlt_timelines_created_total{source="dlt \"daemon\"\\1\n",ecu_id="E\"2"} 0
+modality_dlt_timelines_created_total{source="dlt \"daemon\"\\1\n",ecu_id="ECU1"} 1
+# HELP modality_dlt_messages_suppressed_total DLT messages left out by sampling or rate limits.
+# TYPE modality_dlt_messages_suppressed_total counter
+modality_dlt_messages_suppressed_total{source="dlt \"daemon\"\\1\n",ecu_id="E\"2"} 1
+modality_dlt_messages_suppressed_total{source="dlt \"daemon\"\\1\n",ecu_id="ECU1"} 0
+# HELP modality_dlt_ingest_lag_messages Messages read but not yet sent to Modality.
+# TYPE modality_dlt_ingest_lag_messages gauge
+modality_dlt_ingest_lag_messages{source="dlt \"daemon\"\\1\n"} 0
+"#;
+
+/// Metrics with a little of everything, and ids which need escaping
+fn known_metrics() -> std::sync::Arc<Metrics> {
+    let metrics = Metrics::new("dlt \"daemon\"\\1\n");
+    metrics.record_bytes_read(100);
+    metrics.record_parse_error();
+    metrics.record_message(&log("ECU1", "APP1", "one"));
+    metrics.record_message(&log("ECU1", "APP1", "two"));
+    metrics.record_message(&log("E\"2", "APP1", "three"));
+    metrics.record_events_sent(Some("ECU1"), 2);
+    metrics.record_timeline_created(Some("ECU1"));
+    metrics.record_message_suppressed(Some("E\"2"));
+    metrics.record_reconnect();
+    metrics
+}
+
+#[test]
+fn renders_prometheus_text() {
+    assert_eq!(known_metrics().render(), EXPECTED);
+}
+
+/// The value of the unlabelled-by-ECU metric `name`
+fn value(metrics: &Metrics, name: &str) -> u64 {
+    let prefix = format!("{name}{{source=\"\"}} ");
+    metrics
+        .render()
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix)?.parse().ok())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn lag_only_counts_events_made_from_messages() {
+    let metrics = Metrics::new("");
+    let mut sender = Sender::new(MockIngest::new(), config(|_| ())).with_metrics(metrics.clone());
+    let messages: Vec<_> = (0..3)
+        .map(|n| log("ECU1", "APP1", &n.to_string()))
+        .collect();
+    for parsed_msg in &messages {
+        metrics.record_message(parsed_msg);
+    }
+
+    // One message is still on its way when the collector stops
+    for parsed_msg in messages.into_iter().take(2) {
+        sender.handle_message(parsed_msg).await.unwrap();
+    }
+    sender.stop("collector_stopped", "test").await.unwrap();
+    assert_eq!(sender.sink().events.len(), 3);
+
+    assert_eq!(metrics.summary().events_sent, 2);
+    assert_eq!(value(&metrics, "modality_dlt_ingest_lag_messages"), 1);
+}
+
+#[tokio::test]
+async fn serves_metrics_over_http() {
+    // Find a free port to serve on
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    metrics::serve(addr, known_metrics()).await.unwrap();
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let mut head = head.lines();
+    assert_eq!(head.next(), Some("HTTP/1.1 200 OK"));
+    let headers: Vec<_> = head.collect();
+    assert!(headers.contains(&"Content-Type: text/plain; version=0.0.4"));
+    assert!(headers.contains(&format!("Content-Length: {}", EXPECTED.len()).as_str()));
+    assert_eq!(body, EXPECTED);
+}
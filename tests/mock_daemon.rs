@@ -0,0 +1,319 @@
+//! The collector's pipeline (reading from a daemon over TCP, converting,
+//! batching) against the mock daemon and ingest stand-in.
+
+mod common;
+
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use auxon_sdk::api::AttrVal;
+use dlt_core::parse::ParsedMessage;
+use futures::StreamExt;
+use modality_dlt::{
+    collect::Collector,
+    control::{self, ControlRequest},
+    metrics::Metrics,
+    mock::{Corruption, MockDaemon, MockDaemonHandle, Script},
+    nonverbose::NonVerboseDictionaryConfig,
+    stream::{DltStream, Framing},
+    DltPluginError,
+};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use common::{collector_config, text_message, texts, SharedIngest};
+
+fn script(messages: &[(&str, &str, &str)]) -> Script {
+    let mut script = Script::new();
+    for (ecu_id, application_id, text) in messages {
+        script
+            .push(&text_message(ecu_id, application_id, "CTX1", text))
+            .unwrap();
+    }
+    script
+}
+
+fn numbered_script(len: usize) -> Script {
+    let texts: Vec<String> = (0..len).map(|n| format!("message {n}")).collect();
+    let messages: Vec<_> = texts
+        .iter()
+        .map(|text| ("ECU1", "APP1", text.as_str()))
+        .collect();
+    script(&messages)
+}
+
+fn numbered_texts(len: usize) -> Vec<String> {
+    (0..len).map(|n| format!("message {n}")).collect()
+}
+
+async fn start(daemon: MockDaemon) -> MockDaemonHandle {
+    daemon
+        .start(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap()
+}
+
+/// Run the collector against the daemon at `addr` until it
+/// disconnects, sending to `ingest`.
+async fn collect(
+    addr: SocketAddr,
+    ingest: &SharedIngest,
+    metrics: &Arc<Metrics>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Collector::new(collector_config(|_| ()), ingest.clone())
+        .with_metrics(metrics.clone())
+        .run(addr)
+        .await
+}
+
+#[tokio::test]
+async fn scripted_messages_become_events() {
+    let daemon = start(MockDaemon::new(script(&[
+        ("ECU1", "APP1", "hello"),
+        ("ECU2", "APP2", "from elsewhere"),
+        ("ECU1", "APP1", "goodbye"),
+    ])))
+    .await;
+
+    let ingest = SharedIngest::default();
+    let metrics = Metrics::new("");
+    collect(daemon.local_addr(), &ingest, &metrics)
+        .await
+        .unwrap();
+    assert_eq!(metrics.summary().messages_read, 3);
+
+    let ingest = ingest.0.lock().await;
+    assert_eq!(ingest.timelines.len(), 2);
+    assert_eq!(ingest.events_named("log").count(), 3);
+
+    let ecu1 = ingest.timeline_named("ECU1").unwrap();
+    let payloads: Vec<_> = ingest
+        .events_on(ecu1.id)
+        .filter_map(|ev| ev.attr("event.payload.text").cloned())
+        .collect();
+    assert_eq!(
+        payloads,
+        vec![AttrVal::from("hello"), AttrVal::from("goodbye")]
+    );
+
+    let ecu2 = ingest.timeline_named("ECU2").unwrap();
+    let event = ingest.events_on(ecu2.id).next().unwrap();
+    assert_eq!(
+        event.attr("event.application_id"),
+        Some(&AttrVal::from("APP2"))
+    );
+    assert_eq!(event.attr("event.log_level"), Some(&AttrVal::from("info")));
+
+    // Each timeline is told why collection stopped
+    let stopped: Vec<_> = ingest.events_named("collector_stopped").collect();
+    assert_eq!(stopped.len(), 2);
+    assert!(stopped
+        .iter()
+        .all(|ev| ev.attr("event.reason") == Some(&AttrVal::from("dlt_stream_closed"))));
+}
+
+#[tokio::test]
+async fn reconnecting_resumes_the_script() {
+    let daemon = start(MockDaemon::new(numbered_script(10)).with_disconnect_after(4)).await;
+
+    let ingest = SharedIngest::default();
+    let metrics = Metrics::new("");
+    let mut delivered = vec![];
+    for _ in 0..3 {
+        let before = metrics.summary().messages_read;
+        collect(daemon.local_addr(), &ingest, &metrics)
+            .await
+            .unwrap();
+        delivered.push(metrics.summary().messages_read - before);
+    }
+    assert_eq!(delivered, vec![4, 4, 2]);
+    assert_eq!(daemon.connections(), 3);
+    assert_eq!(daemon.messages_sent(), 10);
+
+    // Nothing was lost or repeated across the reconnections
+    assert_eq!(texts(&*ingest.0.lock().await), numbered_texts(10));
+}
+
+#[tokio::test]
+async fn corrupt_messages_are_skipped() {
+    let daemon =
+        start(MockDaemon::new(numbered_script(6)).with_corruption(3, Corruption::Payload)).await;
+
+    let ingest = SharedIngest::default();
+    let metrics = Metrics::new("");
+    collect(daemon.local_addr(), &ingest, &metrics)
+        .await
+        .unwrap();
+    let summary = metrics.summary();
+    assert_eq!(summary.messages_read, 4);
+    assert_eq!(summary.parse_errors, 2);
+    assert_eq!(texts(&*ingest.0.lock().await).len(), 4);
+}
+
+#[tokio::test]
+async fn truncated_message_ends_the_stream() {
+    let daemon =
+        start(MockDaemon::new(numbered_script(4)).with_corruption(3, Corruption::Truncate)).await;
+
+    let ingest = SharedIngest::default();
+    let metrics = Metrics::new("");
+    let err = collect(daemon.local_addr(), &ingest, &metrics)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DltPluginError>(),
+        Some(DltPluginError::Truncated { .. })
+    ));
+
+    // Everything before the truncated message is still sent
+    assert_eq!(texts(&*ingest.0.lock().await), numbered_texts(2));
+    let stopped = ingest
+        .0
+        .lock()
+        .await
+        .events_named("collector_stopped")
+        .count();
+    assert_eq!(stopped, 1);
+
+    // The next connection carries on after the truncated message
+    collect(daemon.local_addr(), &ingest, &metrics)
+        .await
+        .unwrap();
+    assert_eq!(
+        texts(&*ingest.0.lock().await),
+        vec!["message 0", "message 1", "message 3"]
+    );
+}
+
+#[tokio::test]
+async fn rate_limits_sending() {
+    let daemon = start(MockDaemon::new(numbered_script(5)).with_rate(50.0)).await;
+
+    let started = Instant::now();
+    let ingest = SharedIngest::default();
+    collect(daemon.local_addr(), &ingest, &Metrics::new(""))
+        .await
+        .unwrap();
+    assert_eq!(texts(&*ingest.0.lock().await).len(), 5);
+    // The first message goes right away, then one every 20ms
+    assert!(started.elapsed() >= Duration::from_millis(80));
+}
+
+#[tokio::test]
+async fn answers_software_version_requests() {
+    let daemon = start(
+        MockDaemon::new(Script::new())
+            .with_software_version("1.2.3")
+            .with_hold_open(),
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(daemon.local_addr()).await.unwrap();
+    stream
+        .write_all(&ControlRequest::GetSoftwareVersion.to_bytes("ECU1", 0))
+        .await
+        .unwrap();
+
+    let mut records = DltStream::new(BufReader::new(stream), Framing::Raw);
+    let record = tokio::time::timeout(Duration::from_secs(5), records.next())
+        .await
+        .expect("a response")
+        .unwrap()
+        .unwrap();
+    let ParsedMessage::Item(response) = record.message else {
+        panic!("unexpected message {:?}", record.message);
+    };
+    assert_eq!(
+        control::software_version(&response).as_deref(),
+        Some("1.2.3")
+    );
+
+    let requests = daemon.control_requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        control::request_service_id(&requests[0]),
+        Some(control::SERVICE_GET_SOFTWARE_VERSION)
+    );
+}
+
+#[tokio::test]
+async fn requests_software_versions_per_ecu_and_connection() {
+    let daemon = start(
+        MockDaemon::new(script(&[
+            ("ECU1", "APP1", "one"),
+            ("ECU2", "APP2", "two"),
+            ("ECU2", "APP2", "three"),
+            ("ECU1", "APP1", "four"),
+            ("ECU2", "APP2", "five"),
+            ("ECU1", "APP1", "six"),
+        ]))
+        .with_rate(50.0)
+        .with_disconnect_after(3)
+        .with_software_version("2.0"),
+    )
+    .await;
+
+    // An empty dictionary is enough to make the collector ask
+    let dictionary_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("empty-dictionary");
+    std::fs::create_dir_all(&dictionary_dir).unwrap();
+    let config = || {
+        collector_config(|c| {
+            c.common.non_verbose_dictionaries = vec![NonVerboseDictionaryConfig {
+                name: None,
+                path: dictionary_dir.clone(),
+                ecu_id: None,
+                software_version: Some("2.0".to_owned()),
+            }]
+        })
+    };
+
+    let ingest = SharedIngest::default();
+    for _ in 0..2 {
+        Collector::new(config(), ingest.clone())
+            .run(daemon.local_addr())
+            .await
+            .unwrap();
+    }
+
+    // The configured ECU on connecting, then each other ECU once it's
+    // seen, all over again after reconnecting
+    let requests: Vec<_> = daemon
+        .control_requests()
+        .iter()
+        .map(|request| {
+            assert_eq!(
+                control::request_service_id(request),
+                Some(control::SERVICE_GET_SOFTWARE_VERSION)
+            );
+            request.header.ecu_id.clone().unwrap()
+        })
+        .collect();
+    assert_eq!(requests, vec!["ECU1", "ECU2", "ECU1", "ECU2"]);
+}
+
+#[tokio::test]
+async fn replays_dlt_files() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("integration-test/test-data/foo.dlt");
+    let records = DltStream::new(std::fs::File::open(&path).unwrap(), Framing::StorageHeader)
+        .filter(|record| record.is_ok())
+        .count();
+
+    let script = Script::from_dlt_file(&path).unwrap();
+    assert_eq!(script.len(), records);
+    let daemon = start(MockDaemon::new(script)).await;
+
+    let ingest = SharedIngest::default();
+    let metrics = Metrics::new("");
+    collect(daemon.local_addr(), &ingest, &metrics)
+        .await
+        .unwrap();
+    let summary = metrics.summary();
+    assert_eq!(summary.messages_read, records as u64);
+    assert_eq!(summary.parse_errors, 0);
+}
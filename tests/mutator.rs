@@ -0,0 +1,92 @@
+//! Linking control responses to the mutator requests they answer.
+
+use auxon_sdk::api::{AttrKey, AttrVal, TimelineId};
+use modality_dlt::{
+    control::{ControlStatus, SERVICE_SET_DEFAULT_LOG_LEVEL, SERVICE_SET_LOG_LEVEL},
+    mock::control_response,
+    mutator::ControlConnection,
+    send::EventAnnotator,
+};
+
+/// The attributes `connection` gives a successful response to
+/// `service_id` from `ecu_id`, on top of `attrs`
+fn annotate_response(
+    connection: &ControlConnection,
+    ecu_id: &str,
+    service_id: u32,
+    mut attrs: Vec<(AttrKey, AttrVal)>,
+) -> Vec<(AttrKey, AttrVal)> {
+    let response = control_response(ecu_id, service_id, ControlStatus::Ok, &[]);
+    connection.annotate(&response, &mut attrs);
+    attrs
+}
+
+/// The `event.interaction.remote_*` attributes, as text
+fn remote(attrs: &[(AttrKey, AttrVal)]) -> Vec<(String, String)> {
+    attrs
+        .iter()
+        .filter(|(k, _)| k.as_ref().starts_with("event.interaction.remote_"))
+        .map(|(k, v)| (k.as_ref().to_owned(), format!("{v:?}")))
+        .collect()
+}
+
+fn link(timeline_id: TimelineId, nonce: i64) -> Vec<(String, String)> {
+    vec![
+        (
+            "event.interaction.remote_timeline_id".to_owned(),
+            format!("{:?}", AttrVal::from(timeline_id)),
+        ),
+        (
+            "event.interaction.remote_nonce".to_owned(),
+            format!("{:?}", AttrVal::from(nonce)),
+        ),
+    ]
+}
+
+#[test]
+fn responses_link_to_their_requests() {
+    let connection = ControlConnection::new(tokio::io::sink());
+    let timeline_id = TimelineId::allocate();
+    connection.expect_interaction("ECU1", SERVICE_SET_LOG_LEVEL, timeline_id, 1);
+    connection.expect_interaction("ECU1", SERVICE_SET_DEFAULT_LOG_LEVEL, timeline_id, 2);
+    connection.expect_interaction("ECU1", SERVICE_SET_LOG_LEVEL, timeline_id, 3);
+
+    // Not asked of this ECU
+    let attrs = annotate_response(&connection, "ECU2", SERVICE_SET_LOG_LEVEL, vec![]);
+    assert!(attrs.is_empty());
+
+    // Matched by service, oldest first
+    let attrs = annotate_response(&connection, "ECU1", SERVICE_SET_DEFAULT_LOG_LEVEL, vec![]);
+    assert_eq!(remote(&attrs), link(timeline_id, 2));
+    let attrs = annotate_response(&connection, "ECU1", SERVICE_SET_LOG_LEVEL, vec![]);
+    assert_eq!(remote(&attrs), link(timeline_id, 1));
+    let attrs = annotate_response(&connection, "ECU1", SERVICE_SET_LOG_LEVEL, vec![]);
+    assert_eq!(remote(&attrs), link(timeline_id, 3));
+
+    // Every request has been answered
+    let attrs = annotate_response(&connection, "ECU1", SERVICE_SET_LOG_LEVEL, vec![]);
+    assert!(attrs.is_empty());
+}
+
+#[test]
+fn linked_responses_are_left_alone() {
+    let connection = ControlConnection::new(tokio::io::sink());
+    let timeline_id = TimelineId::allocate();
+    connection.expect_interaction("ECU1", SERVICE_SET_LOG_LEVEL, timeline_id, 1);
+
+    // Already linked to the request as seen on the wire
+    let other_timeline_id = TimelineId::allocate();
+    let linked = vec![
+        (
+            "event.interaction.remote_timeline_id".into(),
+            other_timeline_id.into(),
+        ),
+        ("event.interaction.remote_nonce".into(), 7_i64.into()),
+    ];
+    let attrs = annotate_response(&connection, "ECU1", SERVICE_SET_LOG_LEVEL, linked);
+    assert_eq!(remote(&attrs), link(other_timeline_id, 7));
+
+    // So the mutator's request is still waiting for its response
+    let attrs = annotate_response(&connection, "ECU1", SERVICE_SET_LOG_LEVEL, vec![]);
+    assert_eq!(remote(&attrs), link(timeline_id, 1));
+}
@@ -0,0 +1,173 @@
+//! Finding the frame in each kind of network trace message.
+
+use auxon_sdk::api::AttrVal;
+use dlt_core::dlt::{self, NetworkTraceType};
+use modality_dlt::{
+    mock::{log_message, network_trace_message},
+    network::{can_frame, gather_network_trace_attrs, someip_message},
+};
+
+/// A SOME/IP header for method 0x8001 of service 0x1234: a
+/// notification, from client 0x0042 in session 7
+const SOMEIP_HEADER: [u8; 16] = [
+    0x12, 0x34, 0x80, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x42, 0x00, 0x07, 0x01, 0x03, 0x02, 0x00,
+];
+
+/// An Ethernet II header, from 02:00:00:00:00:02 to 02:00:00:00:00:01,
+/// carrying IPv4
+const ETHERNET_HEADER: [u8; 14] = [
+    0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x08, 0x00,
+];
+
+fn trace(trace_type: NetworkTraceType, header: &[u8], payload: &[u8]) -> dlt::Message {
+    network_trace_message("ECU1", "BUS", "TRC", trace_type, header, payload)
+}
+
+/// The network trace attributes for `msg`, as text
+fn attrs(msg: &dlt::Message) -> Vec<String> {
+    let mut attrs = vec![];
+    gather_network_trace_attrs(msg, &mut attrs);
+    attrs
+        .iter()
+        .map(|(k, v)| format!("{}={v:?}", k.as_ref()))
+        .collect()
+}
+
+fn attr(key: &str, value: impl Into<AttrVal>) -> String {
+    format!("{key}={:?}", value.into())
+}
+
+#[test]
+fn can_ids_are_read_in_the_message_byte_order() {
+    let msg = trace(NetworkTraceType::Can, &0x123u32.to_le_bytes(), &[1, 2, 3]);
+    let frame = can_frame(&msg).unwrap();
+    assert_eq!(frame.id, 0x123);
+    assert!(!frame.extended);
+    assert_eq!(frame.data, &[1, 2, 3]);
+    assert_eq!(
+        attrs(&msg),
+        vec![
+            attr("event.can.id", 0x123i64),
+            attr("event.can.extended", false),
+            attr("event.can.dlc", 3i64),
+            attr("event.can.data", "010203"),
+        ]
+    );
+
+    let mut msg = trace(NetworkTraceType::Can, &0x123u32.to_be_bytes(), &[]);
+    msg.header.endianness = dlt::Endianness::Big;
+    assert_eq!(can_frame(&msg).unwrap().id, 0x123);
+}
+
+#[test]
+fn extended_can_ids_lose_their_flag() {
+    let raw_id = 0x1abc_def0 | 1 << 31;
+    let msg = trace(NetworkTraceType::Can, &u32::to_le_bytes(raw_id), &[0xff]);
+    let frame = can_frame(&msg).unwrap();
+    assert_eq!(frame.id, 0x1abc_def0);
+    assert!(frame.extended);
+    assert_eq!(
+        attrs(&msg),
+        vec![
+            attr("event.can.id", 0x1abc_def0i64),
+            attr("event.can.extended", true),
+            attr("event.can.dlc", 1i64),
+            attr("event.can.data", "ff"),
+        ]
+    );
+}
+
+#[test]
+fn short_can_headers_have_no_frame() {
+    let msg = trace(NetworkTraceType::Can, &[0x23, 0x01], &[1, 2, 3]);
+    assert!(can_frame(&msg).is_none());
+    assert!(attrs(&msg).is_empty());
+}
+
+#[test]
+fn someip_headers_are_decoded() {
+    let msg = trace(NetworkTraceType::SomeIP, &SOMEIP_HEADER, &[0; 2]);
+    let (header, payload) = someip_message(&msg).unwrap();
+    assert_eq!((header.service_id, header.method_id), (0x1234, 0x8001));
+    assert_eq!(payload.len(), 2);
+
+    let expected = vec![
+        attr("event.someip.service_id", 0x1234i64),
+        attr("event.someip.method_id", 0x8001i64),
+        attr("event.someip.client_id", 0x42i64),
+        attr("event.someip.session_id", 7i64),
+        attr("event.someip.interface_version", 3i64),
+        attr("event.someip.message_type", "notification"),
+        attr("event.someip.return_code", "ok"),
+        attr("event.someip.payload_size", 2i64),
+    ];
+    assert_eq!(attrs(&msg), expected);
+
+    // Ethernet traces can carry the SOME/IP header on its own, too
+    let msg = trace(NetworkTraceType::Ethernet, &SOMEIP_HEADER, &[0; 2]);
+    assert!(someip_message(&msg).is_some());
+    assert_eq!(attrs(&msg), expected);
+}
+
+#[test]
+fn ethernet_ii_headers_are_decoded() {
+    let msg = trace(NetworkTraceType::Ethernet, &ETHERNET_HEADER, &[0; 20]);
+    assert!(someip_message(&msg).is_none());
+    assert_eq!(
+        attrs(&msg),
+        vec![
+            attr("event.ethernet.destination", "02:00:00:00:00:01"),
+            attr("event.ethernet.source", "02:00:00:00:00:02"),
+            attr("event.ethernet.ethertype", 0x0800i64),
+            attr("event.ethernet.payload_size", 20i64),
+        ]
+    );
+}
+
+#[test]
+fn ethernet_headers_of_someip_length_need_its_protocol_version() {
+    // An Ethernet II header with a VLAN tag is 16 bytes too; here byte
+    // 12 is the start of the 0x8100 tag protocol id
+    let mut header = ETHERNET_HEADER[..12].to_vec();
+    header.extend_from_slice(&[0x81, 0x00, 0x00, 0x05]);
+    let msg = trace(NetworkTraceType::Ethernet, &header, &[0; 20]);
+    assert!(someip_message(&msg).is_none());
+    assert_eq!(
+        attrs(&msg),
+        vec![
+            attr("event.ethernet.destination", "02:00:00:00:00:01"),
+            attr("event.ethernet.source", "02:00:00:00:00:02"),
+            attr("event.ethernet.ethertype", 0x8100i64),
+            attr("event.ethernet.payload_size", 20i64),
+        ]
+    );
+}
+
+#[test]
+fn short_ethernet_and_someip_headers_have_no_frame() {
+    for trace_type in [NetworkTraceType::Ethernet, NetworkTraceType::SomeIP] {
+        let msg = trace(trace_type, &SOMEIP_HEADER[..10], &[0; 2]);
+        assert!(someip_message(&msg).is_none());
+        assert!(attrs(&msg).is_empty());
+    }
+}
+
+#[test]
+fn other_buses_pass_on_the_bytes() {
+    let msg = trace(NetworkTraceType::Flexray, &[0xab, 0xcd], &[1, 2]);
+    assert_eq!(
+        attrs(&msg),
+        vec![
+            attr("event.network.header", "abcd"),
+            attr("event.network.payload", "0102"),
+        ]
+    );
+}
+
+#[test]
+fn other_messages_have_no_frame() {
+    let msg = log_message("ECU1", "APP1", "CTX1", dlt::LogLevel::Info, vec![]);
+    assert!(can_frame(&msg).is_none());
+    assert!(someip_message(&msg).is_none());
+    assert!(attrs(&msg).is_empty());
+}
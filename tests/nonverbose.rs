@@ -0,0 +1,436 @@
+//! Picking a non-verbose dictionary for each message, by ECU and
+//! software version.
+
+use std::path::Path;
+
+use auxon_sdk::api::{AttrKey, AttrVal};
+use dlt_core::dlt;
+use modality_dlt::{
+    control::{ControlStatus, SERVICE_GET_SOFTWARE_VERSION},
+    mock::control_response,
+    nonverbose::{
+        NonVerboseDecoder, NonVerboseDictionaryConfig, NonVerboseMessage, NonVerboseMessages,
+        PredefinedText,
+    },
+    payload::{DataType, Parameter},
+    send::EventAnnotator,
+};
+
+const MESSAGE_ID: u32 = 1;
+
+/// A decoder with dictionaries for any ECU, for ECU1, for exactly
+/// version 2.0 of ECU1, and for any 3.x version of ECU1. Each describes
+/// [MESSAGE_ID] with an argument-less message named after itself.
+fn decoder() -> NonVerboseDecoder {
+    let mut decoder = NonVerboseDecoder::new();
+    for (name, ecu_id, software_version) in [
+        ("any", None, None),
+        ("ecu1", Some("ECU1"), None),
+        ("ecu1-v2.0", Some("ECU1"), Some("2.0")),
+        ("ecu1-v3", Some("ECU1"), Some("3.*")),
+    ] {
+        let mut messages = NonVerboseMessages::default();
+        messages.add_message(
+            MESSAGE_ID,
+            NonVerboseMessage {
+                name: name.to_owned(),
+                ..Default::default()
+            },
+        );
+        let config = NonVerboseDictionaryConfig {
+            name: Some(name.to_owned()),
+            path: format!("{name}.arxml").into(),
+            ecu_id: ecu_id.map(str::to_owned),
+            software_version: software_version.map(str::to_owned),
+        };
+        decoder.add_dictionary(&config, messages);
+    }
+    decoder
+}
+
+fn non_verbose_message(ecu_id: &str) -> dlt::Message {
+    non_verbose_payload(ecu_id, MESSAGE_ID, vec![])
+}
+
+/// A non-verbose message without an extended header, as ECUs usually
+/// send them
+fn non_verbose_payload(ecu_id: &str, message_id: u32, payload: Vec<u8>) -> dlt::Message {
+    dlt::Message::new(
+        dlt::MessageConfig {
+            version: 1,
+            counter: 0,
+            endianness: dlt::Endianness::Little,
+            ecu_id: Some(ecu_id.to_owned()),
+            session_id: None,
+            timestamp: None,
+            payload: dlt::PayloadContent::NonVerbose(message_id, payload),
+            extended_header_info: None,
+        },
+        None,
+    )
+}
+
+fn report_version(decoder: &NonVerboseDecoder, ecu_id: &str, version: &str) {
+    let mut data = (version.len() as u32).to_le_bytes().to_vec();
+    data.extend_from_slice(version.as_bytes());
+    let response = control_response(
+        ecu_id,
+        SERVICE_GET_SOFTWARE_VERSION,
+        ControlStatus::Ok,
+        &data,
+    );
+    let mut attrs = vec![];
+    decoder.annotate(&response, &mut attrs);
+    assert!(attrs.is_empty());
+}
+
+/// The string attribute `key`
+fn attr(attrs: &[(AttrKey, AttrVal)], key: &str) -> Option<String> {
+    attrs.iter().find_map(|(k, v)| match v {
+        AttrVal::String(s) if k.as_ref() == key => Some(s.to_string()),
+        _ => None,
+    })
+}
+
+/// The dictionary used for a message from `ecu_id`
+fn dictionary_for(decoder: &NonVerboseDecoder, ecu_id: &str) -> Option<String> {
+    let mut attrs = vec![];
+    decoder.annotate(&non_verbose_message(ecu_id), &mut attrs);
+    let dictionary = attr(&attrs, "event.decoder.dictionary");
+    if dictionary.is_some() {
+        assert_eq!(attr(&attrs, "event.message_name"), dictionary);
+    }
+    dictionary
+}
+
+#[test]
+fn ecu_dictionaries_are_preferred() {
+    let decoder = decoder();
+    assert!(decoder.needs_software_version());
+    assert_eq!(dictionary_for(&decoder, "ECU1").as_deref(), Some("ecu1"));
+    assert_eq!(dictionary_for(&decoder, "ECU2").as_deref(), Some("any"));
+}
+
+#[test]
+fn versioned_dictionaries_need_an_exact_version() {
+    let decoder = decoder();
+    report_version(&decoder, "ECU1", "2.0");
+    assert_eq!(
+        dictionary_for(&decoder, "ECU1").as_deref(),
+        Some("ecu1-v2.0")
+    );
+
+    // Containing the wanted version isn't enough
+    for version in ["12.0", "2.0.1", "v2.0"] {
+        report_version(&decoder, "ECU1", version);
+        assert_eq!(dictionary_for(&decoder, "ECU1").as_deref(), Some("ecu1"));
+    }
+}
+
+#[test]
+fn trailing_star_matches_a_version_prefix() {
+    let decoder = decoder();
+    for version in ["3.", "3.1", "3.12-rc1"] {
+        report_version(&decoder, "ECU1", version);
+        assert_eq!(dictionary_for(&decoder, "ECU1").as_deref(), Some("ecu1-v3"));
+    }
+    report_version(&decoder, "ECU1", "30");
+    assert_eq!(dictionary_for(&decoder, "ECU1").as_deref(), Some("ecu1"));
+}
+
+#[test]
+fn versions_are_per_ecu() {
+    let decoder = decoder();
+    report_version(&decoder, "ECU2", "2.0");
+    assert_eq!(dictionary_for(&decoder, "ECU1").as_deref(), Some("ecu1"));
+    assert_eq!(dictionary_for(&decoder, "ECU2").as_deref(), Some("any"));
+
+    // The latest report wins
+    report_version(&decoder, "ECU1", "2.0");
+    report_version(&decoder, "ECU1", "3.0");
+    assert_eq!(dictionary_for(&decoder, "ECU1").as_deref(), Some("ecu1-v3"));
+}
+
+#[test]
+fn no_fitting_dictionary_is_an_error() {
+    let mut decoder = NonVerboseDecoder::new();
+    decoder.add_dictionary(
+        &NonVerboseDictionaryConfig {
+            name: None,
+            path: "ecu1.arxml".into(),
+            ecu_id: Some("ECU1".to_owned()),
+            software_version: None,
+        },
+        NonVerboseMessages::default(),
+    );
+    assert!(!decoder.needs_software_version());
+
+    let mut attrs = vec![];
+    decoder.annotate(&non_verbose_message("ECU2"), &mut attrs);
+    assert_eq!(
+        attr(&attrs, "event.decode_error").as_deref(),
+        Some("No dictionary for ECU 'ECU2'")
+    );
+
+    // Named after its path, but without the message
+    let mut attrs = vec![];
+    decoder.annotate(&non_verbose_message("ECU1"), &mut attrs);
+    assert_eq!(
+        attr(&attrs, "event.decoder.dictionary").as_deref(),
+        Some("ecu1.arxml")
+    );
+    assert_eq!(
+        attr(&attrs, "event.decode_error").as_deref(),
+        Some("Unknown message id 1")
+    );
+}
+
+/// A decoder with the dictionary in tests/data/messages.arxml, whose
+/// EngineStatus message has a format string, then a scaled speed in
+/// rpm, a gear from a text table, and a temperature in degC.
+fn arxml_decoder() -> NonVerboseDecoder {
+    arxml_decoder_rendering(false)
+}
+
+fn arxml_decoder_rendering(render_message: bool) -> NonVerboseDecoder {
+    let mut decoder = NonVerboseDecoder::new().with_message_rendering(render_message);
+    decoder
+        .load_dictionary(&NonVerboseDictionaryConfig {
+            name: Some("messages".to_owned()),
+            path: Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/messages.arxml"),
+            ecu_id: None,
+            software_version: None,
+        })
+        .unwrap();
+    decoder
+}
+
+const ENGINE_STATUS: u32 = 0x10;
+
+/// An EngineStatus payload: 8000 (2000 rpm), gear 3 (Drive), and 90
+/// degrees
+const ENGINE_STATUS_PAYLOAD: [u8; 5] = [0x40, 0x1f, 0x03, 0x5a, 0x00];
+
+fn annotate(
+    decoder: &NonVerboseDecoder,
+    message_id: u32,
+    payload: &[u8],
+) -> Vec<(String, AttrVal)> {
+    let mut attrs = vec![];
+    decoder.annotate(
+        &non_verbose_payload("ECU1", message_id, payload.to_vec()),
+        &mut attrs,
+    );
+    attrs
+        .into_iter()
+        .map(|(k, v)| (k.as_ref().to_owned(), v))
+        .collect()
+}
+
+fn expected(attrs: &[(&str, AttrVal)]) -> Vec<(String, AttrVal)> {
+    attrs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect()
+}
+
+#[test]
+fn arxml_arguments_are_decoded_with_units_and_text_tables() {
+    let attrs = annotate(&arxml_decoder(), ENGINE_STATUS, &ENGINE_STATUS_PAYLOAD);
+    assert_eq!(
+        attrs,
+        expected(&[
+            ("event.decoder.dictionary", "messages".into()),
+            ("event.message_name", "EngineStatus".into()),
+            // The message has no extended header, so they come from
+            // the dictionary
+            ("event.application_id", "ENGN".into()),
+            ("event.context_id", "STAT".into()),
+            ("event.source_file", "engine.c".into()),
+            ("event.source_line", 42_i64.into()),
+            (
+                "event.payload.format",
+                "Engine at %f rpm in %s, coolant %d degrees".into()
+            ),
+            ("event.payload.speed", 2000.0_f64.into()),
+            ("event.payload.speed.unit", "rpm".into()),
+            ("event.payload.gear", "Drive".into()),
+            ("event.payload.coolant", 90_i16.into()),
+            ("event.payload.coolant.unit", "degC".into()),
+        ])
+    );
+}
+
+#[test]
+fn text_tables_cover_ranges() {
+    for (gear, name) in [(0, "Park"), (2, "Neutral"), (8, "Drive")] {
+        let mut payload = ENGINE_STATUS_PAYLOAD;
+        payload[2] = gear;
+        let attrs = annotate(&arxml_decoder(), ENGINE_STATUS, &payload);
+        assert!(attrs.contains(&("event.payload.gear".to_owned(), name.into())));
+    }
+
+    // Values outside the table are left as they are
+    let mut payload = ENGINE_STATUS_PAYLOAD;
+    payload[2] = 9;
+    let attrs = annotate(&arxml_decoder(), ENGINE_STATUS, &payload);
+    assert!(attrs.contains(&("event.payload.gear".to_owned(), 9_u8.into())));
+}
+
+#[test]
+fn unknown_arxml_message_ids_are_an_error() {
+    let attrs = annotate(&arxml_decoder(), 0x11, &ENGINE_STATUS_PAYLOAD);
+    assert_eq!(
+        attrs,
+        expected(&[
+            ("event.decoder.dictionary", "messages".into()),
+            ("event.decode_error", "Unknown message id 17".into()),
+        ])
+    );
+}
+
+#[test]
+fn short_payloads_are_an_error() {
+    // Only the speed
+    let attrs = annotate(&arxml_decoder(), ENGINE_STATUS, &ENGINE_STATUS_PAYLOAD[..2]);
+    assert_eq!(
+        attrs.last(),
+        Some(&(
+            "event.decode_error".to_owned(),
+            "Payload ended in the middle of 'gear'".into()
+        ))
+    );
+    // Nothing is decoded from the payload, even what was there
+    assert!(!attrs
+        .iter()
+        .any(|(k, _)| k.starts_with("event.payload.speed")));
+}
+
+#[test]
+fn rendered_messages_fill_in_format_strings() {
+    let attrs = annotate(
+        &arxml_decoder_rendering(true),
+        ENGINE_STATUS,
+        &ENGINE_STATUS_PAYLOAD,
+    );
+    // Converted values are used whatever the conversion says
+    assert!(attrs.contains(&(
+        "event.message".to_owned(),
+        "Engine at 2000 rpm in Drive, coolant 90 degrees".into()
+    )));
+
+    let attrs = annotate(&arxml_decoder(), ENGINE_STATUS, &ENGINE_STATUS_PAYLOAD);
+    assert!(!attrs.iter().any(|(k, _)| k == "event.message"));
+}
+
+/// The `event.message` of a message with `predefined` text (by how many
+/// arguments come before each), `arguments`, and `payload`
+fn rendered(predefined: &[(usize, &str)], arguments: &[DataType], payload: &[u8]) -> String {
+    let message = NonVerboseMessage {
+        name: "message".to_owned(),
+        arguments: arguments
+            .iter()
+            .enumerate()
+            .map(|(idx, data_type)| Parameter {
+                name: format!("arg{idx}"),
+                data_type: data_type.clone(),
+            })
+            .collect(),
+        predefined_text: predefined
+            .iter()
+            .enumerate()
+            .map(|(idx, (position, text))| PredefinedText {
+                name: format!("text{idx}"),
+                text: text.to_string(),
+                position: *position,
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let mut messages = NonVerboseMessages::default();
+    messages.add_message(MESSAGE_ID, message);
+    let mut decoder = NonVerboseDecoder::new().with_message_rendering(true);
+    decoder.add_dictionary(
+        &NonVerboseDictionaryConfig {
+            name: None,
+            path: "messages.arxml".into(),
+            ecu_id: None,
+            software_version: None,
+        },
+        messages,
+    );
+
+    let mut attrs = vec![];
+    decoder.annotate(
+        &non_verbose_payload("ECU1", MESSAGE_ID, payload.to_vec()),
+        &mut attrs,
+    );
+    assert_eq!(attr(&attrs, "event.decode_error"), None);
+    attr(&attrs, "event.message").unwrap()
+}
+
+#[test]
+fn text_and_values_are_separated_by_spaces() {
+    assert_eq!(
+        rendered(
+            &[(0, "Speed"), (1, "rpm, gear")],
+            &[DataType::Unsigned(2), DataType::Signed(1)],
+            &[0xd0, 0x07, 0xff],
+        ),
+        "Speed 2000 rpm, gear -1"
+    );
+}
+
+#[test]
+fn format_strings_are_filled_in() {
+    let mut payload = vec![0xff, 0x00];
+    payload.extend_from_slice(&1.26_f64.to_le_bytes());
+    payload.extend_from_slice(&(-42_i16).to_le_bytes());
+    payload.extend_from_slice(&[2, 0, b'a', b'b']);
+    assert_eq!(
+        rendered(
+            &[(0, "0x%x is %.1f%% of %5d|%-4s|")],
+            &[
+                DataType::Unsigned(2),
+                DataType::Float64,
+                DataType::Signed(2),
+                DataType::String { fixed_len: None },
+            ],
+            &payload,
+        ),
+        "0xff is 1.3% of   -42|ab  |"
+    );
+}
+
+#[test]
+fn format_strings_without_enough_values_keep_their_conversions() {
+    assert_eq!(
+        rendered(
+            &[(0, "%d of %d done (%.2f)")],
+            &[DataType::Unsigned(1)],
+            &[3]
+        ),
+        "3 of %d done (%.2f)"
+    );
+    assert_eq!(rendered(&[(0, "%s")], &[], &[]), "%s");
+}
+
+#[test]
+fn values_left_over_from_format_strings_are_appended() {
+    let mut payload = vec![3, 4, 0];
+    payload.extend_from_slice(&0.5_f32.to_le_bytes());
+    payload.push(1);
+    assert_eq!(
+        rendered(
+            &[(0, "%d done")],
+            &[
+                DataType::Unsigned(1),
+                DataType::Unsigned(2),
+                DataType::Float32,
+                DataType::Bool,
+            ],
+            &payload,
+        ),
+        "3 done 4 0.5 true"
+    );
+}
@@ -0,0 +1,164 @@
+//! Seeking to, and selecting, part of a .dlt file for import.
+
+use std::io::{Cursor, Read};
+
+use dlt_core::dlt::{self, DltTimeStamp, LogLevel, StorageHeader};
+use modality_dlt::{
+    encode::{encode_framed, verbose_argument},
+    mock::log_message,
+    range::{Bounds, ImportRange, Selection, StartPosition},
+    stream::{DltStream, Framing},
+};
+
+/// Message n is logged at second 100 + n of wall clock time, and has
+/// been up for n / 10 seconds. Its payload mentions the storage header
+/// pattern, to make sure that isn't mistaken for a record boundary.
+fn message(n: u32) -> (StorageHeader, dlt::Message) {
+    let mut msg = log_message(
+        "ECU1",
+        "APP1",
+        "CTX1",
+        LogLevel::Info,
+        vec![verbose_argument(
+            None,
+            dlt::Value::StringVal(format!("DLT\x01 message {n}")),
+        )],
+    );
+    msg.header.timestamp = Some(n * 1000);
+    let storage_header = StorageHeader {
+        timestamp: DltTimeStamp {
+            seconds: 100 + n,
+            microseconds: 0,
+        },
+        ecu_id: "ECU1".to_owned(),
+    };
+    (storage_header, msg)
+}
+
+/// A file of `len` messages, and the offset of each
+fn file(len: u32) -> (Vec<u8>, Vec<u64>) {
+    let mut file = vec![];
+    let mut offsets = vec![];
+    for n in 0..len {
+        let (storage_header, msg) = message(n);
+        offsets.push(file.len() as u64);
+        file.extend(encode_framed(&msg, Framing::StorageHeader, Some(&storage_header)).unwrap());
+    }
+    (file, offsets)
+}
+
+/// Seek, then read and select the way the importer does, returning the
+/// numbers of the messages kept.
+fn import(file: &[u8], range: &ImportRange) -> (StartPosition, Vec<u32>) {
+    let mut cursor = Cursor::new(file);
+    let start = range.seek(&mut cursor).unwrap();
+    assert_eq!(cursor.position(), start.offset);
+
+    let mut rest = vec![];
+    cursor.read_to_end(&mut rest).unwrap();
+    let mut index = start.index;
+    let mut kept = vec![];
+    for record in DltStream::new(rest.as_slice(), Framing::StorageHeader) {
+        let record = record.unwrap();
+        let selection = range.select(index, start.offset + record.offset, &record);
+        index = index.map(|i| i + 1);
+        match selection {
+            Selection::Keep => (),
+            Selection::Skip => continue,
+            Selection::Done => break,
+        }
+        let dlt_core::parse::ParsedMessage::Item(msg) = record.message else {
+            panic!("unexpected message");
+        };
+        kept.push(msg.header.timestamp.unwrap() / 1000);
+    }
+    (start, kept)
+}
+
+#[test]
+fn unbounded_imports_everything() {
+    let (file, _) = file(10);
+    let (start, kept) = import(&file, &ImportRange::default());
+    assert_eq!(start.offset, 0);
+    assert_eq!(kept, (0..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn seeks_by_storage_header_time() {
+    let (file, offsets) = file(200);
+    for (start_time, end_time) in [(150, 160), (100, 101), (299, 400), (50, 110), (400, 500)] {
+        let range = ImportRange {
+            storage_time: Bounds::new(Some(start_time * 1_000_000), Some(end_time * 1_000_000)),
+            ..Default::default()
+        };
+        let (start, kept) = import(&file, &range);
+
+        let first = start_time.clamp(100, 300) as u32 - 100;
+        let last = end_time.clamp(100, 300) as u32 - 100;
+        assert_eq!(kept, (first..last).collect::<Vec<_>>());
+        // Nothing much before the start is read
+        if let Some(first_offset) = offsets.get(first as usize) {
+            assert!(start.offset <= *first_offset);
+            assert!(start.offset >= offsets[first.saturating_sub(1) as usize]);
+        }
+    }
+}
+
+#[test]
+fn seeks_by_index() {
+    let (file, offsets) = file(50);
+    let range = ImportRange {
+        index: Bounds::new(Some(10), Some(13)),
+        ..Default::default()
+    };
+    let (start, kept) = import(&file, &range);
+    assert_eq!(
+        start,
+        StartPosition {
+            offset: offsets[10],
+            index: Some(10)
+        }
+    );
+    assert_eq!(kept, vec![10, 11, 12]);
+}
+
+#[test]
+fn seeks_by_offset_to_the_next_record() {
+    let (file, offsets) = file(50);
+    let range = ImportRange {
+        // Partway through message 20, and just after the start of 30
+        offset: Bounds::new(Some(offsets[20] + 5), Some(offsets[30] + 1)),
+        ..Default::default()
+    };
+    let (start, kept) = import(&file, &range);
+    assert_eq!(start.offset, offsets[21]);
+    assert_eq!(kept, (21..31).collect::<Vec<_>>());
+}
+
+#[test]
+fn counts_the_index_while_seeking_otherwise() {
+    let (file, offsets) = file(50);
+    let range = ImportRange {
+        storage_time: Bounds::new(Some(120 * 1_000_000), None),
+        index: Bounds::new(Some(5), Some(25)),
+        ..Default::default()
+    };
+    let (start, kept) = import(&file, &range);
+    assert_eq!(start.index, Some(20));
+    assert_eq!(start.offset, offsets[20]);
+    assert_eq!(kept, (20..25).collect::<Vec<_>>());
+}
+
+#[test]
+fn filters_by_uptime() {
+    let (file, _) = file(50);
+    let range = ImportRange {
+        // Message n has been up for n / 10 seconds
+        uptime: Bounds::new(Some(3 * 10_000), Some(4 * 10_000)),
+        ..Default::default()
+    };
+    let (start, kept) = import(&file, &range);
+    // Uptime isn't ordered through the file, so there's no seeking
+    assert_eq!(start.offset, 0);
+    assert_eq!(kept, (30..40).collect::<Vec<_>>());
+}
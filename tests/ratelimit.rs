@@ -0,0 +1,336 @@
+//! Sampling and rate limiting in the send path, and the
+//! `suppressed_messages` events summarizing what was left out.
+
+mod common;
+
+use auxon_sdk::api::AttrVal;
+use dlt_core::{
+    dlt::{self, LogLevel, MessageType},
+    parse::ParsedMessage,
+};
+use std::time::Duration;
+
+use modality_dlt::{
+    mock::MockIngest,
+    ratelimit::{RateLimitRule, RateLimiter, SUPPRESSED_MESSAGES_EVENT},
+    send::{message_channel, EventBatcher, Pushed, Sender},
+    CommonConfig,
+};
+
+use common::{config, text_message, texts, SharedIngest};
+
+/// A log message from ECU1, `seconds` after the ECU started
+fn log_at(application_id: &str, context_id: &str, seconds: f64, text: &str) -> ParsedMessage {
+    let mut msg = text_message("ECU1", application_id, context_id, text);
+    msg.header.timestamp = Some((seconds * 1e4) as u32);
+    ParsedMessage::Item(msg)
+}
+
+fn non_verbose_at(message_id: u32, seconds: f64) -> ParsedMessage {
+    let mut msg = dlt::Message::new(
+        dlt::MessageConfig {
+            version: 1,
+            counter: 0,
+            endianness: dlt::Endianness::Little,
+            ecu_id: Some("ECU1".to_owned()),
+            session_id: None,
+            timestamp: None,
+            payload: dlt::PayloadContent::NonVerbose(message_id, vec![1, 2, 3]),
+            extended_header_info: Some(dlt::ExtendedHeaderConfig {
+                message_type: MessageType::Log(LogLevel::Info),
+                app_id: "APP1".to_owned(),
+                context_id: "CTX1".to_owned(),
+            }),
+        },
+        None,
+    );
+    msg.header.timestamp = Some((seconds * 1e4) as u32);
+    ParsedMessage::Item(msg)
+}
+
+fn batcher(rules: Vec<RateLimitRule>, report_interval: f64) -> EventBatcher {
+    EventBatcher::new().with_rate_limiter(RateLimiter::new(rules, report_interval))
+}
+
+#[test]
+fn keeps_one_in_n() {
+    let config = CommonConfig::default();
+    let mut batcher = batcher(
+        vec![RateLimitRule {
+            application_id: Some("APP1".to_owned()),
+            keep_one_in: Some(3),
+            ..Default::default()
+        }],
+        1000.0,
+    );
+    for i in 0..7 {
+        batcher.push(log_at("APP1", "CTX1", 0.0, &i.to_string()), &config);
+        batcher.push(log_at("APP2", "CTX1", 0.0, &format!("other {i}")), &config);
+    }
+    batcher.push_suppression_reports();
+
+    let mut ingest = MockIngest::new();
+    ingest.ingest_from(&mut batcher);
+    let kept: Vec<String> = texts(&ingest)
+        .into_iter()
+        .filter(|p| !p.starts_with("other"))
+        .collect();
+    assert_eq!(kept, vec!["0", "3", "6"]);
+    assert_eq!(
+        texts(&ingest)
+            .iter()
+            .filter(|p| p.starts_with("other"))
+            .count(),
+        7
+    );
+
+    let reports: Vec<_> = ingest.events_named(SUPPRESSED_MESSAGES_EVENT).collect();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(
+        reports[0].attr("event.suppressed_count"),
+        Some(&AttrVal::from(4_i64))
+    );
+    assert_eq!(
+        reports[0].attr("event.kept_count"),
+        Some(&AttrVal::from(3_i64))
+    );
+    assert_eq!(
+        reports[0].attr("event.application_id"),
+        Some(&AttrVal::from("APP1"))
+    );
+}
+
+#[test]
+fn token_bucket_refills_with_message_time() {
+    let config = CommonConfig::default();
+    let mut batcher = batcher(
+        vec![RateLimitRule {
+            max_per_second: Some(2.0),
+            burst: Some(3.0),
+            ..Default::default()
+        }],
+        1000.0,
+    );
+
+    // A burst of 3 gets through at once, then 2 per second
+    let mut pushed = vec![];
+    for i in 0..10 {
+        pushed.push(batcher.push(log_at("APP1", "CTX1", 0.0, &format!("a{i}")), &config));
+    }
+    for i in 0..10 {
+        batcher.push(log_at("APP1", "CTX1", 1.0, &format!("b{i}")), &config);
+    }
+    assert_eq!(pushed.iter().filter(|p| **p == Pushed::Queued).count(), 3);
+    assert_eq!(
+        pushed[3],
+        Pushed::Suppressed {
+            ecu_id: Some("ECU1".to_owned())
+        }
+    );
+
+    let mut ingest = MockIngest::new();
+    ingest.ingest_from(&mut batcher);
+    assert_eq!(texts(&ingest), vec!["a0", "a1", "a2", "b0", "b1"]);
+}
+
+#[test]
+fn each_context_has_its_own_bucket() {
+    let config = CommonConfig::default();
+    let mut batcher = batcher(
+        vec![RateLimitRule {
+            max_per_second: Some(1.0),
+            ..Default::default()
+        }],
+        1000.0,
+    );
+    for ctx in ["CTX1", "CTX2", "CTX1", "CTX2"] {
+        batcher.push(log_at("APP1", ctx, 0.0, ctx), &config);
+    }
+
+    let mut ingest = MockIngest::new();
+    ingest.ingest_from(&mut batcher);
+    assert_eq!(texts(&ingest), vec!["CTX1", "CTX2"]);
+}
+
+#[test]
+fn rules_can_limit_a_single_message_id() {
+    let config = CommonConfig::default();
+    let mut batcher = batcher(
+        vec![RateLimitRule {
+            name: Some("chatty".to_owned()),
+            message_id: Some(42),
+            keep_one_in: Some(10),
+            ..Default::default()
+        }],
+        1000.0,
+    );
+    for _ in 0..10 {
+        batcher.push(non_verbose_at(42, 0.0), &config);
+        batcher.push(non_verbose_at(43, 0.0), &config);
+    }
+    batcher.push_suppression_reports();
+
+    let mut ingest = MockIngest::new();
+    ingest.ingest_from(&mut batcher);
+    let ids = |id: u32| {
+        ingest
+            .events
+            .iter()
+            .filter(|ev| ev.name != SUPPRESSED_MESSAGES_EVENT)
+            .filter(|ev| ev.attr("event.message_id") == Some(&AttrVal::from(id)))
+            .count()
+    };
+    assert_eq!(ids(42), 1);
+    assert_eq!(ids(43), 10);
+
+    let report = ingest
+        .events_named(SUPPRESSED_MESSAGES_EVENT)
+        .next()
+        .unwrap();
+    assert_eq!(report.attr("event.rule"), Some(&AttrVal::from("chatty")));
+    assert_eq!(
+        report.attr("event.message_id"),
+        Some(&AttrVal::from(42_u32))
+    );
+    assert_eq!(
+        report.attr("event.suppressed_count"),
+        Some(&AttrVal::from(9_i64))
+    );
+}
+
+#[test]
+fn summaries_are_sent_periodically_on_the_messages_timeline() {
+    let config = CommonConfig::default();
+    let mut batcher = batcher(
+        vec![RateLimitRule {
+            keep_one_in: Some(2),
+            ..Default::default()
+        }],
+        10.0,
+    );
+    for i in 0..30 {
+        batcher.push(log_at("APP1", "CTX1", i as f64, &i.to_string()), &config);
+    }
+
+    let mut ingest = MockIngest::new();
+    ingest.ingest_from(&mut batcher);
+    let reports: Vec<_> = ingest.events_named(SUPPRESSED_MESSAGES_EVENT).collect();
+    let counts: Vec<_> = reports
+        .iter()
+        .map(|ev| ev.attr("event.suppressed_count").cloned().unwrap())
+        .collect();
+    assert_eq!(counts, vec![AttrVal::from(5_i64), AttrVal::from(5_i64)]);
+
+    let timeline = ingest.timeline_named("ECU1").unwrap();
+    assert!(reports.iter().all(|ev| ev.timeline_id == timeline.id));
+
+    // The summary for the first 10 seconds comes before the message at 10s
+    let position = |payload: &str| {
+        ingest
+            .events
+            .iter()
+            .position(|ev| ev.attr("event.payload.text") == Some(&AttrVal::from(payload)))
+            .unwrap()
+    };
+    let first_report = ingest
+        .events
+        .iter()
+        .position(|ev| ev.name == SUPPRESSED_MESSAGES_EVENT)
+        .unwrap();
+    assert!(position("8") < first_report);
+    assert!(first_report < position("10"));
+
+    // What was suppressed since then is summarized on request, once
+    batcher.push_suppression_reports();
+    assert_eq!(batcher.len(), 1);
+    batcher.take_batches();
+    batcher.push_suppression_reports();
+    assert!(batcher.is_empty());
+}
+
+#[tokio::test]
+async fn sender_counts_suppressed_messages_and_summarizes_them_when_finished() {
+    let config = config(|c| {
+        c.rate_limits = vec![RateLimitRule {
+            keep_one_in: Some(4),
+            ..Default::default()
+        }]
+    });
+    let mut sender = Sender::new(MockIngest::new(), config);
+    for i in 0..8 {
+        sender
+            .handle_message(log_at("APP1", "CTX1", 0.0, &i.to_string()))
+            .await
+            .unwrap();
+    }
+    sender.finish().await.unwrap();
+
+    assert_eq!(sender.metrics().summary().messages_suppressed, 6);
+    let ingest = sender.sink();
+    assert_eq!(texts(ingest), vec!["0", "4"]);
+    let report = ingest
+        .events_named(SUPPRESSED_MESSAGES_EVENT)
+        .next()
+        .unwrap();
+    assert_eq!(
+        report.attr("event.suppressed_count"),
+        Some(&AttrVal::from(6_i64))
+    );
+}
+
+#[test]
+fn suppressed_messages_leave_no_batches_behind() {
+    let config = CommonConfig::default();
+    let mut batcher = batcher(
+        vec![RateLimitRule {
+            keep_one_in: Some(2),
+            ..Default::default()
+        }],
+        10.0,
+    );
+    batcher.push(log_at("APP1", "CTX1", 0.0, "0"), &config);
+    assert_eq!(batcher.take_batches().len(), 1);
+
+    assert!(matches!(
+        batcher.push(log_at("APP1", "CTX1", 0.0, "1"), &config),
+        Pushed::Suppressed { .. }
+    ));
+    assert!(batcher.take_batches().is_empty());
+}
+
+#[tokio::test]
+async fn sender_summarizes_bursts_which_stop() {
+    let config = config(|c| {
+        c.rate_limits = vec![RateLimitRule {
+            keep_one_in: Some(4),
+            ..Default::default()
+        }];
+        c.suppressed_messages_interval = Some(0.1);
+    });
+    let ingest = SharedIngest::default();
+    let mut sender = Sender::new(ingest.clone(), config);
+    let (tx, mut rx) = message_channel(&CommonConfig::default());
+
+    // Every message is logged at the same uptime, so only the timer can
+    // make a summary due
+    let burst = async move {
+        for i in 0..8 {
+            tx.send(log_at("APP1", "CTX1", 0.0, &i.to_string()))
+                .await
+                .unwrap();
+        }
+        for _ in 0..500 {
+            let ingest = ingest.0.lock().await;
+            if let Some(report) = ingest.events_named(SUPPRESSED_MESSAGES_EVENT).next() {
+                assert_eq!(texts(&ingest), vec!["0", "4"]);
+                return report.attr("event.suppressed_count").cloned();
+            }
+            drop(ingest);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        None
+    };
+    let (res, suppressed_count) = tokio::join!(sender.run(&mut rx), burst);
+    res.unwrap();
+    assert_eq!(suppressed_count, Some(AttrVal::from(6_i64)));
+}
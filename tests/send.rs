@@ -0,0 +1,333 @@
+//! [Sender]'s timeline switching and attribute logic, against the
+//! in-memory [MockIngest] sink.
+
+mod common;
+
+use std::sync::Arc;
+
+use auxon_sdk::api::{AttrKey, AttrVal};
+use dlt_core::{dlt, parse::ParsedMessage};
+use modality_dlt::{
+    control::{
+        ControlLogLevel, ControlRequest, ControlStatus, SERVICE_GET_SOFTWARE_VERSION,
+        SERVICE_SET_DEFAULT_LOG_LEVEL,
+    },
+    mock::{control_response, IngestedEvent, MockIngest},
+    send::{EventAnnotator, Sender},
+    CommonConfig,
+};
+
+use common::{config, log, texts, TestConfig};
+
+/// A sender with the default configuration, apart from what
+/// `configure` changes
+fn sender(configure: impl FnOnce(&mut CommonConfig)) -> Sender<TestConfig, MockIngest> {
+    Sender::new(MockIngest::new(), config(configure))
+}
+
+async fn send_all(sender: &mut Sender<TestConfig, MockIngest>, messages: Vec<ParsedMessage>) {
+    for msg in messages {
+        sender.handle_message(msg).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn new_timelines_get_their_attrs_first() {
+    let mut sender = sender(|_| ());
+    send_all(
+        &mut sender,
+        vec![
+            log("ECU1", "APP1", "one"),
+            log("ECU2", "APP1", "two"),
+            log("ECU1", "APP1", "three"),
+        ],
+    )
+    .await;
+    assert!(sender.sink().events.is_empty());
+    sender.flush().await.unwrap();
+
+    let ingest = sender.sink();
+    assert_eq!(ingest.timelines.len(), 2);
+    let ecu1 = ingest.timeline_named("ECU1").unwrap();
+    assert_eq!(
+        ecu1.attrs,
+        vec![
+            ("timeline.ecu_id".to_owned(), AttrVal::from("ECU1")),
+            ("timeline.session_id".to_owned(), AttrVal::from("ECU1")),
+        ]
+    );
+    assert!(ingest.timeline_named("ECU2").is_some());
+
+    // Events are grouped by timeline, but keep their ordering
+    let orderings: Vec<(String, u128)> = ingest
+        .events
+        .iter()
+        .map(|ev| {
+            (
+                ingest.timeline(ev.timeline_id).unwrap().name.clone(),
+                ev.ordering,
+            )
+        })
+        .collect();
+    assert_eq!(
+        orderings,
+        vec![
+            ("ECU1".to_owned(), 0),
+            ("ECU1".to_owned(), 2),
+            ("ECU2".to_owned(), 1)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn event_attrs_come_from_the_message() {
+    let mut sender = sender(|_| ());
+    send_all(&mut sender, vec![log("ECU1", "APP1", "hello")]).await;
+    sender.flush().await.unwrap();
+
+    let event = &sender.sink().events[0];
+    assert_eq!(event.name, "log");
+    assert_eq!(
+        event.attr("event.payload.text"),
+        Some(&AttrVal::from("hello"))
+    );
+    assert_eq!(event.attr("event.ecu_id"), Some(&AttrVal::from("ECU1")));
+    assert_eq!(
+        event.attr("event.application_id"),
+        Some(&AttrVal::from("APP1"))
+    );
+    assert_eq!(event.attr("event.context_id"), Some(&AttrVal::from("CTX1")));
+    assert_eq!(event.attr("event.log_level"), Some(&AttrVal::from("info")));
+}
+
+#[tokio::test]
+async fn timeline_identity_follows_the_config() {
+    let mut sender = sender(|c| c.timeline_from_application_id = Some(true));
+    send_all(
+        &mut sender,
+        vec![log("ECU1", "APP1", "one"), log("ECU1", "APP2", "two")],
+    )
+    .await;
+    sender.flush().await.unwrap();
+
+    let ingest = sender.sink();
+    let names: Vec<&str> = ingest.timelines.iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(names, vec!["ECU1.APP1", "ECU1.APP2"]);
+    assert_eq!(
+        ingest.timelines[1]
+            .attrs
+            .iter()
+            .find(|(k, _)| k == "timeline.application_id")
+            .map(|(_, v)| v),
+        Some(&AttrVal::from("APP2"))
+    );
+}
+
+#[tokio::test]
+async fn switches_timeline_only_when_needed() {
+    let mut sender = sender(|_| ());
+    send_all(
+        &mut sender,
+        vec![
+            log("ECU1", "APP1", "one"),
+            log("ECU2", "APP1", "two"),
+            log("ECU1", "APP1", "three"),
+            log("ECU2", "APP1", "four"),
+        ],
+    )
+    .await;
+    sender.flush().await.unwrap();
+    assert_eq!(sender.sink().timeline_switches, 2);
+
+    // Still on ECU2 from the last flush
+    send_all(&mut sender, vec![log("ECU2", "APP1", "five")]).await;
+    sender.flush().await.unwrap();
+    assert_eq!(sender.sink().timeline_switches, 2);
+
+    send_all(&mut sender, vec![log("ECU1", "APP1", "six")]).await;
+    sender.flush().await.unwrap();
+    assert_eq!(sender.sink().timeline_switches, 3);
+
+    // Known timelines don't have their attrs sent again
+    assert_eq!(sender.sink().timelines.len(), 2);
+    assert_eq!(sender.sink().timelines[0].attrs.len(), 2);
+}
+
+#[tokio::test]
+async fn replacing_the_client_switches_timeline_again() {
+    let mut sender = sender(|_| ());
+    send_all(&mut sender, vec![log("ECU1", "APP1", "one")]).await;
+    sender.flush().await.unwrap();
+
+    sender.set_client(MockIngest::new());
+    send_all(&mut sender, vec![log("ECU1", "APP1", "two")]).await;
+    sender.flush().await.unwrap();
+
+    let ingest = sender.sink();
+    assert_eq!(ingest.timeline_switches, 1);
+    assert_eq!(ingest.events.len(), 1);
+    // The timeline was already created through the old client
+    assert!(ingest.timelines.is_empty());
+}
+
+#[tokio::test]
+async fn sends_once_the_batch_is_full() {
+    let mut sender = sender(|c| c.batch_size = Some(2));
+    send_all(&mut sender, vec![log("ECU1", "APP1", "one")]).await;
+    assert!(sender.sink().events.is_empty());
+    assert!(sender.has_pending_events());
+
+    send_all(&mut sender, vec![log("ECU1", "APP1", "two")]).await;
+    assert_eq!(sender.sink().events.len(), 2);
+    assert!(!sender.has_pending_events());
+}
+
+#[tokio::test]
+async fn unsent_events_are_kept_after_a_failure() {
+    let mut sender = sender(|_| ());
+    send_all(
+        &mut sender,
+        vec![
+            log("ECU1", "APP1", "one"),
+            log("ECU2", "APP1", "two"),
+            log("ECU1", "APP1", "three"),
+        ],
+    )
+    .await;
+
+    sender.sink_mut().set_fail_after(Some(1));
+    assert!(sender.flush().await.is_err());
+    assert_eq!(texts(sender.sink()), vec!["one"]);
+    assert!(sender.has_pending_events());
+
+    // Messages handled in the meantime go after the unsent ones
+    send_all(&mut sender, vec![log("ECU2", "APP1", "four")]).await;
+    sender.sink_mut().set_fail_after(None);
+    sender.flush().await.unwrap();
+
+    assert_eq!(texts(sender.sink()), vec!["one", "three", "two", "four"]);
+    assert_eq!(sender.sink().timelines.len(), 2);
+}
+
+#[tokio::test]
+async fn stop_sends_an_event_to_every_timeline() {
+    let mut sender = sender(|_| ());
+    send_all(
+        &mut sender,
+        vec![log("ECU1", "APP1", "one"), log("ECU2", "APP1", "two")],
+    )
+    .await;
+    sender.stop("shutdown", "ctrl-c").await.unwrap();
+
+    let ingest = sender.sink();
+    let stops: Vec<_> = ingest.events_named("shutdown").collect();
+    assert_eq!(stops.len(), 2);
+    assert_ne!(stops[0].timeline_id, stops[1].timeline_id);
+    for ev in stops {
+        assert_eq!(ev.attr("event.reason"), Some(&AttrVal::from("ctrl-c")));
+    }
+}
+
+#[tokio::test]
+async fn pushes_to_the_last_timeline() {
+    let mut sender = sender(|_| ());
+    assert!(!sender.push_to_last_timeline("note", vec![]));
+
+    send_all(
+        &mut sender,
+        vec![log("ECU1", "APP1", "one"), log("ECU2", "APP1", "two")],
+    )
+    .await;
+    assert!(sender.push_to_last_timeline("note", vec![("event.n".into(), 1_i64.into())]));
+    sender.flush().await.unwrap();
+
+    let ingest = sender.sink();
+    let note = ingest.events_named("note").next().unwrap();
+    assert_eq!(
+        ingest.timeline(note.timeline_id).unwrap().name,
+        "ECU2".to_owned()
+    );
+    assert_eq!(note.attr("event.n"), Some(&AttrVal::from(1_i64)));
+}
+
+struct Greeter;
+
+impl EventAnnotator for Greeter {
+    fn annotate(&self, _msg: &dlt::Message, attrs: &mut Vec<(AttrKey, AttrVal)>) {
+        attrs.push(("event.greeting".into(), "hi".into()));
+    }
+
+    fn event_name(&self, _msg: &dlt::Message) -> Option<String> {
+        Some("greeting".to_owned())
+    }
+}
+
+#[tokio::test]
+async fn annotators_add_attrs_and_names() {
+    let mut sender = sender(|_| ()).with_annotator(Arc::new(Greeter));
+    send_all(&mut sender, vec![log("ECU1", "APP1", "one")]).await;
+    sender.flush().await.unwrap();
+
+    let event = &sender.sink().events[0];
+    assert_eq!(event.name, "greeting");
+    assert_eq!(event.attr("event.greeting"), Some(&AttrVal::from("hi")));
+}
+
+/// `request`, as sent to `ecu_id` and seen again through the daemon
+fn control_request(ecu_id: &str, request: &ControlRequest) -> ParsedMessage {
+    let bytes = request.to_bytes(ecu_id, 0);
+    dlt_core::parse::dlt_message(&bytes, None, false).unwrap().1
+}
+
+fn control_ok(ecu_id: &str, service_id: u32) -> ParsedMessage {
+    ParsedMessage::Item(control_response(ecu_id, service_id, ControlStatus::Ok, &[]))
+}
+
+#[tokio::test]
+async fn control_responses_link_to_their_requests() {
+    let set_level = ControlRequest::SetDefaultLogLevel {
+        log_level: ControlLogLevel::Info,
+    };
+    let mut sender = sender(|_| ());
+    send_all(
+        &mut sender,
+        vec![
+            control_request("ECU1", &ControlRequest::GetSoftwareVersion),
+            control_request("ECU1", &set_level),
+            control_request("ECU2", &set_level),
+            // Answered out of order
+            control_ok("ECU1", SERVICE_SET_DEFAULT_LOG_LEVEL),
+            control_ok("ECU1", SERVICE_GET_SOFTWARE_VERSION),
+            control_ok("ECU2", SERVICE_SET_DEFAULT_LOG_LEVEL),
+            // Every request has been answered already
+            control_ok("ECU1", SERVICE_SET_DEFAULT_LOG_LEVEL),
+        ],
+    )
+    .await;
+    sender.flush().await.unwrap();
+
+    let events = &sender.sink().events;
+    assert_eq!(events.len(), 7);
+    let request = |ev: &IngestedEvent| {
+        let nonce = ev.attr("event.nonce").cloned();
+        assert!(nonce.is_some());
+        (Some(AttrVal::from(ev.timeline_id)), nonce)
+    };
+    let link = |ev: &IngestedEvent| {
+        (
+            ev.attr("event.interaction.remote_timeline_id").cloned(),
+            ev.attr("event.interaction.remote_nonce").cloned(),
+        )
+    };
+    for (request_idx, response_idx) in [(0, 4), (1, 3), (2, 5)] {
+        assert_eq!(link(&events[response_idx]), request(&events[request_idx]));
+    }
+    assert_eq!(link(&events[6]), (None, None));
+
+    // Each request has its own nonce
+    let nonces: std::collections::HashSet<_> = events[..3]
+        .iter()
+        .map(|ev| format!("{:?}", ev.attr("event.nonce").unwrap()))
+        .collect();
+    assert_eq!(nonces.len(), 3);
+}
@@ -0,0 +1,144 @@
+//! Stopping the collector gracefully when asked, and giving up on that
+//! when asked again or when it takes too long.
+
+mod common;
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use auxon_sdk::api::AttrVal;
+use modality_dlt::{
+    collect::Collector,
+    metrics::Metrics,
+    mock::{MockDaemon, MockDaemonHandle, Script},
+    shutdown::{Shutdown, ShutdownTrigger},
+};
+
+use common::{collector_config, text_message, texts, SharedIngest};
+
+const TEXTS: [(&str, &str); 3] = [("ECU1", "one"), ("ECU2", "two"), ("ECU1", "three")];
+
+/// A daemon which sends [TEXTS], then keeps the connection open
+async fn start() -> MockDaemonHandle {
+    let mut script = Script::new();
+    for (ecu_id, text) in TEXTS {
+        script
+            .push(&text_message(ecu_id, "APP1", "CTX1", text))
+            .unwrap();
+    }
+    MockDaemon::new(script)
+        .with_hold_open()
+        .start(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap()
+}
+
+/// Collect from `daemon` into `ingest`, which is locked so nothing can
+/// be sent yet. Once everything has been read, `signals` are delivered;
+/// then `ingest` is unlocked if `unblock` is set. Returns how long the
+/// collector took to stop after the first signal.
+async fn collect(
+    daemon: &MockDaemonHandle,
+    ingest: &SharedIngest,
+    timeout: Duration,
+    signals: &[&'static str],
+    unblock: bool,
+) -> Duration {
+    let metrics = Metrics::new("");
+    let (shutdown, trigger) = Shutdown::manual(timeout);
+    let collector = Collector::new(collector_config(|_| ()), ingest.clone())
+        .with_metrics(metrics.clone())
+        .with_shutdown(shutdown);
+
+    let blocked = ingest.0.lock().await;
+    let stop = signal_when_read(&metrics, &trigger, signals);
+    let (res, (signalled_at, blocked)) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(collector.run(daemon.local_addr()), async {
+            let signalled_at = stop.await;
+            // Dropped here to unblock, or held until the collector stops
+            (signalled_at, (!unblock).then_some(blocked))
+        })
+    })
+    .await
+    .unwrap();
+    res.unwrap();
+    drop(blocked);
+    signalled_at.elapsed()
+}
+
+/// Deliver `signals` once every message has been read, returning when
+/// the first was
+async fn signal_when_read(
+    metrics: &Arc<Metrics>,
+    trigger: &ShutdownTrigger,
+    signals: &[&'static str],
+) -> Instant {
+    while metrics.summary().messages_read < TEXTS.len() as u64 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    let signalled_at = Instant::now();
+    for signal in signals {
+        trigger.signal(signal);
+    }
+    signalled_at
+}
+
+#[tokio::test]
+async fn stopping_sends_everything_read() {
+    let daemon = start().await;
+    let ingest = SharedIngest::default();
+    collect(
+        &daemon,
+        &ingest,
+        Duration::from_secs(60),
+        &["SIGTERM"],
+        true,
+    )
+    .await;
+
+    let ingest = ingest.0.lock().await;
+    let expected: Vec<_> = TEXTS.iter().map(|(_, text)| text.to_string()).collect();
+    assert_eq!(texts(&ingest), expected);
+
+    // Each timeline is told why collection stopped
+    let stopped: Vec<_> = ingest.events_named("collector_stopped").collect();
+    assert_eq!(stopped.len(), 2);
+    assert_ne!(stopped[0].timeline_id, stopped[1].timeline_id);
+    assert!(stopped
+        .iter()
+        .all(|ev| ev.attr("event.reason") == Some(&AttrVal::from("SIGTERM"))));
+}
+
+#[tokio::test]
+async fn stopping_is_abandoned_after_the_timeout() {
+    let daemon = start().await;
+    let ingest = SharedIngest::default();
+    let timeout = Duration::from_millis(200);
+    let took = collect(&daemon, &ingest, timeout, &["SIGINT"], false).await;
+    assert!(took >= timeout, "{took:?}");
+
+    // Nothing got through while the backend was stuck
+    let ingest = ingest.0.lock().await;
+    assert!(ingest.events.is_empty());
+}
+
+#[tokio::test]
+async fn stopping_is_abandoned_when_asked_again() {
+    let daemon = start().await;
+    let ingest = SharedIngest::default();
+    let took = collect(
+        &daemon,
+        &ingest,
+        Duration::from_secs(60),
+        &["SIGINT", "SIGINT"],
+        false,
+    )
+    .await;
+    assert!(took < Duration::from_secs(5), "{took:?}");
+
+    let ingest = ingest.0.lock().await;
+    assert!(ingest.events.is_empty());
+}
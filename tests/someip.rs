@@ -0,0 +1,194 @@
+//! Naming SOME/IP network trace events after their method, and decoding
+//! their payloads, with the same service described in ARXML and in
+//! FIBEX.
+
+use std::{path::Path, sync::Arc};
+
+use auxon_sdk::api::AttrVal;
+use dlt_core::{dlt::NetworkTraceType, parse::ParsedMessage};
+use modality_dlt::{
+    mock::{network_trace_message, IngestedEvent, MockIngest},
+    send::EventBatcher,
+    someip::SomeIpServices,
+    CommonConfig,
+};
+
+/// The Climate service's id, and the ids of its SetTemperature method
+/// and Readings event
+const CLIMATE: u16 = 0x1234;
+const SET_TEMPERATURE: u16 = 0x0001;
+const READINGS: u16 = 0x8001;
+
+const REQUEST: u8 = 0x00;
+const NOTIFICATION: u8 = 0x02;
+const RESPONSE: u8 = 0x80;
+
+/// The same service, described in each format
+fn fixtures() -> Vec<SomeIpServices> {
+    ["services.arxml", "services.fibex"]
+        .into_iter()
+        .map(|file| {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/data")
+                .join(file);
+            SomeIpServices::load(&path).unwrap()
+        })
+        .collect()
+}
+
+/// The event made from a SOME/IP message for `method_id` of the Climate
+/// service, with `payload`
+fn event(
+    services: SomeIpServices,
+    method_id: u16,
+    message_type: u8,
+    payload: &[u8],
+) -> IngestedEvent {
+    let mut header = vec![];
+    header.extend_from_slice(&CLIMATE.to_be_bytes());
+    header.extend_from_slice(&method_id.to_be_bytes());
+    header.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+    // Client 1, session 1, protocol version 1, interface version 1
+    header.extend_from_slice(&[0, 1, 0, 1, 1, 1, message_type, 0]);
+    let msg = network_trace_message(
+        "ECU1",
+        "SOME",
+        "IP",
+        NetworkTraceType::SomeIP,
+        &header,
+        payload,
+    );
+
+    let mut batcher = EventBatcher::new();
+    batcher.add_annotator(Arc::new(services));
+    batcher.push(ParsedMessage::Item(msg), &CommonConfig::default());
+    let mut ingest = MockIngest::new();
+    ingest.ingest_from(&mut batcher);
+    assert_eq!(ingest.events.len(), 1);
+    ingest.events.remove(0)
+}
+
+/// The decoded payload attributes of `event`
+fn payload(event: &IngestedEvent) -> Vec<(String, AttrVal)> {
+    event
+        .attrs
+        .iter()
+        .filter(|(k, _)| k.starts_with("event.payload."))
+        .cloned()
+        .collect()
+}
+
+fn expected(attrs: &[(&str, AttrVal)]) -> Vec<(String, AttrVal)> {
+    attrs
+        .iter()
+        .map(|(k, v)| (format!("event.payload.{k}"), v.clone()))
+        .collect()
+}
+
+#[test]
+fn requests_are_named_after_the_method_and_decoded() {
+    for services in fixtures() {
+        // Zone 1, aiming for 21.5 and -0.5 degrees (in tenths)
+        let ev = event(
+            services,
+            SET_TEMPERATURE,
+            REQUEST,
+            &[0x01, 0x00, 0xd7, 0xff, 0xfb],
+        );
+        assert_eq!(ev.name, "SetTemperature");
+        assert_eq!(
+            ev.attr("event.someip.service_name"),
+            Some(&AttrVal::from("Climate"))
+        );
+        assert_eq!(
+            ev.attr("event.someip.method_name"),
+            Some(&AttrVal::from("SetTemperature"))
+        );
+        // A struct, with a fixed size array in it
+        assert_eq!(
+            payload(&ev),
+            expected(&[
+                ("setting.zone", 1_u8.into()),
+                ("setting.targets.0", 215_i16.into()),
+                ("setting.targets.1", (-5_i16).into()),
+            ])
+        );
+    }
+}
+
+#[test]
+fn responses_are_decoded_with_the_return_parameters() {
+    for services in fixtures() {
+        let ev = event(services, SET_TEMPERATURE, RESPONSE, &[0x01]);
+        assert_eq!(ev.name, "SetTemperature");
+        assert_eq!(payload(&ev), expected(&[("accepted", true.into())]));
+    }
+}
+
+#[test]
+fn events_are_decoded() {
+    for services in fixtures() {
+        // A variable size array of structs: its length in bytes, then
+        // sensor 1 at -10, and sensor 2 at 20
+        let ev = event(
+            services,
+            READINGS,
+            NOTIFICATION,
+            &[0, 0, 0, 8, 0, 1, 0xff, 0xf6, 0, 2, 0, 20],
+        );
+        assert_eq!(ev.name, "Readings");
+        assert_eq!(
+            payload(&ev),
+            expected(&[
+                ("Readings.0.sensor", 1_u16.into()),
+                ("Readings.0.value", (-10_i16).into()),
+                ("Readings.1.sensor", 2_u16.into()),
+                ("Readings.1.value", 20_i16.into()),
+            ])
+        );
+    }
+}
+
+#[test]
+fn truncated_payloads_are_named_but_not_decoded() {
+    for services in fixtures() {
+        // The second target is missing
+        let ev = event(
+            services,
+            SET_TEMPERATURE,
+            REQUEST,
+            &[0x01, 0x00, 0xd7, 0xff],
+        );
+        assert_eq!(ev.name, "SetTemperature");
+        assert_eq!(
+            ev.attr("event.someip.method_name"),
+            Some(&AttrVal::from("SetTemperature"))
+        );
+        assert_eq!(payload(&ev), vec![]);
+    }
+
+    for services in fixtures() {
+        // The length field promises a second reading which isn't there
+        let ev = event(
+            services,
+            READINGS,
+            NOTIFICATION,
+            &[0, 0, 0, 8, 0, 1, 0xff, 0xf6],
+        );
+        assert_eq!(ev.name, "Readings");
+        assert_eq!(payload(&ev), vec![]);
+    }
+}
+
+#[test]
+fn unknown_methods_are_left_alone() {
+    for services in fixtures() {
+        let ev = event(services, 0x0002, REQUEST, &[0x01]);
+        assert_ne!(ev.name, "SetTemperature");
+        assert_eq!(ev.attr("event.someip.method_name"), None);
+        assert_eq!(
+            ev.attr("event.someip.service_id"),
+            Some(&AttrVal::from(CLIMATE as i64))
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! The on-disk spool, and draining it after the backend connection was lost.
+
+mod common;
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use dlt_core::parse::ParsedMessage;
+use modality_dlt::{
+    metrics::Metrics,
+    mock::MockIngest,
+    send::{EventBatcher, Sender},
+    spool::{DropPolicy, Spool, SpoolConfig, SpoolingSender},
+    CommonConfig,
+};
+use tokio::sync::mpsc;
+
+use common::{config, log, texts, SharedIngest};
+
+/// An empty directory for the spool of test `name`
+fn spool_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "modality-dlt-spool-test-{}-{name}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn numbered(range: std::ops::Range<usize>) -> Vec<ParsedMessage> {
+    range
+        .map(|n| log("ECU1", "APP1", &format!("message {n}")))
+        .collect()
+}
+
+fn numbered_texts(range: std::ops::Range<usize>) -> Vec<String> {
+    range.map(|n| format!("message {n}")).collect()
+}
+
+/// The texts of `messages`, as they'd be sent
+fn spooled_texts(messages: Vec<ParsedMessage>) -> Vec<String> {
+    let config = CommonConfig::default();
+    let mut batcher = EventBatcher::new();
+    for parsed_msg in messages {
+        batcher.push(parsed_msg, &config);
+    }
+    let mut ingest = MockIngest::new();
+    ingest.ingest_from(&mut batcher);
+    texts(&ingest)
+}
+
+/// The size of one [numbered] message in the spool: its length,
+/// followed by the message
+fn record_size() -> u64 {
+    let ParsedMessage::Item(msg) = numbered(0..1).remove(0) else {
+        unreachable!()
+    };
+    4 + msg.as_bytes().len() as u64
+}
+
+fn segment_count(dir: &Path) -> usize {
+    std::fs::read_dir(dir).unwrap().count()
+}
+
+#[test]
+fn pushed_messages_are_read_back_in_order() {
+    let dir = spool_dir("push");
+    let mut spool = Spool::open(&dir, &SpoolConfig::default()).unwrap();
+    assert!(spool.is_empty());
+    for parsed_msg in numbered(0..3) {
+        assert!(spool.push(parsed_msg).unwrap());
+    }
+    assert!(!spool.is_empty());
+
+    let messages = spool.read_oldest().unwrap().unwrap();
+    assert_eq!(spooled_texts(messages), numbered_texts(0..3));
+
+    // Reading doesn't consume anything by itself
+    let messages = spool.read_oldest().unwrap().unwrap();
+    assert_eq!(messages.len(), 3);
+
+    spool.remove_oldest().unwrap();
+    assert!(spool.is_empty());
+    assert_eq!(spool.len_bytes(), 0);
+    assert!(spool.read_oldest().unwrap().is_none());
+    assert_eq!(segment_count(&dir), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn consumed_messages_are_not_read_again() {
+    let dir = spool_dir("consumed");
+    let mut spool = Spool::open(&dir, &SpoolConfig::default()).unwrap();
+    for parsed_msg in numbered(0..4) {
+        spool.push(parsed_msg).unwrap();
+    }
+
+    spool.mark_consumed();
+    spool.mark_consumed();
+    let messages = spool.read_oldest().unwrap().unwrap();
+    assert_eq!(spooled_texts(messages), numbered_texts(2..4));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unparseable_records_count_as_consumed() {
+    let dir = spool_dir("unparseable");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // A segment from a previous run with a damaged record in the middle
+    let mut segment = vec![];
+    let mut push_record = |bytes: &[u8]| {
+        segment.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        segment.extend_from_slice(bytes);
+    };
+    for (n, parsed_msg) in numbered(0..3).into_iter().enumerate() {
+        let ParsedMessage::Item(msg) = parsed_msg else {
+            unreachable!()
+        };
+        push_record(&msg.as_bytes());
+        if n == 0 {
+            push_record(&[1, 2, 3]);
+        }
+    }
+    std::fs::write(dir.join("0000000000.spool"), segment).unwrap();
+
+    let mut spool = Spool::open(&dir, &SpoolConfig::default()).unwrap();
+    let messages = spool.read_oldest().unwrap().unwrap();
+    assert_eq!(spooled_texts(messages), numbered_texts(0..3));
+
+    spool.mark_consumed();
+    spool.mark_consumed();
+    let messages = spool.read_oldest().unwrap().unwrap();
+    assert_eq!(spooled_texts(messages), numbered_texts(2..3));
+
+    spool.mark_consumed();
+    assert!(spool.read_oldest().unwrap().unwrap().is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn drop_newest_keeps_what_was_spooled() {
+    let dir = spool_dir("drop_newest");
+    let config = SpoolConfig {
+        spool_max_bytes: Some(record_size() * 3),
+        spool_drop_policy: Some(DropPolicy::DropNewest),
+        ..Default::default()
+    };
+    let metrics = Metrics::new("");
+    let mut spool = Spool::open(&dir, &config)
+        .unwrap()
+        .with_metrics(metrics.clone());
+    let pushed: Vec<bool> = numbered(0..5)
+        .into_iter()
+        .map(|parsed_msg| spool.push(parsed_msg).unwrap())
+        .collect();
+    assert_eq!(pushed, vec![true, true, true, false, false]);
+    assert_eq!(metrics.summary().dropped_messages, 2);
+
+    let messages = spool.read_oldest().unwrap().unwrap();
+    assert_eq!(spooled_texts(messages), numbered_texts(0..3));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn drop_oldest_discards_whole_segments() {
+    let dir = spool_dir("drop_oldest");
+    let record_size = record_size();
+    let config = SpoolConfig {
+        spool_max_bytes: Some(record_size * 4),
+        spool_segment_bytes: Some(record_size * 2),
+        spool_drop_policy: Some(DropPolicy::DropOldest),
+        ..Default::default()
+    };
+    let metrics = Metrics::new("");
+    let mut spool = Spool::open(&dir, &config)
+        .unwrap()
+        .with_metrics(metrics.clone());
+    for parsed_msg in numbered(0..5) {
+        assert!(spool.push(parsed_msg).unwrap());
+    }
+    assert_eq!(spool.len_bytes(), record_size * 3);
+    assert_eq!(segment_count(&dir), 2);
+    assert_eq!(metrics.summary().dropped_messages, 2);
+
+    let mut spooled = vec![];
+    while let Some(messages) = spool.read_oldest().unwrap() {
+        spooled.extend(messages);
+        spool.remove_oldest().unwrap();
+    }
+    assert_eq!(spooled_texts(spooled), numbered_texts(2..5));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reopening_keeps_spooled_messages() {
+    let dir = spool_dir("reopen");
+    let mut spool = Spool::open(&dir, &SpoolConfig::default()).unwrap();
+    for parsed_msg in numbered(0..2) {
+        spool.push(parsed_msg).unwrap();
+    }
+    let len_bytes = spool.len_bytes();
+    drop(spool);
+
+    let mut spool = Spool::open(&dir, &SpoolConfig::default()).unwrap();
+    assert!(!spool.is_empty());
+    assert_eq!(spool.len_bytes(), len_bytes);
+    assert!(spool.oldest_is_leftover());
+
+    // New messages go after the old ones, in a new segment
+    for parsed_msg in numbered(2..4) {
+        spool.push(parsed_msg).unwrap();
+    }
+    assert_eq!(segment_count(&dir), 2);
+    let mut spooled = spool.read_oldest().unwrap().unwrap();
+    spool.remove_oldest().unwrap();
+    assert!(!spool.oldest_is_leftover());
+    spooled.extend(spool.read_oldest().unwrap().unwrap());
+    spool.remove_oldest().unwrap();
+    assert!(spool.is_empty());
+    assert_eq!(spooled_texts(spooled), numbered_texts(0..4));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn draining_after_a_failure_sends_each_message_once() {
+    const MESSAGES: usize = 10;
+    let dir = spool_dir("drain");
+
+    // The first send fails. After the first reconnection, a few more
+    // events get through before the connection is lost again, in the
+    // middle of draining the spool; after the second, nothing fails.
+    let ingest = SharedIngest::default();
+    ingest.0.lock().await.set_fail_after(Some(0));
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    let sender = Sender::new(ingest.clone(), config(|c| c.batch_size = Some(2)));
+    let spool_config = SpoolConfig {
+        reconnect_interval_ms: Some(10),
+        ..Default::default()
+    };
+    let reconnected = ingest.clone();
+    let reconnections = connections.clone();
+    let mut spooling_sender =
+        SpoolingSender::with_connector(sender, &dir, &spool_config, move |_sender| {
+            let ingest = reconnected.clone();
+            let connection = reconnections.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                let mut mock = ingest.0.lock().await;
+                let fail_after = (connection == 0).then(|| mock.events.len() + 3);
+                mock.set_fail_after(fail_after);
+                drop(mock);
+                Ok(ingest)
+            })
+        })
+        .await
+        .unwrap();
+
+    // Keep the channel open until everything has been sent, so the
+    // sender doesn't give up reconnecting
+    let (tx, mut rx) = mpsc::channel(MESSAGES);
+    let feed = {
+        let ingest = ingest.clone();
+        async move {
+            for parsed_msg in numbered(0..MESSAGES) {
+                tx.send(parsed_msg).await.unwrap();
+            }
+            while ingest.0.lock().await.events.len() < MESSAGES {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
+    };
+    let (res, ()) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(spooling_sender.run(&mut rx), feed)
+    })
+    .await
+    .unwrap();
+    res.unwrap();
+
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    let summary = spooling_sender.sender().metrics().summary();
+    assert_eq!(summary.events_sent, MESSAGES as u64);
+    assert_eq!(texts(&*ingest.0.lock().await), numbered_texts(0..MESSAGES));
+    assert_eq!(segment_count(&dir), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,118 @@
+//! Reading records from a stream which delivers them piecemeal.
+
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use dlt_core::parse::ParsedMessage;
+use futures::StreamExt;
+use modality_dlt::{
+    stream::{DltRecord, DltStream, Framing},
+    DltPluginError,
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Delivers its data a few bytes at a time, and only every other time
+/// it's polled.
+struct Trickle {
+    data: Vec<u8>,
+    pos: usize,
+    polls: usize,
+}
+
+impl Trickle {
+    fn new(data: &[u8]) -> Self {
+        Trickle {
+            data: data.to_vec(),
+            pos: 0,
+            polls: 0,
+        }
+    }
+}
+
+impl AsyncRead for Trickle {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.polls += 1;
+        if self.polls % 2 == 0 {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        let len = (self.polls % 7 + 1)
+            .min(buf.remaining())
+            .min(self.data.len() - self.pos);
+        let pos = self.pos;
+        buf.put_slice(&self.data[pos..pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn foo_dlt() -> Vec<u8> {
+    std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("integration-test/test-data/foo.dlt"))
+        .unwrap()
+}
+
+/// What was read, in a comparable form: each record's offset, size and
+/// bytes, or the error
+fn summary(res: Result<DltRecord, DltPluginError>) -> Result<(u64, u64, Vec<u8>), String> {
+    let record = res.map_err(|e| e.to_string())?;
+    let bytes = match &record.message {
+        ParsedMessage::Item(msg) => msg.as_bytes(),
+        _ => vec![],
+    };
+    Ok((record.offset, record.size, bytes))
+}
+
+/// Read `data` all at once, and trickled in, and check both give the
+/// same records. Returns how many records (or errors) there were.
+async fn compare(data: &[u8], max_message_size: usize) -> usize {
+    // A byte slice can be read either way, so say which
+    let records =
+        DltStream::new(data, Framing::StorageHeader).with_max_message_size(max_message_size);
+    let all_at_once: Vec<_> = Iterator::map(records, summary).collect();
+    let trickled: Vec<_> = DltStream::new(Trickle::new(data), Framing::StorageHeader)
+        .with_max_message_size(max_message_size)
+        .map(summary)
+        .collect()
+        .await;
+    assert_eq!(trickled, all_at_once);
+    trickled.len()
+}
+
+#[tokio::test]
+async fn records_are_reassembled_across_reads() {
+    let data = foo_dlt();
+    let records = compare(&data, usize::MAX).await;
+    assert!(records > 1);
+}
+
+#[tokio::test]
+async fn large_messages_are_skipped_across_reads() {
+    let data = foo_dlt();
+    let max_message_size = 100;
+    let len = compare(&data, max_message_size).await;
+
+    let records = DltStream::new(data.as_slice(), Framing::StorageHeader)
+        .with_max_message_size(max_message_size);
+    let skipped = Iterator::filter(records, |res| {
+        matches!(res, Err(DltPluginError::MessageTooLarge { .. }))
+    })
+    .count();
+    assert!(skipped > 0);
+    assert!(skipped < len);
+}
+
+#[tokio::test]
+async fn truncation_is_reported_across_reads() {
+    let data = foo_dlt();
+    for len in 1..data.len() {
+        compare(&data[..len], usize::MAX).await;
+    }
+}